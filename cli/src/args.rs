@@ -1,12 +1,20 @@
 use std::fmt::Display;
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
 use std::str::FromStr;
 
 use anyhow::anyhow;
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use k_means_gpu::Algorithm;
+use k_means_gpu::Backend;
 use k_means_gpu::ColorSpace;
+use k_means_gpu::Estimation;
+use k_means_gpu::Init;
+use k_means_gpu::KCriterion;
+use k_means_gpu::KMeansOptions;
 use k_means_gpu::MixMode;
+use k_means_gpu::Reduction;
 use regex::Regex;
 
 #[derive(Parser)]
@@ -15,15 +23,18 @@ use regex::Regex;
 pub struct Cli {
     #[clap(subcommand)]
     pub commands: Commands,
+    /// Where to run the computations: auto picks the gpu when one is available
+    #[clap(long, global = true, default_value_t = Backend::Auto)]
+    pub backend: Backend,
 }
 
 #[derive(Subcommand)]
 pub enum Commands {
     /// Create an image quantized with kmeans
     Kmeans {
-        /// K value, aka the number of colors we want to extract
+        /// K value, aka the number of colors we want to extract, or auto to pick one
         #[clap(short, validator = validate_k)]
-        k: u32,
+        k: KArg,
         /// Input file
         #[clap(short, long, validator = validate_filenames, parse(from_os_str))]
         input: PathBuf,
@@ -36,12 +47,14 @@ pub enum Commands {
         /// The colorspace to use when calculating colors. Lab gives more natural colors
         #[clap(short, long="colorspace", default_value_t=ColorSpace::Lab)]
         color_space: ColorSpace,
+        #[clap(flatten)]
+        clustering: ClusteringArgs,
     },
     /// Output the palette calculated with k-means
     Palette {
-        /// K value, aka the number of colors we want to extract
+        /// K value, aka the number of colors we want to extract, or auto to pick one
         #[clap(short, validator = validate_k)]
-        k: u32,
+        k: KArg,
         /// Input file
         #[clap(short, long, validator = validate_filenames, parse(from_os_str))]
         input: PathBuf,
@@ -51,6 +64,8 @@ pub enum Commands {
         /// The colorspace to use when calculating colors. Lab gives more natural colors
         #[clap(short, long="colorspace", default_value_t=ColorSpace::Lab)]
         color_space: ColorSpace,
+        #[clap(flatten)]
+        clustering: ClusteringArgs,
     },
     /// Find colors in image that are closest to the replacements, and swap them
     Find {
@@ -69,9 +84,9 @@ pub enum Commands {
     },
     /// Quantized the image with kmeans, then mix it's resulting color.
     Mix {
-        /// K value, aka the number of colors we want to extract
+        /// K value, aka the number of colors we want to extract, or auto to pick one
         #[clap(short, validator = validate_k_for_dithering)]
-        k: u32,
+        k: KArg,
         /// Input file
         #[clap(short, long, validator = validate_filenames, parse(from_os_str))]
         input: PathBuf,
@@ -87,9 +102,222 @@ pub enum Commands {
         /// Mix function to apply on the result
         #[clap(short, long="mixmode", default_value_t=MixMode::Dither)]
         mix_mode: MixMode,
+        #[clap(flatten)]
+        clustering: ClusteringArgs,
     },
 }
 
+#[derive(Args)]
+pub struct ClusteringArgs {
+    /// Maximum number of k-means iterations
+    #[clap(long)]
+    max_iterations: Option<u32>,
+    /// Distance under which a centroid is considered converged
+    #[clap(long)]
+    convergence: Option<f32>,
+    /// Pixels with an alpha below this value (0-255) are ignored when computing the colors
+    #[clap(long)]
+    alpha_threshold: Option<u8>,
+    /// Seed of the random choices made when picking the initial colors
+    #[clap(long)]
+    seed: Option<u64>,
+    /// How the initial colors are picked: plus-plus, farthest-point, random, kmeans-parallel,
+    /// median-cut, or k starting colors chained like #ffaa12,#fe7845,#aabbff
+    #[clap(long)]
+    init: Option<InitArg>,
+    /// Number of runs from different seeds, keeping the best one
+    #[clap(long)]
+    n_init: Option<u32>,
+    /// Sum the colors in fixed point, for the same palette on every run
+    #[clap(long)]
+    deterministic: bool,
+    /// How the gpu sums the pixels of each cluster: per-centroid, single-pass, or auto for a
+    /// single pass from 16 colors
+    #[clap(long, default_value = "auto")]
+    reduction: ReductionArg,
+    /// Cluster the distinct colors weighted by their count instead of every pixel, much faster
+    /// on screenshots and pixel art of up to 4 megapixels
+    #[clap(long)]
+    unique_colors: bool,
+    /// Which pixels the palette is estimated from: full, downscale=<factor> for a copy scaled
+    /// down by that factor, or sample=<size> for about that many pixels. Kmeans and mix then
+    /// label every pixel in a single pass
+    #[clap(long, default_value = "full")]
+    estimation: EstimationArg,
+    /// How the centroids move at each iteration: lloyd, or mini-batch=<size> for random batches
+    /// of that many pixels, as fast whatever the size of the image
+    #[clap(long, default_value = "lloyd")]
+    algorithm: AlgorithmArg,
+    /// Range of k swept by -k auto, like 2-16
+    #[clap(long, default_value = "2-16")]
+    k_range: KRangeArg,
+    /// How -k auto picks k: elbow, silhouette, or mean-error=<ΔE> for the smallest k under that
+    /// mean error
+    #[clap(long, default_value = "elbow")]
+    k_criterion: KCriterionArg,
+}
+
+impl ClusteringArgs {
+    pub fn options(&self) -> KMeansOptions {
+        let mut options = KMeansOptions::new();
+        if let Some(max_iterations) = self.max_iterations {
+            options = options.max_iterations(max_iterations);
+        }
+        if let Some(convergence) = self.convergence {
+            options = options.convergence(convergence);
+        }
+        if let Some(alpha_threshold) = self.alpha_threshold {
+            options = options.alpha_threshold(alpha_threshold);
+        }
+        if let Some(seed) = self.seed {
+            options = options.seed(seed);
+        }
+        if let Some(InitArg(init)) = &self.init {
+            options = options.init(init.clone());
+        }
+        if let Some(n_init) = self.n_init {
+            options = options.n_init(n_init);
+        }
+        options
+            .deterministic(self.deterministic)
+            .reduction(self.reduction.0)
+            .unique_colors(self.unique_colors)
+            .estimation(self.estimation.0)
+            .algorithm(self.algorithm.0)
+    }
+
+    pub fn k_range(&self) -> RangeInclusive<u32> {
+        self.k_range.0.clone()
+    }
+
+    pub fn k_criterion(&self) -> KCriterion {
+        self.k_criterion.0
+    }
+}
+
+pub enum KArg {
+    Fixed(u32),
+    Auto,
+}
+
+impl FromStr for KArg {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(KArg::Auto),
+            _ => Ok(KArg::Fixed(s.parse()?)),
+        }
+    }
+}
+
+pub struct KRangeArg(RangeInclusive<u32>);
+
+impl FromStr for KRangeArg {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || anyhow!("k range should be like 2-16");
+        let (start, end) = s.split_once('-').ok_or_else(error)?;
+        let (start, end) = (start.parse::<u32>()?, end.parse::<u32>()?);
+        if start >= 1 && start <= end {
+            Ok(KRangeArg(start..=end))
+        } else {
+            Err(error())
+        }
+    }
+}
+
+pub struct KCriterionArg(KCriterion);
+
+impl FromStr for KCriterionArg {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            None if s == "elbow" => Ok(KCriterionArg(KCriterion::Elbow)),
+            None if s == "silhouette" => Ok(KCriterionArg(KCriterion::Silhouette)),
+            Some(("mean-error", target)) => {
+                Ok(KCriterionArg(KCriterion::MeanError(target.parse()?)))
+            }
+            _ => Err(anyhow!("Unsupported k criterion {s}")),
+        }
+    }
+}
+
+pub struct InitArg(Init);
+
+impl FromStr for InitArg {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plus-plus" => Ok(InitArg(Init::PlusPlus)),
+            "farthest-point" => Ok(InitArg(Init::FarthestPoint)),
+            "random" => Ok(InitArg(Init::Random)),
+            "kmeans-parallel" => Ok(InitArg(Init::KMeansParallel)),
+            "median-cut" => Ok(InitArg(Init::MedianCut)),
+            _ if validate_replacement(s).is_ok() => {
+                Ok(InitArg(Init::Colors(crate::parse_colors(s)?)))
+            }
+            _ => Err(anyhow!("Unsupported init {s}")),
+        }
+    }
+}
+
+pub struct ReductionArg(Reduction);
+
+impl FromStr for ReductionArg {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ReductionArg(Reduction::Auto)),
+            "per-centroid" => Ok(ReductionArg(Reduction::PerCentroid)),
+            "single-pass" => Ok(ReductionArg(Reduction::SinglePass)),
+            _ => Err(anyhow!("Unsupported reduction {s}")),
+        }
+    }
+}
+
+pub struct EstimationArg(Estimation);
+
+impl FromStr for EstimationArg {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let positive = |value: &str| match value.parse::<u32>()? {
+            0 => Err(anyhow!("Estimation {s} should be higher than 0")),
+            value => Ok(value),
+        };
+        match s.split_once('=') {
+            None if s == "full" => Ok(EstimationArg(Estimation::Full)),
+            Some(("downscale", factor)) => {
+                Ok(EstimationArg(Estimation::Downscale(positive(factor)?)))
+            }
+            Some(("sample", size)) => Ok(EstimationArg(Estimation::Sample(positive(size)?))),
+            _ => Err(anyhow!("Unsupported estimation {s}")),
+        }
+    }
+}
+
+pub struct AlgorithmArg(Algorithm);
+
+impl FromStr for AlgorithmArg {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            None if s == "lloyd" => Ok(AlgorithmArg(Algorithm::Lloyd)),
+            Some(("mini-batch", size)) => match size.parse()? {
+                0 => Err(anyhow!("Mini-batches should hold at least 1 pixel")),
+                size => Ok(AlgorithmArg(Algorithm::MiniBatch(size))),
+            },
+            _ => Err(anyhow!("Unsupported algorithm {s}")),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Extension {
     Png,
@@ -124,6 +352,9 @@ impl Display for Extension {
 }
 
 fn validate_k(s: &str) -> Result<()> {
+    if s == "auto" {
+        return Ok(());
+    }
     match s.parse::<u32>() {
         Ok(k) => {
             if k >= 1 {
@@ -137,6 +368,9 @@ fn validate_k(s: &str) -> Result<()> {
 }
 
 fn validate_k_for_dithering(s: &str) -> Result<()> {
+    if s == "auto" {
+        return Ok(());
+    }
     match s.parse::<u32>() {
         Ok(k) => {
             if k >= 2 {
@@ -180,12 +414,69 @@ mod tests {
         assert!(validate_replacement("").is_err());
     }
 
+    #[test]
+    fn test_init_arg() {
+        assert!(matches!("median-cut".parse(), Ok(InitArg(Init::MedianCut))));
+        assert!(matches!(
+            "#ffffff,#000000".parse(),
+            Ok(InitArg(Init::Colors(colors))) if colors.len() == 2
+        ));
+        assert!("farthest".parse::<InitArg>().is_err());
+    }
+
     #[test]
     fn test_validate_k() {
         assert!(validate_k("1").is_ok());
         assert!(validate_k("150").is_ok());
         assert!(validate_k("abs").is_err());
         assert!(validate_k("0").is_err());
+        assert!(validate_k("auto").is_ok());
+    }
+
+    #[test]
+    fn test_k_sweep_args() {
+        assert!(matches!("auto".parse(), Ok(KArg::Auto)));
+        assert!(matches!("12".parse(), Ok(KArg::Fixed(12))));
+        assert!(matches!("2-16".parse(), Ok(KRangeArg(range)) if range == (2..=16)));
+        assert!("0-16".parse::<KRangeArg>().is_err());
+        assert!("16-2".parse::<KRangeArg>().is_err());
+        assert!(matches!(
+            "mean-error=2.5".parse(),
+            Ok(KCriterionArg(KCriterion::MeanError(target))) if target == 2.5
+        ));
+        assert!("mean-error".parse::<KCriterionArg>().is_err());
+    }
+
+    #[test]
+    fn test_estimation_arg() {
+        assert!(matches!(
+            "full".parse(),
+            Ok(EstimationArg(Estimation::Full))
+        ));
+        assert!(matches!(
+            "downscale=4".parse(),
+            Ok(EstimationArg(Estimation::Downscale(4)))
+        ));
+        assert!(matches!(
+            "sample=100000".parse(),
+            Ok(EstimationArg(Estimation::Sample(100_000)))
+        ));
+        assert!("sample=0".parse::<EstimationArg>().is_err());
+        assert!("downscale".parse::<EstimationArg>().is_err());
+    }
+
+    #[test]
+    fn test_algorithm_arg() {
+        assert!(matches!(
+            "lloyd".parse(),
+            Ok(AlgorithmArg(Algorithm::Lloyd))
+        ));
+        assert!(matches!(
+            "mini-batch=4096".parse(),
+            Ok(AlgorithmArg(Algorithm::MiniBatch(4096)))
+        ));
+        assert!("mini-batch=0".parse::<AlgorithmArg>().is_err());
+        assert!("mini-batch".parse::<AlgorithmArg>().is_err());
     }
 
     #[test]
@@ -5,10 +5,10 @@ use std::{
 };
 
 use anyhow::{Ok, Result};
-use args::{Cli, Commands, Extension};
+use args::{Cli, ClusteringArgs, Commands, Extension, KArg};
 use clap::Parser;
 use image::{ImageBuffer, Rgba};
-use k_means_gpu::{find, kmeans, mix, palette, ColorSpace, Image, MixMode};
+use k_means_gpu::{ColorSpace, Image, KMeansContext, MixMode};
 use pollster::FutureExt;
 
 mod args;
@@ -17,6 +17,7 @@ fn main() -> Result<()> {
     env_logger::init();
 
     let cli = Cli::parse();
+    let context = KMeansContext::with_backend(cli.backend).block_on()?;
 
     match cli.commands {
         Commands::Kmeans {
@@ -25,19 +26,30 @@ fn main() -> Result<()> {
             output,
             extension,
             color_space,
-        } => kmeans_subcommand(k, input, output, extension, color_space).block_on(),
+            clustering,
+        } => kmeans_subcommand(
+            &context,
+            k,
+            input,
+            output,
+            extension,
+            color_space,
+            clustering,
+        )
+        .block_on(),
         Commands::Palette {
             k,
             input,
             output,
             color_space,
-        } => palette_subcommand(k, input, output, color_space).block_on(),
+            clustering,
+        } => palette_subcommand(&context, k, input, output, color_space, clustering).block_on(),
         Commands::Find {
             input,
             output,
             replacement,
             color_space,
-        } => find_subcommand(input, output, replacement, color_space).block_on(),
+        } => find_subcommand(&context, input, output, replacement, color_space).block_on(),
         Commands::Mix {
             k,
             input,
@@ -45,22 +57,38 @@ fn main() -> Result<()> {
             extension,
             color_space,
             mix_mode,
-        } => mix_subcommand(k, input, output, extension, color_space, mix_mode).block_on(),
+            clustering,
+        } => mix_subcommand(
+            &context,
+            k,
+            input,
+            output,
+            extension,
+            color_space,
+            mix_mode,
+            clustering,
+        )
+        .block_on(),
     }?;
 
     Ok(())
 }
 
 async fn kmeans_subcommand(
-    k: u32,
+    context: &KMeansContext<'_>,
+    k: KArg,
     input: PathBuf,
     output: Option<PathBuf>,
     extension: Option<Extension>,
     color_space: ColorSpace,
+    clustering: ClusteringArgs,
 ) -> Result<()> {
     let image = Image::open(&input)?;
+    let k = choose_k(context, k, &image, &color_space, &clustering).await?;
 
-    let result = kmeans(k, &image, &color_space).await?;
+    let result = context
+        .kmeans(k, &image, &color_space, &clustering.options())
+        .await?;
     let (width, height) = result.dimensions();
 
     if let Some(output_image) =
@@ -74,14 +102,19 @@ async fn kmeans_subcommand(
 }
 
 async fn palette_subcommand(
-    k: u32,
+    context: &KMeansContext<'_>,
+    k: KArg,
     input: PathBuf,
     output: Option<PathBuf>,
     color_space: ColorSpace,
+    clustering: ClusteringArgs,
 ) -> Result<()> {
     let image = Image::open(&input)?;
+    let k = choose_k(context, k, &image, &color_space, &clustering).await?;
 
-    let result = palette(k, &image, &color_space).await?;
+    let result = context
+        .palette(k, &image, &color_space, &clustering.options())
+        .await?;
 
     let path = palette_file(k, &input, &output, &color_space)?;
     save_palette(path, &result)?;
@@ -98,6 +131,7 @@ async fn palette_subcommand(
 }
 
 async fn find_subcommand(
+    context: &KMeansContext<'_>,
     input: PathBuf,
     output: Option<PathBuf>,
     replacement: String,
@@ -107,7 +141,7 @@ async fn find_subcommand(
 
     let image = Image::open(&input)?;
 
-    let result = find(&image, &colors, &color_space).await?;
+    let result = context.find(&image, &colors, &color_space).await?;
 
     let (width, height) = result.dimensions();
 
@@ -121,17 +155,23 @@ async fn find_subcommand(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn mix_subcommand(
-    k: u32,
+    context: &KMeansContext<'_>,
+    k: KArg,
     input: PathBuf,
     output: Option<PathBuf>,
     extension: Option<Extension>,
     color_space: ColorSpace,
     mix_mode: MixMode,
+    clustering: ClusteringArgs,
 ) -> Result<()> {
     let image = Image::open(&input)?;
+    let k = choose_k(context, k, &image, &color_space, &clustering).await?;
 
-    let result = mix(k, &image, &color_space, &mix_mode).await?;
+    let result = context
+        .mix(k, &image, &color_space, &mix_mode, &clustering.options())
+        .await?;
     let (width, height) = result.dimensions();
 
     if let Some(output_image) =
@@ -151,6 +191,32 @@ async fn mix_subcommand(
     Ok(())
 }
 
+/// Resolves `-k auto` by sweeping the k range of the clustering args.
+async fn choose_k(
+    context: &KMeansContext<'_>,
+    k: KArg,
+    image: &Image,
+    color_space: &ColorSpace,
+    clustering: &ClusteringArgs,
+) -> Result<u32> {
+    match k {
+        KArg::Fixed(k) => Ok(k),
+        KArg::Auto => {
+            let sweep = context
+                .choose_k(
+                    clustering.k_range(),
+                    image,
+                    color_space,
+                    &clustering.k_criterion(),
+                    &clustering.options(),
+                )
+                .await?;
+            println!("Chosen k: {}", sweep.k);
+            Ok(sweep.k)
+        }
+    }
+}
+
 fn output_file(
     k: u32,
     function: &str,
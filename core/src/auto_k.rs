@@ -0,0 +1,332 @@
+use log::debug;
+use std::ops::RangeInclusive;
+
+use crate::{
+    cpu::convert,
+    init::{random, squared_distance},
+    modules::{ClusterErrors, ClusterStatsModule},
+    tiles::{downsample, downsample_stride},
+    CentroidsBuffer, ColorSpace, GpuContext, Image, ImageTextures, InputTexture, KMeansOptions,
+    Result, Timestamps,
+};
+
+/// Pixels sampled to estimate the silhouette.
+const SILHOUETTE_SAMPLE_SIZE: u32 = 1024;
+
+/// How [`crate::KMeansContext::choose_k`] picks k out of the sweep.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KCriterion {
+    /// The elbow of the inertia curve: once both axes are normalized, the k the furthest below
+    /// the line joining both ends of the curve.
+    Elbow,
+    /// The highest mean silhouette, estimated on a sample of the pixels. Never picks a k of 1.
+    Silhouette,
+    /// The smallest k whose mean ΔE between the pixels and their centroid is below the target,
+    /// measured in Lab whatever the color space the clustering works in. The sweep stops at
+    /// that k.
+    MeanError(f32),
+}
+
+/// How well the image is clustered with one k of the sweep.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KScore {
+    pub k: u32,
+    /// Sum of the squared distances between each pixel and its centroid, in the work color
+    /// space.
+    pub inertia: f64,
+    /// Mean ΔE between each pixel and its centroid, whatever the color space.
+    pub mean_error: f64,
+    /// Mean silhouette of the sampled pixels, from -1 to 1, higher being better separated.
+    /// `None` for a k of 1.
+    pub silhouette: Option<f64>,
+}
+
+/// The k picked by [`crate::KMeansContext::choose_k`], along with the score of each k swept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KSweep {
+    pub k: u32,
+    pub curve: Vec<KScore>,
+}
+
+impl KCriterion {
+    /// Whether the sweep can stop at this score.
+    pub(crate) fn reached(&self, score: &KScore) -> bool {
+        match self {
+            KCriterion::MeanError(target) => score.mean_error <= *target as f64,
+            _ => false,
+        }
+    }
+
+    /// Picks a k out of the curve, the smallest one on ties.
+    fn pick(&self, curve: &[KScore]) -> u32 {
+        let first = &curve[0];
+        let last = &curve[curve.len() - 1];
+        let best = match self {
+            KCriterion::Elbow => {
+                let k_range = (last.k - first.k) as f64;
+                let inertia_range = first.inertia - last.inertia;
+                if k_range == 0.0 || inertia_range <= 0.0 {
+                    first
+                } else {
+                    curve
+                        .iter()
+                        .rev()
+                        .map(|score| {
+                            let x = (score.k - first.k) as f64 / k_range;
+                            let y = (score.inertia - last.inertia) / inertia_range;
+                            (score, 1.0 - x - y)
+                        })
+                        .max_by(|a, b| a.1.total_cmp(&b.1))
+                        .map_or(first, |(score, _)| score)
+                }
+            }
+            KCriterion::Silhouette => curve
+                .iter()
+                .rev()
+                .filter_map(|score| score.silhouette.map(|silhouette| (score, silhouette)))
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map_or(first, |(score, _)| score),
+            KCriterion::MeanError(_) => curve
+                .iter()
+                .find(|score| self.reached(score))
+                .unwrap_or(last),
+        };
+        best.k
+    }
+}
+
+impl KSweep {
+    pub(crate) fn new(curve: Vec<KScore>, criterion: &KCriterion) -> Self {
+        Self {
+            k: criterion.pick(&curve),
+            curve,
+        }
+    }
+}
+
+/// Opaque enough pixels picked at random, in the work color space.
+pub(crate) fn silhouette_sample(
+    image: &Image,
+    color_space: &ColorSpace,
+    options: &KMeansOptions,
+) -> Vec<[f32; 3]> {
+    let included = image
+        .rgba
+        .iter()
+        .filter(|rgba| rgba[3] >= options.alpha_threshold)
+        .copied()
+        .collect::<Vec<_>>();
+    if included.is_empty() {
+        return Vec::new();
+    }
+
+    let sample = (0..SILHOUETTE_SAMPLE_SIZE)
+        .map(|index| included[random(options.seed, index) as usize % included.len()])
+        .collect::<Vec<_>>();
+    convert(&Image::new((sample.len() as u32, 1), sample), color_space)
+}
+
+/// Scores the clusters of one k, from the errors of every pixel and the silhouette of the sample.
+pub(crate) fn score(
+    k: u32,
+    errors: &ClusterErrors,
+    centroids: &[[f32; 3]],
+    sample: &[[f32; 3]],
+) -> KScore {
+    KScore {
+        k,
+        inertia: errors.inertia,
+        mean_error: if errors.pixels > 0 {
+            errors.distance / errors.pixels as f64
+        } else {
+            0.0
+        },
+        silhouette: (k > 1).then(|| silhouette(centroids, sample)),
+    }
+}
+
+/// Mean silhouette of the sample, each pixel belonging to its closest centroid.
+fn silhouette(centroids: &[[f32; 3]], sample: &[[f32; 3]]) -> f64 {
+    if sample.is_empty() {
+        return 0.0;
+    }
+
+    let labels = sample
+        .iter()
+        .map(|pixel| {
+            centroids
+                .iter()
+                .map(|centroid| squared_distance(pixel, centroid))
+                .enumerate()
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .map_or(0, |(index, _)| index)
+        })
+        .collect::<Vec<_>>();
+    let mut sizes = vec![0usize; centroids.len()];
+    for &label in &labels {
+        sizes[label] += 1;
+    }
+
+    let mut total = 0.0;
+    let mut distances = vec![0.0f64; centroids.len()];
+    for (pixel, &label) in sample.iter().zip(&labels) {
+        // Alone in its cluster, the silhouette of a pixel is 0.
+        if sizes[label] < 2 {
+            continue;
+        }
+
+        distances.fill(0.0);
+        for (other, &other_label) in sample.iter().zip(&labels) {
+            distances[other_label] += squared_distance(pixel, other).sqrt() as f64;
+        }
+        let a = distances[label] / (sizes[label] - 1) as f64;
+        let b = distances
+            .iter()
+            .zip(&sizes)
+            .enumerate()
+            .filter(|&(other_label, (_, &size))| other_label != label && size > 0)
+            .map(|(_, (distance, &size))| distance / size as f64)
+            .min_by(|a, b| a.total_cmp(b));
+        if let Some(b) = b {
+            let max = a.max(b);
+            if max > 0.0 {
+                total += (b - a) / max;
+            }
+        }
+    }
+    total / sample.len() as f64
+}
+
+impl GpuContext<'_> {
+    /// Converts the image once, then clusters it with every k of the sweep. Images too large for
+    /// a texture are swept downsampled, and estimated ones only over their estimate.
+    pub(crate) async fn choose_k(
+        &self,
+        ks: RangeInclusive<u32>,
+        image: &Image,
+        color_space: &ColorSpace,
+        criterion: &KCriterion,
+        options: &KMeansOptions,
+    ) -> Result<KSweep> {
+        let stride = downsample_stride(image.dimensions, self.tile_size());
+        let downsampled;
+        let image = if self.needs_tiles(image) {
+            downsampled = downsample(image, self.tile_size());
+            &downsampled
+        } else {
+            image
+        };
+        let device = &self.device;
+        let queue = &self.queue;
+
+        let input_texture = InputTexture::new(device, queue, image);
+        // The sweep reports no timings.
+        let timestamps = Timestamps::new(self, 0);
+        let (textures, unique_textures) = match self.estimate(
+            image.dimensions,
+            color_space,
+            &input_texture.view(),
+            stride,
+            options,
+            &timestamps,
+        )? {
+            Some(textures) => (textures, None),
+            None => {
+                let textures = ImageTextures::new(device, image.dimensions);
+                self.convert(
+                    image.dimensions,
+                    color_space,
+                    &input_texture.view(),
+                    &textures.work_texture,
+                    options,
+                    &timestamps,
+                )?;
+                (textures, self.unique_textures(image, color_space, options))
+            }
+        };
+        let dimensions = textures.dimensions;
+
+        let sample = silhouette_sample(image, color_space, options);
+        let mut curve = Vec::new();
+        for k in ks {
+            let centroids_buffer = CentroidsBuffer::empty_centroids(k, device);
+            self.cluster_converted(
+                k,
+                dimensions,
+                color_space,
+                &textures.work_texture,
+                &textures.color_index_texture,
+                &centroids_buffer,
+                unique_textures.as_ref(),
+                options,
+                &timestamps,
+            )
+            .await?;
+
+            let errors = ClusterStatsModule::new(
+                device,
+                &self.pipelines.cluster_stats,
+                dimensions,
+                k,
+                color_space,
+                &textures.work_texture,
+                &centroids_buffer,
+                &textures.color_index_texture,
+            )
+            .errors(device, queue)
+            .await?;
+            let centroids = self.read_centroids(&centroids_buffer).await?;
+
+            let score = score(k, &errors, &centroids, &sample);
+            debug!("k {k}: {score:?}");
+            let reached = criterion.reached(&score);
+            curve.push(score);
+            if reached {
+                break;
+            }
+        }
+
+        Ok(KSweep::new(curve, criterion))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve(inertias: &[f64]) -> Vec<KScore> {
+        inertias
+            .iter()
+            .enumerate()
+            .map(|(index, &inertia)| KScore {
+                k: index as u32 + 1,
+                inertia,
+                mean_error: inertia.sqrt(),
+                silhouette: (index > 0).then(|| 1.0 / (index as f64 - 2.5).abs()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_criteria() {
+        let curve = curve(&[100.0, 40.0, 10.0, 8.0, 7.0, 6.5]);
+        assert_eq!(KCriterion::Elbow.pick(&curve), 3);
+        assert_eq!(KCriterion::Silhouette.pick(&curve), 3);
+        assert_eq!(KCriterion::MeanError(3.0).pick(&curve), 4);
+        assert_eq!(KCriterion::MeanError(1.0).pick(&curve), 6);
+    }
+
+    #[test]
+    fn test_silhouette() {
+        let sample = [
+            [0.0, 0.0, 0.0],
+            [0.1, 0.0, 0.0],
+            [10.0, 0.0, 0.0],
+            [10.1, 0.0, 0.0],
+        ];
+        let separated = silhouette(&[[0.05, 0.0, 0.0], [10.05, 0.0, 0.0]], &sample);
+        assert!(separated > 0.95);
+        let merged = silhouette(&[[0.0, 0.0, 0.0], [0.1, 0.0, 0.0]], &sample);
+        assert!(merged < separated);
+    }
+}
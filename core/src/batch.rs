@@ -0,0 +1,182 @@
+use wgpu::{Device, Queue};
+
+use crate::{
+    CentroidsBuffer, ColorSpace, GpuContext, Image, ImageTextures, InputTexture, KMeansOptions,
+    OutputTexture, Result,
+};
+
+struct SizedInputTexture {
+    dimensions: (u32, u32),
+    texture: InputTexture,
+}
+
+/// Keeps the texture if it is still of the right size, or makes a new one.
+fn reuse(
+    texture: Option<SizedInputTexture>,
+    device: &Device,
+    dimensions: (u32, u32),
+) -> SizedInputTexture {
+    match texture {
+        Some(texture) if texture.dimensions == dimensions => texture,
+        _ => SizedInputTexture {
+            dimensions,
+            texture: InputTexture::empty(device, dimensions),
+        },
+    }
+}
+
+/// Double buffered input textures: while an image is processed from `current`, the next one is
+/// uploaded to `next`.
+#[derive(Default)]
+struct BatchInputs {
+    current: Option<SizedInputTexture>,
+    next: Option<SizedInputTexture>,
+    prefetched: bool,
+}
+
+impl BatchInputs {
+    /// Returns the texture holding the image, uploading it unless it was prefetched, and the
+    /// texture the next image should be uploaded to.
+    fn prepare<'a, 'b>(
+        &'a mut self,
+        device: &Device,
+        queue: &Queue,
+        image: &Image,
+        next: Option<&'b Image>,
+    ) -> (&'a InputTexture, Option<(&'a InputTexture, &'b Image)>) {
+        let current = match self.current.take() {
+            Some(current) if self.prefetched => current,
+            current => {
+                let current = reuse(current, device, image.dimensions);
+                current.texture.write(queue, image);
+                current
+            }
+        };
+        let current = &*self.current.insert(current);
+
+        self.prefetched = next.is_some();
+        let prefetch = next.map(|next| {
+            let texture = reuse(self.next.take(), device, next.dimensions);
+            (&self.next.insert(texture).texture, next)
+        });
+
+        (&current.texture, prefetch)
+    }
+
+    /// Moves to the next image, once the current one is done.
+    fn advance(&mut self) {
+        if self.prefetched {
+            std::mem::swap(&mut self.current, &mut self.next);
+        }
+    }
+}
+
+impl GpuContext<'_> {
+    /// The next image of the batch which can be prefetched, tiled images being uploaded tile by
+    /// tile.
+    fn prefetchable<'a>(&self, images: &'a [Image], index: usize) -> Option<&'a Image> {
+        images
+            .get(index + 1)
+            .filter(|image| !self.needs_tiles(image))
+    }
+
+    pub(crate) async fn kmeans_batch(
+        &self,
+        k: u32,
+        images: &[Image],
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Result<Vec<Image>> {
+        let device = &self.device;
+
+        let centroids_buffer = CentroidsBuffer::empty_centroids(k, device);
+        let mut inputs = BatchInputs::default();
+        let mut textures: Option<(ImageTextures, OutputTexture)> = None;
+
+        let mut results = Vec::with_capacity(images.len());
+        for (index, image) in images.iter().enumerate() {
+            if self.needs_tiles(image) {
+                results.push(self.kmeans_tiled(k, image, color_space, options).await?);
+                continue;
+            }
+
+            let reused = match textures.take() {
+                Some(reused) if reused.0.dimensions == image.dimensions => reused,
+                _ => (
+                    ImageTextures::new(device, image.dimensions),
+                    OutputTexture::new(device, image.dimensions),
+                ),
+            };
+            let (image_textures, output_texture) = &*textures.insert(reused);
+
+            let next = self.prefetchable(images, index);
+            let (input_texture, prefetch) = inputs.prepare(device, &self.queue, image, next);
+
+            let result = self
+                .kmeans_with(
+                    k,
+                    image,
+                    color_space,
+                    options,
+                    input_texture,
+                    image_textures,
+                    output_texture,
+                    &centroids_buffer,
+                    prefetch,
+                )
+                .await?;
+            results.push(result);
+            inputs.advance();
+        }
+
+        Ok(results)
+    }
+
+    pub(crate) async fn palette_batch(
+        &self,
+        k: u32,
+        images: &[Image],
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Result<Vec<Vec<[u8; 4]>>> {
+        let device = &self.device;
+
+        let centroids_buffer = CentroidsBuffer::empty_centroids(k, device);
+        let mut inputs = BatchInputs::default();
+        let mut textures: Option<ImageTextures> = None;
+
+        let mut results = Vec::with_capacity(images.len());
+        for (index, image) in images.iter().enumerate() {
+            if self.needs_tiles(image) {
+                results.push(self.palette_tiled(k, image, color_space, options).await?);
+                continue;
+            }
+
+            let reused = match textures.take() {
+                Some(reused) if reused.dimensions == image.dimensions => reused,
+                _ => ImageTextures::new(device, image.dimensions),
+            };
+            let image_textures = &*textures.insert(reused);
+
+            let next = self.prefetchable(images, index);
+            let (input_texture, prefetch) = inputs.prepare(device, &self.queue, image, next);
+
+            let result = self
+                .palette_with(
+                    k,
+                    image,
+                    color_space,
+                    options,
+                    input_texture,
+                    image_textures,
+                    &centroids_buffer,
+                    prefetch,
+                )
+                .await?;
+            results.push(result);
+            inputs.advance();
+        }
+
+        Ok(results)
+    }
+}
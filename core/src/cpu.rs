@@ -0,0 +1,1299 @@
+use log::debug;
+use std::{
+    ops::RangeInclusive,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    auto_k::{score, silhouette_sample, KCriterion, KSweep},
+    estimate::cell_size,
+    init::{
+        candidate_capacity, histogram_bin, histogram_bounds, histogram_colors, median_cut,
+        oversampling, pcg, pick_weighted, random, random_unit, recluster, sort_candidates,
+        squared_distance, HISTOGRAM_BINS, KMEANS_PARALLEL_ROUNDS, PRIORITY_BINS,
+    },
+    modules::{ClusterErrors, Convergence},
+    sort_by_lightness, Algorithm, ColorSpace, Estimation, Image, Init, KMeansOptions, KMeansResult,
+    MixMode, Phase, Result, Timings,
+};
+
+/// Runs the same algorithms as the compute shaders, on the cpu.
+///
+/// Every step mirrors its shader counterpart, down to the color conversion formulas, so both
+/// backends agree up to float rounding. It is used when no adapter is available, or on request.
+pub(crate) struct CpuContext;
+
+impl CpuContext {
+    pub(crate) fn kmeans(
+        &self,
+        k: u32,
+        image: &Image,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Result<Image> {
+        let clustering = Clustering::new(k, image, color_space, options)?;
+
+        let start = Instant::now();
+        let image = clustering.swap(image, color_space);
+        clustering.timings(start);
+        Ok(image)
+    }
+
+    pub(crate) fn palette(
+        &self,
+        k: u32,
+        image: &Image,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Result<Vec<[u8; 4]>> {
+        let clustering = Clustering::new(k, image, color_space, options)?;
+
+        let start = Instant::now();
+        let mut colors: Vec<_> = clustering
+            .centroids
+            .iter()
+            .map(|centroid| color_space.to_rgba(centroid))
+            .collect();
+        sort_by_lightness(&mut colors);
+        clustering.timings(start);
+        Ok(colors)
+    }
+
+    pub(crate) fn cluster(
+        &self,
+        k: u32,
+        image: &Image,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Result<KMeansResult> {
+        let clustering = Clustering::new(k, image, color_space, options)?;
+
+        let start = Instant::now();
+        let mut counts = vec![0; k as usize];
+        for (&label, _) in clustering
+            .labels
+            .iter()
+            .zip(&clustering.included)
+            .filter(|(_, &included)| included)
+        {
+            counts[label as usize] += 1;
+        }
+        let inertia = inertia(
+            &clustering.pixels,
+            &clustering.included,
+            &clustering.labels,
+            &clustering.centroids,
+        );
+
+        let timings = clustering.timings(start);
+        Ok(KMeansResult {
+            dimensions: image.dimensions,
+            colors: clustering
+                .centroids
+                .iter()
+                .map(|centroid| color_space.to_rgba(centroid))
+                .collect(),
+            centroids: clustering.centroids,
+            counts,
+            labels: clustering.labels,
+            inertia,
+            iterations: clustering.convergence.iterations,
+            converged: clustering.convergence.converged,
+            reseeds: clustering.convergence.reseeds,
+            timings: Some(timings),
+        })
+    }
+
+    pub(crate) fn find(
+        &self,
+        image: &Image,
+        colors: &[[u8; 4]],
+        color_space: &ColorSpace,
+    ) -> Image {
+        let pixels = convert(image, color_space);
+        let centroids = colors
+            .iter()
+            .map(|color| color_space.rgba_to_color(color))
+            .collect::<Vec<_>>();
+        let labels = find_centroids(&pixels, &centroids);
+
+        Clustering {
+            included: vec![true; pixels.len()],
+            pixels,
+            centroids,
+            labels,
+            convergence: Convergence {
+                iterations: 0,
+                converged: true,
+                reseeds: 0,
+            },
+            timings: Timings::default(),
+        }
+        .swap(image, color_space)
+    }
+
+    pub(crate) fn mix(
+        &self,
+        k: u32,
+        image: &Image,
+        color_space: &ColorSpace,
+        mix_mode: &MixMode,
+        options: &KMeansOptions,
+    ) -> Result<Image> {
+        let clustering = Clustering::new(k, image, color_space, options)?;
+        let start = Instant::now();
+        let width = image.dimensions.0 as usize;
+
+        let mixed = clustering
+            .pixels
+            .iter()
+            .enumerate()
+            .map(|(index, pixel)| {
+                let coords = (index % width, index / width);
+                match mix_mode {
+                    MixMode::Dither => dither(pixel, coords, &clustering.centroids),
+                    MixMode::Meld => meld(pixel, &clustering.centroids),
+                }
+            })
+            .zip(&image.rgba)
+            .map(|(color, rgba)| revert(&color, rgba[3], color_space))
+            .collect();
+
+        clustering.timings(start);
+        Ok(Image::new(image.dimensions, mixed))
+    }
+
+    pub(crate) fn choose_k(
+        &self,
+        ks: RangeInclusive<u32>,
+        image: &Image,
+        color_space: &ColorSpace,
+        criterion: &KCriterion,
+        options: &KMeansOptions,
+    ) -> Result<KSweep> {
+        // Estimated images are only swept over their estimate, like on the gpu.
+        let estimate = estimate(image, options);
+        let (mut pixels, mut included, _) =
+            Clustering::prepare(estimate.as_ref().unwrap_or(image), color_space, options)?;
+        let sample = silhouette_sample(image, color_space, options);
+
+        let mut curve = Vec::new();
+        for k in ks {
+            let clustering = Clustering::run(
+                k,
+                pixels,
+                included,
+                color_space,
+                options,
+                Timings::default(),
+            )?;
+            let errors = clustering.errors(color_space);
+            let score = score(k, &errors, &clustering.centroids, &sample);
+            debug!("k {k}: {score:?}");
+            let reached = criterion.reached(&score);
+            curve.push(score);
+            if reached {
+                break;
+            }
+            // The converted pixels go on to the next k.
+            (pixels, included) = (clustering.pixels, clustering.included);
+        }
+
+        Ok(KSweep::new(curve, criterion))
+    }
+}
+
+/// The image in the work color space, along with the centroids and the label of each pixel.
+struct Clustering {
+    pixels: Vec<[f32; 3]>,
+    /// Whether each pixel is opaque enough to take part in the clustering.
+    included: Vec<bool>,
+    centroids: Vec<[f32; 3]>,
+    labels: Vec<u32>,
+    convergence: Convergence,
+    timings: Timings,
+}
+
+impl Clustering {
+    /// Converts the image, then picks the initial centroids and runs the k-means iterations,
+    /// keeping the run of lowest inertia when restarting. With an estimation, they run over the
+    /// estimate, then every pixel of the image is labelled once.
+    fn new(
+        k: u32,
+        image: &Image,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Result<Self> {
+        let Some(estimate) = estimate(image, options) else {
+            let (pixels, included, timings) = Self::prepare(image, color_space, options)?;
+            return Self::run(k, pixels, included, color_space, options, timings);
+        };
+
+        let (pixels, included, timings) = Self::prepare(&estimate, color_space, options)?;
+        let mut clustering = Self::run(k, pixels, included, color_space, options, timings)?;
+        let start = Instant::now();
+        clustering.pixels = convert(image, color_space);
+        clustering.included = included_pixels(image, options);
+        clustering.labels = find_centroids(&clustering.pixels, &clustering.centroids);
+        clustering.timings.conversion += start.elapsed();
+        Ok(clustering)
+    }
+
+    /// Converts the image to the work color space, and tells which pixels are opaque enough.
+    fn prepare(
+        image: &Image,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Result<(Vec<[f32; 3]>, Vec<bool>, Timings)> {
+        let mut timings = Timings::default();
+
+        options.report_progress(Phase::Convert, 0, 0)?;
+        let start = Instant::now();
+        let pixels = convert(image, color_space);
+        let included = included_pixels(image, options);
+        timings.conversion = start.elapsed();
+
+        Ok((pixels, included, timings))
+    }
+
+    /// Picks the initial centroids of the converted pixels and runs the k-means iterations,
+    /// keeping the run of lowest inertia when restarting.
+    fn run(
+        k: u32,
+        pixels: Vec<[f32; 3]>,
+        included: Vec<bool>,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+        mut timings: Timings,
+    ) -> Result<Self> {
+        let runs = options.runs();
+        let mut best_inertia = f64::INFINITY;
+        let mut best = None;
+        for run in 0..runs {
+            // Earlier runs count in the init, like on the gpu.
+            timings.init += timings.iterations.drain(..).sum::<Duration>();
+
+            options.report_progress(Phase::Init, 0, 0)?;
+            let start = Instant::now();
+            let seed = options.seed.wrapping_add(run as u64);
+            let mut centroids = init_centroids(&pixels, &included, k, color_space, options, seed);
+            let mut labels = find_centroids(&pixels, &centroids);
+            timings.init += start.elapsed();
+
+            let convergence = options.convergence_for(color_space);
+            let mut result = Convergence {
+                iterations: options.max_iterations,
+                converged: false,
+                reseeds: 0,
+            };
+
+            let mut mini_batch = match options.algorithm {
+                Algorithm::Lloyd => None,
+                Algorithm::MiniBatch(batch_size) => Some(MiniBatch::new(k, batch_size, options)),
+            };
+            let mut converged = 0;
+            for iteration in 0..options.max_iterations {
+                options.report_progress(Phase::Iterate, iteration, converged)?;
+                let start = Instant::now();
+                if let Some(mini_batch) = &mut mini_batch {
+                    converged = mini_batch.iterate(
+                        &pixels,
+                        &included,
+                        &mut centroids,
+                        iteration + 1,
+                        convergence,
+                    );
+                } else {
+                    let reseeded;
+                    (converged, reseeded) =
+                        choose_centroids(&pixels, &included, &labels, &mut centroids, convergence);
+                    result.reseeds += reseeded;
+                    labels = find_centroids(&pixels, &centroids);
+                }
+                timings.iterations.push(start.elapsed());
+
+                // Stop at the same iteration as the gpu, so both report the same convergence.
+                if converged == k {
+                    debug!("We have convergence at iteration {iteration}");
+                    result = Convergence {
+                        iterations: iteration + 1,
+                        converged: true,
+                        reseeds: result.reseeds,
+                    };
+                    break;
+                }
+            }
+            if mini_batch.is_some() {
+                // The pixels are labelled once, after the last batch.
+                labels = find_centroids(&pixels, &centroids);
+            }
+            options.report_progress(Phase::Finalize, result.iterations, converged)?;
+
+            let inertia = inertia(&pixels, &included, &labels, &centroids);
+            if runs > 1 {
+                debug!("Run {run}: inertia {inertia}");
+            }
+            if best.is_none() || inertia < best_inertia {
+                best_inertia = inertia;
+                best = Some((centroids, labels, result));
+            }
+        }
+
+        let (centroids, labels, convergence) = best.expect("There is at least one run");
+        Ok(Self {
+            pixels,
+            included,
+            centroids,
+            labels,
+            convergence,
+            timings,
+        })
+    }
+
+    /// How far the pixels are from their centroid, like `cluster_stats.wgsl`: the inertia in
+    /// the work color space, the distances as ΔE.
+    fn errors(&self, color_space: &ColorSpace) -> ClusterErrors {
+        let mut errors = ClusterErrors {
+            inertia: 0.0,
+            distance: 0.0,
+            pixels: 0,
+        };
+        for ((pixel, &label), _) in self
+            .pixels
+            .iter()
+            .zip(&self.labels)
+            .zip(&self.included)
+            .filter(|(_, &included)| included)
+        {
+            let centroid = &self.centroids[label as usize];
+            let squared_distance = squared_distance(pixel, centroid);
+            errors.inertia += squared_distance as f64;
+            errors.distance += match color_space {
+                ColorSpace::Lab => squared_distance.sqrt(),
+                ColorSpace::Rgb => distance(
+                    &xyz_to_lab(rgb_to_xyz(*pixel)),
+                    &xyz_to_lab(rgb_to_xyz(*centroid)),
+                ),
+            } as f64;
+            errors.pixels += 1;
+        }
+        errors
+    }
+
+    /// The timings of the clustering, completed with the readback started at `start`.
+    fn timings(&self, start: Instant) -> Timings {
+        let timings = Timings {
+            readback: start.elapsed(),
+            ..self.timings.clone()
+        };
+        timings.log();
+        timings
+    }
+
+    /// Replaces each pixel of the image by its centroid, back in sRGB, keeping its alpha.
+    fn swap(&self, image: &Image, color_space: &ColorSpace) -> Image {
+        let colors = self
+            .centroids
+            .iter()
+            .map(|centroid| revert(centroid, 255, color_space))
+            .collect::<Vec<_>>();
+
+        Image::new(
+            image.dimensions,
+            self.labels
+                .iter()
+                .zip(&image.rgba)
+                .map(|(&label, rgba)| {
+                    let [r, g, b, _] = colors[label as usize];
+                    [r, g, b, rgba[3]]
+                })
+                .collect(),
+        )
+    }
+}
+
+/// The state of the mini-batch iterations, like the `seen` and `convergence` buffers of
+/// `mini_batch.wgsl`.
+struct MiniBatch {
+    batch_size: u32,
+    seed: u64,
+    /// The pixels each centroid has seen over all the batches so far.
+    seen: Vec<f32>,
+    /// Whether each centroid converged the last time it was in a batch.
+    converged: Vec<bool>,
+}
+
+impl MiniBatch {
+    fn new(k: u32, batch_size: u32, options: &KMeansOptions) -> Self {
+        Self {
+            batch_size,
+            seed: options.seed,
+            seen: vec![0.0; k as usize],
+            converged: vec![false; k as usize],
+        }
+    }
+
+    /// Samples a batch of pixels at random, then moves each centroid toward the mean of its
+    /// pixels in the batch, like the `sample` and `update` entries of `mini_batch.wgsl`. The
+    /// iterations count from 1. Returns how many centroids converged.
+    fn iterate(
+        &mut self,
+        pixels: &[[f32; 3]],
+        included: &[bool],
+        centroids: &mut [[f32; 3]],
+        iteration: u32,
+        convergence: f32,
+    ) -> u32 {
+        let mut sums = vec![([0.0f64; 3], 0u32); centroids.len()];
+        for index in 0..self.batch_size {
+            let stream = iteration.wrapping_mul(self.batch_size).wrapping_add(index);
+            let pixel_index = random(self.seed, stream) as usize % pixels.len();
+            if !included[pixel_index] {
+                continue;
+            }
+            let pixel = &pixels[pixel_index];
+            let (sum, count) = &mut sums[closest_centroid(pixel, centroids) as usize];
+            for (sum, &component) in sum.iter_mut().zip(pixel) {
+                *sum += component as f64;
+            }
+            *count += 1;
+        }
+
+        for (((centroid, (sum, count)), seen), converged) in centroids
+            .iter_mut()
+            .zip(sums)
+            .zip(&mut self.seen)
+            .zip(&mut self.converged)
+        {
+            if count == 0 {
+                continue;
+            }
+            *seen += count as f32;
+            let rate = count as f32 / *seen;
+            let mean = sum.map(|component| (component / count as f64) as f32);
+            let previous = *centroid;
+            for (component, mean) in centroid.iter_mut().zip(mean) {
+                *component += (mean - *component) * rate;
+            }
+            *converged = distance(centroid, &previous) < convergence;
+        }
+        self.converged
+            .iter()
+            .filter(|&&converged| converged)
+            .count() as u32
+    }
+}
+
+/// Whether each pixel is opaque enough to take part in the clustering.
+fn included_pixels(image: &Image, options: &KMeansOptions) -> Vec<bool> {
+    image
+        .rgba
+        .iter()
+        .map(|rgba| rgba[3] >= options.alpha_threshold)
+        .collect()
+}
+
+/// The smaller copy of the image the options cluster, like `estimate.wgsl`, or `None` when they
+/// cluster every pixel.
+fn estimate(image: &Image, options: &KMeansOptions) -> Option<Image> {
+    let cell = cell_size(&options.estimation, image.dimensions, 1)? as usize;
+    let (width, height) = (image.dimensions.0 as usize, image.dimensions.1 as usize);
+    let dimensions = (width.div_ceil(cell), height.div_ceil(cell));
+    debug!(
+        "Estimating {:?} pixels from {dimensions:?}",
+        image.dimensions
+    );
+
+    let mut texels = Vec::with_capacity(dimensions.0 * dimensions.1);
+    for y in 0..dimensions.1 {
+        for x in 0..dimensions.0 {
+            let start = (x * cell, y * cell);
+            let end = ((start.0 + cell).min(width), (start.1 + cell).min(height));
+            let pixel = |x: usize, y: usize| image.rgba[x + y * width];
+            texels.push(match options.estimation {
+                // The mean of the opaque enough pixels of the cell, transparent without any.
+                Estimation::Downscale(_) => {
+                    let mut sum = [0.0f32; 3];
+                    let mut count = 0;
+                    for y in start.1..end.1 {
+                        for x in start.0..end.0 {
+                            let rgba = pixel(x, y);
+                            if rgba[3] >= options.alpha_threshold {
+                                for (sum, &component) in sum.iter_mut().zip(&rgba) {
+                                    *sum += component as f32 / 255.0;
+                                }
+                                count += 1;
+                            }
+                        }
+                    }
+                    if count > 0 {
+                        let [r, g, b] = sum.map(|sum| (sum / count as f32 * 255.0).round() as u8);
+                        [r, g, b, 255]
+                    } else {
+                        [0; 4]
+                    }
+                }
+                // A pixel of the cell picked at random.
+                _ => {
+                    let size = (end.0 - start.0, end.1 - start.1);
+                    let stream = (x + y * dimensions.0) as u32;
+                    let picked = random(options.seed, stream) as usize % (size.0 * size.1);
+                    pixel(start.0 + picked % size.0, start.1 + picked / size.0)
+                }
+            });
+        }
+    }
+    Some(Image::new(
+        (dimensions.0 as u32, dimensions.1 as u32),
+        texels,
+    ))
+}
+
+fn distance(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+/// Weighs the included pixels 1 and the others 0, for them to be all equally likely.
+fn opaque_weights(included: &[bool]) -> Vec<f64> {
+    included
+        .iter()
+        .map(|&included| if included { 1.0 } else { 0.0 })
+        .collect()
+}
+
+/// A pixel picked with the prefix sum of `plus_plus_sample.wgsl`, each stream drawing its own
+/// threshold. The first pixel when none can be picked.
+fn random_pixel(weights: &[f64], seed: u64, stream: u32) -> usize {
+    pick_weighted(weights, random_unit(seed, stream)).unwrap_or_default()
+}
+
+/// Picks the initial centroids the same way as the gpu.
+fn init_centroids(
+    pixels: &[[f32; 3]],
+    included: &[bool],
+    k: u32,
+    color_space: &ColorSpace,
+    options: &KMeansOptions,
+    seed: u64,
+) -> Vec<[f32; 3]> {
+    match &options.init {
+        Init::PlusPlus | Init::FarthestPoint => {
+            plus_plus_init(pixels, included, k, &options.init, seed)
+        }
+        Init::Random => {
+            let weights = opaque_weights(included);
+            (0..k)
+                .map(|index| pixels[random_pixel(&weights, seed, index)])
+                .collect()
+        }
+        Init::KMeansParallel => kmeans_parallel_init(pixels, included, k, seed),
+        Init::MedianCut => {
+            let bounds = histogram_bounds(color_space);
+            let mut histogram = vec![0; HISTOGRAM_BINS.pow(3)];
+            for (pixel, _) in pixels
+                .iter()
+                .zip(included)
+                .filter(|(_, &included)| included)
+            {
+                histogram[histogram_bin(pixel, &bounds)] += 1;
+            }
+            median_cut(&histogram_colors(&histogram, &bounds), k)
+        }
+        Init::Colors(colors) => colors
+            .iter()
+            .map(|color| color_space.rgba_to_color(color))
+            .collect(),
+    }
+}
+
+fn plus_plus_init(
+    pixels: &[[f32; 3]],
+    included: &[bool],
+    k: u32,
+    init: &Init,
+    seed: u64,
+) -> Vec<[f32; 3]> {
+    // The first centroid is any included pixel, all equally likely.
+    let first = random_pixel(&opaque_weights(included), seed, 0);
+    let mut centroids = Vec::with_capacity(k as usize);
+    centroids.push(pixels[first]);
+
+    // Transparent pixels can never be picked.
+    let mut distance_map = included
+        .iter()
+        .map(|&included| if included { f32::MAX } else { -1.0 })
+        .collect::<Vec<_>>();
+    for k_index in 1..k {
+        let last_centroid = centroids[centroids.len() - 1];
+        let mut furthest = (0, -1.0);
+        for (index, (pixel, min_distance)) in pixels.iter().zip(&mut distance_map).enumerate() {
+            if *min_distance < 0.0 {
+                continue;
+            }
+            *min_distance = min_distance.min(distance(pixel, &last_centroid));
+            if furthest.1 < *min_distance {
+                furthest = (index, *min_distance);
+            }
+        }
+        let picked = match init {
+            Init::FarthestPoint => Some(furthest.0),
+            _ => {
+                let weights = distance_map
+                    .iter()
+                    .map(|distance| distance.max(0.0).powi(2) as f64)
+                    .collect::<Vec<_>>();
+                pick_weighted(&weights, random_unit(seed, k_index))
+            }
+        };
+        centroids.push(picked.map_or(last_centroid, |index| pixels[index]));
+    }
+
+    centroids
+}
+
+/// k-means||: a few rounds sampling many candidates at once, each pixel with a probability
+/// proportional to its squared distance to the closest candidate, then reduced to k centroids.
+fn kmeans_parallel_init(
+    pixels: &[[f32; 3]],
+    included: &[bool],
+    k: u32,
+    seed: u64,
+) -> Vec<[f32; 3]> {
+    let capacity = candidate_capacity(k) as usize;
+    // The first candidate is the first centroid of the ++ init.
+    let mut candidates = vec![pixels[random_pixel(&opaque_weights(included), seed, 0)]];
+    let mut distances = vec![f32::MAX; pixels.len()];
+    let mut checked = 0;
+    for round in 0..KMEANS_PARALLEL_ROUNDS {
+        for ((pixel, distance), &included) in pixels.iter().zip(&mut distances).zip(included) {
+            *distance = if included {
+                candidates[checked..]
+                    .iter()
+                    .fold(*distance, |min, candidate| {
+                        min.min(squared_distance(pixel, candidate))
+                    })
+            } else {
+                0.0
+            };
+        }
+        checked = candidates.len();
+
+        let total = distances.iter().sum::<f32>();
+        if total <= 0.0 {
+            continue;
+        }
+        // The priority bin of each pixel sampled, see `priority` in kmeans_parallel.wgsl.
+        let bins = distances
+            .iter()
+            .enumerate()
+            .map(|(index, &distance)| {
+                let probability = oversampling(k) * distance / total;
+                let value = random_unit(seed, pcg(round) ^ index as u32);
+                let priority = value / probability;
+                (distance > 0.0 && value < probability && priority < 1.0)
+                    .then(|| ((priority * PRIORITY_BINS as f32) as usize).min(PRIORITY_BINS - 1))
+            })
+            .collect::<Vec<_>>();
+        let limit = priority_limit(&bins, capacity - candidates.len());
+        candidates.extend(
+            bins.iter()
+                .zip(pixels)
+                .filter(|(bin, _)| bin.is_some_and(|bin| bin < limit))
+                .map(|(_, pixel)| *pixel),
+        );
+    }
+
+    let mut weights = vec![0.0; candidates.len()];
+    for (pixel, _) in pixels
+        .iter()
+        .zip(included)
+        .filter(|(_, &included)| included)
+    {
+        weights[closest_centroid(pixel, &candidates) as usize] += 1.0;
+    }
+    let mut weighted = candidates.into_iter().zip(weights).collect::<Vec<_>>();
+    sort_candidates(&mut weighted);
+    recluster(&weighted, k, seed)
+}
+
+/// The bins of priorities kept, from the highest, as many samples as `remaining` holds. Same as
+/// the `threshold` pass of kmeans_parallel.wgsl.
+fn priority_limit(bins: &[Option<usize>], remaining: usize) -> usize {
+    let mut counts = [0; PRIORITY_BINS];
+    for &bin in bins.iter().flatten() {
+        counts[bin] += 1;
+    }
+    let mut kept = 0;
+    counts
+        .iter()
+        .take_while(|&&count| {
+            kept += count;
+            kept <= remaining
+        })
+        .count()
+}
+
+/// The sum of the squared distances between each pixel and its centroid.
+fn inertia(pixels: &[[f32; 3]], included: &[bool], labels: &[u32], centroids: &[[f32; 3]]) -> f64 {
+    pixels
+        .iter()
+        .zip(labels)
+        .zip(included)
+        .filter(|(_, &included)| included)
+        .map(|((pixel, &label), _)| squared_distance(pixel, &centroids[label as usize]) as f64)
+        .sum()
+}
+
+fn closest_centroid(pixel: &[f32; 3], centroids: &[[f32; 3]]) -> u32 {
+    let mut min_distance = f32::MAX;
+    let mut found_index = 0;
+    for (index, centroid) in centroids.iter().enumerate() {
+        let distance = distance(pixel, centroid);
+        if distance < min_distance {
+            min_distance = distance;
+            found_index = index as u32;
+        }
+    }
+    found_index
+}
+
+fn find_centroids(pixels: &[[f32; 3]], centroids: &[[f32; 3]]) -> Vec<u32> {
+    pixels
+        .iter()
+        .map(|pixel| closest_centroid(pixel, centroids))
+        .collect()
+}
+
+/// The pixel of each cluster the farthest from its centroid, the first one on ties, with that
+/// distance, like the `measure_farthest` and `select_farthest` entries of `choose_centroid.wgsl`.
+/// `None` for the clusters whose pixels all sit on their centroid.
+fn farthest_pixels(
+    pixels: &[[f32; 3]],
+    included: &[bool],
+    labels: &[u32],
+    centroids: &[[f32; 3]],
+) -> Vec<Option<(usize, f32)>> {
+    let mut farthest = vec![None; centroids.len()];
+    for (index, ((pixel, &label), _)) in pixels
+        .iter()
+        .zip(labels)
+        .zip(included)
+        .enumerate()
+        .filter(|(_, (_, &included))| included)
+    {
+        let distance = distance(pixel, &centroids[label as usize]);
+        let max_distance = farthest[label as usize].map_or(0.0, |(_, distance)| distance);
+        if distance > max_distance {
+            farthest[label as usize] = Some((index, distance));
+        }
+    }
+    farthest
+}
+
+/// Moves each centroid to the mean of its pixels, and returns how many of them moved by less
+/// than `convergence`. Empty clusters never count as converged: like the `reseed` entry of
+/// `choose_centroid.wgsl`, each of them moves to the farthest pixel of a distinct cluster,
+/// measured from the moved centroids, the farthest first. When the other clusters have too few
+/// pixels off their centroid, the remaining empty clusters wait for the next iterations. Also
+/// returns how many clusters moved that way.
+fn choose_centroids(
+    pixels: &[[f32; 3]],
+    included: &[bool],
+    labels: &[u32],
+    centroids: &mut [[f32; 3]],
+    convergence: f32,
+) -> (u32, u32) {
+    let mut sums = vec![([0.0f64; 3], 0u32); centroids.len()];
+    for ((pixel, &label), _) in pixels
+        .iter()
+        .zip(labels)
+        .zip(included)
+        .filter(|(_, &included)| included)
+    {
+        let (sum, count) = &mut sums[label as usize];
+        for (sum, &component) in sum.iter_mut().zip(pixel) {
+            *sum += component as f64;
+        }
+        *count += 1;
+    }
+
+    let mut converged = 0;
+    let mut empties = Vec::new();
+    for (index, (centroid, (sum, count))) in centroids.iter_mut().zip(sums).enumerate() {
+        if count > 0 {
+            let new_centroid = sum.map(|component| (component / count as f64) as f32);
+            if distance(&new_centroid, centroid) < convergence {
+                converged += 1;
+            }
+            *centroid = new_centroid;
+        } else {
+            empties.push(index);
+        }
+    }
+    if empties.is_empty() {
+        return (converged, 0);
+    }
+
+    let mut farthest = farthest_pixels(pixels, included, labels, centroids);
+    let mut reseeded = 0;
+    for empty in empties {
+        // The lowest cluster on ties.
+        let mut candidate = None;
+        let mut max_distance = 0.0;
+        for (cluster, pixel) in farthest.iter().enumerate() {
+            if let Some((pixel, distance)) = *pixel {
+                if distance > max_distance {
+                    candidate = Some((cluster, pixel));
+                    max_distance = distance;
+                }
+            }
+        }
+        let Some((cluster, pixel)) = candidate else {
+            break;
+        };
+        centroids[empty] = pixels[pixel];
+        farthest[cluster] = None;
+        reseeded += 1;
+    }
+    (converged, reseeded)
+}
+
+const INDEX_MATRIX: [u8; 16] = [0, 8, 2, 10, 12, 4, 14, 6, 3, 11, 1, 9, 15, 7, 13, 5];
+
+fn index_value(coords: (usize, usize)) -> f32 {
+    INDEX_MATRIX[coords.0 % 4 + (coords.1 % 4) * 4] as f32 / 16.0
+}
+
+/// Same as `dither2` in `mix_colors.wgsl`.
+fn dither(color: &[f32; 3], coords: (usize, usize), centroids: &[[f32; 3]]) -> [f32; 3] {
+    let threshold = 100.0 / (centroids.len() as f32).sqrt();
+    let index_value = index_value(coords) - 0.5;
+
+    let adjusted = [color[0] + threshold * index_value, color[1], color[2]];
+
+    let mut closest = [10000.0; 3];
+    for centroid in centroids {
+        if distance(&adjusted, centroid) < distance(&adjusted, &closest) {
+            closest = *centroid;
+        }
+    }
+    closest
+}
+
+fn meld(color: &[f32; 3], centroids: &[[f32; 3]]) -> [f32; 3] {
+    let mut closest = [10000.0; 3];
+    let mut second_closest = [10000.0; 3];
+    for centroid in centroids {
+        let centroid_distance = distance(color, centroid);
+        if centroid_distance < distance(color, &closest) {
+            second_closest = closest;
+            closest = *centroid;
+        } else if centroid_distance < distance(color, &second_closest) {
+            second_closest = *centroid;
+        }
+    }
+
+    let factor = distance(color, &second_closest) / distance(&closest, &second_closest);
+    [0, 1, 2].map(|i| factor * closest[i] + (1.0 - factor) * second_closest[i])
+}
+
+pub(crate) fn convert(image: &Image, color_space: &ColorSpace) -> Vec<[f32; 3]> {
+    image
+        .rgba
+        .iter()
+        .map(|rgba| {
+            let rgb = [0, 1, 2].map(|i| rgba[i] as f32 / 255.0);
+            match color_space {
+                ColorSpace::Lab => xyz_to_lab(rgb_to_xyz(rgb)),
+                ColorSpace::Rgb => rgb,
+            }
+        })
+        .collect()
+}
+
+/// Converts back to sRGB, the way an `rgba8unorm` storage texture stores the value.
+fn revert(color: &[f32; 3], alpha: u8, color_space: &ColorSpace) -> [u8; 4] {
+    let rgb = match color_space {
+        ColorSpace::Lab => xyz_to_rgb(lab_to_xyz(*color)),
+        ColorSpace::Rgb => *color,
+    };
+    let [r, g, b] = rgb.map(|component| (component.clamp(0.0, 1.0) * 255.0).round() as u8);
+    [r, g, b, alpha]
+}
+
+fn rgb_to_xyz(rgb: [f32; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb.map(|component| {
+        let linear = if component > 0.04045 {
+            ((component + 0.055) / 1.055).powf(2.4)
+        } else {
+            component / 12.92
+        };
+        linear * 100.0
+    });
+
+    [
+        r * 0.4124 + g * 0.3576 + b * 0.1805,
+        r * 0.2126 + g * 0.7152 + b * 0.0722,
+        r * 0.0193 + g * 0.1192 + b * 0.9505,
+    ]
+}
+
+fn xyz_to_lab(xyz: [f32; 3]) -> [f32; 3] {
+    let [x, y, z] = [xyz[0] / 95.047, xyz[1] / 100.0, xyz[2] / 108.883].map(|component| {
+        if component > 0.008856 {
+            component.powf(1.0 / 3.0)
+        } else {
+            7.787 * component + 16.0 / 116.0
+        }
+    });
+
+    [116.0 * y - 16.0, 500.0 * (x - y), 200.0 * (y - z)]
+}
+
+fn lab_to_xyz(lab: [f32; 3]) -> [f32; 3] {
+    let y = (lab[0] + 16.0) / 116.0;
+    let x = lab[1] / 500.0 + y;
+    let z = y - lab[2] / 200.0;
+
+    let [x, y, z] = [x, y, z].map(|component| {
+        if component.powi(3) > 0.008856 {
+            component.powi(3)
+        } else {
+            (component - 16.0 / 116.0) / 7.787
+        }
+    });
+
+    [x * 95.047, y * 100.0, z * 108.883]
+}
+
+fn xyz_to_rgb(xyz: [f32; 3]) -> [f32; 3] {
+    let [x, y, z] = xyz.map(|component| component / 100.0);
+
+    [
+        x * 3.2406 + y * -1.5372 + z * -0.4986,
+        x * -0.9689 + y * 1.8758 + z * 0.0415,
+        x * 0.0557 + y * -0.2040 + z * 1.0570,
+    ]
+    .map(|component| {
+        if component > 0.0031308 {
+            1.055 * component.powf(1.0 / 2.4) - 0.055
+        } else {
+            12.92 * component
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CancellationToken, Error};
+    use std::sync::{Arc, Mutex};
+
+    fn two_colors_image() -> Image {
+        let rgba = (0..64)
+            .map(|i| {
+                if i % 3 == 0 {
+                    [200, 30, 40, 255]
+                } else {
+                    [20, 90, 180, 255]
+                }
+            })
+            .collect();
+        Image::new((8, 8), rgba)
+    }
+
+    #[test]
+    fn test_lab_round_trip() {
+        for rgba in [[0, 0, 0, 255], [255, 255, 255, 255], [12, 200, 99, 255]] {
+            let image = Image::new((1, 1), vec![rgba]);
+            let lab = convert(&image, &ColorSpace::Lab);
+            let reverted = revert(&lab[0], 255, &ColorSpace::Lab);
+            for (a, b) in reverted.iter().zip(rgba) {
+                assert!(
+                    (*a as i16 - b as i16).abs() <= 1,
+                    "{reverted:?} != {rgba:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_kmeans_finds_exact_colors() {
+        let image = two_colors_image();
+
+        let inits = [
+            Init::PlusPlus,
+            Init::FarthestPoint,
+            Init::KMeansParallel,
+            Init::MedianCut,
+            Init::Colors(vec![[190, 40, 40, 255], [30, 80, 170, 255]]),
+        ];
+        for init in inits {
+            for color_space in [ColorSpace::Lab, ColorSpace::Rgb] {
+                let options = KMeansOptions::new().init(init.clone());
+                let result = CpuContext
+                    .kmeans(2, &image, &color_space, &options)
+                    .unwrap();
+                assert_eq!(result.rgba, image.rgba);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mini_batch() {
+        let image = two_colors_image();
+        let options = KMeansOptions::new()
+            .init(Init::Colors(vec![[190, 40, 40, 255], [30, 80, 170, 255]]))
+            .algorithm(Algorithm::MiniBatch(16));
+        let result = CpuContext
+            .cluster(2, &image, &ColorSpace::Lab, &options)
+            .unwrap();
+        assert!(result.converged());
+        assert_eq!(result.reseeds(), 0);
+        assert_eq!(result.counts(), [22, 42]);
+    }
+
+    #[test]
+    fn test_estimation() {
+        let image = two_colors_image();
+        for estimation in [Estimation::Sample(16), Estimation::Downscale(1)] {
+            let options = KMeansOptions::new().estimation(estimation);
+            let result = CpuContext
+                .kmeans(2, &image, &ColorSpace::Lab, &options)
+                .unwrap();
+            // Every pixel is labelled, not only the estimate.
+            assert_eq!(result.rgba, image.rgba);
+        }
+    }
+
+    #[test]
+    fn test_priority_limit() {
+        let bins = [Some(2), None, Some(0), Some(1), Some(1), Some(3), None];
+        assert_eq!(priority_limit(&bins, 5), PRIORITY_BINS);
+        assert_eq!(priority_limit(&bins, 4), 3);
+        // The whole bin 1 is dropped rather than part of it.
+        assert_eq!(priority_limit(&bins, 2), 1);
+        assert_eq!(priority_limit(&bins, 0), 0);
+    }
+
+    #[test]
+    fn test_random_init() {
+        let image = two_colors_image();
+        let options = KMeansOptions::new().init(Init::Random).max_iterations(1);
+        let palette = CpuContext
+            .palette(3, &image, &ColorSpace::Rgb, &options)
+            .unwrap();
+        assert_eq!(palette.len(), 3);
+        for color in palette {
+            assert!(image.rgba.contains(&color));
+        }
+
+        let options = KMeansOptions::new().init(Init::Colors(vec![[0, 0, 0, 255]]));
+        assert!(matches!(
+            options.validate(2),
+            Err(Error::InitColors { k: 2, colors: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_transparent_pixels_are_ignored() {
+        let mut image = two_colors_image();
+        for rgba in image.rgba.iter_mut().step_by(4) {
+            *rgba = [0, 255, 0, 0];
+        }
+
+        let options = KMeansOptions::new().alpha_threshold(1);
+        let palette = CpuContext
+            .palette(2, &image, &ColorSpace::Rgb, &options)
+            .unwrap();
+        assert!(!palette.iter().any(|color| color[..3] == [0, 255, 0]));
+
+        let result = CpuContext
+            .kmeans(2, &image, &ColorSpace::Rgb, &options)
+            .unwrap();
+        for (output, input) in result.rgba.iter().zip(&image.rgba) {
+            assert_eq!(output[3], input[3]);
+        }
+    }
+
+    #[test]
+    fn test_choose_k() {
+        let image = two_colors_image();
+        let options = KMeansOptions::new();
+
+        for criterion in [KCriterion::Elbow, KCriterion::Silhouette] {
+            let sweep = CpuContext
+                .choose_k(1..=4, &image, &ColorSpace::Lab, &criterion, &options)
+                .unwrap();
+            assert_eq!(sweep.k, 2);
+            assert_eq!(sweep.curve.len(), 4);
+            assert_eq!(sweep.curve[0].silhouette, None);
+        }
+
+        // The sweep stops as soon as the error is low enough, a ΔE in both color spaces.
+        for color_space in [ColorSpace::Lab, ColorSpace::Rgb] {
+            let sweep = CpuContext
+                .choose_k(
+                    1..=4,
+                    &image,
+                    &color_space,
+                    &KCriterion::MeanError(2.3),
+                    &options,
+                )
+                .unwrap();
+            assert_eq!(sweep.k, 2);
+            assert_eq!(sweep.curve.len(), 2);
+            assert!(sweep.curve[0].mean_error > 10.0);
+            assert!(sweep.curve[1].mean_error < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_cluster() {
+        let image = two_colors_image();
+
+        let result = CpuContext
+            .cluster(2, &image, &ColorSpace::Rgb, &KMeansOptions::new())
+            .unwrap();
+
+        let mut counts = result.counts().to_vec();
+        counts.sort_unstable();
+        assert_eq!(counts, vec![22, 42]);
+        assert!(result.converged());
+        assert!(result.inertia() < 1e-6);
+
+        let timings = result.timings().unwrap();
+        assert_eq!(timings.iterations.len() as u32, result.iterations());
+    }
+
+    #[test]
+    fn test_empty_clusters_are_reseeded() {
+        let rgba = (0..64)
+            .map(|i| [[0, 0, 0, 255], [255, 255, 255, 255], [200, 0, 0, 255]][i % 3])
+            .collect();
+        let image = Image::new((8, 8), rgba);
+
+        // Green is the closest to no pixel, while gray takes both black and white.
+        let init = Init::Colors(vec![
+            [128, 128, 128, 255],
+            [190, 0, 0, 255],
+            [0, 255, 0, 255],
+        ]);
+        let result = CpuContext
+            .cluster(
+                3,
+                &image,
+                &ColorSpace::Rgb,
+                &KMeansOptions::new().init(init),
+            )
+            .unwrap();
+
+        assert!(result.reseeds() > 0);
+        let mut counts = result.counts().to_vec();
+        counts.sort_unstable();
+        assert_eq!(counts, vec![21, 21, 22]);
+        assert!(result.converged());
+    }
+
+    #[test]
+    fn test_empty_clusters_are_reseeded_at_once() {
+        const COLORS: [[u8; 4]; 6] = [
+            [0, 0, 0, 255],
+            [60, 0, 0, 255],
+            [255, 255, 255, 255],
+            [255, 195, 255, 255],
+            [0, 0, 255, 255],
+            [0, 60, 255, 255],
+        ];
+        let image = Image::new((6, 6), (0..36).map(|i| COLORS[i % 6]).collect());
+
+        // Each of the first three takes two colors, the last three are the closest to none.
+        let init = Init::Colors(vec![
+            [30, 0, 0, 255],
+            [255, 225, 255, 255],
+            [0, 30, 255, 255],
+            [128, 128, 0, 255],
+            [128, 128, 10, 255],
+            [128, 128, 20, 255],
+        ]);
+        let result = CpuContext
+            .cluster(
+                6,
+                &image,
+                &ColorSpace::Rgb,
+                &KMeansOptions::new().init(init),
+            )
+            .unwrap();
+
+        assert_eq!(result.reseeds(), 3);
+        assert_eq!(result.iterations(), 3);
+        assert_eq!(result.counts(), &[6; 6]);
+        assert!(result.converged());
+    }
+
+    #[test]
+    fn test_seed_is_deterministic() {
+        let rgba = (0..256)
+            .map(|i| [i as u8, (i * 7) as u8, (i * 13) as u8, 255])
+            .collect();
+        let image = Image::new((16, 16), rgba);
+
+        let palette = |seed| {
+            let options = KMeansOptions::new().seed(seed);
+            CpuContext
+                .palette(4, &image, &ColorSpace::Lab, &options)
+                .unwrap()
+        };
+        assert_eq!(palette(3), palette(3));
+        assert_ne!(random(3, 0), random(4, 0));
+    }
+
+    #[test]
+    fn test_restarts_keep_lowest_inertia() {
+        let rgba = (0..256)
+            .map(|i| [i as u8, (i * 7) as u8, (i * 13) as u8, 255])
+            .collect();
+        let image = Image::new((16, 16), rgba);
+
+        let inertia = |options: KMeansOptions| {
+            CpuContext
+                .cluster(4, &image, &ColorSpace::Rgb, &options.init(Init::Random))
+                .unwrap()
+                .inertia()
+        };
+        let best = inertia(KMeansOptions::new().seed(5).n_init(4));
+        let runs = (5..9)
+            .map(|seed| inertia(KMeansOptions::new().seed(seed)))
+            .collect::<Vec<_>>();
+        assert!(runs.iter().all(|&run| best <= run));
+        assert!(runs.contains(&best));
+    }
+
+    #[test]
+    fn test_progress_and_cancellation() {
+        let image = two_colors_image();
+
+        let phases = Arc::new(Mutex::new(Vec::new()));
+        let reported = phases.clone();
+        let options = KMeansOptions::new()
+            .on_progress(move |progress| reported.lock().unwrap().push(progress.phase));
+        CpuContext
+            .palette(2, &image, &ColorSpace::Rgb, &options)
+            .unwrap();
+        let phases = phases.lock().unwrap();
+        assert_eq!(phases[..3], [Phase::Convert, Phase::Init, Phase::Iterate]);
+        assert_eq!(phases.last(), Some(&Phase::Finalize));
+
+        let token = CancellationToken::new();
+        let cancel = token.clone();
+        let options = KMeansOptions::new()
+            .cancellation_token(token)
+            .on_progress(move |progress| {
+                if progress.phase == Phase::Iterate {
+                    cancel.cancel();
+                }
+            });
+        let result = CpuContext.palette(2, &image, &ColorSpace::Rgb, &options);
+        assert!(matches!(result, Err(Error::Cancelled)));
+    }
+}
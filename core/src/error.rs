@@ -0,0 +1,96 @@
+use std::fmt::Display;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// No adapter matching the requirements could be found.
+    NoAdapter,
+    /// The adapter was found, but refused to create a device.
+    RequestDevice(wgpu::RequestDeviceError),
+    /// One of the dimensions of a texture passed to [`crate::KMeansContext::kmeans_texture`]
+    /// exceeds what the device supports. Images that large are split in tiles instead.
+    ImageTooLarge {
+        dimensions: (u32, u32),
+        max_dimension: u32,
+    },
+    /// k is either 0, or higher than the number of pixels in the image.
+    InvalidK(u32),
+    /// The image has no pixels.
+    EmptyImage,
+    /// No pixel of the image has an alpha at or above the threshold of the options.
+    TransparentImage(u8),
+    /// Reading back a result from the gpu failed.
+    BufferMap(wgpu::BufferAsyncError),
+    /// The device reported an error it cannot recover from, such as running out of memory or
+    /// being lost. The context should be dropped and recreated.
+    DeviceLost(String),
+    /// The [`crate::CancellationToken`] of the options was cancelled.
+    Cancelled,
+    /// The operation works on gpu textures, which the cpu backend doesn't have.
+    GpuRequired,
+    /// [`crate::Init::Colors`] doesn't hold exactly k colors.
+    InitColors {
+        k: u32,
+        colors: usize,
+    },
+    /// [`crate::Algorithm::MiniBatch`] was given a batch size of 0.
+    EmptyMiniBatch,
+    UnknownColorSpace(String),
+    UnknownMixMode(String),
+    UnknownBackend(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::NoAdapter => write!(f, "Couldn't create the adapter"),
+            Error::RequestDevice(e) => write!(f, "{e}"),
+            Error::ImageTooLarge {
+                dimensions: (width, height),
+                max_dimension,
+            } => write!(
+                f,
+                "Image of {width}x{height} pixels exceeds the maximum dimension of {max_dimension}"
+            ),
+            Error::InvalidK(k) => write!(f, "Invalid k {k} for this image"),
+            Error::EmptyImage => write!(f, "Image is empty"),
+            Error::TransparentImage(alpha_threshold) => {
+                write!(f, "No pixel has an alpha of at least {alpha_threshold}")
+            }
+            Error::BufferMap(e) => write!(f, "{e}"),
+            Error::DeviceLost(reason) => write!(f, "Device lost: {reason}"),
+            Error::Cancelled => write!(f, "Clustering cancelled"),
+            Error::GpuRequired => write!(f, "This operation requires the gpu backend"),
+            Error::InitColors { k, colors } => {
+                write!(f, "Expected {k} initial colors, got {colors}")
+            }
+            Error::EmptyMiniBatch => write!(f, "Mini-batches should hold at least 1 pixel"),
+            Error::UnknownColorSpace(s) => write!(f, "Unsupported color space {s}"),
+            Error::UnknownMixMode(s) => write!(f, "Unsupported mix mode {s}"),
+            Error::UnknownBackend(s) => write!(f, "Unsupported backend {s}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::RequestDevice(e) => Some(e),
+            Error::BufferMap(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<wgpu::RequestDeviceError> for Error {
+    fn from(e: wgpu::RequestDeviceError) -> Self {
+        Error::RequestDevice(e)
+    }
+}
+
+impl From<wgpu::BufferAsyncError> for Error {
+    fn from(e: wgpu::BufferAsyncError) -> Self {
+        Error::BufferMap(e)
+    }
+}
@@ -0,0 +1,148 @@
+use log::debug;
+use wgpu::{CommandEncoderDescriptor, ComputePassDescriptor, TextureView};
+
+use crate::{
+    modules::{ColorConverterModule, EstimateModule, FindCentroidModule, Module},
+    CentroidsBuffer, ColorIndexTexture, ColorSpace, Estimation, GpuContext, ImageTextures,
+    InputTexture, KMeansOptions, Result, Timestamps, WorkTexture,
+};
+
+/// The side of the square of pixels each texel of the estimate stands for, or `None` when every
+/// pixel is clustered. `stride` is the one of the copy downsampled to fit in a texture, which
+/// already stands for as many pixels.
+pub(crate) fn cell_size(
+    estimation: &Estimation,
+    (width, height): (u32, u32),
+    stride: u32,
+) -> Option<u32> {
+    match estimation {
+        Estimation::Full => None,
+        Estimation::Downscale(factor) => Some((factor / stride).max(1)),
+        Estimation::Sample(size) => {
+            let area = width as f64 * height as f64;
+            Some(((area / (*size).max(1) as f64).sqrt() as u32).max(1))
+        }
+    }
+}
+
+impl GpuContext<'_> {
+    /// Writes the estimate of the input and converts it to the work color space, or returns
+    /// `None` when the options cluster every pixel.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn estimate(
+        &self,
+        dimensions: (u32, u32),
+        color_space: &ColorSpace,
+        input_view: &TextureView,
+        stride: u32,
+        options: &KMeansOptions,
+        timestamps: &Timestamps,
+    ) -> Result<Option<ImageTextures>> {
+        let Some(cell) = cell_size(&options.estimation, dimensions, stride) else {
+            return Ok(None);
+        };
+        let device = &self.device;
+        let estimate_dimensions = (dimensions.0.div_ceil(cell), dimensions.1.div_ceil(cell));
+        debug!("Estimating {dimensions:?} pixels from {estimate_dimensions:?}");
+
+        let estimate_texture = InputTexture::storage(device, estimate_dimensions);
+        let estimate_module = EstimateModule::new(
+            device,
+            &self.pipelines.estimate,
+            &options.estimation,
+            input_view,
+            &estimate_texture.view(),
+            estimate_dimensions,
+            cell,
+            options,
+        );
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("Estimate pass"),
+            });
+            estimate_module.dispatch(&mut compute_pass);
+        }
+        self.queue.submit(Some(encoder.finish()));
+
+        let textures = ImageTextures::new(device, estimate_dimensions);
+        self.convert(
+            estimate_dimensions,
+            color_space,
+            &estimate_texture.view(),
+            &textures.work_texture,
+            options,
+            timestamps,
+        )?;
+        Ok(Some(textures))
+    }
+
+    /// Converts the whole input, and gives each pixel the label of its closest centroid, once
+    /// they were estimated.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn assign(
+        &self,
+        dimensions: (u32, u32),
+        color_space: &ColorSpace,
+        input_view: &TextureView,
+        work_texture: &WorkTexture,
+        color_index_texture: &ColorIndexTexture,
+        centroids_buffer: &CentroidsBuffer,
+        options: &KMeansOptions,
+    ) {
+        let device = &self.device;
+
+        let color_converter_module = ColorConverterModule::new(
+            device,
+            self.pipelines.converter(color_space),
+            dimensions,
+            input_view,
+            work_texture,
+            options.alpha_threshold,
+        );
+        let find_centroid_module = FindCentroidModule::new(
+            device,
+            &self.pipelines.find_centroid,
+            dimensions,
+            work_texture,
+            centroids_buffer,
+            color_index_texture,
+        );
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("Full resolution pass"),
+            });
+            color_converter_module.dispatch(&mut compute_pass);
+            find_centroid_module.dispatch(&mut compute_pass);
+        }
+        self.queue.submit(Some(encoder.finish()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cell_size() {
+        assert_eq!(cell_size(&Estimation::Full, (4000, 3000), 1), None);
+        assert_eq!(
+            cell_size(&Estimation::Downscale(4), (4000, 3000), 1),
+            Some(4)
+        );
+        // Downsampled to fit, each pixel already stands for a square of 3.
+        assert_eq!(
+            cell_size(&Estimation::Downscale(4), (4000, 3000), 3),
+            Some(1)
+        );
+        assert_eq!(
+            cell_size(&Estimation::Sample(120_000), (4000, 3000), 1),
+            Some(10)
+        );
+        assert_eq!(cell_size(&Estimation::Sample(0), (4, 3), 1), Some(3));
+        // More samples than pixels.
+        assert_eq!(cell_size(&Estimation::Sample(100), (4, 3), 1), Some(1));
+    }
+}
@@ -0,0 +1,283 @@
+use crate::ColorSpace;
+
+/// Bins of the color histogram along each channel, see `color_histogram.wgsl`.
+pub(crate) const HISTOGRAM_BINS: usize = 64;
+
+/// Sampling rounds of k-means||.
+pub(crate) const KMEANS_PARALLEL_ROUNDS: u32 = 5;
+
+/// Bins of the priorities of the pixels sampled in a round of k-means||. When they overflow the
+/// candidates, whole bins are kept from the highest priority, the same whatever the order the
+/// pixels are sampled in.
+pub(crate) const PRIORITY_BINS: usize = 256;
+
+/// Weighted iterations run on the candidates of k-means||, once reduced to k centroids.
+const RECLUSTER_ITERATIONS: u32 = 8;
+
+/// Same as the `pcg` function of the shaders.
+pub(crate) fn pcg(value: u32) -> u32 {
+    let state = value.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+/// Same as the `random` function of the shaders.
+pub(crate) fn random(seed: u64, stream: u32) -> u32 {
+    pcg(seed as u32 ^ pcg((seed >> 32) as u32 ^ pcg(stream)))
+}
+
+/// Same as [`random`], as a float in `[0, 1)`.
+pub(crate) fn random_unit(seed: u64, stream: u32) -> f32 {
+    (random(seed, stream) >> 8) as f32 / 16777216.0
+}
+
+/// Picks the index where the running sum of the weights crosses `ratio` of their total, or
+/// `None` if they are all 0.
+pub(crate) fn pick_weighted(weights: &[f64], ratio: f32) -> Option<usize> {
+    let total = weights.iter().sum::<f64>();
+    if total <= 0.0 {
+        return None;
+    }
+
+    let threshold = ratio as f64 * total;
+    let mut sum = 0.0;
+    let mut picked = None;
+    for (index, &weight) in weights.iter().enumerate() {
+        if weight > 0.0 {
+            picked = Some(index);
+            sum += weight;
+            if threshold < sum {
+                break;
+            }
+        }
+    }
+    picked
+}
+
+/// Candidates sampled each round of k-means||, on average.
+pub(crate) fn oversampling(k: u32) -> f32 {
+    2.0 * k as f32
+}
+
+/// How many candidates k-means|| keeps: the first one, then twice the expected samples of each
+/// round. Beyond, only the samples of the highest priorities are kept, see [`PRIORITY_BINS`].
+pub(crate) fn candidate_capacity(k: u32) -> u32 {
+    1 + 2 * KMEANS_PARALLEL_ROUNDS * oversampling(k) as u32
+}
+
+/// The lowest value of each channel in the histogram, and the bins per unit of each channel.
+pub(crate) fn histogram_bounds(color_space: &ColorSpace) -> ([f32; 3], [f32; 3]) {
+    let (min, max) = match color_space {
+        ColorSpace::Lab => ([0.0, -128.0, -128.0], [100.0, 128.0, 128.0]),
+        ColorSpace::Rgb => ([0.0; 3], [1.0; 3]),
+    };
+    let scale = [0, 1, 2].map(|channel| HISTOGRAM_BINS as f32 / (max[channel] - min[channel]));
+    (min, scale)
+}
+
+/// The histogram bin of a color, same as `color_histogram.wgsl`.
+pub(crate) fn histogram_bin(color: &[f32; 3], (min, scale): &([f32; 3], [f32; 3])) -> usize {
+    let [r, g, b] = [0, 1, 2].map(|channel| {
+        ((color[channel] - min[channel]) * scale[channel]).clamp(0.0, (HISTOGRAM_BINS - 1) as f32)
+            as usize
+    });
+    (r * HISTOGRAM_BINS + g) * HISTOGRAM_BINS + b
+}
+
+/// The center of each bin holding pixels, weighted by their count.
+pub(crate) fn histogram_colors(
+    histogram: &[u32],
+    (min, scale): &([f32; 3], [f32; 3]),
+) -> Vec<([f32; 3], f32)> {
+    histogram
+        .iter()
+        .enumerate()
+        .filter(|(_, &count)| count > 0)
+        .map(|(bin, &count)| {
+            let bins = [
+                bin / (HISTOGRAM_BINS * HISTOGRAM_BINS),
+                bin / HISTOGRAM_BINS % HISTOGRAM_BINS,
+                bin % HISTOGRAM_BINS,
+            ];
+            let color = [0, 1, 2]
+                .map(|channel| min[channel] + (bins[channel] as f32 + 0.5) / scale[channel]);
+            (color, count as f32)
+        })
+        .collect()
+}
+
+pub(crate) fn squared_distance(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)
+}
+
+fn closest(color: &[f32; 3], centroids: &[[f32; 3]]) -> usize {
+    centroids
+        .iter()
+        .map(|centroid| squared_distance(color, centroid))
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or(0, |(index, _)| index)
+}
+
+fn weighted_mean(colors: &[([f32; 3], f32)]) -> Option<[f32; 3]> {
+    let mut sum = [0.0; 3];
+    let mut total = 0.0;
+    for (color, weight) in colors {
+        for channel in 0..3 {
+            sum[channel] += color[channel] as f64 * *weight as f64;
+        }
+        total += *weight as f64;
+    }
+    (total > 0.0).then(|| sum.map(|channel| (channel / total) as f32))
+}
+
+/// Median cut: splits the weighted colors in two at the median of the channel with the widest
+/// range, until there are k boxes, and returns the mean color of each box. Boxes missing when
+/// there are fewer colors than k repeat the last color.
+pub(crate) fn median_cut(colors: &[([f32; 3], f32)], k: u32) -> Vec<[f32; 3]> {
+    let mut boxes = vec![colors.to_vec()];
+    while boxes.len() < k as usize {
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, colors)| colors.len() > 1)
+            .flat_map(|(index, colors)| {
+                (0..3).map(move |channel| {
+                    let (low, high) = colors.iter().fold((f32::MAX, f32::MIN), |range, color| {
+                        (range.0.min(color.0[channel]), range.1.max(color.0[channel]))
+                    });
+                    (index, channel, high - low)
+                })
+            })
+            .max_by(|a, b| a.2.total_cmp(&b.2));
+        let Some((index, channel, _)) = widest else {
+            break;
+        };
+
+        let mut lower = boxes.swap_remove(index);
+        lower.sort_by(|a, b| a.0[channel].total_cmp(&b.0[channel]));
+        let half = lower.iter().map(|(_, weight)| weight).sum::<f32>() / 2.0;
+        let mut sum = 0.0;
+        let median = lower
+            .iter()
+            .position(|(_, weight)| {
+                sum += weight;
+                sum >= half
+            })
+            .unwrap_or(0);
+        let upper = lower.split_off((median + 1).min(lower.len() - 1));
+        boxes.push(lower);
+        boxes.push(upper);
+    }
+
+    let mut centroids = boxes
+        .iter()
+        .filter_map(|colors| weighted_mean(colors))
+        .collect::<Vec<_>>();
+    let last = centroids.last().copied().unwrap_or_default();
+    centroids.resize(k as usize, last);
+    centroids
+}
+
+/// Sorts the candidates of k-means|| by color, as the gpu samples them in no particular order.
+pub(crate) fn sort_candidates(candidates: &mut [([f32; 3], f32)]) {
+    candidates.sort_by(|a, b| {
+        (a.0.iter().zip(&b.0))
+            .map(|(a, b)| a.total_cmp(b))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+}
+
+/// Reduces the weighted candidates of k-means|| to k centroids: a weighted k-means++ seeding,
+/// followed by a few weighted k-means iterations.
+pub(crate) fn recluster(candidates: &[([f32; 3], f32)], k: u32, seed: u64) -> Vec<[f32; 3]> {
+    // Another seed, to not reuse the draws of the sampling rounds.
+    let seed = seed.wrapping_add(1);
+
+    let mut centroids: Vec<[f32; 3]> = Vec::with_capacity(k as usize);
+    let mut distances = vec![f32::MAX; candidates.len()];
+    for index in 0..k {
+        let weights = candidates
+            .iter()
+            .zip(&distances)
+            .map(|((_, weight), &distance)| {
+                // The first centroid only depends on the weights.
+                if index == 0 {
+                    *weight as f64
+                } else {
+                    *weight as f64 * distance as f64
+                }
+            })
+            .collect::<Vec<_>>();
+        let centroid = match pick_weighted(&weights, random_unit(seed, index)) {
+            Some(picked) => candidates[picked].0,
+            None => centroids
+                .last()
+                .copied()
+                .or_else(|| candidates.first().map(|(color, _)| *color))
+                .unwrap_or_default(),
+        };
+        for ((color, _), distance) in candidates.iter().zip(&mut distances) {
+            *distance = distance.min(squared_distance(color, &centroid));
+        }
+        centroids.push(centroid);
+    }
+
+    for _ in 0..RECLUSTER_ITERATIONS {
+        let mut clusters = vec![Vec::new(); k as usize];
+        for &(color, weight) in candidates {
+            clusters[closest(&color, &centroids)].push((color, weight));
+        }
+        for (centroid, cluster) in centroids.iter_mut().zip(&clusters) {
+            if let Some(mean) = weighted_mean(cluster) {
+                *centroid = mean;
+            }
+        }
+    }
+
+    centroids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick_weighted() {
+        let weights = [0.0, 1.0, 0.0, 4.0, 0.0];
+        assert_eq!(pick_weighted(&weights, 0.0), Some(1));
+        assert_eq!(pick_weighted(&weights, 0.19), Some(1));
+        assert_eq!(pick_weighted(&weights, 0.21), Some(3));
+        assert_eq!(pick_weighted(&weights, 0.99), Some(3));
+        assert_eq!(pick_weighted(&[0.0, 0.0], 0.5), None);
+    }
+
+    #[test]
+    fn test_median_cut() {
+        let colors = [
+            ([0.0, 0.0, 0.0], 1.0),
+            ([0.1, 0.0, 0.0], 1.0),
+            ([1.0, 0.0, 0.0], 2.0),
+        ];
+        assert_eq!(
+            median_cut(&colors, 2),
+            vec![[0.05, 0.0, 0.0], [1.0, 0.0, 0.0]]
+        );
+        // Not enough colors for k boxes.
+        assert_eq!(median_cut(&colors[..1], 2), vec![[0.0; 3]; 2]);
+    }
+
+    #[test]
+    fn test_histogram_round_trip() {
+        let bounds = histogram_bounds(&ColorSpace::Lab);
+        let mut histogram = vec![0; HISTOGRAM_BINS.pow(3)];
+        histogram[histogram_bin(&[50.0, 10.0, -20.0], &bounds)] += 3;
+        let colors = histogram_colors(&histogram, &bounds);
+        assert_eq!(colors.len(), 1);
+        assert_eq!(colors[0].1, 3.0);
+        for (center, color) in colors[0].0.iter().zip([50.0, 10.0, -20.0]) {
+            assert!((center - color).abs() < 4.0);
+        }
+    }
+}
@@ -1,23 +1,58 @@
-use anyhow::{anyhow, Result};
+use cpu::CpuContext;
+use init::{histogram_bounds, histogram_colors, median_cut, recluster};
+use log::{debug, warn};
 use modules::{
-    ChooseCentroidModule, ColorConverterModule, ColorReverterModule, FindCentroidModule,
-    MixColorsModule, Module, PlusPlusInitModule, SwapModule,
+    ChooseCentroidModule, ChooseCentroidState, ClusterStatsModule, ColorConverterModule,
+    ColorHistogramModule, ColorReverterModule, Convergence, FindCentroidModule,
+    KMeansParallelModule, MiniBatchModule, MixColorsModule, Module, Pipelines, PlusPlusInitModule,
+    Selection, SwapModule,
 };
 use palette::{IntoColor, Lab, Pixel, Srgb, Srgba};
-use std::{fmt::Display, ops::Deref, str::FromStr, vec};
+use std::{
+    cell::Cell,
+    fmt::Display,
+    num::NonZeroU32,
+    ops::{Deref, RangeInclusive},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+    vec,
+};
 use utils::padded_bytes_per_row;
 use wgpu::{
     util::{BufferInitDescriptor, DeviceExt},
     Backends, BindGroupLayoutEntry, BindingType, Buffer, BufferBindingType, BufferUsages,
     CommandEncoder, CommandEncoderDescriptor, ComputePassDescriptor, Device, DeviceDescriptor,
-    Features, ImageDataLayout, Instance, MapMode, PowerPreference, QuerySetDescriptor, QueryType,
-    Queue, RequestAdapterOptionsBase, ShaderStages, StorageTextureAccess, Texture,
-    TextureDimension, TextureFormat, TextureSampleType, TextureUsages, TextureViewDimension,
+    Features, ImageDataLayout, Instance, MapMode, PowerPreference, QuerySet, QuerySetDescriptor,
+    QueryType, Queue, RequestAdapterOptionsBase, ShaderStages, StorageTextureAccess, Texture,
+    TextureDimension, TextureFormat, TextureSampleType, TextureUsages, TextureView,
+    TextureViewDescriptor, TextureViewDimension, QUERY_SET_MAX_QUERIES, QUERY_SIZE,
 };
 
+mod auto_k;
+mod batch;
+mod cpu;
+mod error;
+mod estimate;
+mod init;
 mod modules;
+mod options;
+mod progress;
+mod result;
+mod texture_output;
+mod tiles;
+mod timings;
+mod unique;
 mod utils;
 
+pub use auto_k::{KCriterion, KScore, KSweep};
+pub use error::{Error, Result};
+pub use options::{Algorithm, Estimation, Init, KMeansOptions, Reduction};
+pub use progress::{CancellationToken, Phase, Progress};
+pub use result::KMeansResult;
+pub use texture_output::TextureOutput;
+pub use timings::Timings;
+
 pub struct Image {
     pub(crate) dimensions: (u32, u32),
     pub(crate) rgba: Vec<[u8; 4]>,
@@ -78,16 +113,55 @@ impl ColorSpace {
             ColorSpace::Rgb => 0.01,
         }
     }
+
+    /// Converts a color expressed in this color space to sRGB.
+    pub(crate) fn to_rgba(&self, color: &[f32]) -> [u8; 4] {
+        match self {
+            ColorSpace::Lab => {
+                IntoColor::<Srgba>::into_color(Lab::new(color[0], color[1], color[2]))
+                    .into_format()
+                    .into_raw()
+            }
+            ColorSpace::Rgb => Srgba::new(color[0], color[1], color[2], 1.0)
+                .into_format()
+                .into_raw(),
+        }
+    }
+
+    /// Converts an sRGB color to this color space.
+    pub(crate) fn rgba_to_color(&self, color: &[u8; 4]) -> [f32; 3] {
+        match self {
+            ColorSpace::Lab => {
+                let lab: Lab = Srgb::new(color[0], color[1], color[2])
+                    .into_format()
+                    .into_color();
+                [lab.l, lab.a, lab.b]
+            }
+            ColorSpace::Rgb => {
+                let srgb: Srgb = Srgb::new(color[0], color[1], color[2]).into_format();
+                [srgb.red, srgb.green, srgb.blue]
+            }
+        }
+    }
+}
+
+/// Sorts a palette from the darkest to the lightest color.
+pub(crate) fn sort_by_lightness(colors: &mut [[u8; 4]]) {
+    colors.sort_unstable_by(|a, b| {
+        let a: Lab = Srgba::from_raw(a).into_format::<_, f32>().into_color();
+        let b: Lab = Srgba::from_raw(b).into_format::<_, f32>().into_color();
+        a.l.partial_cmp(&b.l).unwrap()
+    });
 }
 
 impl FromStr for ColorSpace {
-    type Err = anyhow::Error;
+    type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "lab" => Ok(ColorSpace::Lab),
             "rgb" => Ok(ColorSpace::Rgb),
-            _ => Err(anyhow!("Unsupported color space {s}")),
+            _ => Err(Error::UnknownColorSpace(s.to_owned())),
         }
     }
 }
@@ -113,13 +187,13 @@ impl MixMode {
 }
 
 impl FromStr for MixMode {
-    type Err = anyhow::Error;
+    type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "dither" => Ok(MixMode::Dither),
             "meld" => Ok(MixMode::Meld),
-            _ => Err(anyhow!("Unsupported mix mode {s}")),
+            _ => Err(Error::UnknownMixMode(s.to_owned())),
         }
     }
 }
@@ -130,11 +204,39 @@ impl Display for MixMode {
     }
 }
 
+/// A single level 2D view of an `Rgba8Unorm` texture, as the shaders expect for the input and
+/// output images.
+fn rgba8_view(texture: &Texture) -> TextureView {
+    texture.create_view(&TextureViewDescriptor {
+        label: None,
+        format: Some(TextureFormat::Rgba8Unorm),
+        aspect: wgpu::TextureAspect::All,
+        base_mip_level: 0,
+        mip_level_count: NonZeroU32::new(1),
+        dimension: Some(TextureViewDimension::D2),
+        ..Default::default()
+    })
+}
+
 struct InputTexture(Texture);
 
 impl InputTexture {
     fn new(device: &Device, queue: &Queue, image: &Image) -> Self {
-        let (width, height) = image.dimensions;
+        let input_texture = Self::empty(device, image.dimensions);
+        input_texture.write(queue, image);
+        input_texture
+    }
+
+    fn empty(device: &Device, dimensions: (u32, u32)) -> Self {
+        Self::with_usage(device, dimensions, TextureUsages::COPY_DST)
+    }
+
+    /// An input written by a shader instead of uploaded.
+    fn storage(device: &Device, dimensions: (u32, u32)) -> Self {
+        Self::with_usage(device, dimensions, TextureUsages::STORAGE_BINDING)
+    }
+
+    fn with_usage(device: &Device, (width, height): (u32, u32), usage: TextureUsages) -> Self {
         let texture_size = wgpu::Extent3d {
             width,
             height,
@@ -148,11 +250,27 @@ impl InputTexture {
             sample_count: 1,
             dimension: TextureDimension::D2,
             format: TextureFormat::Rgba8Unorm,
-            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+            usage: TextureUsages::TEXTURE_BINDING | usage,
         });
 
+        Self(texture)
+    }
+
+    fn view(&self) -> TextureView {
+        rgba8_view(self)
+    }
+
+    /// Uploads an image of the same dimensions as the texture.
+    fn write(&self, queue: &Queue, image: &Image) {
+        let (width, height) = image.dimensions;
+        let texture_size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+
         queue.write_texture(
-            texture.as_image_copy(),
+            self.as_image_copy(),
             bytemuck::cast_slice(&image.rgba),
             ImageDataLayout {
                 offset: 0,
@@ -161,8 +279,6 @@ impl InputTexture {
             },
             texture_size,
         );
-
-        Self(texture)
     }
 }
 
@@ -177,8 +293,7 @@ impl Deref for InputTexture {
 struct WorkTexture(Texture);
 
 impl WorkTexture {
-    fn new(device: &Device, image: &Image) -> Self {
-        let (width, height) = image.dimensions;
+    fn new(device: &Device, (width, height): (u32, u32)) -> Self {
         let texture_size = wgpu::Extent3d {
             width,
             height,
@@ -191,12 +306,32 @@ impl WorkTexture {
             sample_count: 1,
             dimension: TextureDimension::D2,
             format: TextureFormat::Rgba32Float,
-            usage: TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING,
+            usage: TextureUsages::STORAGE_BINDING
+                | TextureUsages::TEXTURE_BINDING
+                | TextureUsages::COPY_DST,
         });
 
         Self(texture)
     }
 
+    /// Uploads texels already in the work color space.
+    fn write(&self, queue: &Queue, (width, height): (u32, u32), texels: &[[f32; 4]]) {
+        queue.write_texture(
+            self.as_image_copy(),
+            bytemuck::cast_slice(texels),
+            ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(16 * width),
+                rows_per_image: None,
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
     fn texture_2d_layout(binding: u32) -> BindGroupLayoutEntry {
         BindGroupLayoutEntry {
             binding,
@@ -235,8 +370,7 @@ impl Deref for WorkTexture {
 struct ColorIndexTexture(Texture);
 
 impl ColorIndexTexture {
-    fn new(device: &Device, image: &Image) -> Self {
-        let (width, height) = image.dimensions;
+    fn new(device: &Device, (width, height): (u32, u32)) -> Self {
         let texture_size = wgpu::Extent3d {
             width,
             height,
@@ -249,12 +383,23 @@ impl ColorIndexTexture {
             sample_count: 1,
             dimension: TextureDimension::D2,
             format: TextureFormat::R32Uint,
-            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::STORAGE_BINDING,
+            usage: TextureUsages::TEXTURE_BINDING
+                | TextureUsages::STORAGE_BINDING
+                | TextureUsages::COPY_SRC,
         });
 
         Self(texture)
     }
 
+    fn output_buffer(
+        &self,
+        device: &Device,
+        encoder: &mut CommandEncoder,
+        image_dimensions: (u32, u32),
+    ) -> OutputBuffer {
+        OutputBuffer::copy_from(device, encoder, self, image_dimensions)
+    }
+
     fn texture_2d_layout(binding: u32) -> BindGroupLayoutEntry {
         BindGroupLayoutEntry {
             binding,
@@ -277,14 +422,31 @@ impl Deref for ColorIndexTexture {
     }
 }
 
+/// The textures holding an image during the clustering, which can be reused for the next
+/// images of the same dimensions.
+struct ImageTextures {
+    dimensions: (u32, u32),
+    work_texture: WorkTexture,
+    color_index_texture: ColorIndexTexture,
+}
+
+impl ImageTextures {
+    fn new(device: &Device, dimensions: (u32, u32)) -> Self {
+        Self {
+            dimensions,
+            work_texture: WorkTexture::new(device, dimensions),
+            color_index_texture: ColorIndexTexture::new(device, dimensions),
+        }
+    }
+}
+
 struct OutputTexture {
     texture: Texture,
     texture_size: wgpu::Extent3d,
 }
 
 impl OutputTexture {
-    fn new(device: &Device, image: &Image) -> Self {
-        let (width, height) = image.dimensions;
+    fn new(device: &Device, (width, height): (u32, u32)) -> Self {
         let texture_size = wgpu::Extent3d {
             width,
             height,
@@ -306,12 +468,47 @@ impl OutputTexture {
         }
     }
 
+    fn view(&self) -> TextureView {
+        rgba8_view(self)
+    }
+
     fn output_buffer(&self, device: &Device, encoder: &mut CommandEncoder) -> OutputBuffer {
-        let wgpu::Extent3d {
+        OutputBuffer::copy_from(
+            device,
+            encoder,
+            self,
+            (self.texture_size.width, self.texture_size.height),
+        )
+    }
+}
+
+impl Deref for OutputTexture {
+    type Target = Texture;
+
+    fn deref(&self) -> &Self::Target {
+        &self.texture
+    }
+}
+
+struct OutputBuffer {
+    buffer: Buffer,
+    unpadded_bytes_per_row: usize,
+    padded_bytes_per_row: usize,
+}
+
+impl OutputBuffer {
+    /// Copies a texture with 4 bytes per texel into a mappable buffer.
+    fn copy_from(
+        device: &Device,
+        encoder: &mut CommandEncoder,
+        texture: &Texture,
+        (width, height): (u32, u32),
+    ) -> Self {
+        let texture_size = wgpu::Extent3d {
             width,
             height,
-            depth_or_array_layers: _,
-        } = self.texture_size;
+            depth_or_array_layers: 1,
+        };
         let padded_bytes_per_row = padded_bytes_per_row(width as u64 * 4) as usize;
         let unpadded_bytes_per_row = width as usize * 4;
 
@@ -327,7 +524,7 @@ impl OutputTexture {
         encoder.copy_texture_to_buffer(
             wgpu::ImageCopyTexture {
                 aspect: wgpu::TextureAspect::All,
-                texture: self,
+                texture,
                 mip_level: 0,
                 origin: wgpu::Origin3d::ZERO,
             },
@@ -339,31 +536,30 @@ impl OutputTexture {
                     rows_per_image: std::num::NonZeroU32::new(height),
                 },
             },
-            self.texture_size,
+            texture_size,
         );
 
-        OutputBuffer {
+        Self {
             buffer,
             unpadded_bytes_per_row,
             padded_bytes_per_row,
         }
     }
-}
-
-impl Deref for OutputTexture {
-    type Target = Texture;
 
-    fn deref(&self) -> &Self::Target {
-        &self.texture
+    /// Strips the row padding from the mapped data.
+    fn unpad(&self, padded_data: &[u8]) -> Vec<u8> {
+        let height = padded_data.len() / self.padded_bytes_per_row;
+        let mut pixels: Vec<u8> = vec![0; self.unpadded_bytes_per_row * height];
+        for (padded, pixels) in padded_data
+            .chunks_exact(self.padded_bytes_per_row)
+            .zip(pixels.chunks_exact_mut(self.unpadded_bytes_per_row))
+        {
+            pixels.copy_from_slice(&padded[..self.unpadded_bytes_per_row]);
+        }
+        pixels
     }
 }
 
-struct OutputBuffer {
-    buffer: Buffer,
-    unpadded_bytes_per_row: usize,
-    padded_bytes_per_row: usize,
-}
-
 impl Deref for OutputBuffer {
     type Target = Buffer;
 
@@ -392,7 +588,7 @@ impl CentroidsBuffer {
         let buffer = device.create_buffer_init(&BufferInitDescriptor {
             label: None,
             contents: &centroids,
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC | BufferUsages::COPY_DST,
         });
 
         let copy_size = centroids.len() as u64;
@@ -400,6 +596,15 @@ impl CentroidsBuffer {
         Self { copy_size, buffer }
     }
 
+    /// Replaces the centroids, from the work color space.
+    fn write(&self, queue: &Queue, centroids: &[[f32; 3]]) {
+        let centroids = centroids
+            .iter()
+            .map(|&[a, b, c]| [a, b, c, 1.0])
+            .collect::<Vec<[f32; 4]>>();
+        queue.write_buffer(&self.buffer, 16, bytemuck::cast_slice(&centroids));
+    }
+
     fn fixed_centroids(colors: &[[u8; 4]], color_space: &ColorSpace, device: &Device) -> Self {
         let mut centroids: Vec<u8> = Vec::with_capacity(16 * (colors.len() + 1));
 
@@ -409,15 +614,9 @@ impl CentroidsBuffer {
         centroids.extend_from_slice(bytemuck::cast_slice(
             &colors
                 .iter()
-                .map(|c| match color_space {
-                    ColorSpace::Lab => {
-                        let lab: Lab = Srgb::new(c[0], c[1], c[2]).into_format().into_color();
-                        [lab.l, lab.a, lab.b, 1.0]
-                    }
-                    ColorSpace::Rgb => {
-                        let srgb: Srgb = Srgb::new(c[0], c[1], c[2]).into_format();
-                        [srgb.red, srgb.green, srgb.blue, 1.0]
-                    }
+                .map(|color| {
+                    let [a, b, c] = color_space.rgba_to_color(color);
+                    [a, b, c, 1.0]
                 })
                 .collect::<Vec<[f32; 4]>>(),
         ));
@@ -445,6 +644,16 @@ impl CentroidsBuffer {
         staging_buffer
     }
 
+    /// The number of centroids.
+    fn k(&self) -> u32 {
+        (self.copy_size / 16) as u32 - 1
+    }
+
+    /// Records a copy of the centroids to another buffer of the same k.
+    fn copy_to(&self, encoder: &mut CommandEncoder, destination: &CentroidsBuffer) {
+        encoder.copy_buffer_to_buffer(&self.buffer, 0, &destination.buffer, 0, self.copy_size);
+    }
+
     fn layout(binding: u32, read_only: bool) -> BindGroupLayoutEntry {
         BindGroupLayoutEntry {
             binding,
@@ -467,652 +676,1735 @@ impl Deref for CentroidsBuffer {
     }
 }
 
-pub async fn kmeans(k: u32, image: &Image, color_space: &ColorSpace) -> Result<Image> {
-    let (width, height) = image.dimensions;
+/// Where the clustering runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// The gpu when an adapter is available, the cpu otherwise.
+    #[default]
+    Auto,
+    Gpu,
+    /// Much slower, but works everywhere. Results match the gpu up to float rounding. Runs
+    /// every option of [`KMeansOptions`], except the ones only making the gpu faster:
+    /// [`KMeansOptions::reduction`], [`KMeansOptions::unique_colors`] and
+    /// [`KMeansOptions::convergence_check_interval`] are ignored, the results being the same.
+    Cpu,
+}
 
-    let instance = Instance::new(Backends::all());
-    let adapter = instance
-        .request_adapter(&RequestAdapterOptionsBase {
-            power_preference: PowerPreference::HighPerformance,
-            force_fallback_adapter: false,
-            compatible_surface: None,
-        })
-        .await
-        .ok_or_else(|| anyhow::anyhow!("Couldn't create the adapter"))?;
+impl Backend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Auto => "auto",
+            Backend::Gpu => "gpu",
+            Backend::Cpu => "cpu",
+        }
+    }
+}
 
-    let features = adapter.features();
-    let (device, queue) = adapter
-        .request_device(
-            &DeviceDescriptor {
-                label: None,
-                features: features & (Features::TIMESTAMP_QUERY),
-                limits: Default::default(),
+impl FromStr for Backend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Backend::Auto),
+            "gpu" => Ok(Backend::Gpu),
+            "cpu" => Ok(Backend::Cpu),
+            _ => Err(Error::UnknownBackend(s.to_owned())),
+        }
+    }
+}
+
+impl Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+enum ContextBackend<'a> {
+    Gpu(Box<GpuContext<'a>>),
+    Cpu(CpuContext),
+}
+
+/// Owns everything needed to run the clustering: on the gpu, the device and every compiled
+/// compute pipeline.
+///
+/// Creating a gpu context requests an adapter and a device, and compiles all the shaders, which
+/// is costly. Keep it around to process as many images as needed.
+///
+/// A context can also borrow the device of an application already using wgpu, see
+/// [`KMeansContext::from_device`].
+pub struct KMeansContext<'a> {
+    backend: ContextBackend<'a>,
+}
+
+impl KMeansContext<'static> {
+    /// Creates a context on the gpu, falling back to the cpu when no device can be created.
+    pub async fn new() -> Result<Self> {
+        Self::with_backend(Backend::Auto).await
+    }
+
+    pub async fn with_backend(backend: Backend) -> Result<Self> {
+        let backend = match backend {
+            Backend::Auto => match GpuContext::new().await {
+                Ok(gpu) => ContextBackend::Gpu(Box::new(gpu)),
+                Err(e @ (Error::NoAdapter | Error::RequestDevice(_))) => {
+                    warn!("{e}, falling back to the cpu");
+                    ContextBackend::Cpu(CpuContext)
+                }
+                Err(e) => return Err(e),
             },
-            None,
-        )
-        .await?;
+            Backend::Gpu => ContextBackend::Gpu(Box::new(GpuContext::new().await?)),
+            Backend::Cpu => ContextBackend::Cpu(CpuContext),
+        };
 
-    let centroids_buffer = CentroidsBuffer::empty_centroids(k, &device);
+        Ok(Self { backend })
+    }
+}
 
-    let query_set = if features.contains(Features::TIMESTAMP_QUERY) {
-        Some(device.create_query_set(&QuerySetDescriptor {
-            count: 2,
-            ty: QueryType::Timestamp,
-            label: None,
-        }))
-    } else {
-        None
-    };
-    let query_buf = device.create_buffer_init(&BufferInitDescriptor {
-        label: None,
-        contents: &[0; 16],
-        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
-    });
+impl<'a> KMeansContext<'a> {
+    /// Creates a gpu context on a device owned by the caller, to avoid a second device and its
+    /// memory when the application already uses wgpu.
+    ///
+    /// No feature is required, but [`Features::TIMESTAMP_QUERY`] enables the
+    /// [`KMeansResult::timings`]. The default [`wgpu::Limits`] are enough; a higher
+    /// `max_texture_dimension_2d` lets larger images be processed without splitting them in
+    /// tiles.
+    ///
+    /// The context doesn't install an error handler on a borrowed device: errors are reported
+    /// through the handler of the caller, if any.
+    pub fn from_device(device: &'a Device, queue: &'a Queue) -> Self {
+        Self {
+            backend: ContextBackend::Gpu(Box::new(GpuContext::from_device(device, queue))),
+        }
+    }
 
-    let input_texture = InputTexture::new(&device, &queue, image);
-    let work_texture = WorkTexture::new(&device, image);
-    let color_index_texture = ColorIndexTexture::new(&device, image);
-    let output_texture = OutputTexture::new(&device, image);
-
-    let plus_plus_init_module =
-        PlusPlusInitModule::new(image.dimensions, k, &work_texture, &centroids_buffer);
-    let color_converter_module = ColorConverterModule::new(
-        &device,
-        color_space,
-        image.dimensions,
-        &input_texture,
-        &work_texture,
-    );
-    let color_reverter_module = ColorReverterModule::new(
-        &device,
-        color_space,
-        image.dimensions,
-        &work_texture,
-        &output_texture,
-    );
-    let find_centroid_module = FindCentroidModule::new(
-        &device,
-        image.dimensions,
-        &work_texture,
-        &centroids_buffer,
-        &color_index_texture,
-    );
-    let choose_centroid_module = ChooseCentroidModule::new(
-        &device,
-        color_space,
-        image.dimensions,
-        k,
-        &work_texture,
-        &centroids_buffer,
-        &color_index_texture,
-        &find_centroid_module,
-    );
-    let swap_module = SwapModule::new(
-        &device,
-        image.dimensions,
-        &work_texture,
-        &centroids_buffer,
-        &color_index_texture,
-    );
-
-    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
-    if let Some(query_set) = &query_set {
-        encoder.write_timestamp(query_set, 0);
+    /// The backend in use, either [`Backend::Gpu`] or [`Backend::Cpu`].
+    pub fn backend(&self) -> Backend {
+        match self.backend {
+            ContextBackend::Gpu(_) => Backend::Gpu,
+            ContextBackend::Cpu(_) => Backend::Cpu,
+        }
     }
 
-    {
-        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
-            label: Some("Init pass"),
-        });
-        color_converter_module.dispatch(&mut compute_pass);
+    pub async fn kmeans(
+        &self,
+        k: u32,
+        image: &Image,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Result<Image> {
+        validate(k, image.dimensions)?;
+        options.validate(k)?;
+        validate_alpha(image, options)?;
+
+        match &self.backend {
+            ContextBackend::Gpu(gpu) => gpu.kmeans(k, image, color_space, options).await,
+            ContextBackend::Cpu(cpu) => cpu.kmeans(k, image, color_space, options),
+        }
     }
-    queue.submit(Some(encoder.finish()));
 
-    plus_plus_init_module.compute(&device, &queue).await;
+    /// Returns the k colors of the palette, sorted by lightness.
+    pub async fn palette(
+        &self,
+        k: u32,
+        image: &Image,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Result<Vec<[u8; 4]>> {
+        validate(k, image.dimensions)?;
+        options.validate(k)?;
+        validate_alpha(image, options)?;
+
+        match &self.backend {
+            ContextBackend::Gpu(gpu) => gpu.palette(k, image, color_space, options).await,
+            ContextBackend::Cpu(cpu) => cpu.palette(k, image, color_space, options),
+        }
+    }
 
-    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
-    {
-        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
-            label: Some("Init pass"),
-        });
-        find_centroid_module.dispatch(&mut compute_pass);
+    /// Runs [`KMeansContext::kmeans`] on every image, reusing the gpu textures between images of
+    /// the same dimensions and uploading each image while the previous one is processed.
+    pub async fn kmeans_batch(
+        &self,
+        k: u32,
+        images: &[Image],
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Result<Vec<Image>> {
+        for image in images {
+            validate(k, image.dimensions)?;
+            validate_alpha(image, options)?;
+        }
+        options.validate(k)?;
+
+        match &self.backend {
+            ContextBackend::Gpu(gpu) => gpu.kmeans_batch(k, images, color_space, options).await,
+            ContextBackend::Cpu(cpu) => images
+                .iter()
+                .map(|image| cpu.kmeans(k, image, color_space, options))
+                .collect(),
+        }
     }
 
-    queue.submit(Some(encoder.finish()));
+    /// Runs [`KMeansContext::palette`] on every image, reusing the gpu textures between images of
+    /// the same dimensions and uploading each image while the previous one is processed.
+    pub async fn palette_batch(
+        &self,
+        k: u32,
+        images: &[Image],
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Result<Vec<Vec<[u8; 4]>>> {
+        for image in images {
+            validate(k, image.dimensions)?;
+            validate_alpha(image, options)?;
+        }
+        options.validate(k)?;
 
-    choose_centroid_module.compute(&device, &queue).await;
+        match &self.backend {
+            ContextBackend::Gpu(gpu) => gpu.palette_batch(k, images, color_space, options).await,
+            ContextBackend::Cpu(cpu) => images
+                .iter()
+                .map(|image| cpu.palette(k, image, color_space, options))
+                .collect(),
+        }
+    }
 
-    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
-    {
-        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
-            label: Some("Swap and fetch result pass"),
-        });
-        swap_module.dispatch(&mut compute_pass);
-        color_reverter_module.dispatch(&mut compute_pass);
+    /// Runs the k-means clustering, and returns everything known about the clusters: centroids,
+    /// populations, the label of each pixel and how well the iterations converged.
+    pub async fn cluster(
+        &self,
+        k: u32,
+        image: &Image,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Result<KMeansResult> {
+        validate(k, image.dimensions)?;
+        options.validate(k)?;
+        validate_alpha(image, options)?;
+
+        match &self.backend {
+            ContextBackend::Gpu(gpu) => gpu.cluster(k, image, color_space, options).await,
+            ContextBackend::Cpu(cpu) => cpu.cluster(k, image, color_space, options),
+        }
+    }
+
+    /// Clusters an image already on the gpu, and writes the result to a texture of the caller.
+    /// Nothing is copied back to the cpu, so the quantizer can run as a post process pass.
+    ///
+    /// The input is a view of an `Rgba8Unorm` texture of the given dimensions, created with
+    /// [`wgpu::TextureUsages::TEXTURE_BINDING`] on the device of the context, see
+    /// [`KMeansContext::from_device`]. sRGB views don't work, the shaders expect encoded colors.
+    ///
+    /// The commands are submitted to the queue without waiting for them to complete. Fails with
+    /// [`Error::GpuRequired`] on the cpu backend, and with [`Error::ImageTooLarge`] when the
+    /// dimensions exceed `max_texture_dimension_2d`, as textures can't be tiled. The pixels stay on the gpu, so an input below
+    /// the alpha threshold everywhere isn't reported as [`Error::TransparentImage`], and gives
+    /// meaningless colors.
+    pub async fn kmeans_texture(
+        &self,
+        k: u32,
+        input: &TextureView,
+        dimensions: (u32, u32),
+        output: TextureOutput<'_>,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Result<()> {
+        validate(k, dimensions)?;
+        options.validate(k)?;
+
+        match &self.backend {
+            ContextBackend::Gpu(gpu) => {
+                gpu.kmeans_texture(k, input, dimensions, output, color_space, options)
+                    .await
+            }
+            ContextBackend::Cpu(_) => Err(Error::GpuRequired),
+        }
+    }
+
+    pub async fn find(
+        &self,
+        image: &Image,
+        colors: &[[u8; 4]],
+        color_space: &ColorSpace,
+    ) -> Result<Image> {
+        validate(colors.len() as u32, image.dimensions)?;
+
+        match &self.backend {
+            ContextBackend::Gpu(gpu) => gpu.find(image, colors, color_space).await,
+            ContextBackend::Cpu(cpu) => Ok(cpu.find(image, colors, color_space)),
+        }
     }
-    if let Some(query_set) = &query_set {
-        encoder.write_timestamp(query_set, 1);
+
+    pub async fn mix(
+        &self,
+        k: u32,
+        image: &Image,
+        color_space: &ColorSpace,
+        mix_mode: &MixMode,
+        options: &KMeansOptions,
+    ) -> Result<Image> {
+        validate(k, image.dimensions)?;
+        options.validate(k)?;
+        validate_alpha(image, options)?;
+
+        match &self.backend {
+            ContextBackend::Gpu(gpu) => gpu.mix(k, image, color_space, mix_mode, options).await,
+            ContextBackend::Cpu(cpu) => cpu.mix(k, image, color_space, mix_mode, options),
+        }
     }
 
-    let output_buffer = output_texture.output_buffer(&device, &mut encoder);
+    /// Clusters the image with each k of the range, and picks the best one by the criterion.
+    /// Returns the chosen k along with the score of every k swept.
+    ///
+    /// The image is converted once for the whole sweep. Images too large for a texture are
+    /// swept downsampled.
+    pub async fn choose_k(
+        &self,
+        ks: RangeInclusive<u32>,
+        image: &Image,
+        color_space: &ColorSpace,
+        criterion: &KCriterion,
+        options: &KMeansOptions,
+    ) -> Result<KSweep> {
+        if ks.is_empty() {
+            return Err(Error::InvalidK(*ks.start()));
+        }
+        for k in ks.clone() {
+            validate(k, image.dimensions)?;
+            options.validate(k)?;
+        }
+        validate_alpha(image, options)?;
 
-    if let Some(query_set) = &query_set {
-        encoder.resolve_query_set(query_set, 0..2, &query_buf, 0);
+        match &self.backend {
+            ContextBackend::Gpu(gpu) => {
+                gpu.choose_k(ks, image, color_space, criterion, options)
+                    .await
+            }
+            ContextBackend::Cpu(cpu) => cpu.choose_k(ks, image, color_space, criterion, options),
+        }
     }
-    queue.submit(Some(encoder.finish()));
+}
 
-    let buffer_slice = output_buffer.slice(..);
-    let buffer_future = buffer_slice.map_async(MapMode::Read);
+/// Checks that the image is not empty, and has at least k pixels.
+fn validate(k: u32, (width, height): (u32, u32)) -> Result<()> {
+    if width == 0 || height == 0 {
+        return Err(Error::EmptyImage);
+    }
 
-    let query_slice = query_buf.slice(..);
-    let query_future = query_slice.map_async(MapMode::Read);
+    if k == 0 || k as u64 > width as u64 * height as u64 {
+        return Err(Error::InvalidK(k));
+    }
 
-    device.poll(wgpu::Maintain::Wait);
+    Ok(())
+}
 
-    if query_future.await.is_ok() && features.contains(Features::TIMESTAMP_QUERY) {
-        let ts_period = queue.get_timestamp_period();
-        let ts_data_raw = &*query_slice.get_mapped_range();
-        let ts_data: &[u64] = bytemuck::cast_slice(ts_data_raw);
-        println!(
-            "Compute shader elapsed: {:?}ms",
-            (ts_data[1] - ts_data[0]) as f64 * ts_period as f64 * 1e-6
-        );
+/// Checks that some pixels are opaque enough to be clustered.
+fn validate_alpha(image: &Image, options: &KMeansOptions) -> Result<()> {
+    if image
+        .rgba
+        .iter()
+        .any(|rgba| rgba[3] >= options.alpha_threshold)
+    {
+        Ok(())
+    } else {
+        Err(Error::TransparentImage(options.alpha_threshold))
     }
+}
 
-    match buffer_future.await {
-        Ok(()) => {
-            let padded_data = buffer_slice.get_mapped_range();
-            let mut pixels: Vec<u8> =
-                vec![0; output_buffer.unpadded_bytes_per_row * height as usize];
-            for (padded, pixels) in padded_data
-                .chunks_exact(output_buffer.padded_bytes_per_row)
-                .zip(pixels.chunks_exact_mut(output_buffer.unpadded_bytes_per_row))
-            {
-                pixels.copy_from_slice(&padded[..output_buffer.unpadded_bytes_per_row]);
-            }
+/// A gpu object, either created by the context or lent by the caller.
+enum Handle<'a, T> {
+    Owned(T),
+    Borrowed(&'a T),
+}
 
-            let result = Image::from_raw_pixels((width, height), &pixels);
+impl<T> Deref for Handle<'_, T> {
+    type Target = T;
 
-            Ok(result)
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Handle::Owned(value) => value,
+            Handle::Borrowed(value) => value,
         }
-        Err(e) => Err(e.into()),
     }
 }
 
-pub async fn palette(k: u32, image: &Image, color_space: &ColorSpace) -> Result<Vec<[u8; 4]>> {
-    let instance = Instance::new(Backends::all());
-    let adapter = instance
-        .request_adapter(&RequestAdapterOptionsBase {
-            power_preference: PowerPreference::HighPerformance,
-            force_fallback_adapter: false,
-            compatible_surface: None,
+/// Owns the gpu device and every compiled compute pipeline.
+struct GpuContext<'a> {
+    device: Handle<'a, Device>,
+    queue: Handle<'a, Queue>,
+    features: Features,
+    pipelines: Pipelines,
+    device_error: Arc<Mutex<Option<String>>>,
+}
+
+impl<'a> GpuContext<'a> {
+    async fn new() -> Result<Self> {
+        let instance = Instance::new(Backends::all());
+        let adapter = instance
+            .request_adapter(&RequestAdapterOptionsBase {
+                power_preference: PowerPreference::HighPerformance,
+                force_fallback_adapter: false,
+                compatible_surface: None,
+            })
+            .await
+            .ok_or(Error::NoAdapter)?;
+
+        let features = adapter.features() & Features::TIMESTAMP_QUERY;
+        let (device, queue) = adapter
+            .request_device(
+                &DeviceDescriptor {
+                    label: None,
+                    features,
+                    // The defaults are lower than what most adapters support, notably for the
+                    // size of the textures: ask for everything available.
+                    limits: adapter.limits(),
+                },
+                None,
+            )
+            .await?;
+
+        // Keep track of errors instead of letting wgpu panic, so they can be reported.
+        let device_error = Arc::new(Mutex::new(None));
+        let handler_device_error = device_error.clone();
+        device.on_uncaptured_error(move |error| {
+            if let Ok(mut device_error) = handler_device_error.lock() {
+                device_error.get_or_insert_with(|| error.to_string());
+            }
+        });
+
+        let pipelines = Pipelines::new(&device);
+
+        Ok(Self {
+            device: Handle::Owned(device),
+            queue: Handle::Owned(queue),
+            features,
+            pipelines,
+            device_error,
         })
-        .await
-        .ok_or_else(|| anyhow::anyhow!("Couldn't create the adapter"))?;
+    }
 
-    let features = adapter.features();
-    let (device, queue) = adapter
-        .request_device(
-            &DeviceDescriptor {
-                label: None,
-                features: features & (Features::TIMESTAMP_QUERY),
-                limits: Default::default(),
-            },
+    fn from_device(device: &'a Device, queue: &'a Queue) -> Self {
+        Self {
+            features: device.features(),
+            pipelines: Pipelines::new(device),
+            device: Handle::Borrowed(device),
+            queue: Handle::Borrowed(queue),
+            device_error: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    async fn kmeans(
+        &self,
+        k: u32,
+        image: &Image,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Result<Image> {
+        if self.needs_tiles(image) {
+            return self.kmeans_tiled(k, image, color_space, options).await;
+        }
+
+        let device = &self.device;
+
+        let centroids_buffer = CentroidsBuffer::empty_centroids(k, device);
+        let input_texture = InputTexture::new(device, &self.queue, image);
+        let textures = ImageTextures::new(device, image.dimensions);
+        let output_texture = OutputTexture::new(device, image.dimensions);
+
+        self.kmeans_with(
+            k,
+            image,
+            color_space,
+            options,
+            &input_texture,
+            &textures,
+            &output_texture,
+            &centroids_buffer,
             None,
         )
+        .await
+    }
+
+    /// Same as [`GpuContext::kmeans`], with textures and buffers that already exist. The
+    /// `prefetch` image is uploaded while this one is being processed.
+    #[allow(clippy::too_many_arguments)]
+    async fn kmeans_with(
+        &self,
+        k: u32,
+        image: &Image,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+        input_texture: &InputTexture,
+        textures: &ImageTextures,
+        output_texture: &OutputTexture,
+        centroids_buffer: &CentroidsBuffer,
+        prefetch: Option<(&InputTexture, &Image)>,
+    ) -> Result<Image> {
+        let device = &self.device;
+        let timestamps = Timestamps::new(self, options.max_iterations);
+
+        self.compute_centroids(
+            k,
+            image.dimensions,
+            color_space,
+            &input_texture.view(),
+            &textures.work_texture,
+            &textures.color_index_texture,
+            centroids_buffer,
+            options,
+            &timestamps,
+            Some(image),
+            true,
+            prefetch,
+        )
         .await?;
 
-    let centroids_buffer = CentroidsBuffer::empty_centroids(k, &device);
+        let swap_module = SwapModule::new(
+            device,
+            &self.pipelines.swap,
+            image.dimensions,
+            &textures.work_texture,
+            centroids_buffer,
+            &textures.color_index_texture,
+        );
+        let color_reverter_module = ColorReverterModule::new(
+            device,
+            self.pipelines.reverter(color_space),
+            image.dimensions,
+            &textures.work_texture,
+            &output_texture.view(),
+            &input_texture.view(),
+        );
 
-    let query_set = if features.contains(Features::TIMESTAMP_QUERY) {
-        Some(device.create_query_set(&QuerySetDescriptor {
-            count: 2,
-            ty: QueryType::Timestamp,
-            label: None,
-        }))
-    } else {
-        None
-    };
-    let query_buf = device.create_buffer_init(&BufferInitDescriptor {
-        label: None,
-        contents: &[0; 16],
-        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
-    });
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("Swap and fetch result pass"),
+            });
+            swap_module.dispatch(&mut compute_pass);
+            color_reverter_module.dispatch(&mut compute_pass);
+        }
+        timestamps.end(&mut encoder);
 
-    let input_texture = InputTexture::new(&device, &queue, image);
-    let work_texture = WorkTexture::new(&device, image);
-    let color_index_texture = ColorIndexTexture::new(&device, image);
-    let plus_plus_init_module =
-        PlusPlusInitModule::new(image.dimensions, k, &work_texture, &centroids_buffer);
-    let color_converter_module = ColorConverterModule::new(
-        &device,
-        color_space,
-        image.dimensions,
-        &input_texture,
-        &work_texture,
-    );
-    let find_centroid_module = FindCentroidModule::new(
-        &device,
-        image.dimensions,
-        &work_texture,
-        &centroids_buffer,
-        &color_index_texture,
-    );
-    let choose_centroid_module = ChooseCentroidModule::new(
-        &device,
-        color_space,
-        image.dimensions,
-        k,
-        &work_texture,
-        &centroids_buffer,
-        &color_index_texture,
-        &find_centroid_module,
-    );
-
-    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
-    if let Some(query_set) = &query_set {
-        encoder.write_timestamp(query_set, 0);
-    }
+        let output_buffer = output_texture.output_buffer(device, &mut encoder);
+        self.queue.submit(Some(encoder.finish()));
 
-    {
-        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
-            label: Some("Init pass"),
-        });
-        color_converter_module.dispatch(&mut compute_pass);
+        self.read_output(&output_buffer, image.dimensions, &timestamps)
+            .await
     }
-    queue.submit(Some(encoder.finish()));
 
-    plus_plus_init_module.compute(&device, &queue).await;
+    async fn palette(
+        &self,
+        k: u32,
+        image: &Image,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Result<Vec<[u8; 4]>> {
+        if self.needs_tiles(image) {
+            return self.palette_tiled(k, image, color_space, options).await;
+        }
+
+        let device = &self.device;
 
-    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
-    {
-        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
-            label: Some("Init pass"),
-        });
-        find_centroid_module.dispatch(&mut compute_pass);
+        let centroids_buffer = CentroidsBuffer::empty_centroids(k, device);
+        let input_texture = InputTexture::new(device, &self.queue, image);
+        let textures = ImageTextures::new(device, image.dimensions);
+
+        self.palette_with(
+            k,
+            image,
+            color_space,
+            options,
+            &input_texture,
+            &textures,
+            &centroids_buffer,
+            None,
+        )
+        .await
     }
 
-    queue.submit(Some(encoder.finish()));
+    /// Same as [`GpuContext::palette`], with textures and buffers that already exist. The
+    /// `prefetch` image is uploaded while this one is being processed.
+    #[allow(clippy::too_many_arguments)]
+    async fn palette_with(
+        &self,
+        k: u32,
+        image: &Image,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+        input_texture: &InputTexture,
+        textures: &ImageTextures,
+        centroids_buffer: &CentroidsBuffer,
+        prefetch: Option<(&InputTexture, &Image)>,
+    ) -> Result<Vec<[u8; 4]>> {
+        let device = &self.device;
+        let timestamps = Timestamps::new(self, options.max_iterations);
+
+        self.compute_centroids(
+            k,
+            image.dimensions,
+            color_space,
+            &input_texture.view(),
+            &textures.work_texture,
+            &textures.color_index_texture,
+            centroids_buffer,
+            options,
+            &timestamps,
+            Some(image),
+            false,
+            prefetch,
+        )
+        .await?;
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        timestamps.end(&mut encoder);
+
+        let staging_buffer = centroids_buffer.staging_buffer(device, &mut encoder);
+        self.queue.submit(Some(encoder.finish()));
 
-    choose_centroid_module.compute(&device, &queue).await;
+        let cent_buffer_slice = staging_buffer.slice(..);
+        let cent_buffer_future = cent_buffer_slice.map_async(MapMode::Read);
 
-    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
-    if let Some(query_set) = &query_set {
-        encoder.write_timestamp(query_set, 1);
+        timestamps.read(self).await?;
+
+        match cent_buffer_future.await {
+            Ok(()) => {
+                let data = cent_buffer_slice.get_mapped_range();
+
+                let mut colors: Vec<_> = bytemuck::cast_slice::<u8, f32>(&data[16..])
+                    .chunks_exact(4)
+                    .map(|color| color_space.to_rgba(color))
+                    .collect();
+                sort_by_lightness(&mut colors);
+                Ok(colors)
+            }
+            Err(e) => Err(e.into()),
+        }
     }
 
-    let staging_buffer = centroids_buffer.staging_buffer(&device, &mut encoder);
+    /// Runs the k-means clustering, and returns everything known about the clusters: centroids,
+    /// populations, the label of each pixel and how well the iterations converged.
+    async fn cluster(
+        &self,
+        k: u32,
+        image: &Image,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Result<KMeansResult> {
+        if self.needs_tiles(image) {
+            return self.cluster_tiled(k, image, color_space, options).await;
+        }
+
+        let device = &self.device;
+
+        let centroids_buffer = CentroidsBuffer::empty_centroids(k, device);
+        let timestamps = Timestamps::new(self, options.max_iterations);
+
+        let input_texture = InputTexture::new(device, &self.queue, image);
+        let work_texture = WorkTexture::new(device, image.dimensions);
+        let color_index_texture = ColorIndexTexture::new(device, image.dimensions);
+
+        let convergence = self
+            .compute_centroids(
+                k,
+                image.dimensions,
+                color_space,
+                &input_texture.view(),
+                &work_texture,
+                &color_index_texture,
+                &centroids_buffer,
+                options,
+                &timestamps,
+                Some(image),
+                true,
+                None,
+            )
+            .await?;
+
+        let cluster_stats_module = ClusterStatsModule::new(
+            device,
+            &self.pipelines.cluster_stats,
+            image.dimensions,
+            k,
+            color_space,
+            &work_texture,
+            &centroids_buffer,
+            &color_index_texture,
+        );
 
-    if let Some(query_set) = &query_set {
-        encoder.resolve_query_set(query_set, 0..2, &query_buf, 0);
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("Cluster stats pass"),
+            });
+            cluster_stats_module.dispatch(&mut compute_pass);
+        }
+        timestamps.end(&mut encoder);
+
+        let centroids_staging_buffer = centroids_buffer.staging_buffer(device, &mut encoder);
+        let (counts_staging_buffer, inertia_staging_buffer) =
+            cluster_stats_module.staging_buffers(device, &mut encoder);
+        let labels_buffer =
+            color_index_texture.output_buffer(device, &mut encoder, image.dimensions);
+        self.queue.submit(Some(encoder.finish()));
+
+        let centroids_slice = centroids_staging_buffer.slice(..);
+        let centroids_future = centroids_slice.map_async(MapMode::Read);
+        let counts_slice = counts_staging_buffer.slice(..);
+        let counts_future = counts_slice.map_async(MapMode::Read);
+        let inertia_slice = inertia_staging_buffer.slice(..);
+        let inertia_future = inertia_slice.map_async(MapMode::Read);
+        let labels_slice = labels_buffer.slice(..);
+        let labels_future = labels_slice.map_async(MapMode::Read);
+
+        let timings = timestamps.read(self).await?;
+
+        centroids_future.await?;
+        counts_future.await?;
+        inertia_future.await?;
+        labels_future.await?;
+
+        let centroids: Vec<[f32; 3]> =
+            bytemuck::cast_slice::<u8, f32>(&centroids_slice.get_mapped_range()[16..])
+                .chunks_exact(4)
+                .map(|centroid| [centroid[0], centroid[1], centroid[2]])
+                .collect();
+        let colors = centroids
+            .iter()
+            .map(|centroid| color_space.to_rgba(centroid))
+            .collect();
+        let counts = bytemuck::cast_slice::<u8, u32>(&counts_slice.get_mapped_range()).to_vec();
+        let inertia = bytemuck::cast_slice::<u8, f32>(&inertia_slice.get_mapped_range())
+            .iter()
+            .map(|&partial_sum| partial_sum as f64)
+            .sum();
+        let labels =
+            bytemuck::cast_slice::<u8, u32>(&labels_buffer.unpad(&labels_slice.get_mapped_range()))
+                .to_vec();
+
+        Ok(KMeansResult {
+            dimensions: image.dimensions,
+            centroids,
+            colors,
+            counts,
+            labels,
+            inertia,
+            iterations: convergence.iterations,
+            converged: convergence.converged,
+            reseeds: convergence.reseeds,
+            timings,
+        })
     }
-    queue.submit(Some(encoder.finish()));
 
-    let cent_buffer_slice = staging_buffer.slice(..);
-    let cent_buffer_future = cent_buffer_slice.map_async(MapMode::Read);
+    async fn find(
+        &self,
+        image: &Image,
+        colors: &[[u8; 4]],
+        color_space: &ColorSpace,
+    ) -> Result<Image> {
+        if self.needs_tiles(image) {
+            return self.find_tiled(image, colors, color_space).await;
+        }
+
+        let device = &self.device;
 
-    let query_slice = query_buf.slice(..);
-    let query_future = query_slice.map_async(MapMode::Read);
+        let centroids = CentroidsBuffer::fixed_centroids(colors, color_space, device);
+        let timestamps = Timestamps::new(self, 0);
 
-    device.poll(wgpu::Maintain::Wait);
+        let input_texture = InputTexture::new(device, &self.queue, image);
+        let work_texture = WorkTexture::new(device, image.dimensions);
+        let color_index_texture = ColorIndexTexture::new(device, image.dimensions);
+        let output_texture = OutputTexture::new(device, image.dimensions);
 
-    if query_future.await.is_ok() && features.contains(Features::TIMESTAMP_QUERY) {
-        let ts_period = queue.get_timestamp_period();
-        let ts_data_raw = &*query_slice.get_mapped_range();
-        let ts_data: &[u64] = bytemuck::cast_slice(ts_data_raw);
-        println!(
-            "Compute shader elapsed: {:?}ms",
-            (ts_data[1] - ts_data[0]) as f64 * ts_period as f64 * 1e-6
+        let color_converter_module = ColorConverterModule::new(
+            device,
+            self.pipelines.converter(color_space),
+            image.dimensions,
+            &input_texture.view(),
+            &work_texture,
+            0,
+        );
+        let color_reverter_module = ColorReverterModule::new(
+            device,
+            self.pipelines.reverter(color_space),
+            image.dimensions,
+            &work_texture,
+            &output_texture.view(),
+            &input_texture.view(),
+        );
+        let find_centroid_module = FindCentroidModule::new(
+            device,
+            &self.pipelines.find_centroid,
+            image.dimensions,
+            &work_texture,
+            &centroids,
+            &color_index_texture,
+        );
+        let swap_module = SwapModule::new(
+            device,
+            &self.pipelines.swap,
+            image.dimensions,
+            &work_texture,
+            &centroids,
+            &color_index_texture,
         );
-    }
 
-    match cent_buffer_future.await {
-        Ok(()) => {
-            let data = cent_buffer_slice.get_mapped_range();
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        timestamps.start(&mut encoder);
 
-            let mut colors: Vec<_> = bytemuck::cast_slice::<u8, f32>(&data[16..])
-                .chunks_exact(4)
-                .map(|color| {
-                    let raw: [u8; 4] = match color_space {
-                        ColorSpace::Lab => {
-                            IntoColor::<Srgba>::into_color(Lab::new(color[0], color[1], color[2]))
-                                .into_format()
-                                .into_raw()
-                        }
-                        ColorSpace::Rgb => Srgba::new(color[0], color[1], color[2], 1.0)
-                            .into_format()
-                            .into_raw(),
-                    };
-                    raw
-                })
-                .collect();
-            colors.sort_unstable_by(|a, b| {
-                let a: Lab = Srgba::from_raw(a).into_format::<_, f32>().into_color();
-                let b: Lab = Srgba::from_raw(b).into_format::<_, f32>().into_color();
-                a.l.partial_cmp(&b.l).unwrap()
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("Init pass"),
             });
-            Ok(colors)
+            color_converter_module.dispatch(&mut compute_pass);
+            find_centroid_module.dispatch(&mut compute_pass);
+            swap_module.dispatch(&mut compute_pass);
+            color_reverter_module.dispatch(&mut compute_pass);
         }
-        Err(e) => Err(e.into()),
+        timestamps.end(&mut encoder);
+
+        let output_buffer = output_texture.output_buffer(device, &mut encoder);
+        self.queue.submit(Some(encoder.finish()));
+
+        self.read_output(&output_buffer, image.dimensions, &timestamps)
+            .await
     }
-}
 
-pub async fn find(image: &Image, colors: &[[u8; 4]], color_space: &ColorSpace) -> Result<Image> {
-    let (width, height) = image.dimensions;
-
-    let instance = Instance::new(Backends::all());
-    let adapter = instance
-        .request_adapter(&RequestAdapterOptionsBase {
-            power_preference: PowerPreference::HighPerformance,
-            force_fallback_adapter: false,
-            compatible_surface: None,
-        })
-        .await
-        .ok_or_else(|| anyhow::anyhow!("Couldn't create the adapter"))?;
+    async fn mix(
+        &self,
+        k: u32,
+        image: &Image,
+        color_space: &ColorSpace,
+        mix_mode: &MixMode,
+        options: &KMeansOptions,
+    ) -> Result<Image> {
+        if self.needs_tiles(image) {
+            return self
+                .mix_tiled(k, image, color_space, mix_mode, options)
+                .await;
+        }
 
-    let features = adapter.features();
-    let (device, queue) = adapter
-        .request_device(
-            &DeviceDescriptor {
-                label: None,
-                features: features & (Features::TIMESTAMP_QUERY),
-                limits: Default::default(),
-            },
+        let device = &self.device;
+
+        let centroids_buffer = CentroidsBuffer::empty_centroids(k, device);
+        let timestamps = Timestamps::new(self, options.max_iterations);
+
+        let input_texture = InputTexture::new(device, &self.queue, image);
+        let work_texture = WorkTexture::new(device, image.dimensions);
+        let dithered_texture = WorkTexture::new(device, image.dimensions);
+        let color_index_texture = ColorIndexTexture::new(device, image.dimensions);
+        let output_texture = OutputTexture::new(device, image.dimensions);
+
+        self.compute_centroids(
+            k,
+            image.dimensions,
+            color_space,
+            &input_texture.view(),
+            &work_texture,
+            &color_index_texture,
+            &centroids_buffer,
+            options,
+            &timestamps,
+            Some(image),
+            true,
             None,
         )
         .await?;
 
-    let centroids = CentroidsBuffer::fixed_centroids(colors, color_space, &device);
+        let mix_colors_module = MixColorsModule::new(
+            device,
+            &self.pipelines.mix_colors,
+            Some(&self.pipelines.centroid_table),
+            image.dimensions,
+            &work_texture,
+            &dithered_texture,
+            &color_index_texture,
+            &centroids_buffer,
+            mix_mode,
+        );
+        let color_reverter_module = ColorReverterModule::new(
+            device,
+            self.pipelines.reverter(color_space),
+            image.dimensions,
+            &dithered_texture,
+            &output_texture.view(),
+            &input_texture.view(),
+        );
 
-    let query_set = if features.contains(Features::TIMESTAMP_QUERY) {
-        Some(device.create_query_set(&QuerySetDescriptor {
-            count: 2,
-            ty: QueryType::Timestamp,
-            label: None,
-        }))
-    } else {
-        None
-    };
-    let query_buf = device.create_buffer_init(&BufferInitDescriptor {
-        label: None,
-        contents: &[0; 16],
-        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
-    });
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("Swap and fetch result pass"),
+            });
+            mix_colors_module.dispatch(&mut compute_pass);
+            color_reverter_module.dispatch(&mut compute_pass);
+        }
+        timestamps.end(&mut encoder);
 
-    let input_texture = InputTexture::new(&device, &queue, image);
-    let work_texture = WorkTexture::new(&device, image);
-    let color_index_texture = ColorIndexTexture::new(&device, image);
-    let output_texture = OutputTexture::new(&device, image);
-
-    let color_converter_module = ColorConverterModule::new(
-        &device,
-        color_space,
-        image.dimensions,
-        &input_texture,
-        &work_texture,
-    );
-    let color_reverter_module = ColorReverterModule::new(
-        &device,
-        color_space,
-        image.dimensions,
-        &work_texture,
-        &output_texture,
-    );
-    let find_centroid_module = FindCentroidModule::new(
-        &device,
-        image.dimensions,
-        &work_texture,
-        &centroids,
-        &color_index_texture,
-    );
-
-    let swap_module = SwapModule::new(
-        &device,
-        image.dimensions,
-        &work_texture,
-        &centroids,
-        &color_index_texture,
-    );
-
-    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
-    if let Some(query_set) = &query_set {
-        encoder.write_timestamp(query_set, 0);
-    }
+        let output_buffer = output_texture.output_buffer(device, &mut encoder);
+        self.queue.submit(Some(encoder.finish()));
 
-    {
-        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
-            label: Some("Init pass"),
-        });
-        color_converter_module.dispatch(&mut compute_pass);
-        find_centroid_module.dispatch(&mut compute_pass);
-        swap_module.dispatch(&mut compute_pass);
-        color_reverter_module.dispatch(&mut compute_pass);
-    }
-    if let Some(query_set) = &query_set {
-        encoder.write_timestamp(query_set, 1);
+        self.read_output(&output_buffer, image.dimensions, &timestamps)
+            .await
     }
 
-    let output_buffer = output_texture.output_buffer(&device, &mut encoder);
+    /// Picks the initial centroids from the converted image, as set by [`KMeansOptions::init`].
+    #[allow(clippy::too_many_arguments)]
+    async fn init_centroids(
+        &self,
+        k: u32,
+        dimensions: (u32, u32),
+        color_space: &ColorSpace,
+        work_texture: &WorkTexture,
+        centroids_buffer: &CentroidsBuffer,
+        options: &KMeansOptions,
+        seed: u64,
+    ) -> Result<()> {
+        let device = &self.device;
+        let queue = &self.queue;
+
+        let plus_plus_init_module = || {
+            PlusPlusInitModule::new(
+                &self.pipelines.plus_plus_init,
+                dimensions,
+                k,
+                seed,
+                work_texture,
+                centroids_buffer,
+            )
+        };
+        match &options.init {
+            Init::PlusPlus => {
+                plus_plus_init_module()
+                    .compute(device, queue, Selection::Weighted)
+                    .await
+            }
+            Init::FarthestPoint => {
+                plus_plus_init_module()
+                    .compute(device, queue, Selection::Farthest)
+                    .await
+            }
+            Init::Random => plus_plus_init_module().pick_random(device, queue),
+            Init::KMeansParallel => {
+                let candidates = KMeansParallelModule::new(
+                    &self.pipelines.kmeans_parallel,
+                    &self.pipelines.plus_plus_init,
+                    dimensions,
+                    k,
+                    seed,
+                    work_texture,
+                )
+                .compute(device, queue)
+                .await?;
+                centroids_buffer.write(queue, &recluster(&candidates, k, seed));
+            }
+            Init::MedianCut => {
+                let bounds = histogram_bounds(color_space);
+                let histogram = ColorHistogramModule::new(
+                    &self.pipelines.color_histogram,
+                    dimensions,
+                    &bounds,
+                    work_texture,
+                )
+                .compute(device, queue)
+                .await?;
+                let colors = histogram_colors(&histogram, &bounds);
+                centroids_buffer.write(queue, &median_cut(&colors, k));
+            }
+            Init::Colors(colors) => {
+                let centroids = colors
+                    .iter()
+                    .map(|color| color_space.rgba_to_color(color))
+                    .collect::<Vec<_>>();
+                centroids_buffer.write(queue, &centroids);
+            }
+        }
 
-    if let Some(query_set) = &query_set {
-        encoder.resolve_query_set(query_set, 0..2, &query_buf, 0);
+        Ok(())
     }
-    queue.submit(Some(encoder.finish()));
 
-    let buffer_slice = output_buffer.slice(..);
-    let buffer_future = buffer_slice.map_async(MapMode::Read);
+    /// Converts the image to the work color space, then picks the initial centroids and runs the
+    /// k-means iterations, leaving the final centroids in `centroids_buffer`. With restarts, the
+    /// run of lowest inertia is kept.
+    #[allow(clippy::too_many_arguments)]
+    async fn compute_centroids(
+        &self,
+        k: u32,
+        dimensions: (u32, u32),
+        color_space: &ColorSpace,
+        input_view: &TextureView,
+        work_texture: &WorkTexture,
+        color_index_texture: &ColorIndexTexture,
+        centroids_buffer: &CentroidsBuffer,
+        options: &KMeansOptions,
+        timestamps: &Timestamps,
+        image: Option<&Image>,
+        labels: bool,
+        prefetch: Option<(&InputTexture, &Image)>,
+    ) -> Result<Convergence> {
+        if let Some(estimate_textures) =
+            self.estimate(dimensions, color_space, input_view, 1, options, timestamps)?
+        {
+            if let Some((input_texture, image)) = prefetch {
+                input_texture.write(&self.queue, image);
+            }
+
+            let convergence = self
+                .cluster_converted(
+                    k,
+                    estimate_textures.dimensions,
+                    color_space,
+                    &estimate_textures.work_texture,
+                    &estimate_textures.color_index_texture,
+                    centroids_buffer,
+                    None,
+                    options,
+                    timestamps,
+                )
+                .await?;
+            if labels {
+                self.assign(
+                    dimensions,
+                    color_space,
+                    input_view,
+                    work_texture,
+                    color_index_texture,
+                    centroids_buffer,
+                    options,
+                );
+            }
+            return Ok(convergence);
+        }
 
-    let query_slice = query_buf.slice(..);
-    let query_future = query_slice.map_async(MapMode::Read);
+        self.convert(
+            dimensions,
+            color_space,
+            input_view,
+            work_texture,
+            options,
+            timestamps,
+        )?;
+
+        // Counted while the gpu converts the image.
+        let unique_textures =
+            image.and_then(|image| self.unique_textures(image, color_space, options));
+
+        if let Some((input_texture, image)) = prefetch {
+            input_texture.write(&self.queue, image);
+        }
 
-    device.poll(wgpu::Maintain::Wait);
+        self.cluster_converted(
+            k,
+            dimensions,
+            color_space,
+            work_texture,
+            color_index_texture,
+            centroids_buffer,
+            unique_textures.as_ref(),
+            options,
+            timestamps,
+        )
+        .await
+    }
 
-    if query_future.await.is_ok() && features.contains(Features::TIMESTAMP_QUERY) {
-        let ts_period = queue.get_timestamp_period();
-        let ts_data_raw = &*query_slice.get_mapped_range();
-        let ts_data: &[u64] = bytemuck::cast_slice(ts_data_raw);
-        println!(
-            "Compute shader elapsed: {:?}ms",
-            (ts_data[1] - ts_data[0]) as f64 * ts_period as f64 * 1e-6
+    /// Submits the conversion of the input to the work color space.
+    fn convert(
+        &self,
+        dimensions: (u32, u32),
+        color_space: &ColorSpace,
+        input_view: &TextureView,
+        work_texture: &WorkTexture,
+        options: &KMeansOptions,
+        timestamps: &Timestamps,
+    ) -> Result<()> {
+        let device = &self.device;
+
+        let color_converter_module = ColorConverterModule::new(
+            device,
+            self.pipelines.converter(color_space),
+            dimensions,
+            input_view,
+            work_texture,
+            options.alpha_threshold,
         );
+
+        options.report_progress(Phase::Convert, 0, 0)?;
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        timestamps.start(&mut encoder);
+
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("Init pass"),
+            });
+            color_converter_module.dispatch(&mut compute_pass);
+        }
+        timestamps.converted(&mut encoder);
+        self.queue.submit(Some(encoder.finish()));
+
+        Ok(())
     }
 
-    match buffer_future.await {
-        Ok(()) => {
-            let padded_data = buffer_slice.get_mapped_range();
-            let mut pixels: Vec<u8> =
-                vec![0; output_buffer.unpadded_bytes_per_row * height as usize];
-            for (padded, pixels) in padded_data
-                .chunks_exact(output_buffer.padded_bytes_per_row)
-                .zip(pixels.chunks_exact_mut(output_buffer.unpadded_bytes_per_row))
+    /// Picks the initial centroids of the converted image and runs the k-means iterations,
+    /// leaving the final centroids in `centroids_buffer`. With restarts, the run of lowest
+    /// inertia is kept. Given its `unique_textures`, the iterations run over the unique colors
+    /// of the image, and a last pass gives each pixel the label of its color.
+    #[allow(clippy::too_many_arguments)]
+    async fn cluster_converted(
+        &self,
+        k: u32,
+        dimensions: (u32, u32),
+        color_space: &ColorSpace,
+        work_texture: &WorkTexture,
+        color_index_texture: &ColorIndexTexture,
+        centroids_buffer: &CentroidsBuffer,
+        unique_textures: Option<&ImageTextures>,
+        options: &KMeansOptions,
+        timestamps: &Timestamps,
+    ) -> Result<Convergence> {
+        let device = &self.device;
+        let queue = &self.queue;
+
+        let (clustered_dimensions, clustered_texture, clustered_index_texture) =
+            match unique_textures {
+                Some(textures) => (
+                    textures.dimensions,
+                    &textures.work_texture,
+                    &textures.color_index_texture,
+                ),
+                None => (dimensions, work_texture, color_index_texture),
+            };
+        let find_centroid_module = FindCentroidModule::bounded(
+            device,
+            &self.pipelines.find_centroid,
+            &self.pipelines.centroid_table,
+            clustered_dimensions,
+            clustered_texture,
+            centroids_buffer,
+            clustered_index_texture,
+        );
+        let expand_module = unique_textures.map(|_| {
+            FindCentroidModule::new(
+                device,
+                &self.pipelines.find_centroid,
+                dimensions,
+                work_texture,
+                centroids_buffer,
+                color_index_texture,
+            )
+        });
+        let label_module = expand_module.as_ref().unwrap_or(&find_centroid_module);
+
+        let runs = options.runs();
+        let best_centroids_buffer = (runs > 1).then(|| CentroidsBuffer::empty_centroids(k, device));
+        let mut best: Option<(f64, Convergence)> = None;
+        for run in 0..runs {
+            options.report_progress(Phase::Init, 0, 0)?;
+            self.init_centroids(
+                k,
+                dimensions,
+                color_space,
+                work_texture,
+                centroids_buffer,
+                options,
+                options.seed.wrapping_add(run as u64),
+            )
+            .await?;
+
+            let mut encoder =
+                device.create_command_encoder(&CommandEncoderDescriptor { label: None });
             {
-                pixels.copy_from_slice(&padded[..output_buffer.unpadded_bytes_per_row]);
+                let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("Init pass"),
+                });
+                find_centroid_module.dispatch(&mut compute_pass);
             }
+            timestamps.initialized(&mut encoder);
+
+            queue.submit(Some(encoder.finish()));
+
+            let convergence = match options.algorithm {
+                Algorithm::Lloyd => {
+                    let choose_centroid_state =
+                        ChooseCentroidState::new(device, k, color_space, options);
+                    ChooseCentroidModule::new(
+                        device,
+                        &self.pipelines.choose_centroid,
+                        &choose_centroid_state,
+                        clustered_dimensions,
+                        k,
+                        clustered_texture,
+                        centroids_buffer,
+                        clustered_index_texture,
+                    )
+                    .compute(device, queue, options, &find_centroid_module, timestamps)
+                    .await?
+                }
+                Algorithm::MiniBatch(batch_size) => {
+                    MiniBatchModule::new(
+                        device,
+                        &self.pipelines.mini_batch,
+                        k,
+                        batch_size,
+                        clustered_texture,
+                        centroids_buffer,
+                        color_space,
+                        options,
+                    )
+                    .compute(device, queue, options, &find_centroid_module, timestamps)
+                    .await?
+                }
+            };
+
+            let Some(best_centroids_buffer) = &best_centroids_buffer else {
+                if let Some(expand_module) = &expand_module {
+                    let mut encoder =
+                        device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+                    {
+                        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                            label: Some("Expand labels pass"),
+                        });
+                        expand_module.dispatch(&mut compute_pass);
+                    }
+                    queue.submit(Some(encoder.finish()));
+                }
+                return Ok(convergence);
+            };
+            let inertia = ClusterStatsModule::new(
+                device,
+                &self.pipelines.cluster_stats,
+                clustered_dimensions,
+                k,
+                color_space,
+                clustered_texture,
+                centroids_buffer,
+                clustered_index_texture,
+            )
+            .inertia(device, queue)
+            .await?;
+            debug!("Run {run}: inertia {inertia}");
+            if best
+                .as_ref()
+                .is_none_or(|(best_inertia, _)| inertia < *best_inertia)
+            {
+                let mut encoder =
+                    device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+                centroids_buffer.copy_to(&mut encoder, best_centroids_buffer);
+                queue.submit(Some(encoder.finish()));
+                best = Some((inertia, convergence));
+            }
+        }
+
+        // Back to the best run, along with its labels.
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        if let Some(best_centroids_buffer) = &best_centroids_buffer {
+            best_centroids_buffer.copy_to(&mut encoder, centroids_buffer);
+        }
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("Best run pass"),
+            });
+            label_module.dispatch(&mut compute_pass);
+        }
+        queue.submit(Some(encoder.finish()));
+
+        Ok(best
+            .map(|(_, convergence)| convergence)
+            .unwrap_or(Convergence {
+                iterations: 0,
+                converged: false,
+                reseeds: 0,
+            }))
+    }
+
+    /// Fails if the device reported an error since it was created.
+    fn check_device(&self) -> Result<()> {
+        match self.device_error.lock() {
+            Ok(device_error) => match device_error.as_ref() {
+                Some(reason) => Err(Error::DeviceLost(reason.clone())),
+                None => Ok(()),
+            },
+            Err(_) => Err(Error::DeviceLost(
+                "Device error handler panicked".to_owned(),
+            )),
+        }
+    }
+
+    /// Waits for the output texture to be copied, and strips the row padding.
+    async fn read_output(
+        &self,
+        output_buffer: &OutputBuffer,
+        image_dimensions: (u32, u32),
+        timestamps: &Timestamps,
+    ) -> Result<Image> {
+        let buffer_slice = output_buffer.slice(..);
+        let buffer_future = buffer_slice.map_async(MapMode::Read);
+
+        timestamps.read(self).await?;
 
-            let result = Image::from_raw_pixels((width, height), &pixels);
+        match buffer_future.await {
+            Ok(()) => {
+                let pixels = output_buffer.unpad(&buffer_slice.get_mapped_range());
 
-            Ok(result)
+                let result = Image::from_raw_pixels(image_dimensions, &pixels);
+
+                Ok(result)
+            }
+            Err(e) => Err(e.into()),
         }
-        Err(e) => Err(e.into()),
     }
 }
 
+/// Measures the time spent on the gpu by each phase, when the adapter supports timestamp queries.
+///
+/// Each phase writes a timestamp when it ends, in its own slot of the query set: the conversion,
+/// the init, every iteration, and finally the readback.
+struct Timestamps {
+    query_set: Option<QuerySet>,
+    query_buf: Buffer,
+    count: u32,
+    iterations: Cell<u32>,
+}
+
+impl Timestamps {
+    const START: u32 = 0;
+    const CONVERTED: u32 = 1;
+    const INITIALIZED: u32 = 2;
+    const FIRST_ITERATION: u32 = 3;
+
+    fn new(context: &GpuContext<'_>, max_iterations: u32) -> Self {
+        let count = (max_iterations + Self::FIRST_ITERATION + 1).min(QUERY_SET_MAX_QUERIES);
+        let query_set = if context.features.contains(Features::TIMESTAMP_QUERY) {
+            Some(context.device.create_query_set(&QuerySetDescriptor {
+                count,
+                ty: QueryType::Timestamp,
+                label: None,
+            }))
+        } else {
+            None
+        };
+        let query_buf = context.device.create_buffer_init(&BufferInitDescriptor {
+            label: None,
+            contents: &vec![0; (count * QUERY_SIZE) as usize],
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+        });
+
+        Self {
+            query_set,
+            query_buf,
+            count,
+            iterations: Cell::new(0),
+        }
+    }
+
+    fn write(&self, encoder: &mut CommandEncoder, index: u32) {
+        if let Some(query_set) = &self.query_set {
+            encoder.write_timestamp(query_set, index);
+        }
+    }
+
+    fn start(&self, encoder: &mut CommandEncoder) {
+        self.write(encoder, Self::START);
+    }
+
+    fn converted(&self, encoder: &mut CommandEncoder) {
+        self.write(encoder, Self::CONVERTED);
+    }
+
+    fn initialized(&self, encoder: &mut CommandEncoder) {
+        self.write(encoder, Self::INITIALIZED);
+    }
+
+    fn iterated(&self, encoder: &mut CommandEncoder, iteration: u32) {
+        let index = Self::FIRST_ITERATION + iteration;
+        // The last slot is kept for the end.
+        if index + 1 < self.count {
+            self.write(encoder, index);
+            self.iterations.set(iteration + 1);
+        }
+    }
+
+    /// Keeps the timestamps of the iterations the gpu ran, dropping the ones recorded after the
+    /// convergence, which it skipped.
+    fn ran(&self, iterations: u32) {
+        self.iterations.set(self.iterations.get().min(iterations));
+    }
+
+    fn end(&self, encoder: &mut CommandEncoder) {
+        if let Some(query_set) = &self.query_set {
+            encoder.write_timestamp(query_set, self.count - 1);
+            encoder.resolve_query_set(query_set, 0..self.count, &self.query_buf, 0);
+        }
+    }
+
+    /// Waits for the device to finish its work, and logs the time spent in each phase.
+    async fn read(&self, context: &GpuContext<'_>) -> Result<Option<Timings>> {
+        let query_slice = self.query_buf.slice(..);
+        let query_future = query_slice.map_async(MapMode::Read);
+
+        context.device.poll(wgpu::Maintain::Wait);
+        context.check_device()?;
+
+        if query_future.await.is_err() || self.query_set.is_none() {
+            return Ok(None);
+        }
+
+        let period = context.queue.get_timestamp_period() as f64;
+        let timings = {
+            let data = query_slice.get_mapped_range();
+            let timestamps: &[u64] = bytemuck::cast_slice(&data);
+
+            // Phases that didn't run left their slot empty, their time goes to the next phase.
+            let mut previous = timestamps[Self::START as usize];
+            let mut elapsed = |index: u32| {
+                let timestamp = timestamps[index as usize];
+                if timestamp == 0 {
+                    return Duration::ZERO;
+                }
+                let nanos = timestamp.saturating_sub(previous) as f64 * period;
+                previous = timestamp;
+                Duration::from_nanos(nanos as u64)
+            };
+
+            Timings {
+                conversion: elapsed(Self::CONVERTED),
+                init: elapsed(Self::INITIALIZED),
+                iterations: (0..self.iterations.get())
+                    .map(|iteration| elapsed(Self::FIRST_ITERATION + iteration))
+                    .collect(),
+                readback: elapsed(self.count - 1),
+            }
+        };
+        self.query_buf.unmap();
+
+        timings.log();
+        Ok(Some(timings))
+    }
+}
+
+pub async fn kmeans(
+    k: u32,
+    image: &Image,
+    color_space: &ColorSpace,
+    options: &KMeansOptions,
+) -> Result<Image> {
+    KMeansContext::new()
+        .await?
+        .kmeans(k, image, color_space, options)
+        .await
+}
+
+pub async fn palette(
+    k: u32,
+    image: &Image,
+    color_space: &ColorSpace,
+    options: &KMeansOptions,
+) -> Result<Vec<[u8; 4]>> {
+    KMeansContext::new()
+        .await?
+        .palette(k, image, color_space, options)
+        .await
+}
+
+pub async fn kmeans_batch(
+    k: u32,
+    images: &[Image],
+    color_space: &ColorSpace,
+    options: &KMeansOptions,
+) -> Result<Vec<Image>> {
+    KMeansContext::new()
+        .await?
+        .kmeans_batch(k, images, color_space, options)
+        .await
+}
+
+pub async fn palette_batch(
+    k: u32,
+    images: &[Image],
+    color_space: &ColorSpace,
+    options: &KMeansOptions,
+) -> Result<Vec<Vec<[u8; 4]>>> {
+    KMeansContext::new()
+        .await?
+        .palette_batch(k, images, color_space, options)
+        .await
+}
+
+pub async fn cluster(
+    k: u32,
+    image: &Image,
+    color_space: &ColorSpace,
+    options: &KMeansOptions,
+) -> Result<KMeansResult> {
+    KMeansContext::new()
+        .await?
+        .cluster(k, image, color_space, options)
+        .await
+}
+
+pub async fn find(image: &Image, colors: &[[u8; 4]], color_space: &ColorSpace) -> Result<Image> {
+    KMeansContext::new()
+        .await?
+        .find(image, colors, color_space)
+        .await
+}
+
 pub async fn mix(
     k: u32,
     image: &Image,
     color_space: &ColorSpace,
     mix_mode: &MixMode,
+    options: &KMeansOptions,
 ) -> Result<Image> {
-    let (width, height) = image.dimensions;
-
-    let instance = Instance::new(Backends::all());
-    let adapter = instance
-        .request_adapter(&RequestAdapterOptionsBase {
-            power_preference: PowerPreference::HighPerformance,
-            force_fallback_adapter: false,
-            compatible_surface: None,
-        })
+    KMeansContext::new()
+        .await?
+        .mix(k, image, color_space, mix_mode, options)
         .await
-        .ok_or_else(|| anyhow::anyhow!("Couldn't create the adapter"))?;
-
-    let features = adapter.features();
-    let (device, queue) = adapter
-        .request_device(
-            &DeviceDescriptor {
-                label: None,
-                features: features & (Features::TIMESTAMP_QUERY),
-                limits: Default::default(),
-            },
-            None,
-        )
-        .await?;
+}
 
-    let centroids_buffer = CentroidsBuffer::empty_centroids(k, &device);
+pub async fn choose_k(
+    ks: RangeInclusive<u32>,
+    image: &Image,
+    color_space: &ColorSpace,
+    criterion: &KCriterion,
+    options: &KMeansOptions,
+) -> Result<KSweep> {
+    KMeansContext::new()
+        .await?
+        .choose_k(ks, image, color_space, criterion, options)
+        .await
+}
 
-    let query_set = if features.contains(Features::TIMESTAMP_QUERY) {
-        Some(device.create_query_set(&QuerySetDescriptor {
-            count: 2,
-            ty: QueryType::Timestamp,
-            label: None,
-        }))
-    } else {
-        None
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
     };
-    let query_buf = device.create_buffer_init(&BufferInitDescriptor {
-        label: None,
-        contents: &[0; 16],
-        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
-    });
 
-    let input_texture = InputTexture::new(&device, &queue, image);
-    let work_texture = WorkTexture::new(&device, image);
-    let dithered_texture = WorkTexture::new(&device, image);
-    let color_index_texture = ColorIndexTexture::new(&device, image);
-    let output_texture = OutputTexture::new(&device, image);
-
-    let plus_plus_init_module =
-        PlusPlusInitModule::new(image.dimensions, k, &work_texture, &centroids_buffer);
-    let color_converter_module = ColorConverterModule::new(
-        &device,
-        color_space,
-        image.dimensions,
-        &input_texture,
-        &work_texture,
-    );
-    let find_centroid_module = FindCentroidModule::new(
-        &device,
-        image.dimensions,
-        &work_texture,
-        &centroids_buffer,
-        &color_index_texture,
-    );
-    let choose_centroid_module = ChooseCentroidModule::new(
-        &device,
-        color_space,
-        image.dimensions,
-        k,
-        &work_texture,
-        &centroids_buffer,
-        &color_index_texture,
-        &find_centroid_module,
-    );
-    let mix_colors_module = MixColorsModule::new(
-        &device,
-        image.dimensions,
-        &work_texture,
-        &dithered_texture,
-        &color_index_texture,
-        &centroids_buffer,
-        mix_mode,
-    );
-    let color_reverter_module = ColorReverterModule::new(
-        &device,
-        color_space,
-        image.dimensions,
-        &dithered_texture,
-        &output_texture,
-    );
-
-    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
-    if let Some(query_set) = &query_set {
-        encoder.write_timestamp(query_set, 0);
-    }
+    use super::*;
 
-    {
-        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
-            label: Some("Init pass"),
-        });
-        color_converter_module.dispatch(&mut compute_pass);
+    /// A context on the gpu. The tests needing one are ignored, for machines without an adapter:
+    /// run them with `cargo test -- --ignored`, where they fail without one.
+    pub(crate) fn gpu_context() -> KMeansContext<'static> {
+        pollster::block_on(KMeansContext::with_backend(Backend::Gpu)).unwrap()
     }
-    queue.submit(Some(encoder.finish()));
-
-    plus_plus_init_module.compute(&device, &queue).await;
 
-    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
-    {
-        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
-            label: Some("Init pass"),
-        });
-        find_centroid_module.dispatch(&mut compute_pass);
+    /// Four colors in quadrants, each pixel shifted by up to `noise` from its color.
+    pub(crate) fn quadrants_image(noise: u8) -> Image {
+        const COLORS: [[u8; 4]; 4] = [
+            [200, 30, 40, 255],
+            [20, 90, 180, 255],
+            [40, 180, 60, 255],
+            [230, 220, 200, 255],
+        ];
+        let mut state = 0x2545f491u32;
+        let rgba = (0..64 * 64)
+            .map(|i| {
+                let quadrant = (i % 64 / 32) + (i / 64 / 32) * 2;
+                let mut color = COLORS[quadrant];
+                for channel in &mut color[..3] {
+                    // xorshift32, for noise that is the same on every run.
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    if noise > 0 {
+                        *channel = channel.saturating_add((state % noise as u32) as u8);
+                    }
+                }
+                color
+            })
+            .collect();
+        Image::new((64, 64), rgba)
     }
 
-    queue.submit(Some(encoder.finish()));
+    #[test]
+    fn test_transparent_image() {
+        let context = pollster::block_on(KMeansContext::with_backend(Backend::Cpu)).unwrap();
+        let image = Image::new((4, 4), vec![[200, 30, 40, 100]; 16]);
 
-    choose_centroid_module.compute(&device, &queue).await;
+        let options = KMeansOptions::new().alpha_threshold(101);
+        let result = pollster::block_on(context.palette(2, &image, &ColorSpace::Lab, &options));
+        assert!(matches!(result, Err(Error::TransparentImage(101))));
 
-    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
-    {
-        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
-            label: Some("Swap and fetch result pass"),
-        });
-        mix_colors_module.dispatch(&mut compute_pass);
-        color_reverter_module.dispatch(&mut compute_pass);
-    }
-    if let Some(query_set) = &query_set {
-        encoder.write_timestamp(query_set, 1);
+        let options = KMeansOptions::new().alpha_threshold(100);
+        let result = pollster::block_on(context.palette(2, &image, &ColorSpace::Lab, &options));
+        assert!(result.is_ok());
     }
 
-    let output_buffer = output_texture.output_buffer(&device, &mut encoder);
+    #[test]
+    fn test_empty_mini_batch() {
+        let context = pollster::block_on(KMeansContext::with_backend(Backend::Cpu)).unwrap();
+        let image = quadrants_image(0);
 
-    if let Some(query_set) = &query_set {
-        encoder.resolve_query_set(query_set, 0..2, &query_buf, 0);
+        let options = KMeansOptions::new().algorithm(Algorithm::MiniBatch(0));
+        let result = pollster::block_on(context.palette(4, &image, &ColorSpace::Lab, &options));
+        assert!(matches!(result, Err(Error::EmptyMiniBatch)));
     }
-    queue.submit(Some(encoder.finish()));
 
-    let buffer_slice = output_buffer.slice(..);
-    let buffer_future = buffer_slice.map_async(MapMode::Read);
+    #[test]
+    #[ignore = "needs a gpu adapter, run with --ignored"]
+    fn test_gpu_lloyd() {
+        let context = gpu_context();
+        let image = quadrants_image(0);
+
+        for reduction in [Reduction::PerCentroid, Reduction::SinglePass] {
+            let options = KMeansOptions::new().reduction(reduction);
+            let result =
+                pollster::block_on(context.cluster(4, &image, &ColorSpace::Rgb, &options)).unwrap();
+            assert!(result.converged(), "{reduction:?}");
+
+            let mut colors = result.colors().to_vec();
+            colors.sort();
+            let mut expected = image.rgba.clone();
+            expected.sort();
+            expected.dedup();
+            assert_eq!(colors, expected, "{reduction:?}");
+            assert_eq!(result.image().rgba, image.rgba, "{reduction:?}");
+        }
+    }
 
-    let query_slice = query_buf.slice(..);
-    let query_future = query_slice.map_async(MapMode::Read);
+    #[test]
+    #[ignore = "needs a gpu adapter, run with --ignored"]
+    fn test_gpu_texture_too_large() {
+        let context = gpu_context();
+        let ContextBackend::Gpu(gpu) = &context.backend else {
+            unreachable!()
+        };
+        let max_dimension = gpu.device.limits().max_texture_dimension_2d;
+        let dimensions = (max_dimension + 1, 1);
+        let input = InputTexture::storage(&gpu.device, (1, 1));
+        let output = OutputTexture::new(&gpu.device, (1, 1));
+        let result = pollster::block_on(context.kmeans_texture(
+            1,
+            &input.view(),
+            dimensions,
+            TextureOutput::Colors(&output.view()),
+            &ColorSpace::Lab,
+            &KMeansOptions::new(),
+        ));
+        assert!(matches!(
+            result,
+            Err(Error::ImageTooLarge { dimensions: d, max_dimension: m })
+                if d == dimensions && m == max_dimension
+        ));
+    }
 
-    device.poll(wgpu::Maintain::Wait);
+    #[test]
+    #[ignore = "needs a gpu adapter, run with --ignored"]
+    fn test_gpu_progress_per_batch() {
+        let context = gpu_context();
+        let image = quadrants_image(40);
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let token = CancellationToken::new();
+
+        let options = {
+            let reports = reports.clone();
+            KMeansOptions::new()
+                .convergence_check_interval(4)
+                .on_progress(move |progress| {
+                    if progress.phase == Phase::Iterate {
+                        reports.lock().unwrap().push(*progress);
+                    }
+                })
+        };
+        let result =
+            pollster::block_on(context.cluster(4, &image, &ColorSpace::Lab, &options)).unwrap();
+        let reports = std::mem::take(&mut *reports.lock().unwrap());
+        // Once per batch, with the iterations the gpu ran before it.
+        assert!(!reports.is_empty());
+        for (batch, progress) in reports.iter().enumerate() {
+            assert_eq!(progress.iteration, batch as u32 * 4);
+            assert!(progress.iteration < result.iterations());
+        }
+        assert_eq!(reports[0].converged, 0);
+
+        let calls = Arc::new(AtomicU32::new(0));
+        let options = {
+            let (token, calls) = (token.clone(), calls.clone());
+            KMeansOptions::new()
+                .convergence_check_interval(4)
+                .cancellation_token(token.clone())
+                .on_progress(move |progress| {
+                    if progress.phase == Phase::Iterate {
+                        calls.fetch_add(1, Ordering::Relaxed);
+                        token.cancel();
+                    }
+                })
+        };
+        let result = pollster::block_on(context.cluster(4, &image, &ColorSpace::Lab, &options));
+        assert!(matches!(result, Err(Error::Cancelled)));
+        // Stopped before submitting the batch that followed the cancellation.
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
 
-    if query_future.await.is_ok() && features.contains(Features::TIMESTAMP_QUERY) {
-        let ts_period = queue.get_timestamp_period();
-        let ts_data_raw = &*query_slice.get_mapped_range();
-        let ts_data: &[u64] = bytemuck::cast_slice(ts_data_raw);
-        println!(
-            "Compute shader elapsed: {:?}ms",
-            (ts_data[1] - ts_data[0]) as f64 * ts_period as f64 * 1e-6
-        );
+    #[test]
+    #[ignore = "needs a gpu adapter, run with --ignored"]
+    fn test_gpu_empty_clusters_are_reseeded_at_once() {
+        let context = gpu_context();
+        const COLORS: [[u8; 4]; 6] = [
+            [0, 0, 0, 255],
+            [60, 0, 0, 255],
+            [255, 255, 255, 255],
+            [255, 195, 255, 255],
+            [0, 0, 255, 255],
+            [0, 60, 255, 255],
+        ];
+        let image = Image::new((6, 6), (0..36).map(|i| COLORS[i % 6]).collect());
+        let init = Init::Colors(vec![
+            [30, 0, 0, 255],
+            [255, 225, 255, 255],
+            [0, 30, 255, 255],
+            [128, 128, 0, 255],
+            [128, 128, 10, 255],
+            [128, 128, 20, 255],
+        ]);
+
+        for reduction in [Reduction::PerCentroid, Reduction::SinglePass] {
+            let options = KMeansOptions::new().init(init.clone()).reduction(reduction);
+            let result =
+                pollster::block_on(context.cluster(6, &image, &ColorSpace::Rgb, &options)).unwrap();
+            assert_eq!(result.reseeds(), 3, "{reduction:?}");
+            assert_eq!(result.counts(), &[6; 6], "{reduction:?}");
+            assert!(result.converged(), "{reduction:?}");
+        }
     }
 
-    match buffer_future.await {
-        Ok(()) => {
-            let padded_data = buffer_slice.get_mapped_range();
-            let mut pixels: Vec<u8> =
-                vec![0; output_buffer.unpadded_bytes_per_row * height as usize];
-            for (padded, pixels) in padded_data
-                .chunks_exact(output_buffer.padded_bytes_per_row)
-                .zip(pixels.chunks_exact_mut(output_buffer.unpadded_bytes_per_row))
-            {
-                pixels.copy_from_slice(&padded[..output_buffer.unpadded_bytes_per_row]);
-            }
+    #[test]
+    #[ignore = "needs a gpu adapter, run with --ignored"]
+    fn test_gpu_deterministic() {
+        let context = gpu_context();
+        let image = quadrants_image(40);
+        let options = KMeansOptions::new()
+            .reduction(Reduction::PerCentroid)
+            .deterministic(true);
+
+        let run =
+            || pollster::block_on(context.cluster(4, &image, &ColorSpace::Lab, &options)).unwrap();
+        let first = run();
+        let second = run();
+        assert!(first.converged());
+        assert_eq!(first.centroids(), second.centroids());
+        assert_eq!(first.labels(), second.labels());
+    }
 
-            let result = Image::from_raw_pixels((width, height), &pixels);
+    #[test]
+    #[ignore = "needs a gpu adapter, run with --ignored"]
+    fn test_gpu_single_pass_matches_per_centroid() {
+        let context = gpu_context();
+        let image = quadrants_image(40);
 
-            Ok(result)
+        let run = |reduction| {
+            let options = KMeansOptions::new().reduction(reduction);
+            pollster::block_on(context.cluster(4, &image, &ColorSpace::Lab, &options)).unwrap()
+        };
+        let per_centroid = run(Reduction::PerCentroid);
+        let single_pass = run(Reduction::SinglePass);
+        assert!(single_pass.converged());
+        assert_eq!(per_centroid.labels(), single_pass.labels());
+        // Floats against fixed point sums, rounded to 1/65536.
+        for (a, b) in per_centroid.centroids().iter().zip(single_pass.centroids()) {
+            for (a, b) in a.iter().zip(b) {
+                assert!((a - b).abs() < 0.001, "{a} != {b}");
+            }
         }
-        Err(e) => Err(e.into()),
     }
 }
@@ -1,39 +1,134 @@
 use log::{debug, log_enabled};
-use std::num::NonZeroU32;
+use std::cell::Cell;
 use wgpu::{
     util::{BufferInitDescriptor, DeviceExt},
-    BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayoutDescriptor,
+    BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor,
     BindGroupLayoutEntry, BindingResource, BindingType, Buffer, BufferAddress, BufferBinding,
-    BufferBindingType, BufferDescriptor, BufferUsages, CommandEncoderDescriptor, ComputePass,
-    ComputePassDescriptor, ComputePipeline, ComputePipelineDescriptor, Device, MapMode,
-    PipelineLayoutDescriptor, Queue, ShaderSource, ShaderStages, StorageTextureAccess, Texture,
-    TextureDimension, TextureFormat, TextureSampleType, TextureUsages, TextureViewDescriptor,
-    TextureViewDimension,
+    BufferBindingType, BufferDescriptor, BufferSlice, BufferUsages, CommandEncoder,
+    CommandEncoderDescriptor, ComputePass, ComputePassDescriptor, ComputePipeline,
+    ComputePipelineDescriptor, Device, MapMode, PipelineLayoutDescriptor, Queue, ShaderSource,
+    ShaderStages, StorageTextureAccess, Texture, TextureDimension, TextureFormat,
+    TextureSampleType, TextureUsages, TextureView, TextureViewDescriptor, TextureViewDimension,
 };
 
 use crate::{
-    utils::compute_work_group_count, CentroidsBuffer, ColorIndexTexture, ColorSpace, InputTexture,
-    MixMode, OutputTexture, WorkTexture,
+    init::{
+        candidate_capacity, oversampling, sort_candidates, HISTOGRAM_BINS, KMEANS_PARALLEL_ROUNDS,
+        PRIORITY_BINS,
+    },
+    utils::compute_work_group_count,
+    CentroidsBuffer, ColorIndexTexture, ColorSpace, Estimation, KMeansOptions, MixMode, Phase,
+    Reduction, Result, Timestamps, WorkTexture,
 };
 
 pub(crate) trait Module {
     fn dispatch<'a>(&'a self, compute_pass: &mut ComputePass<'a>);
 }
 
-pub(crate) struct ColorConverterModule {
+/// Every compute pipeline used by the library, compiled once per device so that
+/// a single context can process many images.
+pub(crate) struct Pipelines {
+    lab_converter: ColorConverterPipeline,
+    rgb_converter: ColorConverterPipeline,
+    lab_reverter: ColorReverterPipeline,
+    rgb_reverter: ColorReverterPipeline,
+    pub swap: SwapPipeline,
+    pub find_centroid: FindCentroidPipeline,
+    pub choose_centroid: ChooseCentroidPipeline,
+    pub plus_plus_init: PlusPlusInitPipeline,
+    pub kmeans_parallel: KMeansParallelPipeline,
+    pub color_histogram: ColorHistogramPipeline,
+    pub mix_colors: MixColorsPipeline,
+    pub cluster_stats: ClusterStatsPipeline,
+    pub estimate: EstimatePipeline,
+    pub mini_batch: MiniBatchPipeline,
+    pub centroid_table: CentroidTablePipeline,
+}
+
+impl Pipelines {
+    pub fn new(device: &Device) -> Self {
+        Self {
+            lab_converter: ColorConverterPipeline::new(device, &ColorSpace::Lab),
+            rgb_converter: ColorConverterPipeline::new(device, &ColorSpace::Rgb),
+            lab_reverter: ColorReverterPipeline::new(device, &ColorSpace::Lab),
+            rgb_reverter: ColorReverterPipeline::new(device, &ColorSpace::Rgb),
+            swap: SwapPipeline::new(device),
+            find_centroid: FindCentroidPipeline::new(device),
+            choose_centroid: ChooseCentroidPipeline::new(device),
+            plus_plus_init: PlusPlusInitPipeline::new(device),
+            kmeans_parallel: KMeansParallelPipeline::new(device),
+            color_histogram: ColorHistogramPipeline::new(device),
+            mix_colors: MixColorsPipeline::new(device),
+            cluster_stats: ClusterStatsPipeline::new(device),
+            estimate: EstimatePipeline::new(device),
+            mini_batch: MiniBatchPipeline::new(device),
+            centroid_table: CentroidTablePipeline::new(device),
+        }
+    }
+
+    pub fn converter(&self, color_space: &ColorSpace) -> &ColorConverterPipeline {
+        match color_space {
+            ColorSpace::Lab => &self.lab_converter,
+            ColorSpace::Rgb => &self.rgb_converter,
+        }
+    }
+
+    pub fn reverter(&self, color_space: &ColorSpace) -> &ColorReverterPipeline {
+        match color_space {
+            ColorSpace::Lab => &self.lab_reverter,
+            ColorSpace::Rgb => &self.rgb_reverter,
+        }
+    }
+}
+
+fn k_index_bind_group_layout(device: &Device) -> BindGroupLayout {
+    device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some("K index bind group layout"),
+        entries: &[BindGroupLayoutEntry {
+            binding: 0,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        }],
+    })
+}
+
+/// One bind group per centroid, each exposing its own index as a uniform.
+fn k_index_bind_groups(device: &Device, layout: &BindGroupLayout, k: u32) -> Vec<BindGroup> {
+    (0..k)
+        .map(|k| {
+            let buffer = device.create_buffer_init(&BufferInitDescriptor {
+                label: None,
+                contents: bytemuck::cast_slice(&[k]),
+                usage: BufferUsages::UNIFORM,
+            });
+            device.create_bind_group(&BindGroupDescriptor {
+                label: None,
+                layout,
+                entries: &[BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::Buffer(BufferBinding {
+                        buffer: &buffer,
+                        offset: 0,
+                        size: None,
+                    }),
+                }],
+            })
+        })
+        .collect()
+}
+
+pub(crate) struct ColorConverterPipeline {
     pipeline: ComputePipeline,
-    bind_group: BindGroup,
-    dispatch_size: (u32, u32),
+    bind_group_layout: BindGroupLayout,
 }
 
-impl ColorConverterModule {
-    pub fn new(
-        device: &Device,
-        color_space: &ColorSpace,
-        image_dimensions: (u32, u32),
-        input_texture: &InputTexture,
-        work_texture: &WorkTexture,
-    ) -> Self {
+impl ColorConverterPipeline {
+    pub fn new(device: &Device, color_space: &ColorSpace) -> Self {
         let convert_color_shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
             label: Some("Convert color shader"),
             source: ShaderSource::Wgsl(
@@ -60,6 +155,16 @@ impl ColorConverterModule {
                         count: None,
                     },
                     WorkTexture::texture_storage_layout(1),
+                    BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
                 ],
             });
 
@@ -77,23 +182,41 @@ impl ColorConverterModule {
             entry_point: "main",
         });
 
+        Self {
+            pipeline,
+            bind_group_layout: convert_color_bind_group_layout,
+        }
+    }
+}
+
+pub(crate) struct ColorConverterModule<'a> {
+    pipeline: &'a ComputePipeline,
+    bind_group: BindGroup,
+    dispatch_size: (u32, u32),
+}
+
+impl<'a> ColorConverterModule<'a> {
+    pub fn new(
+        device: &Device,
+        pipeline: &'a ColorConverterPipeline,
+        image_dimensions: (u32, u32),
+        input_view: &TextureView,
+        work_texture: &WorkTexture,
+        alpha_threshold: u8,
+    ) -> Self {
+        let settings_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Convert color settings buffer"),
+            contents: bytemuck::cast_slice(&[alpha_threshold as u32]),
+            usage: BufferUsages::UNIFORM,
+        });
+
         let bind_group = device.create_bind_group(&BindGroupDescriptor {
             label: Some("Convert color bind group"),
-            layout: &convert_color_bind_group_layout,
+            layout: &pipeline.bind_group_layout,
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: BindingResource::TextureView(&input_texture.create_view(
-                        &TextureViewDescriptor {
-                            label: None,
-                            format: Some(TextureFormat::Rgba8Unorm),
-                            aspect: wgpu::TextureAspect::All,
-                            base_mip_level: 0,
-                            mip_level_count: NonZeroU32::new(1),
-                            dimension: Some(TextureViewDimension::D2),
-                            ..Default::default()
-                        },
-                    )),
+                    resource: BindingResource::TextureView(input_view),
                 },
                 BindGroupEntry {
                     binding: 1,
@@ -101,41 +224,178 @@ impl ColorConverterModule {
                         &work_texture.create_view(&TextureViewDescriptor::default()),
                     ),
                 },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: settings_buffer.as_entire_binding(),
+                },
             ],
         });
 
         let dispatch_size = compute_work_group_count(image_dimensions, (16, 16));
 
         Self {
-            pipeline,
+            pipeline: &pipeline.pipeline,
             bind_group,
             dispatch_size,
         }
     }
 }
 
-impl Module for ColorConverterModule {
+impl Module for ColorConverterModule<'_> {
     fn dispatch<'a>(&'a self, compute_pass: &mut ComputePass<'a>) {
-        compute_pass.set_pipeline(&self.pipeline);
+        compute_pass.set_pipeline(self.pipeline);
         compute_pass.set_bind_group(0, &self.bind_group, &[]);
         compute_pass.dispatch(self.dispatch_size.0, self.dispatch_size.1, 1);
     }
 }
 
-pub(crate) struct ColorReverterModule {
-    pipeline: ComputePipeline,
+pub(crate) struct EstimatePipeline {
+    downscale_pipeline: ComputePipeline,
+    sample_pipeline: ComputePipeline,
+    bind_group_layout: BindGroupLayout,
+}
+
+impl EstimatePipeline {
+    pub fn new(device: &Device) -> Self {
+        let estimate_shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("Estimate shader"),
+            source: ShaderSource::Wgsl(include_str!("shaders/estimate.wgsl").into()),
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Estimate bind group layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::StorageTexture {
+                        access: StorageTextureAccess::WriteOnly,
+                        format: TextureFormat::Rgba8Unorm,
+                        view_dimension: TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("Estimate layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let entry_pipeline = |label, entry_point| {
+            device.create_compute_pipeline(&ComputePipelineDescriptor {
+                label: Some(label),
+                layout: Some(&pipeline_layout),
+                module: &estimate_shader,
+                entry_point,
+            })
+        };
+
+        Self {
+            downscale_pipeline: entry_pipeline("Downscale pipeline", "downscale"),
+            sample_pipeline: entry_pipeline("Sample pipeline", "sample"),
+            bind_group_layout,
+        }
+    }
+}
+
+/// Writes the smaller copy of the image the clustering runs over, each texel standing for a
+/// square `cell` of pixels.
+pub(crate) struct EstimateModule<'a> {
+    pipeline: &'a ComputePipeline,
     bind_group: BindGroup,
     dispatch_size: (u32, u32),
 }
 
-impl ColorReverterModule {
+impl<'a> EstimateModule<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         device: &Device,
-        color_space: &ColorSpace,
-        image_dimensions: (u32, u32),
-        work_texture: &WorkTexture,
-        output_texture: &OutputTexture,
+        pipeline: &'a EstimatePipeline,
+        estimation: &Estimation,
+        input_view: &TextureView,
+        output_view: &TextureView,
+        output_dimensions: (u32, u32),
+        cell: u32,
+        options: &KMeansOptions,
     ) -> Self {
+        let settings_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Estimate settings buffer"),
+            contents: bytemuck::cast_slice(&[
+                options.seed as u32,
+                (options.seed >> 32) as u32,
+                cell,
+                options.alpha_threshold as u32,
+            ]),
+            usage: BufferUsages::UNIFORM,
+        });
+
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("Estimate bind group"),
+            layout: &pipeline.bind_group_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(input_view),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(output_view),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: settings_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        Self {
+            pipeline: match estimation {
+                Estimation::Sample(_) => &pipeline.sample_pipeline,
+                _ => &pipeline.downscale_pipeline,
+            },
+            bind_group,
+            dispatch_size: compute_work_group_count(output_dimensions, (16, 16)),
+        }
+    }
+}
+
+impl Module for EstimateModule<'_> {
+    fn dispatch<'a>(&'a self, compute_pass: &mut ComputePass<'a>) {
+        compute_pass.set_pipeline(self.pipeline);
+        compute_pass.set_bind_group(0, &self.bind_group, &[]);
+        compute_pass.dispatch(self.dispatch_size.0, self.dispatch_size.1, 1);
+    }
+}
+
+pub(crate) struct ColorReverterPipeline {
+    pipeline: ComputePipeline,
+    bind_group_layout: BindGroupLayout,
+}
+
+impl ColorReverterPipeline {
+    pub fn new(device: &Device, color_space: &ColorSpace) -> Self {
         let revert_color_shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
             label: Some("Revert color shader"),
             source: ShaderSource::Wgsl(
@@ -171,6 +431,16 @@ impl ColorReverterModule {
                         },
                         count: None,
                     },
+                    BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Texture {
+                            sample_type: TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
                 ],
             });
 
@@ -188,9 +458,31 @@ impl ColorReverterModule {
             entry_point: "main",
         });
 
+        Self {
+            pipeline,
+            bind_group_layout: revert_color_bind_group_layout,
+        }
+    }
+}
+
+pub(crate) struct ColorReverterModule<'a> {
+    pipeline: &'a ComputePipeline,
+    bind_group: BindGroup,
+    dispatch_size: (u32, u32),
+}
+
+impl<'a> ColorReverterModule<'a> {
+    pub fn new(
+        device: &Device,
+        pipeline: &'a ColorReverterPipeline,
+        image_dimensions: (u32, u32),
+        work_texture: &WorkTexture,
+        output_view: &TextureView,
+        input_view: &TextureView,
+    ) -> Self {
         let bind_group = device.create_bind_group(&BindGroupDescriptor {
             label: Some("Revert color bind group"),
-            layout: &revert_color_bind_group_layout,
+            layout: &pipeline.bind_group_layout,
             entries: &[
                 BindGroupEntry {
                     binding: 0,
@@ -200,17 +492,11 @@ impl ColorReverterModule {
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: BindingResource::TextureView(&output_texture.create_view(
-                        &TextureViewDescriptor {
-                            label: None,
-                            format: Some(TextureFormat::Rgba8Unorm),
-                            aspect: wgpu::TextureAspect::All,
-                            base_mip_level: 0,
-                            mip_level_count: NonZeroU32::new(1),
-                            dimension: Some(TextureViewDimension::D2),
-                            ..Default::default()
-                        },
-                    )),
+                    resource: BindingResource::TextureView(output_view),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::TextureView(input_view),
                 },
             ],
         });
@@ -218,35 +504,28 @@ impl ColorReverterModule {
         let dispatch_size = compute_work_group_count(image_dimensions, (16, 16));
 
         Self {
-            pipeline,
+            pipeline: &pipeline.pipeline,
             bind_group,
             dispatch_size,
         }
     }
 }
 
-impl Module for ColorReverterModule {
+impl Module for ColorReverterModule<'_> {
     fn dispatch<'a>(&'a self, compute_pass: &mut ComputePass<'a>) {
-        compute_pass.set_pipeline(&self.pipeline);
+        compute_pass.set_pipeline(self.pipeline);
         compute_pass.set_bind_group(0, &self.bind_group, &[]);
         compute_pass.dispatch(self.dispatch_size.0, self.dispatch_size.1, 1);
     }
 }
 
-pub(crate) struct SwapModule {
+pub(crate) struct SwapPipeline {
     pipeline: ComputePipeline,
-    bind_group: BindGroup,
-    dispatch_size: (u32, u32),
+    bind_group_layout: BindGroupLayout,
 }
 
-impl SwapModule {
-    pub fn new(
-        device: &Device,
-        image_dimensions: (u32, u32),
-        work_texture: &WorkTexture,
-        centroid_buffer: &CentroidsBuffer,
-        color_index_texture: &ColorIndexTexture,
-    ) -> Self {
+impl SwapPipeline {
+    pub fn new(device: &Device) -> Self {
         let swap_shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
             label: Some("Swap colors shader"),
             source: ShaderSource::Wgsl(include_str!("shaders/swap.wgsl").into()),
@@ -279,9 +558,43 @@ impl SwapModule {
             ],
         });
 
+        let swap_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("Swap pipeline layout"),
+            bind_group_layouts: &[&swap_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let swap_pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: Some("Swap pipeline"),
+            layout: Some(&swap_pipeline_layout),
+            module: &swap_shader,
+            entry_point: "main",
+        });
+
+        Self {
+            pipeline: swap_pipeline,
+            bind_group_layout: swap_bind_group_layout,
+        }
+    }
+}
+
+pub(crate) struct SwapModule<'a> {
+    pipeline: &'a ComputePipeline,
+    bind_group: BindGroup,
+    dispatch_size: (u32, u32),
+}
+
+impl<'a> SwapModule<'a> {
+    pub fn new(
+        device: &Device,
+        pipeline: &'a SwapPipeline,
+        image_dimensions: (u32, u32),
+        work_texture: &WorkTexture,
+        centroid_buffer: &CentroidsBuffer,
+        color_index_texture: &ColorIndexTexture,
+    ) -> Self {
         let swap_bind_group = device.create_bind_group(&BindGroupDescriptor {
             label: None,
-            layout: &swap_bind_group_layout,
+            layout: &pipeline.bind_group_layout,
             entries: &[
                 BindGroupEntry {
                     binding: 0,
@@ -302,89 +615,210 @@ impl SwapModule {
             ],
         });
 
-        let swap_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
-            label: Some("Swap pipeline layout"),
-            bind_group_layouts: &[&swap_bind_group_layout],
-            push_constant_ranges: &[],
-        });
-        let swap_pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
-            label: Some("Swap pipeline"),
-            layout: Some(&swap_pipeline_layout),
-            module: &swap_shader,
-            entry_point: "main",
-        });
-
         let dispatch_size = compute_work_group_count(image_dimensions, (16, 16));
 
         Self {
-            pipeline: swap_pipeline,
+            pipeline: &pipeline.pipeline,
             bind_group: swap_bind_group,
             dispatch_size,
         }
     }
 }
 
-impl Module for SwapModule {
+impl Module for SwapModule<'_> {
     fn dispatch<'a>(&'a self, compute_pass: &mut ComputePass<'a>) {
-        compute_pass.set_pipeline(&self.pipeline);
+        compute_pass.set_pipeline(self.pipeline);
         compute_pass.set_bind_group(0, &self.bind_group, &[]);
         compute_pass.dispatch(self.dispatch_size.0, self.dispatch_size.1, 1);
     }
 }
 
-pub(crate) struct FindCentroidModule {
-    pipeline: ComputePipeline,
-    bind_group: BindGroup,
-    dispatch_size: (u32, u32),
+pub(crate) struct CentroidTablePipeline {
+    table_pipeline: ComputePipeline,
+    half_min_pipeline: ComputePipeline,
+    bind_group_layout: BindGroupLayout,
 }
 
-impl FindCentroidModule {
-    pub fn new(
-        device: &Device,
-        image_dimensions: (u32, u32),
-        work_texture: &WorkTexture,
-        centroid_buffer: &CentroidsBuffer,
-        color_index_texture: &ColorIndexTexture,
-    ) -> Self {
-        let find_centroid_shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-            label: Some("Find centroid shader"),
-            source: ShaderSource::Wgsl(include_str!("shaders/find_centroid.wgsl").into()),
+impl CentroidTablePipeline {
+    pub fn new(device: &Device) -> Self {
+        let centroid_table_shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("Centroid table shader"),
+            source: ShaderSource::Wgsl(include_str!("shaders/centroid_table.wgsl").into()),
         });
 
-        let find_centroid_bind_group_layout =
-            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-                label: Some("Find centroid bind group layout"),
-                entries: &[
-                    BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: ShaderStages::COMPUTE,
-                        ty: BindingType::Texture {
-                            sample_type: TextureSampleType::Float { filterable: false },
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                            multisampled: false,
+        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Centroid table bind group layout"),
+            entries: &[
+                CentroidsBuffer::layout(0, true),
+                CentroidsBuffer::layout(1, false),
+                CentroidsBuffer::layout(2, false),
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("Centroid table layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let entry_pipeline = |label, entry_point| {
+            device.create_compute_pipeline(&ComputePipelineDescriptor {
+                label: Some(label),
+                layout: Some(&pipeline_layout),
+                module: &centroid_table_shader,
+                entry_point,
+            })
+        };
+
+        Self {
+            table_pipeline: entry_pipeline("Centroid table pipeline", "main"),
+            half_min_pipeline: entry_pipeline("Centroid half min pipeline", "main_half_min"),
+            bind_group_layout,
+        }
+    }
+}
+
+/// The distances between every pair of centroids, and half the distance between each centroid
+/// and the closest other one, which bound the distances between pixels and centroids.
+pub(crate) struct CentroidTable<'a> {
+    pipeline: &'a CentroidTablePipeline,
+    bind_group: BindGroup,
+    k: u32,
+    table_buffer: Buffer,
+    half_min_buffer: Buffer,
+}
+
+impl<'a> CentroidTable<'a> {
+    /// Above, the table would take megabytes, and the searches fall back to going over every
+    /// centroid.
+    pub const MAX_K: u32 = 1024;
+
+    pub fn new(
+        device: &Device,
+        pipeline: &'a CentroidTablePipeline,
+        centroid_buffer: &CentroidsBuffer,
+    ) -> Self {
+        let k = centroid_buffer.k();
+        let table_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Centroid table buffer"),
+            size: k as u64 * k as u64 * 4,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let half_min_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Centroid half min buffer"),
+            size: k as u64 * 4,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("Centroid table bind group"),
+            layout: &pipeline.bind_group_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: centroid_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: table_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: half_min_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        Self {
+            pipeline,
+            bind_group,
+            k,
+            table_buffer,
+            half_min_buffer,
+        }
+    }
+}
+
+impl Module for CentroidTable<'_> {
+    fn dispatch<'a>(&'a self, compute_pass: &mut ComputePass<'a>) {
+        let (table_x, table_y) = compute_work_group_count((self.k, self.k), (16, 16));
+        compute_pass.set_bind_group(0, &self.bind_group, &[]);
+        compute_pass.set_pipeline(&self.pipeline.table_pipeline);
+        compute_pass.dispatch(table_x, table_y, 1);
+        compute_pass.set_pipeline(&self.pipeline.half_min_pipeline);
+        compute_pass.dispatch(self.k.div_ceil(256), 1, 1);
+    }
+}
+
+pub(crate) struct FindCentroidPipeline {
+    pipeline: ComputePipeline,
+    prepare_pipeline: ComputePipeline,
+    bounded_pipeline: ComputePipeline,
+    bind_group_layout: BindGroupLayout,
+    bounded_bind_group_layout: BindGroupLayout,
+}
+
+impl FindCentroidPipeline {
+    pub fn new(device: &Device) -> Self {
+        let find_centroid_shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("Find centroid shader"),
+            source: ShaderSource::Wgsl(include_str!("shaders/find_centroid.wgsl").into()),
+        });
+
+        let color_indices_layout = BindGroupLayoutEntry {
+            binding: 2,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::StorageTexture {
+                access: StorageTextureAccess::WriteOnly,
+                format: TextureFormat::R32Uint,
+                view_dimension: TextureViewDimension::D2,
+            },
+            count: None,
+        };
+        let find_centroid_bind_group_layout =
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("Find centroid bind group layout"),
+                entries: &[
+                    WorkTexture::texture_2d_layout(0),
+                    CentroidsBuffer::layout(1, true),
+                    color_indices_layout,
+                ],
+            });
+
+        let bounded_bind_group_layout =
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("Find centroid bounded bind group layout"),
+                entries: &[
+                    WorkTexture::texture_2d_layout(0),
+                    CentroidsBuffer::layout(1, true),
+                    color_indices_layout,
+                    ColorIndexTexture::texture_2d_layout(3),
+                    BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::StorageTexture {
+                            access: StorageTextureAccess::WriteOnly,
+                            format: BoundsTextures::FORMAT,
+                            view_dimension: TextureViewDimension::D2,
                         },
                         count: None,
                     },
+                    CentroidsBuffer::layout(5, false),
+                    CentroidsBuffer::layout(6, false),
+                    CentroidsBuffer::layout(7, false),
+                    CentroidsBuffer::layout(8, true),
+                    CentroidsBuffer::layout(9, true),
                     BindGroupLayoutEntry {
-                        binding: 1,
+                        binding: 10,
                         visibility: ShaderStages::COMPUTE,
                         ty: BindingType::Buffer {
-                            ty: BufferBindingType::Storage { read_only: true },
+                            ty: BufferBindingType::Uniform,
                             has_dynamic_offset: false,
                             min_binding_size: None,
                         },
                         count: None,
                     },
-                    BindGroupLayoutEntry {
-                        binding: 2,
-                        visibility: ShaderStages::COMPUTE,
-                        ty: BindingType::StorageTexture {
-                            access: StorageTextureAccess::WriteOnly,
-                            format: TextureFormat::R32Uint,
-                            view_dimension: TextureViewDimension::D2,
-                        },
-                        count: None,
-                    },
                 ],
             });
 
@@ -402,9 +836,88 @@ impl FindCentroidModule {
             entry_point: "main",
         });
 
+        let bounded_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("Find centroid bounded layout"),
+            bind_group_layouts: &[&bounded_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let entry_pipeline = |label, entry_point| {
+            device.create_compute_pipeline(&ComputePipelineDescriptor {
+                label: Some(label),
+                layout: Some(&bounded_pipeline_layout),
+                module: &find_centroid_shader,
+                entry_point,
+            })
+        };
+
+        Self {
+            pipeline: find_centroid_pipeline,
+            prepare_pipeline: entry_pipeline("Prepare bounds pipeline", "prepare"),
+            bounded_pipeline: entry_pipeline("Find centroid bounded pipeline", "main_bounded"),
+            bind_group_layout: find_centroid_bind_group_layout,
+            bounded_bind_group_layout,
+        }
+    }
+}
+
+/// The two textures of bounds a bounded search alternates between, see `bounds_in` in
+/// `find_centroid.wgsl`.
+struct BoundsTextures([Texture; 2]);
+
+impl BoundsTextures {
+    const FORMAT: TextureFormat = TextureFormat::Rg32Uint;
+
+    fn new(device: &Device, (width, height): (u32, u32)) -> Self {
+        Self([(); 2].map(|_| {
+            device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("Bounds texture"),
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: Self::FORMAT,
+                usage: TextureUsages::TEXTURE_BINDING | TextureUsages::STORAGE_BINDING,
+            })
+        }))
+    }
+}
+
+/// What a bounded search keeps from one dispatch to the next.
+struct FindBounds<'a> {
+    table: CentroidTable<'a>,
+    /// Reading each texture of bounds, and writing the other.
+    bind_groups: [BindGroup; 2],
+    /// The bind group of the next dispatch. The gpu tells whether it reads the latest bounds,
+    /// in case the dispatch before was skipped.
+    parity: Cell<usize>,
+}
+
+pub(crate) struct FindCentroidModule<'a> {
+    pipeline: &'a FindCentroidPipeline,
+    bind_group: BindGroup,
+    bounds: Option<FindBounds<'a>>,
+    dispatch_size: (u32, u32),
+}
+
+impl<'a> FindCentroidModule<'a> {
+    /// Below, going over every centroid is about as fast as checking the bounds.
+    const BOUNDED_MIN_K: u32 = 8;
+
+    pub fn new(
+        device: &Device,
+        pipeline: &'a FindCentroidPipeline,
+        image_dimensions: (u32, u32),
+        work_texture: &WorkTexture,
+        centroid_buffer: &CentroidsBuffer,
+        color_index_texture: &ColorIndexTexture,
+    ) -> Self {
         let find_centroid_bind_group = device.create_bind_group(&BindGroupDescriptor {
             label: Some("Find centroid bind group"),
-            layout: &find_centroid_bind_group_layout,
+            layout: &pipeline.bind_group_layout,
             entries: &[
                 BindGroupEntry {
                     binding: 0,
@@ -428,180 +941,425 @@ impl FindCentroidModule {
         let dispatch_size = compute_work_group_count(image_dimensions, (16, 16));
 
         Self {
-            pipeline: find_centroid_pipeline,
+            pipeline,
             bind_group: find_centroid_bind_group,
+            bounds: None,
             dispatch_size,
         }
     }
-}
-
-impl Module for FindCentroidModule {
-    fn dispatch<'a>(&'a self, compute_pass: &mut ComputePass<'a>) {
-        compute_pass.set_pipeline(&self.pipeline);
-        compute_pass.set_bind_group(0, &self.bind_group, &[]);
-        compute_pass.dispatch(self.dispatch_size.0, self.dispatch_size.1, 1);
-    }
-}
-
-pub(crate) struct ConvergenceBuffer {
-    gpu_buffer: Buffer,
-    mapped_buffer: Buffer,
-}
-
-pub(crate) struct ChooseCentroidModule<'a> {
-    k: u32,
-    pipeline: ComputePipeline,
-    pick_pipeline: ComputePipeline,
-    bind_group_0: BindGroup,
-    bind_group_1: BindGroup,
-    bind_groups: Vec<BindGroup>,
-    dispatch_size: u32,
-    convergence_buffer: ConvergenceBuffer,
-    find_centroid_module: &'a FindCentroidModule,
-    centroid_buffer: &'a CentroidsBuffer,
-}
 
-impl<'a> ChooseCentroidModule<'a> {
-    #[allow(clippy::too_many_arguments)]
-    pub fn new(
+    /// Same as [`FindCentroidModule::new`], for a search dispatched again and again over the
+    /// same pixels while the centroids move: each pixel keeps bounds of its distances to the
+    /// centroids, and skips the ones that can't be the closest. The indices are the same as
+    /// going over every centroid.
+    pub fn bounded(
         device: &Device,
-        color_space: &ColorSpace,
+        pipeline: &'a FindCentroidPipeline,
+        table_pipeline: &'a CentroidTablePipeline,
         image_dimensions: (u32, u32),
-        k: u32,
         work_texture: &WorkTexture,
-        centroid_buffer: &'a CentroidsBuffer,
+        centroid_buffer: &CentroidsBuffer,
         color_index_texture: &ColorIndexTexture,
-        find_centroid_module: &'a FindCentroidModule,
     ) -> Self {
-        const WORKGROUP_SIZE: u32 = 256;
-        const N_SEQ: u32 = 20;
-        let choose_centroid_shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-            label: Some("Choose centroid shader"),
-            source: ShaderSource::Wgsl(include_str!("shaders/choose_centroid.wgsl").into()),
+        let mut module = Self::new(
+            device,
+            pipeline,
+            image_dimensions,
+            work_texture,
+            centroid_buffer,
+            color_index_texture,
+        );
+        let k = centroid_buffer.k();
+        if !(Self::BOUNDED_MIN_K..=CentroidTable::MAX_K).contains(&k) {
+            return module;
+        }
+
+        let table = CentroidTable::new(device, table_pipeline, centroid_buffer);
+        let bounds_textures = BoundsTextures::new(device, image_dimensions);
+        // No bounds written yet, see `BoundsState` in `find_centroid.wgsl`.
+        let state_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Bounds state buffer"),
+            contents: bytemuck::cast_slice(&[2u32, 0, 0, 0]),
+            usage: BufferUsages::STORAGE,
+        });
+        let snapshot_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Centroids snapshot buffer"),
+            size: k as u64 * 16,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let drifts_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Drifts buffer"),
+            size: k as u64 * 4,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
         });
 
-        let choose_centroid_bind_group_0_layout =
-            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-                label: Some("Choose centroid bind group 0 layout"),
+        let work_view = work_texture.create_view(&TextureViewDescriptor::default());
+        let color_index_view = color_index_texture.create_view(&TextureViewDescriptor::default());
+        let bounds_views = bounds_textures
+            .0
+            .each_ref()
+            .map(|texture| texture.create_view(&TextureViewDescriptor::default()));
+        let bind_groups = [0, 1].map(|input: u32| {
+            let parity_buffer = device.create_buffer_init(&BufferInitDescriptor {
+                label: Some("Bounds parity buffer"),
+                contents: bytemuck::cast_slice(&[input]),
+                usage: BufferUsages::UNIFORM,
+            });
+            device.create_bind_group(&BindGroupDescriptor {
+                label: Some("Find centroid bounded bind group"),
+                layout: &pipeline.bounded_bind_group_layout,
                 entries: &[
-                    BindGroupLayoutEntry {
+                    BindGroupEntry {
                         binding: 0,
-                        visibility: ShaderStages::COMPUTE,
-                        ty: BindingType::Buffer {
-                            ty: BufferBindingType::Storage { read_only: false },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
+                        resource: BindingResource::TextureView(&work_view),
                     },
-                    BindGroupLayoutEntry {
+                    BindGroupEntry {
                         binding: 1,
-                        visibility: ShaderStages::COMPUTE,
-                        ty: BindingType::Texture {
-                            sample_type: TextureSampleType::Uint,
-                            view_dimension: TextureViewDimension::D2,
-                            multisampled: false,
-                        },
-                        count: None,
+                        resource: centroid_buffer.as_entire_binding(),
                     },
-                    BindGroupLayoutEntry {
+                    BindGroupEntry {
                         binding: 2,
-                        visibility: ShaderStages::COMPUTE,
-                        ty: BindingType::Texture {
-                            sample_type: TextureSampleType::Float { filterable: false },
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                            multisampled: false,
-                        },
-                        count: None,
+                        resource: BindingResource::TextureView(&color_index_view),
                     },
-                    BindGroupLayoutEntry {
+                    BindGroupEntry {
                         binding: 3,
-                        visibility: ShaderStages::COMPUTE,
-                        ty: BindingType::Buffer {
-                            ty: BufferBindingType::Storage { read_only: false },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
+                        resource: BindingResource::TextureView(&bounds_views[input as usize]),
+                    },
+                    BindGroupEntry {
+                        binding: 4,
+                        resource: BindingResource::TextureView(&bounds_views[1 - input as usize]),
+                    },
+                    BindGroupEntry {
+                        binding: 5,
+                        resource: state_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 6,
+                        resource: snapshot_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 7,
+                        resource: drifts_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 8,
+                        resource: table.table_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 9,
+                        resource: table.half_min_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 10,
+                        resource: parity_buffer.as_entire_binding(),
                     },
                 ],
-            });
+            })
+        });
 
-        let part_id_buffer = device.create_buffer(&BufferDescriptor {
-            label: None,
-            size: 4,
-            usage: BufferUsages::STORAGE,
-            mapped_at_creation: false,
+        module.bounds = Some(FindBounds {
+            table,
+            bind_groups,
+            parity: Cell::new(0),
         });
+        module
+    }
 
-        let choose_centroid_bind_group_0 = device.create_bind_group(&BindGroupDescriptor {
-            label: None,
-            layout: &choose_centroid_bind_group_0_layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: centroid_buffer.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: BindingResource::TextureView(
-                        &color_index_texture.create_view(&TextureViewDescriptor::default()),
-                    ),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: BindingResource::TextureView(
-                        &work_texture.create_view(&TextureViewDescriptor::default()),
-                    ),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: part_id_buffer.as_entire_binding(),
-                },
-            ],
-        });
+    /// Records the table and the drifts of the centroids, and sets the pipeline and bind group
+    /// of the search.
+    fn prepare(&'a self, compute_pass: &mut ComputePass<'a>) {
+        let Some(bounds) = &self.bounds else {
+            compute_pass.set_pipeline(&self.pipeline.pipeline);
+            compute_pass.set_bind_group(0, &self.bind_group, &[]);
+            return;
+        };
+
+        let parity = bounds.parity.get();
+        bounds.parity.set(1 - parity);
+        bounds.table.dispatch(compute_pass);
+        compute_pass.set_bind_group(0, &bounds.bind_groups[parity], &[]);
+        compute_pass.set_pipeline(&self.pipeline.prepare_pipeline);
+        compute_pass.dispatch(1, 1, 1);
+        compute_pass.set_pipeline(&self.pipeline.bounded_pipeline);
+    }
+
+    /// Same as [`Module::dispatch`], with the size read from the arguments at `offset` in
+    /// `buffer`.
+    pub(crate) fn dispatch_indirect(
+        &'a self,
+        compute_pass: &mut ComputePass<'a>,
+        buffer: &'a Buffer,
+        offset: BufferAddress,
+    ) {
+        self.prepare(compute_pass);
+        compute_pass.dispatch_indirect(buffer, offset);
+    }
+}
+
+impl Module for FindCentroidModule<'_> {
+    fn dispatch<'a>(&'a self, compute_pass: &mut ComputePass<'a>) {
+        self.prepare(compute_pass);
+        compute_pass.dispatch(self.dispatch_size.0, self.dispatch_size.1, 1);
+    }
+}
+
+/// How the k-means iterations ended.
+pub(crate) struct Convergence {
+    pub iterations: u32,
+    pub converged: bool,
+    /// Times an empty cluster was moved to the pixel the farthest from its centroid.
+    pub reseeds: u32,
+}
+
+/// What the gpu counted so far.
+#[derive(Default)]
+pub(crate) struct ConvergenceCounts {
+    pub converged: u32,
+    pub reseeds: u32,
+    /// Iterations run before every centroid converged, the following ones being skipped.
+    pub iterations: u32,
+    /// Clusters left without any pixel by the last move of the centroids.
+    pub empties: u32,
+}
 
-        let mut choose_centroid_settings_content: Vec<u8> = Vec::new();
-        choose_centroid_settings_content.extend_from_slice(bytemuck::cast_slice(&[N_SEQ]));
-        choose_centroid_settings_content
-            .extend_from_slice(bytemuck::cast_slice(&[color_space.convergence()]));
-        let choose_centroid_settings_buffer = device.create_buffer_init(&BufferInitDescriptor {
+pub(crate) struct ConvergenceBuffer {
+    gpu_buffer: Buffer,
+    mapped_buffer: Buffer,
+}
+
+impl ConvergenceBuffer {
+    /// Zeroed, for k centroids.
+    fn new(device: &Device, k: u32) -> Self {
+        let gpu_buffer = device.create_buffer_init(&BufferInitDescriptor {
             label: None,
-            contents: &choose_centroid_settings_content,
-            usage: BufferUsages::UNIFORM,
+            contents: bytemuck::cast_slice::<u32, u8>(&vec![0; k as usize + 4]),
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
         });
-
-        let (dispatch_size, _) = compute_work_group_count(
-            (image_dimensions.0 * image_dimensions.1, 1),
-            (WORKGROUP_SIZE * N_SEQ, 1),
-        );
-        let color_buffer_size = dispatch_size * 8 * 4;
-        let color_buffer = device.create_buffer(&BufferDescriptor {
+        let mapped_buffer = device.create_buffer(&BufferDescriptor {
             label: None,
-            size: color_buffer_size as BufferAddress,
-            usage: BufferUsages::STORAGE,
+            size: (k + 4) as u64 * 4,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
             mapped_at_creation: false,
         });
-        let flag_buffer_size = dispatch_size * 4;
-        let flag_buffer = device.create_buffer(&BufferDescriptor {
+
+        Self {
+            gpu_buffer,
+            mapped_buffer,
+        }
+    }
+
+    /// Waits for the commands recorded so far, and tells how many of the k centroids converged,
+    /// how many empty clusters were re-seeded, and how many iterations ran.
+    async fn converged_count(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        mut encoder: CommandEncoder,
+        k: u32,
+    ) -> Result<ConvergenceCounts> {
+        encoder.copy_buffer_to_buffer(
+            &self.gpu_buffer,
+            0,
+            &self.mapped_buffer,
+            0,
+            (k + 4) as u64 * 4,
+        );
+
+        queue.submit(Some(encoder.finish()));
+        let check_convergence_slice = self.mapped_buffer.slice(..);
+        let check_convergence_future = check_convergence_slice.map_async(MapMode::Read);
+
+        device.poll(wgpu::Maintain::Wait);
+
+        check_convergence_future.await?;
+        let counts = {
+            let data = check_convergence_slice.get_mapped_range();
+            let data = bytemuck::cast_slice::<u8, u32>(&data);
+            ConvergenceCounts {
+                converged: data[k as usize],
+                reseeds: data[k as usize + 1],
+                iterations: data[k as usize + 2],
+                empties: data[k as usize + 3],
+            }
+        };
+        self.mapped_buffer.unmap();
+
+        Ok(counts)
+    }
+}
+
+/// The buffers shared by every choose centroid pass of an image: the settings, the convergence
+/// of each centroid followed by their count, the count of re-seeds, the count of iterations run
+/// and the count of empty clusters, the sums accumulated over the tiles of images larger than a
+/// texture, the search of the pixels to re-seed with, and the fixed point sums of the
+/// deterministic mode.
+pub(crate) struct ChooseCentroidState {
+    k: u32,
+    deterministic: bool,
+    single_pass: bool,
+    settings_buffer: Buffer,
+    tile_sums_buffer: Buffer,
+    convergence_buffer: ConvergenceBuffer,
+    reseed_buffer: Buffer,
+    fixed_sums_buffer: Buffer,
+}
+
+impl ChooseCentroidState {
+    const N_SEQ: u32 = 20;
+    /// Below, going over the image once per centroid is faster than contending for the bins.
+    const SINGLE_PASS_MIN_K: u32 = 16;
+    /// The bins of every centroid must fit in the memory of a workgroup, see `BIN_WORDS` in
+    /// `choose_centroid.wgsl`.
+    const SINGLE_PASS_MAX_K: u32 = 256;
+
+    pub fn new(device: &Device, k: u32, color_space: &ColorSpace, options: &KMeansOptions) -> Self {
+        let convergence = options.convergence_for(color_space);
+        let single_pass = k <= Self::SINGLE_PASS_MAX_K
+            && match options.reduction {
+                Reduction::Auto => k >= Self::SINGLE_PASS_MIN_K,
+                Reduction::PerCentroid => false,
+                Reduction::SinglePass => true,
+            };
+        debug!("Single pass reduction: {single_pass}");
+
+        let mut settings_content: Vec<u8> = Vec::new();
+        settings_content.extend_from_slice(bytemuck::cast_slice(&[Self::N_SEQ]));
+        settings_content.extend_from_slice(bytemuck::cast_slice(&[convergence]));
+        let settings_buffer = device.create_buffer_init(&BufferInitDescriptor {
             label: None,
-            size: flag_buffer_size as BufferAddress,
+            contents: &settings_content,
+            usage: BufferUsages::UNIFORM,
+        });
+
+        let tile_sums_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Tile sums buffer"),
+            contents: bytemuck::cast_slice::<u32, u8>(&vec![0; k as usize * 4]),
             usage: BufferUsages::STORAGE,
-            mapped_at_creation: false,
         });
-        let convergence_buffer = device.create_buffer_init(&BufferInitDescriptor {
-            label: None,
-            contents: bytemuck::cast_slice::<u32, u8>(&vec![0; k as usize + 1]),
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+
+        // No empty cluster and no pixel picked yet, see `Reseed` in `choose_centroid.wgsl`.
+        let mut reseed_content = vec![0u32; 4];
+        for _ in 0..k {
+            reseed_content.extend_from_slice(&[0, u32::MAX, 0, 0, 0, 0, 0, 0]);
+        }
+        let reseed_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Reseed buffer"),
+            contents: bytemuck::cast_slice(&reseed_content),
+            usage: BufferUsages::STORAGE,
         });
 
-        let check_convergence_buffer = device.create_buffer(&BufferDescriptor {
-            label: None,
-            size: (k + 1) as u64 * 4,
-            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+        // Zeroed, see `FixedSums` in `choose_centroid.wgsl`.
+        let fixed_sums_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Fixed sums buffer"),
+            size: k as u64 * 8 * 4,
+            usage: BufferUsages::STORAGE,
             mapped_at_creation: false,
         });
 
+        Self {
+            k,
+            deterministic: options.deterministic,
+            single_pass,
+            settings_buffer,
+            tile_sums_buffer,
+            convergence_buffer: ConvergenceBuffer::new(device, k),
+            reseed_buffer,
+            fixed_sums_buffer,
+        }
+    }
+
+    /// Waits for the commands recorded so far, and tells how many centroids converged, how many
+    /// empty clusters were re-seeded, how many iterations ran, and how many clusters the last
+    /// pick left empty.
+    pub async fn converged_count(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        encoder: CommandEncoder,
+    ) -> Result<ConvergenceCounts> {
+        self.convergence_buffer
+            .converged_count(device, queue, encoder, self.k)
+            .await
+    }
+}
+
+pub(crate) struct ChooseCentroidPipeline {
+    pipeline: ComputePipeline,
+    pick_pipeline: ComputePipeline,
+    accumulate_pipeline: ComputePipeline,
+    finalize_pipeline: ComputePipeline,
+    fixed_pipeline: ComputePipeline,
+    pick_fixed_pipeline: ComputePipeline,
+    sum_all_pipeline: ComputePipeline,
+    pick_all_pipeline: ComputePipeline,
+    measure_farthest_pipeline: ComputePipeline,
+    select_farthest_pipeline: ComputePipeline,
+    keep_farthest_pipeline: ComputePipeline,
+    reseed_pipeline: ComputePipeline,
+    gate_pipeline: ComputePipeline,
+    gate_reseed_pipeline: ComputePipeline,
+    bind_group_0_layout: BindGroupLayout,
+    bind_group_1_layout: BindGroupLayout,
+    dispatches_bind_group_layout: BindGroupLayout,
+    k_index_bind_group_layout: BindGroupLayout,
+}
+
+impl ChooseCentroidPipeline {
+    pub fn new(device: &Device) -> Self {
+        let choose_centroid_shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("Choose centroid shader"),
+            source: ShaderSource::Wgsl(include_str!("shaders/choose_centroid.wgsl").into()),
+        });
+
+        let choose_centroid_bind_group_0_layout =
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("Choose centroid bind group 0 layout"),
+                entries: &[
+                    BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Texture {
+                            sample_type: TextureSampleType::Uint,
+                            view_dimension: TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Texture {
+                            sample_type: TextureSampleType::Float { filterable: false },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
+            });
+
         let choose_centroid_bind_group_1_layout =
             device.create_bind_group_layout(&BindGroupLayoutDescriptor {
                 label: Some("Choose centroid bind group 1 layout"),
@@ -646,72 +1404,55 @@ impl<'a> ChooseCentroidModule<'a> {
                         },
                         count: None,
                     },
+                    BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 6,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
                 ],
             });
-
-        let choose_centroid_bind_group_1 = device.create_bind_group(&BindGroupDescriptor {
-            label: None,
-            layout: &choose_centroid_bind_group_1_layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: color_buffer.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: flag_buffer.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: convergence_buffer.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: choose_centroid_settings_buffer.as_entire_binding(),
-                },
-            ],
-        });
-
-        let k_index_buffers: Vec<Buffer> = (0..k)
-            .map(|k| {
-                device.create_buffer_init(&BufferInitDescriptor {
-                    label: None,
-                    contents: bytemuck::cast_slice(&[k]),
-                    usage: BufferUsages::UNIFORM,
-                })
-            })
-            .collect();
-
-        let k_index_bind_group_layout =
+        // Apart, so that the dispatches are never bound as storage while read as arguments.
+        let dispatches_bind_group_layout =
             device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-                label: Some("Choose centroid bind group 2 layout"),
+                label: Some("Choose centroid dispatches bind group layout"),
                 entries: &[BindGroupLayoutEntry {
                     binding: 0,
                     visibility: ShaderStages::COMPUTE,
                     ty: BindingType::Buffer {
-                        ty: BufferBindingType::Uniform,
+                        ty: BufferBindingType::Storage { read_only: false },
                         has_dynamic_offset: false,
                         min_binding_size: None,
                     },
                     count: None,
                 }],
             });
-        let bind_groups: Vec<_> = (0..k)
-            .map(|k| {
-                device.create_bind_group(&BindGroupDescriptor {
-                    label: None,
-                    layout: &k_index_bind_group_layout,
-                    entries: &[BindGroupEntry {
-                        binding: 0,
-                        resource: BindingResource::Buffer(BufferBinding {
-                            buffer: &k_index_buffers[k as usize],
-                            offset: 0,
-                            size: None,
-                        }),
-                    }],
-                })
-            })
-            .collect();
+
+        let k_index_bind_group_layout = k_index_bind_group_layout(device);
 
         let choose_centroid_pipeline_layout =
             device.create_pipeline_layout(&PipelineLayoutDescriptor {
@@ -737,102 +1478,462 @@ impl<'a> ChooseCentroidModule<'a> {
             entry_point: "pick",
         });
 
+        let accumulate_pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: Some("Accumulate tile pipeline"),
+            layout: Some(&choose_centroid_pipeline_layout),
+            module: &choose_centroid_shader,
+            entry_point: "accumulate",
+        });
+
+        let finalize_pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: Some("Finalize tiles pipeline"),
+            layout: Some(&choose_centroid_pipeline_layout),
+            module: &choose_centroid_shader,
+            entry_point: "finalize",
+        });
+
+        let entry_pipeline = |label, entry_point| {
+            device.create_compute_pipeline(&ComputePipelineDescriptor {
+                label: Some(label),
+                layout: Some(&choose_centroid_pipeline_layout),
+                module: &choose_centroid_shader,
+                entry_point,
+            })
+        };
+        let fixed_pipeline = entry_pipeline("Choose centroid fixed pipeline", "main_fixed");
+        let pick_fixed_pipeline = entry_pipeline("Pick fixed pipeline", "pick_fixed");
+        let sum_all_pipeline = entry_pipeline("Sum all centroids pipeline", "sum_all");
+        let pick_all_pipeline = entry_pipeline("Pick all centroids pipeline", "pick_all");
+        let measure_farthest_pipeline =
+            entry_pipeline("Measure farthest pipeline", "measure_farthest");
+        let select_farthest_pipeline =
+            entry_pipeline("Select farthest pipeline", "select_farthest");
+        let keep_farthest_pipeline = entry_pipeline("Keep farthest pipeline", "keep_farthest");
+        let reseed_pipeline = entry_pipeline("Reseed pipeline", "reseed");
+
+        let gate_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("Gate iteration pipeline layout"),
+            bind_group_layouts: &[
+                &choose_centroid_bind_group_0_layout,
+                &choose_centroid_bind_group_1_layout,
+                &k_index_bind_group_layout,
+                &dispatches_bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+        let gate_pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: Some("Gate iteration pipeline"),
+            layout: Some(&gate_pipeline_layout),
+            module: &choose_centroid_shader,
+            entry_point: "gate",
+        });
+        let gate_reseed_pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: Some("Gate reseed pipeline"),
+            layout: Some(&gate_pipeline_layout),
+            module: &choose_centroid_shader,
+            entry_point: "gate_reseed",
+        });
+
         Self {
-            k,
             pipeline,
             pick_pipeline,
-            bind_group_0: choose_centroid_bind_group_0,
-            bind_group_1: choose_centroid_bind_group_1,
-            bind_groups,
-            dispatch_size,
-            convergence_buffer: ConvergenceBuffer {
-                gpu_buffer: convergence_buffer,
-                mapped_buffer: check_convergence_buffer,
-            },
-            find_centroid_module,
-            centroid_buffer,
+            accumulate_pipeline,
+            finalize_pipeline,
+            fixed_pipeline,
+            pick_fixed_pipeline,
+            sum_all_pipeline,
+            pick_all_pipeline,
+            measure_farthest_pipeline,
+            select_farthest_pipeline,
+            keep_farthest_pipeline,
+            reseed_pipeline,
+            gate_pipeline,
+            gate_reseed_pipeline,
+            bind_group_0_layout: choose_centroid_bind_group_0_layout,
+            bind_group_1_layout: choose_centroid_bind_group_1_layout,
+            dispatches_bind_group_layout,
+            k_index_bind_group_layout,
         }
     }
+}
 
-    pub(crate) async fn compute(&self, device: &Device, queue: &Queue) {
-        const MAX_OBS_CHAIN: usize = 64;
-        const MAX_ITERATION: u32 = 128;
-        const MAX_ITERATION_BEFORE_CONVERGENCE_CHECK: u32 = 8;
-        let mut current_iteration = 0;
+pub(crate) struct ChooseCentroidModule<'a> {
+    k: u32,
+    pipeline: &'a ChooseCentroidPipeline,
+    state: &'a ChooseCentroidState,
+    bind_group_0: BindGroup,
+    bind_group_1: BindGroup,
+    bind_groups: Vec<BindGroup>,
+    dispatch_size: u32,
+    dispatches_bind_group: BindGroup,
+    dispatches_buffer: Buffer,
+    centroid_buffer: &'a CentroidsBuffer,
+}
 
-        println!("Dispatch size {}", self.dispatch_size);
+impl<'a> ChooseCentroidModule<'a> {
+    const MAX_OBS_CHAIN: usize = 64;
+    /// Offsets of the arguments of the indirect dispatches, see `DISPATCH_WORDS` in
+    /// `choose_centroid.wgsl`: over the image in 16x16 workgroups, over the image in sequences
+    /// of pixels, a single workgroup, then the first and last ones again for the re-seed.
+    const IMAGE_DISPATCH: BufferAddress = 0;
+    const SEQUENCE_DISPATCH: BufferAddress = 12;
+    const SINGLE_DISPATCH: BufferAddress = 24;
+    const RESEED_IMAGE_DISPATCH: BufferAddress = 36;
+    const RESEED_SINGLE_DISPATCH: BufferAddress = 48;
 
-        'iteration: for iteration in 0..MAX_ITERATION {
-            current_iteration = iteration;
-            let mut encoder =
-                device.create_command_encoder(&CommandEncoderDescriptor { label: None });
-            for k_start in (0..self.k as usize).step_by(MAX_OBS_CHAIN) {
-                let max_k = (k_start + MAX_OBS_CHAIN).min(self.k as usize);
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &Device,
+        pipeline: &'a ChooseCentroidPipeline,
+        state: &'a ChooseCentroidState,
+        image_dimensions: (u32, u32),
+        k: u32,
+        work_texture: &WorkTexture,
+        centroid_buffer: &'a CentroidsBuffer,
+        color_index_texture: &ColorIndexTexture,
+    ) -> Self {
+        const WORKGROUP_SIZE: u32 = 256;
+        const N_SEQ: u32 = ChooseCentroidState::N_SEQ;
 
-                let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
-                    label: Some("Choose centroid pass"),
-                });
-                compute_pass.set_bind_group(0, &self.bind_group_0, &[]);
-                compute_pass.set_bind_group(1, &self.bind_group_1, &[]);
-                for k in k_start..max_k {
-                    compute_pass.set_bind_group(2, &self.bind_groups[k as usize], &[]);
-                    compute_pass.set_pipeline(&self.pipeline);
-                    compute_pass.dispatch(self.dispatch_size, 1, 1);
-                    compute_pass.set_pipeline(&self.pick_pipeline);
-                    compute_pass.dispatch(1, 1, 1);
-                }
-            }
+        let part_id_buffer = device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: 4,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
 
-            queue.submit(Some(encoder.finish()));
-            device.poll(wgpu::Maintain::Wait);
+        let choose_centroid_bind_group_0 = device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.bind_group_0_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: centroid_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(
+                        &color_index_texture.create_view(&TextureViewDescriptor::default()),
+                    ),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::TextureView(
+                        &work_texture.create_view(&TextureViewDescriptor::default()),
+                    ),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: part_id_buffer.as_entire_binding(),
+                },
+            ],
+        });
 
-            let mut encoder =
-                device.create_command_encoder(&CommandEncoderDescriptor { label: None });
-            {
-                let mut compute_pass =
-                    encoder.begin_compute_pass(&ComputePassDescriptor { label: None });
+        let (dispatch_size, _) = compute_work_group_count(
+            (image_dimensions.0 * image_dimensions.1, 1),
+            (WORKGROUP_SIZE * N_SEQ, 1),
+        );
+        let color_buffer_size = dispatch_size * 8 * 4;
+        let color_buffer = device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: color_buffer_size as BufferAddress,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let flag_buffer_size = dispatch_size * 4;
+        let flag_buffer = device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: flag_buffer_size as BufferAddress,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        // The dispatches of an iteration, followed by their full sizes, which `gate` copies over
+        // them until every centroid converged, and `gate_reseed` while clusters are empty.
+        let (image_x, image_y) = compute_work_group_count(image_dimensions, (16, 16));
+        let dispatches = [
+            [image_x, image_y, 1],
+            [dispatch_size, 1, 1],
+            [1, 1, 1],
+            [image_x, image_y, 1],
+            [1, 1, 1],
+        ];
+        let dispatches_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Dispatches buffer"),
+            contents: bytemuck::cast_slice(&[dispatches, dispatches]),
+            usage: BufferUsages::STORAGE | BufferUsages::INDIRECT,
+        });
+        let choose_centroid_bind_group_1 = device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.bind_group_1_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: color_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: flag_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: state.convergence_buffer.gpu_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: state.settings_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: state.tile_sums_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: state.reseed_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: state.fixed_sums_buffer.as_entire_binding(),
+                },
+            ],
+        });
+        let dispatches_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("Choose centroid dispatches bind group"),
+            layout: &pipeline.dispatches_bind_group_layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: dispatches_buffer.as_entire_binding(),
+            }],
+        });
+
+        let bind_groups = k_index_bind_groups(device, &pipeline.k_index_bind_group_layout, k);
+
+        Self {
+            k,
+            pipeline,
+            state,
+            bind_group_0: choose_centroid_bind_group_0,
+            bind_group_1: choose_centroid_bind_group_1,
+            bind_groups,
+            dispatch_size,
+            dispatches_bind_group,
+            dispatches_buffer,
+            centroid_buffer,
+        }
+    }
+
+    /// Records, for each centroid, the sum of its pixels followed by the `second_pipeline`, if
+    /// any.
+    fn record(&self, encoder: &mut CommandEncoder, second_pipeline: Option<&ComputePipeline>) {
+        let sum_pipeline = if self.state.deterministic {
+            &self.pipeline.fixed_pipeline
+        } else {
+            &self.pipeline.pipeline
+        };
+
+        for k_start in (0..self.k as usize).step_by(Self::MAX_OBS_CHAIN) {
+            let max_k = (k_start + Self::MAX_OBS_CHAIN).min(self.k as usize);
 
-                self.find_centroid_module.dispatch(&mut compute_pass);
+            let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("Choose centroid pass"),
+            });
+            compute_pass.set_bind_group(0, &self.bind_group_0, &[]);
+            compute_pass.set_bind_group(1, &self.bind_group_1, &[]);
+            for k_bind_group in &self.bind_groups[k_start..max_k] {
+                compute_pass.set_bind_group(2, k_bind_group, &[]);
+                compute_pass.set_pipeline(sum_pipeline);
+                compute_pass.dispatch_indirect(&self.dispatches_buffer, Self::SEQUENCE_DISPATCH);
+                if let Some(second_pipeline) = second_pipeline {
+                    compute_pass.set_pipeline(second_pipeline);
+                    compute_pass.dispatch_indirect(&self.dispatches_buffer, Self::SINGLE_DISPATCH);
+                }
             }
+        }
+    }
 
-            if iteration > 0 && iteration % MAX_ITERATION_BEFORE_CONVERGENCE_CHECK == 0 {
-                encoder.copy_buffer_to_buffer(
-                    &self.convergence_buffer.gpu_buffer,
-                    0,
-                    &self.convergence_buffer.mapped_buffer,
-                    0,
-                    (self.k + 1) as u64 * 4,
-                );
-
-                queue.submit(Some(encoder.finish()));
-                let check_convergence_slice = self.convergence_buffer.mapped_buffer.slice(..);
-                let check_convergence_future = check_convergence_slice.map_async(MapMode::Read);
-
-                device.poll(wgpu::Maintain::Wait);
-
-                match check_convergence_future.await {
-                    Ok(_) => {
-                        let convergence_data = bytemuck::cast_slice::<u8, u32>(
-                            &check_convergence_slice.get_mapped_range(),
-                        )
-                        .to_vec();
-                        if convergence_data[self.k as usize] >= self.k {
-                            // We converged, time to go.
-                            debug!("We have convergence, checked at iteration {iteration}");
-                            break 'iteration;
-                        }
-                    }
-                    Err(_) => break 'iteration,
-                };
+    /// Records the search of the pixel of each cluster the farthest from its centroid, among
+    /// this texture and the previous tiles, where the empty clusters can be re-seeded.
+    pub(crate) fn measure_farthest(&self, encoder: &mut CommandEncoder) {
+        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("Measure farthest pass"),
+        });
+        compute_pass.set_bind_group(0, &self.bind_group_0, &[]);
+        compute_pass.set_bind_group(1, &self.bind_group_1, &[]);
+        compute_pass.set_bind_group(2, &self.bind_groups[0], &[]);
+        compute_pass.set_pipeline(&self.pipeline.measure_farthest_pipeline);
+        compute_pass.dispatch_indirect(&self.dispatches_buffer, Self::RESEED_IMAGE_DISPATCH);
+        compute_pass.set_pipeline(&self.pipeline.select_farthest_pipeline);
+        compute_pass.dispatch_indirect(&self.dispatches_buffer, Self::RESEED_IMAGE_DISPATCH);
+        compute_pass.set_pipeline(&self.pipeline.keep_farthest_pipeline);
+        compute_pass.dispatch_indirect(&self.dispatches_buffer, Self::RESEED_SINGLE_DISPATCH);
+    }
+
+    /// Records the move of every empty cluster to the farthest pixel of another cluster, the
+    /// farthest first.
+    pub(crate) fn reseed(&self, encoder: &mut CommandEncoder) {
+        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("Reseed pass"),
+        });
+        compute_pass.set_bind_group(0, &self.bind_group_0, &[]);
+        compute_pass.set_bind_group(1, &self.bind_group_1, &[]);
+        compute_pass.set_bind_group(2, &self.bind_groups[0], &[]);
+        compute_pass.set_pipeline(&self.pipeline.reseed_pipeline);
+        compute_pass.dispatch_indirect(&self.dispatches_buffer, Self::RESEED_SINGLE_DISPATCH);
+    }
+
+    /// Records the check skipping the iteration that follows, and the ones after, once every
+    /// centroid converged.
+    fn gate(&self, encoder: &mut CommandEncoder) {
+        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("Gate iteration pass"),
+        });
+        compute_pass.set_bind_group(0, &self.bind_group_0, &[]);
+        compute_pass.set_bind_group(1, &self.bind_group_1, &[]);
+        compute_pass.set_bind_group(2, &self.bind_groups[0], &[]);
+        compute_pass.set_bind_group(3, &self.dispatches_bind_group, &[]);
+        compute_pass.set_pipeline(&self.pipeline.gate_pipeline);
+        compute_pass.dispatch(1, 1, 1);
+    }
+
+    /// Records the check skipping the search of the farthest pixels and the re-seed, unless the
+    /// pick left empty clusters.
+    fn gate_reseed(&self, encoder: &mut CommandEncoder) {
+        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("Gate reseed pass"),
+        });
+        compute_pass.set_bind_group(0, &self.bind_group_0, &[]);
+        compute_pass.set_bind_group(1, &self.bind_group_1, &[]);
+        compute_pass.set_bind_group(2, &self.bind_groups[0], &[]);
+        compute_pass.set_bind_group(3, &self.dispatches_bind_group, &[]);
+        compute_pass.set_pipeline(&self.pipeline.gate_reseed_pipeline);
+        compute_pass.dispatch(1, 1, 1);
+    }
+
+    /// Records the sums of every centroid in a single pass, followed by the `pick_pipeline`, if
+    /// any.
+    fn record_single_pass(&self, encoder: &mut CommandEncoder, pick: bool) {
+        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("Sum all centroids pass"),
+        });
+        compute_pass.set_bind_group(0, &self.bind_group_0, &[]);
+        compute_pass.set_bind_group(1, &self.bind_group_1, &[]);
+        compute_pass.set_bind_group(2, &self.bind_groups[0], &[]);
+        compute_pass.set_pipeline(&self.pipeline.sum_all_pipeline);
+        compute_pass.dispatch_indirect(&self.dispatches_buffer, Self::SEQUENCE_DISPATCH);
+        if pick {
+            compute_pass.set_pipeline(&self.pipeline.pick_all_pipeline);
+            compute_pass.dispatch_indirect(&self.dispatches_buffer, Self::SINGLE_DISPATCH);
+        }
+    }
+
+    /// Adds the sums of this tile to the ones of the previous tiles. Fixed point sums are
+    /// already accumulated in place.
+    pub(crate) fn accumulate(&self, encoder: &mut CommandEncoder) {
+        if self.state.single_pass {
+            self.record_single_pass(encoder, false);
+        } else if self.state.deterministic {
+            self.record(encoder, None);
+        } else {
+            self.record(encoder, Some(&self.pipeline.accumulate_pipeline));
+        }
+    }
+
+    /// Picks each centroid from its sums.
+    fn pick(&self, encoder: &mut CommandEncoder) {
+        if self.state.single_pass {
+            self.record_single_pass(encoder, true);
+        } else if self.state.deterministic {
+            self.record(encoder, Some(&self.pipeline.pick_fixed_pipeline));
+        } else {
+            self.record(encoder, Some(&self.pipeline.pick_pipeline));
+        }
+    }
+
+    /// Moves the centroids to the mean of all the accumulated tiles.
+    pub(crate) fn finalize(&self, encoder: &mut CommandEncoder) {
+        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("Finalize tiles pass"),
+        });
+        compute_pass.set_bind_group(0, &self.bind_group_0, &[]);
+        compute_pass.set_bind_group(1, &self.bind_group_1, &[]);
+        if self.state.single_pass {
+            compute_pass.set_bind_group(2, &self.bind_groups[0], &[]);
+            compute_pass.set_pipeline(&self.pipeline.pick_all_pipeline);
+            compute_pass.dispatch(1, 1, 1);
+            return;
+        }
+        compute_pass.set_pipeline(if self.state.deterministic {
+            &self.pipeline.pick_fixed_pipeline
+        } else {
+            &self.pipeline.finalize_pipeline
+        });
+        for k_bind_group in &self.bind_groups {
+            compute_pass.set_bind_group(2, k_bind_group, &[]);
+            compute_pass.dispatch(1, 1, 1);
+        }
+    }
 
-                self.convergence_buffer.mapped_buffer.unmap();
-            } else {
-                queue.submit(Some(encoder.finish()));
+    pub(crate) async fn compute(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        options: &KMeansOptions,
+        find_centroid_module: &FindCentroidModule<'_>,
+        timestamps: &Timestamps,
+    ) -> Result<Convergence> {
+        debug!("Dispatch size {}", self.dispatch_size);
+
+        // Records the iterations in batches, and only waits for the gpu at the end of each.
+        // Iterations following the convergence are skipped by the gpu itself. The progress is
+        // reported, and the cancellation checked, before each batch with what the gpu counted.
+        let mut counts = ConvergenceCounts::default();
+        let mut batch_start = 0;
+        while batch_start < options.max_iterations && counts.converged < self.k {
+            options.report_progress(Phase::Iterate, counts.iterations, counts.converged)?;
+            let batch_end =
+                (batch_start + options.convergence_check_interval).min(options.max_iterations);
+            let mut encoder =
+                device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+            for iteration in batch_start..batch_end {
+                self.gate(&mut encoder);
+                self.pick(&mut encoder);
+                // The clusters left empty move to the pixels the farthest from the centroids
+                // that just moved.
+                self.gate_reseed(&mut encoder);
+                self.measure_farthest(&mut encoder);
+                self.reseed(&mut encoder);
+                {
+                    let mut compute_pass =
+                        encoder.begin_compute_pass(&ComputePassDescriptor { label: None });
+                    find_centroid_module.dispatch_indirect(
+                        &mut compute_pass,
+                        &self.dispatches_buffer,
+                        Self::IMAGE_DISPATCH,
+                    );
+                }
+                timestamps.iterated(&mut encoder, iteration);
             }
+            counts = self.state.converged_count(device, queue, encoder).await?;
+            batch_start = batch_end;
+        }
+        timestamps.ran(counts.iterations);
+
+        let convergence = Convergence {
+            iterations: counts.iterations,
+            converged: counts.converged >= self.k,
+            reseeds: counts.reseeds,
+        };
+        if convergence.converged {
+            debug!(
+                "We have convergence at iteration {}",
+                convergence.iterations - 1
+            );
         }
 
         if log_enabled!(log::Level::Debug) {
-            debug!("== Final centroids at iteration {current_iteration}: ==");
+            debug!(
+                "== Final centroids at iteration {}: ==",
+                convergence.iterations.saturating_sub(1)
+            );
             let mut encoder =
                 device.create_command_encoder(&CommandEncoderDescriptor { label: None });
 
@@ -857,6 +1958,283 @@ impl<'a> ChooseCentroidModule<'a> {
             staging_buffer.unmap();
             debug!("========================");
         }
+
+        options.report_progress(Phase::Finalize, convergence.iterations, counts.converged)?;
+        Ok(convergence)
+    }
+}
+
+pub(crate) struct MiniBatchPipeline {
+    sample_pipeline: ComputePipeline,
+    update_pipeline: ComputePipeline,
+    gate_pipeline: ComputePipeline,
+    bind_group_layout: BindGroupLayout,
+    dispatches_bind_group_layout: BindGroupLayout,
+}
+
+impl MiniBatchPipeline {
+    pub fn new(device: &Device) -> Self {
+        let mini_batch_shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("Mini-batch shader"),
+            source: ShaderSource::Wgsl(include_str!("shaders/mini_batch.wgsl").into()),
+        });
+
+        let storage_layout = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only: false },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Mini-batch bind group layout"),
+            entries: &[
+                WorkTexture::texture_2d_layout(0),
+                CentroidsBuffer::layout(1, false),
+                storage_layout(2),
+                storage_layout(3),
+                storage_layout(4),
+                BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        // Apart, so that the dispatches are never bound as storage while read as arguments.
+        let dispatches_bind_group_layout =
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("Mini-batch dispatches bind group layout"),
+                entries: &[storage_layout(0)],
+            });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("Mini-batch layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let gate_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("Mini-batch gate layout"),
+            bind_group_layouts: &[&bind_group_layout, &dispatches_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let entry_pipeline = |label, layout, entry_point| {
+            device.create_compute_pipeline(&ComputePipelineDescriptor {
+                label: Some(label),
+                layout: Some(layout),
+                module: &mini_batch_shader,
+                entry_point,
+            })
+        };
+
+        Self {
+            sample_pipeline: entry_pipeline("Sample batch pipeline", &pipeline_layout, "sample"),
+            update_pipeline: entry_pipeline("Update batch pipeline", &pipeline_layout, "update"),
+            gate_pipeline: entry_pipeline("Gate batch pipeline", &gate_pipeline_layout, "gate"),
+            bind_group_layout,
+            dispatches_bind_group_layout,
+        }
+    }
+}
+
+/// Mini-batch k-means: each iteration moves the centroids from a random batch of pixels, with a
+/// learning rate per centroid, instead of going over the whole image.
+pub(crate) struct MiniBatchModule<'a> {
+    k: u32,
+    pipeline: &'a MiniBatchPipeline,
+    bind_group: BindGroup,
+    dispatches_bind_group: BindGroup,
+    dispatches_buffer: Buffer,
+    convergence_buffer: ConvergenceBuffer,
+}
+
+impl<'a> MiniBatchModule<'a> {
+    const WORKGROUP_SIZE: u32 = 256;
+    /// Offsets of the arguments of the indirect dispatches, see `Dispatches` in
+    /// `mini_batch.wgsl`: over the batch, and a single workgroup.
+    const BATCH_DISPATCH: BufferAddress = 0;
+    const SINGLE_DISPATCH: BufferAddress = 12;
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &Device,
+        pipeline: &'a MiniBatchPipeline,
+        k: u32,
+        batch_size: u32,
+        work_texture: &WorkTexture,
+        centroid_buffer: &CentroidsBuffer,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Self {
+        let mut settings_content: Vec<u8> = Vec::new();
+        settings_content.extend_from_slice(bytemuck::cast_slice(&[
+            options.seed as u32,
+            (options.seed >> 32) as u32,
+            batch_size,
+        ]));
+        settings_content.extend_from_slice(bytemuck::cast_slice(&[
+            options.convergence_for(color_space)
+        ]));
+        let settings_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Mini-batch settings buffer"),
+            contents: &settings_content,
+            usage: BufferUsages::UNIFORM,
+        });
+        // Zeroed, see `batch_sums` in `mini_batch.wgsl`.
+        let batch_sums_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Batch sums buffer"),
+            size: k as u64 * 8 * 4,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let seen_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Seen buffer"),
+            size: k as u64 * 4,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let convergence_buffer = ConvergenceBuffer::new(device, k);
+
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("Mini-batch bind group"),
+            layout: &pipeline.bind_group_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(
+                        &work_texture.create_view(&TextureViewDescriptor::default()),
+                    ),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: centroid_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: batch_sums_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: seen_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: convergence_buffer.gpu_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: settings_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        // The dispatches of an iteration, followed by their full sizes, which `gate` copies over
+        // them until every centroid converged.
+        let (batch_x, _) = compute_work_group_count((batch_size, 1), (Self::WORKGROUP_SIZE, 1));
+        let dispatches = [batch_x, 1, 1, 1, 1, 1];
+        let dispatches_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Mini-batch dispatches buffer"),
+            contents: bytemuck::cast_slice(&[dispatches, dispatches]),
+            usage: BufferUsages::STORAGE | BufferUsages::INDIRECT,
+        });
+        let dispatches_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("Mini-batch dispatches bind group"),
+            layout: &pipeline.dispatches_bind_group_layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: dispatches_buffer.as_entire_binding(),
+            }],
+        });
+
+        Self {
+            k,
+            pipeline,
+            bind_group,
+            dispatches_bind_group,
+            dispatches_buffer,
+            convergence_buffer,
+        }
+    }
+
+    /// Records an iteration: the gate, the sums of a batch, and the move of the centroids.
+    fn record(&self, encoder: &mut CommandEncoder) {
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("Gate batch pass"),
+            });
+            compute_pass.set_bind_group(0, &self.bind_group, &[]);
+            compute_pass.set_bind_group(1, &self.dispatches_bind_group, &[]);
+            compute_pass.set_pipeline(&self.pipeline.gate_pipeline);
+            compute_pass.dispatch(1, 1, 1);
+        }
+        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("Mini-batch pass"),
+        });
+        compute_pass.set_bind_group(0, &self.bind_group, &[]);
+        compute_pass.set_pipeline(&self.pipeline.sample_pipeline);
+        compute_pass.dispatch_indirect(&self.dispatches_buffer, Self::BATCH_DISPATCH);
+        compute_pass.set_pipeline(&self.pipeline.update_pipeline);
+        compute_pass.dispatch_indirect(&self.dispatches_buffer, Self::SINGLE_DISPATCH);
+    }
+
+    /// Runs the iterations like [`ChooseCentroidModule::compute`], then labels every pixel with
+    /// the `find_centroid_module`, once.
+    pub(crate) async fn compute(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        options: &KMeansOptions,
+        find_centroid_module: &FindCentroidModule<'_>,
+        timestamps: &Timestamps,
+    ) -> Result<Convergence> {
+        let mut counts = ConvergenceCounts::default();
+        let mut batch_start = 0;
+        while batch_start < options.max_iterations && counts.converged < self.k {
+            options.report_progress(Phase::Iterate, counts.iterations, counts.converged)?;
+            let batch_end =
+                (batch_start + options.convergence_check_interval).min(options.max_iterations);
+            let mut encoder =
+                device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+            for iteration in batch_start..batch_end {
+                self.record(&mut encoder);
+                timestamps.iterated(&mut encoder, iteration);
+            }
+            counts = self
+                .convergence_buffer
+                .converged_count(device, queue, encoder, self.k)
+                .await?;
+            batch_start = batch_end;
+        }
+        timestamps.ran(counts.iterations);
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("Label pass"),
+            });
+            find_centroid_module.dispatch(&mut compute_pass);
+        }
+        queue.submit(Some(encoder.finish()));
+
+        let convergence = Convergence {
+            iterations: counts.iterations,
+            converged: counts.converged >= self.k,
+            reseeds: 0,
+        };
+        debug!(
+            "Mini-batch ran {} iterations, converged: {}",
+            convergence.iterations, convergence.converged
+        );
+        options.report_progress(Phase::Finalize, convergence.iterations, counts.converged)?;
+        Ok(convergence)
     }
 }
 
@@ -909,34 +2287,20 @@ impl DistanceMapTexture {
     }
 }
 
-pub(crate) struct PlusPlusInitModule<'a> {
-    k: u32,
-    image_dimensions: (u32, u32),
-    centroid_buffer: &'a CentroidsBuffer,
-    work_texture: &'a WorkTexture,
+pub(crate) struct PlusPlusInitPipeline {
+    pipeline: ComputePipeline,
+    pick_pipeline: ComputePipeline,
+    calc_diff_pipeline: ComputePipeline,
+    sum_pipeline: ComputePipeline,
+    sample_pipeline: ComputePipeline,
+    bind_group_layout: BindGroupLayout,
+    calc_diff_bind_group_layout: BindGroupLayout,
+    sample_bind_group_layout: BindGroupLayout,
+    k_index_bind_group_layout: BindGroupLayout,
 }
 
-impl<'a> PlusPlusInitModule<'a> {
-    pub(crate) fn new(
-        image_dimensions: (u32, u32),
-        k: u32,
-        work_texture: &'a WorkTexture,
-        centroid_buffer: &'a CentroidsBuffer,
-    ) -> Self {
-        Self {
-            k,
-            image_dimensions,
-            centroid_buffer,
-            work_texture,
-        }
-    }
-
-    pub(crate) async fn compute(&self, device: &Device, queue: &Queue) {
-        const WORKGROUP_SIZE: u32 = 256;
-        const N_SEQ: u32 = 16;
-        const MAX_OPERATIONS_CHAIN: usize = 32;
-
-        let distance_map_texture = DistanceMapTexture::new(device, self.image_dimensions);
+impl PlusPlusInitPipeline {
+    pub fn new(device: &Device) -> Self {
         let choose_centroid_shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
             label: Some("Plus plus init shader"),
             source: ShaderSource::Wgsl(include_str!("shaders/plus_plus_init.wgsl").into()),
@@ -1000,108 +2364,7 @@ impl<'a> PlusPlusInitModule<'a> {
                 ],
             });
 
-        let (dispatch_size, _) = compute_work_group_count(
-            (self.image_dimensions.0 * self.image_dimensions.1, 1),
-            (WORKGROUP_SIZE * N_SEQ, 1),
-        );
-        let prefix_buffer_size = dispatch_size * 4 * 4;
-        let prefix_buffer = device.create_buffer(&BufferDescriptor {
-            label: None,
-            size: prefix_buffer_size as BufferAddress,
-            usage: BufferUsages::STORAGE,
-            mapped_at_creation: false,
-        });
-        let flag_buffer = device.create_buffer_init(&BufferInitDescriptor {
-            label: None,
-            contents: bytemuck::cast_slice::<u32, u8>(&vec![0; dispatch_size as usize]),
-            usage: BufferUsages::STORAGE,
-        });
-        let part_id_buffer = device.create_buffer(&BufferDescriptor {
-            label: None,
-            size: 4,
-            usage: BufferUsages::STORAGE,
-            mapped_at_creation: false,
-        });
-
-        let bind_group = device.create_bind_group(&BindGroupDescriptor {
-            label: None,
-            layout: &choose_centroid_bind_group_layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: self.centroid_buffer.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: BindingResource::TextureView(
-                        &self
-                            .work_texture
-                            .create_view(&TextureViewDescriptor::default()),
-                    ),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: prefix_buffer.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: flag_buffer.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 4,
-                    resource: part_id_buffer.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 5,
-                    resource: BindingResource::TextureView(
-                        &distance_map_texture
-                            .0
-                            .create_view(&TextureViewDescriptor::default()),
-                    ),
-                },
-            ],
-        });
-
-        let k_index_buffers: Vec<Buffer> = (0..self.k)
-            .map(|k| {
-                device.create_buffer_init(&BufferInitDescriptor {
-                    label: None,
-                    contents: bytemuck::cast_slice(&[k]),
-                    usage: BufferUsages::UNIFORM,
-                })
-            })
-            .collect();
-
-        let k_index_bind_group_layout =
-            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-                label: Some("Choose centroid bind group 2 layout"),
-                entries: &[BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                }],
-            });
-        let bind_groups: Vec<_> = (0..self.k)
-            .map(|k| {
-                device.create_bind_group(&BindGroupDescriptor {
-                    label: None,
-                    layout: &k_index_bind_group_layout,
-                    entries: &[BindGroupEntry {
-                        binding: 0,
-                        resource: BindingResource::Buffer(BufferBinding {
-                            buffer: &k_index_buffers[k as usize],
-                            offset: 0,
-                            size: None,
-                        }),
-                    }],
-                })
-            })
-            .collect();
+        let k_index_bind_group_layout = k_index_bind_group_layout(device);
 
         let choose_centroid_pipeline_layout =
             device.create_pipeline_layout(&PipelineLayoutDescriptor {
@@ -1112,12 +2375,6 @@ impl<'a> PlusPlusInitModule<'a> {
                 ],
                 push_constant_ranges: &[],
             });
-        let initial_pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
-            label: Some("Choose centroid pipeline"),
-            layout: Some(&choose_centroid_pipeline_layout),
-            module: &choose_centroid_shader,
-            entry_point: "initial",
-        });
         let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
             label: Some("Choose centroid pipeline"),
             layout: Some(&choose_centroid_pipeline_layout),
@@ -1131,14 +2388,6 @@ impl<'a> PlusPlusInitModule<'a> {
             entry_point: "pick",
         });
 
-        let centroid_size = (self.k as u64 + 1) * 16;
-        let staging_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
-            size: centroid_size,
-            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
-            mapped_at_creation: false,
-        });
-
         let calc_diff_bind_group_layout =
             device.create_bind_group_layout(&BindGroupLayoutDescriptor {
                 label: None,
@@ -1146,6 +2395,7 @@ impl<'a> PlusPlusInitModule<'a> {
                     CentroidsBuffer::layout(0, true),
                     WorkTexture::texture_2d_layout(1),
                     DistanceMapTexture::texture_storage_layout(2),
+                    DistanceMapTexture::texture_2d_layout(3),
                 ],
             });
 
@@ -1160,33 +2410,6 @@ impl<'a> PlusPlusInitModule<'a> {
             source: ShaderSource::Wgsl(include_str!("shaders/kmeans++_calc_diff.wgsl").into()),
         });
 
-        let calc_diff_bind_group = device.create_bind_group(&BindGroupDescriptor {
-            label: None,
-            layout: &calc_diff_bind_group_layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: self.centroid_buffer.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: BindingResource::TextureView(
-                        &self
-                            .work_texture
-                            .create_view(&TextureViewDescriptor::default()),
-                    ),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: BindingResource::TextureView(
-                        &distance_map_texture
-                            .0
-                            .create_view(&TextureViewDescriptor::default()),
-                    ),
-                },
-            ],
-        });
-
         let calc_diff_pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
             label: None,
             layout: Some(&calc_diff_pipeline_layout),
@@ -1194,126 +2417,1036 @@ impl<'a> PlusPlusInitModule<'a> {
             entry_point: "main",
         });
 
-        let calc_diff_dispatch_size = compute_work_group_count(self.image_dimensions, (16, 16));
-
-        for k_start in (0..self.k as usize).step_by(MAX_OPERATIONS_CHAIN) {
-            let max_k = (k_start + MAX_OPERATIONS_CHAIN).min(self.k as usize);
-
-            let mut encoder =
-                device.create_command_encoder(&CommandEncoderDescriptor { label: None });
-            {
-                let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
-                    label: Some("Plus plus init pass"),
-                });
-                for k in k_start..max_k {
-                    compute_pass.set_bind_group(1, &bind_groups[k as usize], &[]);
-                    if k == 0 {
-                        compute_pass.set_pipeline(&initial_pipeline);
-                        compute_pass.set_bind_group(0, &bind_group, &[]);
-                        compute_pass.dispatch(1, 1, 1);
-                    } else {
-                        // Calculate difference
-                        compute_pass.set_pipeline(&calc_diff_pipeline);
-                        compute_pass.set_bind_group(0, &calc_diff_bind_group, &[]);
-                        compute_pass.dispatch(
-                            calc_diff_dispatch_size.0,
-                            calc_diff_dispatch_size.1,
-                            1,
-                        );
-
-                        compute_pass.set_pipeline(&pipeline);
-                        compute_pass.set_bind_group(0, &bind_group, &[]);
-                        compute_pass.dispatch(dispatch_size, 1, 1);
-                        compute_pass.set_pipeline(&pick_pipeline);
-                        compute_pass.dispatch(1, 1, 1);
-                    }
-                }
-            }
-
-            queue.submit(Some(encoder.finish()));
-            device.poll(wgpu::Maintain::Wait);
-        }
-
-        if log_enabled!(log::Level::Debug) {
-            debug!("== Initial centroids: ==");
-            let mut encoder =
-                device.create_command_encoder(&CommandEncoderDescriptor { label: None });
-            encoder.copy_buffer_to_buffer(
-                self.centroid_buffer,
-                0,
-                &staging_buffer,
-                0,
-                centroid_size,
-            );
-
-            queue.submit(Some(encoder.finish()));
-            let cent_buffer_slice = staging_buffer.slice(..);
-            let cent_buffer_future = cent_buffer_slice.map_async(MapMode::Read);
+        let sample_bind_group_layout =
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("Plus plus sample bind group layout"),
+                entries: &[
+                    CentroidsBuffer::layout(0, false),
+                    WorkTexture::texture_2d_layout(1),
+                    DistanceMapTexture::texture_2d_layout(2),
+                    BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
+            });
 
-            device.poll(wgpu::Maintain::Wait);
+        let sample_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("Plus plus sample pipeline layout"),
+            bind_group_layouts: &[&sample_bind_group_layout, &k_index_bind_group_layout],
+            push_constant_ranges: &[],
+        });
 
-            if let Ok(()) = cent_buffer_future.await {
-                let data = cent_buffer_slice.get_mapped_range();
+        let sample_shader_module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("Plus plus sample shader"),
+            source: ShaderSource::Wgsl(include_str!("shaders/plus_plus_sample.wgsl").into()),
+        });
 
-                for (index, k) in bytemuck::cast_slice::<u8, f32>(&data[16..])
-                    .chunks_exact(4)
-                    .enumerate()
-                {
-                    debug!("Centroid {index} = {k:?}")
-                }
-            }
-            debug!("========================");
+        let sum_pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: Some("Plus plus sum pipeline"),
+            layout: Some(&sample_pipeline_layout),
+            module: &sample_shader_module,
+            entry_point: "sum",
+        });
+        let sample_pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: Some("Plus plus sample pipeline"),
+            layout: Some(&sample_pipeline_layout),
+            module: &sample_shader_module,
+            entry_point: "sample",
+        });
 
-            staging_buffer.unmap();
+        Self {
+            pipeline,
+            pick_pipeline,
+            calc_diff_pipeline,
+            sum_pipeline,
+            sample_pipeline,
+            bind_group_layout: choose_centroid_bind_group_layout,
+            calc_diff_bind_group_layout,
+            sample_bind_group_layout,
+            k_index_bind_group_layout,
         }
     }
-}
-
-pub(crate) struct MixColorsModule {
-    pipeline: ComputePipeline,
-    bind_group: BindGroup,
-    dispatch_size: (u32, u32),
-}
 
-impl MixColorsModule {
-    pub fn new(
+    /// The sample bind group picking centroids uniformly at random among the opaque pixels, into
+    /// `centroids` laid out like a [`CentroidsBuffer`]. No distance map is read then, the work
+    /// texture stands in for it.
+    fn opaque_sample_bind_group(
+        &self,
         device: &Device,
-        image_dimensions: (u32, u32),
-        input_texture: &WorkTexture,
-        output_texture: &WorkTexture,
-        color_index_texture: &ColorIndexTexture,
-        centroids_buffer: &CentroidsBuffer,
-        mix_mode: &MixMode,
-    ) -> Self {
-        let shader_module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-            label: Some("Mix colors shader"),
-            source: ShaderSource::Wgsl(include_str!("shaders/mix_colors.wgsl").into()),
-        });
-
-        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-            label: Some("Mix colors bind group layout"),
-            entries: &[
-                WorkTexture::texture_2d_layout(0),
-                WorkTexture::texture_storage_layout(1),
-                ColorIndexTexture::texture_2d_layout(2),
-                CentroidsBuffer::layout(3, true),
-            ],
-        });
-
-        let bind_group = device.create_bind_group(&BindGroupDescriptor {
-            label: Some("Mix colors bind group"),
-            layout: &bind_group_layout,
+        centroids: &Buffer,
+        work_view: &TextureView,
+        partial_sums: &Buffer,
+        settings: &Buffer,
+    ) -> BindGroup {
+        device.create_bind_group(&BindGroupDescriptor {
+            label: Some("Opaque sample bind group"),
+            layout: &self.sample_bind_group_layout,
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: BindingResource::TextureView(
-                        &input_texture.create_view(&TextureViewDescriptor::default()),
-                    ),
+                    resource: centroids.as_entire_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: BindingResource::TextureView(
-                        &output_texture.create_view(&TextureViewDescriptor::default()),
+                    resource: BindingResource::TextureView(work_view),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::TextureView(work_view),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: partial_sums.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: settings.as_entire_binding(),
+                },
+            ],
+        })
+    }
+}
+
+/// The settings of `plus_plus_sample.wgsl`: the seed, then whether every centroid is sampled
+/// uniformly among the opaque pixels.
+fn sample_settings(seed: u64, opaque_only: bool) -> [u32; 4] {
+    [seed as u32, (seed >> 32) as u32, opaque_only as u32, 0]
+}
+
+/// How the ++ init picks each new centroid, from the distance of every pixel to the closest
+/// centroid already picked.
+pub(crate) enum Selection {
+    /// The pixel the furthest away.
+    Farthest,
+    /// A random pixel, with a probability proportional to the squared distance.
+    Weighted,
+}
+
+pub(crate) struct PlusPlusInitModule<'a> {
+    k: u32,
+    seed: u64,
+    image_dimensions: (u32, u32),
+    pipeline: &'a PlusPlusInitPipeline,
+    centroid_buffer: &'a CentroidsBuffer,
+    work_texture: &'a WorkTexture,
+}
+
+impl<'a> PlusPlusInitModule<'a> {
+    pub(crate) fn new(
+        pipeline: &'a PlusPlusInitPipeline,
+        image_dimensions: (u32, u32),
+        k: u32,
+        seed: u64,
+        work_texture: &'a WorkTexture,
+        centroid_buffer: &'a CentroidsBuffer,
+    ) -> Self {
+        Self {
+            k,
+            seed,
+            image_dimensions,
+            pipeline,
+            centroid_buffer,
+            work_texture,
+        }
+    }
+
+    /// Picks each centroid uniformly at random among the opaque pixels, with the same two-level
+    /// prefix sum as the ++ init: the sums are computed once, then each centroid draws its own
+    /// threshold.
+    pub(crate) fn pick_random(&self, device: &Device, queue: &Queue) {
+        const WORKGROUP_SIZE: u32 = 256;
+        const N_SEQ: u32 = 16;
+
+        let (dispatch_size, _) = compute_work_group_count(
+            (self.image_dimensions.0 * self.image_dimensions.1, 1),
+            (WORKGROUP_SIZE * N_SEQ, 1),
+        );
+        let partial_sums_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Random init partial sums buffer"),
+            size: dispatch_size as BufferAddress * 4,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let settings_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Random init settings buffer"),
+            contents: bytemuck::cast_slice(&sample_settings(self.seed, true)),
+            usage: BufferUsages::UNIFORM,
+        });
+        let bind_group = self.pipeline.opaque_sample_bind_group(
+            device,
+            self.centroid_buffer,
+            &self
+                .work_texture
+                .create_view(&TextureViewDescriptor::default()),
+            &partial_sums_buffer,
+            &settings_buffer,
+        );
+        let k_bind_groups =
+            k_index_bind_groups(device, &self.pipeline.k_index_bind_group_layout, self.k);
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("Random init pass"),
+            });
+            compute_pass.set_bind_group(0, &bind_group, &[]);
+            compute_pass.set_bind_group(1, &k_bind_groups[0], &[]);
+            compute_pass.set_pipeline(&self.pipeline.sum_pipeline);
+            compute_pass.dispatch(dispatch_size, 1, 1);
+            compute_pass.set_pipeline(&self.pipeline.sample_pipeline);
+            for k_bind_group in &k_bind_groups {
+                compute_pass.set_bind_group(1, k_bind_group, &[]);
+                compute_pass.dispatch(1, 1, 1);
+            }
+        }
+        queue.submit(Some(encoder.finish()));
+    }
+
+    pub(crate) async fn compute(&self, device: &Device, queue: &Queue, selection: Selection) {
+        const WORKGROUP_SIZE: u32 = 256;
+        const N_SEQ: u32 = 16;
+        const MAX_OPERATIONS_CHAIN: usize = 32;
+
+        // Each pass of calc diff reads the map of the pass before, and writes the other one.
+        let distance_map_textures =
+            [(); 2].map(|_| DistanceMapTexture::new(device, self.image_dimensions));
+        let distance_map_views = distance_map_textures
+            .each_ref()
+            .map(|texture| texture.0.create_view(&TextureViewDescriptor::default()));
+
+        let (dispatch_size, _) = compute_work_group_count(
+            (self.image_dimensions.0 * self.image_dimensions.1, 1),
+            (WORKGROUP_SIZE * N_SEQ, 1),
+        );
+        let prefix_buffer_size = dispatch_size * 4 * 4;
+        let prefix_buffer = device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: prefix_buffer_size as BufferAddress,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let flag_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice::<u32, u8>(&vec![0; dispatch_size as usize]),
+            usage: BufferUsages::STORAGE,
+        });
+        let part_id_buffer = device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: 4,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let settings_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Plus plus init settings buffer"),
+            contents: bytemuck::cast_slice(&sample_settings(self.seed, false)),
+            usage: BufferUsages::UNIFORM,
+        });
+
+        let bind_groups_by_map = distance_map_views.each_ref().map(|distance_map_view| {
+            device.create_bind_group(&BindGroupDescriptor {
+                label: None,
+                layout: &self.pipeline.bind_group_layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: self.centroid_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: BindingResource::TextureView(
+                            &self
+                                .work_texture
+                                .create_view(&TextureViewDescriptor::default()),
+                        ),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: prefix_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 3,
+                        resource: flag_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 4,
+                        resource: part_id_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 5,
+                        resource: BindingResource::TextureView(distance_map_view),
+                    },
+                ],
+            })
+        });
+
+        let bind_groups =
+            k_index_bind_groups(device, &self.pipeline.k_index_bind_group_layout, self.k);
+
+        let centroid_size = (self.k as u64 + 1) * 16;
+        let staging_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: centroid_size,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let calc_diff_bind_groups = [0, 1].map(|output| {
+            device.create_bind_group(&BindGroupDescriptor {
+                label: None,
+                layout: &self.pipeline.calc_diff_bind_group_layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: self.centroid_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: BindingResource::TextureView(
+                            &self
+                                .work_texture
+                                .create_view(&TextureViewDescriptor::default()),
+                        ),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: BindingResource::TextureView(&distance_map_views[output]),
+                    },
+                    BindGroupEntry {
+                        binding: 3,
+                        resource: BindingResource::TextureView(&distance_map_views[1 - output]),
+                    },
+                ],
+            })
+        });
+
+        let partial_sums_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Plus plus partial sums buffer"),
+            size: dispatch_size as BufferAddress * 4,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let sample_bind_groups = distance_map_views.each_ref().map(|distance_map_view| {
+            device.create_bind_group(&BindGroupDescriptor {
+                label: Some("Plus plus sample bind group"),
+                layout: &self.pipeline.sample_bind_group_layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: self.centroid_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: BindingResource::TextureView(
+                            &self
+                                .work_texture
+                                .create_view(&TextureViewDescriptor::default()),
+                        ),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: BindingResource::TextureView(distance_map_view),
+                    },
+                    BindGroupEntry {
+                        binding: 3,
+                        resource: partial_sums_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 4,
+                        resource: settings_buffer.as_entire_binding(),
+                    },
+                ],
+            })
+        });
+
+        let calc_diff_dispatch_size = compute_work_group_count(self.image_dimensions, (16, 16));
+
+        for k_start in (0..self.k as usize).step_by(MAX_OPERATIONS_CHAIN) {
+            let max_k = (k_start + MAX_OPERATIONS_CHAIN).min(self.k as usize);
+
+            let mut encoder =
+                device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+            {
+                let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("Plus plus init pass"),
+                });
+                for (k, k_bind_group) in bind_groups.iter().enumerate().take(max_k).skip(k_start) {
+                    compute_pass.set_bind_group(1, k_bind_group, &[]);
+                    if k > 0 {
+                        // Calculate difference
+                        compute_pass.set_pipeline(&self.pipeline.calc_diff_pipeline);
+                        compute_pass.set_bind_group(0, &calc_diff_bind_groups[k % 2], &[]);
+                        compute_pass.dispatch(
+                            calc_diff_dispatch_size.0,
+                            calc_diff_dispatch_size.1,
+                            1,
+                        );
+                    }
+
+                    match selection {
+                        Selection::Farthest if k > 0 => {
+                            compute_pass.set_pipeline(&self.pipeline.pipeline);
+                            compute_pass.set_bind_group(0, &bind_groups_by_map[k % 2], &[]);
+                            compute_pass.dispatch(dispatch_size, 1, 1);
+                            compute_pass.set_pipeline(&self.pipeline.pick_pipeline);
+                            compute_pass.dispatch(1, 1, 1);
+                        }
+                        // Also the first centroid of both, sampled among the opaque pixels.
+                        _ => {
+                            compute_pass.set_pipeline(&self.pipeline.sum_pipeline);
+                            compute_pass.set_bind_group(0, &sample_bind_groups[k % 2], &[]);
+                            compute_pass.dispatch(dispatch_size, 1, 1);
+                            compute_pass.set_pipeline(&self.pipeline.sample_pipeline);
+                            compute_pass.dispatch(1, 1, 1);
+                        }
+                    }
+                }
+            }
+
+            queue.submit(Some(encoder.finish()));
+            device.poll(wgpu::Maintain::Wait);
+        }
+
+        if log_enabled!(log::Level::Debug) {
+            debug!("== Initial centroids: ==");
+            let mut encoder =
+                device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+            encoder.copy_buffer_to_buffer(
+                self.centroid_buffer,
+                0,
+                &staging_buffer,
+                0,
+                centroid_size,
+            );
+
+            queue.submit(Some(encoder.finish()));
+            let cent_buffer_slice = staging_buffer.slice(..);
+            let cent_buffer_future = cent_buffer_slice.map_async(MapMode::Read);
+
+            device.poll(wgpu::Maintain::Wait);
+
+            if let Ok(()) = cent_buffer_future.await {
+                let data = cent_buffer_slice.get_mapped_range();
+
+                for (index, k) in bytemuck::cast_slice::<u8, f32>(&data[16..])
+                    .chunks_exact(4)
+                    .enumerate()
+                {
+                    debug!("Centroid {index} = {k:?}")
+                }
+            }
+            debug!("========================");
+
+            staging_buffer.unmap();
+        }
+    }
+}
+
+pub(crate) struct KMeansParallelPipeline {
+    initial_pipeline: ComputePipeline,
+    measure_pipeline: ComputePipeline,
+    advance_pipeline: ComputePipeline,
+    rank_pipeline: ComputePipeline,
+    threshold_pipeline: ComputePipeline,
+    oversample_pipeline: ComputePipeline,
+    weigh_pipeline: ComputePipeline,
+    bind_group_layout: BindGroupLayout,
+    round_bind_group_layout: BindGroupLayout,
+}
+
+impl KMeansParallelPipeline {
+    pub fn new(device: &Device) -> Self {
+        let storage_layout = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only: false },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("k-means|| bind group layout"),
+            entries: &[
+                storage_layout(0),
+                WorkTexture::texture_2d_layout(1),
+                storage_layout(2),
+                storage_layout(3),
+                storage_layout(4),
+                BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                storage_layout(6),
+            ],
+        });
+        let round_bind_group_layout = k_index_bind_group_layout(device);
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("k-means|| pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout, &round_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader_module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("k-means|| shader"),
+            source: ShaderSource::Wgsl(include_str!("shaders/kmeans_parallel.wgsl").into()),
+        });
+
+        let pipeline = |entry_point| {
+            device.create_compute_pipeline(&ComputePipelineDescriptor {
+                label: Some("k-means|| pipeline"),
+                layout: Some(&pipeline_layout),
+                module: &shader_module,
+                entry_point,
+            })
+        };
+
+        Self {
+            initial_pipeline: pipeline("initial"),
+            measure_pipeline: pipeline("measure"),
+            advance_pipeline: pipeline("advance"),
+            rank_pipeline: pipeline("rank"),
+            threshold_pipeline: pipeline("threshold"),
+            oversample_pipeline: pipeline("oversample"),
+            weigh_pipeline: pipeline("weigh"),
+            bind_group_layout,
+            round_bind_group_layout,
+        }
+    }
+}
+
+/// Samples the candidates of k-means||, and counts the pixels closest to each. They are reduced
+/// to k centroids on the cpu, see [`crate::init::recluster`].
+pub(crate) struct KMeansParallelModule<'a> {
+    k: u32,
+    seed: u64,
+    capacity: u32,
+    image_dimensions: (u32, u32),
+    pipeline: &'a KMeansParallelPipeline,
+    plus_plus_pipeline: &'a PlusPlusInitPipeline,
+    work_texture: &'a WorkTexture,
+}
+
+impl<'a> KMeansParallelModule<'a> {
+    pub(crate) fn new(
+        pipeline: &'a KMeansParallelPipeline,
+        plus_plus_pipeline: &'a PlusPlusInitPipeline,
+        image_dimensions: (u32, u32),
+        k: u32,
+        seed: u64,
+        work_texture: &'a WorkTexture,
+    ) -> Self {
+        Self {
+            k,
+            seed,
+            capacity: candidate_capacity(k),
+            image_dimensions,
+            pipeline,
+            plus_plus_pipeline,
+            work_texture,
+        }
+    }
+
+    /// Keeps fewer candidates, for the samples of a round to overflow.
+    #[cfg(test)]
+    fn capacity(mut self, capacity: u32) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Returns the candidates with their weight, sorted by color.
+    pub(crate) async fn compute(
+        &self,
+        device: &Device,
+        queue: &Queue,
+    ) -> Result<Vec<([f32; 3], f32)>> {
+        const WORKGROUP_SIZE: u32 = 256;
+        const N_SEQ: u32 = 16;
+
+        let (width, height) = self.image_dimensions;
+        let (dispatch_size, _) =
+            compute_work_group_count((width * height, 1), (WORKGROUP_SIZE * N_SEQ, 1));
+
+        let capacity = self.capacity;
+        // Aligned 16, see https://www.w3.org/TR/WGSL/#address-space-layout-constraints
+        let candidates_size = 16 + capacity as BufferAddress * 16;
+        let candidates_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("k-means|| candidates buffer"),
+            size: candidates_size,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let distances_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("k-means|| distances buffer"),
+            size: width as BufferAddress * height as BufferAddress * 4,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let partial_sums_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("k-means|| partial sums buffer"),
+            size: dispatch_size as BufferAddress * 4,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let weights_size = capacity as BufferAddress * 4;
+        let weights_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("k-means|| weights buffer"),
+            contents: bytemuck::cast_slice::<u32, u8>(&vec![0; capacity as usize]),
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+        });
+        let settings_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("k-means|| settings buffer"),
+            contents: bytemuck::cast_slice(&[
+                self.seed as u32,
+                (self.seed >> 32) as u32,
+                oversampling(self.k).to_bits(),
+                0,
+            ]),
+            usage: BufferUsages::UNIFORM,
+        });
+        // The limit, then the count of each bin.
+        let priorities_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("k-means|| priorities buffer"),
+            contents: bytemuck::cast_slice::<u32, u8>(&[0; 1 + PRIORITY_BINS]),
+            usage: BufferUsages::STORAGE,
+        });
+
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("k-means|| bind group"),
+            layout: &self.pipeline.bind_group_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: candidates_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(
+                        &self
+                            .work_texture
+                            .create_view(&TextureViewDescriptor::default()),
+                    ),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: distances_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: partial_sums_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: weights_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: settings_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: priorities_buffer.as_entire_binding(),
+                },
+            ],
+        });
+        let round_bind_groups = k_index_bind_groups(
+            device,
+            &self.pipeline.round_bind_group_layout,
+            KMEANS_PARALLEL_ROUNDS,
+        );
+
+        // The first candidate is sampled like the first centroid of the ++ init.
+        let sample_settings_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("k-means|| sample settings buffer"),
+            contents: bytemuck::cast_slice(&sample_settings(self.seed, true)),
+            usage: BufferUsages::UNIFORM,
+        });
+        let sample_bind_group = self.plus_plus_pipeline.opaque_sample_bind_group(
+            device,
+            &candidates_buffer,
+            &self
+                .work_texture
+                .create_view(&TextureViewDescriptor::default()),
+            &partial_sums_buffer,
+            &sample_settings_buffer,
+        );
+        let first_bind_group = k_index_bind_groups(
+            device,
+            &self.plus_plus_pipeline.k_index_bind_group_layout,
+            1,
+        );
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("k-means|| pass"),
+            });
+            compute_pass.set_bind_group(0, &sample_bind_group, &[]);
+            compute_pass.set_bind_group(1, &first_bind_group[0], &[]);
+            compute_pass.set_pipeline(&self.plus_plus_pipeline.sum_pipeline);
+            compute_pass.dispatch(dispatch_size, 1, 1);
+            compute_pass.set_pipeline(&self.plus_plus_pipeline.sample_pipeline);
+            compute_pass.dispatch(1, 1, 1);
+
+            compute_pass.set_bind_group(0, &bind_group, &[]);
+            compute_pass.set_bind_group(1, &round_bind_groups[0], &[]);
+            compute_pass.set_pipeline(&self.pipeline.initial_pipeline);
+            compute_pass.dispatch(1, 1, 1);
+            for round_bind_group in &round_bind_groups {
+                compute_pass.set_bind_group(1, round_bind_group, &[]);
+                compute_pass.set_pipeline(&self.pipeline.measure_pipeline);
+                compute_pass.dispatch(dispatch_size, 1, 1);
+                compute_pass.set_pipeline(&self.pipeline.advance_pipeline);
+                compute_pass.dispatch(1, 1, 1);
+                compute_pass.set_pipeline(&self.pipeline.rank_pipeline);
+                compute_pass.dispatch(dispatch_size, 1, 1);
+                compute_pass.set_pipeline(&self.pipeline.threshold_pipeline);
+                compute_pass.dispatch(1, 1, 1);
+                compute_pass.set_pipeline(&self.pipeline.oversample_pipeline);
+                compute_pass.dispatch(dispatch_size, 1, 1);
+            }
+            compute_pass.set_pipeline(&self.pipeline.weigh_pipeline);
+            compute_pass.dispatch(dispatch_size, 1, 1);
+        }
+
+        let candidates_staging_buffer = device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: candidates_size,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        encoder.copy_buffer_to_buffer(
+            &candidates_buffer,
+            0,
+            &candidates_staging_buffer,
+            0,
+            candidates_size,
+        );
+        let weights_staging_buffer = device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: weights_size,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        encoder.copy_buffer_to_buffer(&weights_buffer, 0, &weights_staging_buffer, 0, weights_size);
+        queue.submit(Some(encoder.finish()));
+
+        let candidates_slice = candidates_staging_buffer.slice(..);
+        let candidates_future = candidates_slice.map_async(MapMode::Read);
+        let weights_slice = weights_staging_buffer.slice(..);
+        let weights_future = weights_slice.map_async(MapMode::Read);
+
+        device.poll(wgpu::Maintain::Wait);
+
+        candidates_future.await?;
+        weights_future.await?;
+
+        let mut candidates = {
+            let candidates_data = candidates_slice.get_mapped_range();
+            let weights_data = weights_slice.get_mapped_range();
+            let count = bytemuck::cast_slice::<u8, u32>(&candidates_data[..4])[0].min(capacity);
+            bytemuck::cast_slice::<u8, f32>(&candidates_data[16..])
+                .chunks_exact(4)
+                .zip(bytemuck::cast_slice::<u8, u32>(&weights_data))
+                .take(count as usize)
+                .map(|(candidate, &weight)| {
+                    ([candidate[0], candidate[1], candidate[2]], weight as f32)
+                })
+                .collect::<Vec<_>>()
+        };
+        candidates_staging_buffer.unmap();
+        weights_staging_buffer.unmap();
+
+        debug!("k-means|| sampled {} candidates", candidates.len());
+        sort_candidates(&mut candidates);
+        Ok(candidates)
+    }
+}
+
+pub(crate) struct ColorHistogramPipeline {
+    pipeline: ComputePipeline,
+    bind_group_layout: BindGroupLayout,
+}
+
+impl ColorHistogramPipeline {
+    pub fn new(device: &Device) -> Self {
+        let shader_module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("Color histogram shader"),
+            source: ShaderSource::Wgsl(include_str!("shaders/color_histogram.wgsl").into()),
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Color histogram bind group layout"),
+            entries: &[
+                WorkTexture::texture_2d_layout(0),
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("Color histogram pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: Some("Color histogram pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &shader_module,
+            entry_point: "main",
+        });
+
+        Self {
+            pipeline,
+            bind_group_layout,
+        }
+    }
+}
+
+/// Counts the pixels in each bin of the color space, for the median cut init.
+pub(crate) struct ColorHistogramModule<'a> {
+    image_dimensions: (u32, u32),
+    bounds: &'a ([f32; 3], [f32; 3]),
+    pipeline: &'a ColorHistogramPipeline,
+    work_texture: &'a WorkTexture,
+}
+
+impl<'a> ColorHistogramModule<'a> {
+    pub(crate) fn new(
+        pipeline: &'a ColorHistogramPipeline,
+        image_dimensions: (u32, u32),
+        bounds: &'a ([f32; 3], [f32; 3]),
+        work_texture: &'a WorkTexture,
+    ) -> Self {
+        Self {
+            image_dimensions,
+            bounds,
+            pipeline,
+            work_texture,
+        }
+    }
+
+    pub(crate) async fn compute(&self, device: &Device, queue: &Queue) -> Result<Vec<u32>> {
+        let histogram_size = HISTOGRAM_BINS.pow(3) as BufferAddress * 4;
+        let histogram_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Color histogram buffer"),
+            contents: bytemuck::cast_slice::<u32, u8>(&vec![0; HISTOGRAM_BINS.pow(3)]),
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+        });
+        let (min, scale) = self.bounds;
+        let bounds_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Color histogram bounds buffer"),
+            contents: bytemuck::cast_slice(&[
+                min[0], min[1], min[2], 0.0, scale[0], scale[1], scale[2], 0.0,
+            ]),
+            usage: BufferUsages::UNIFORM,
+        });
+
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("Color histogram bind group"),
+            layout: &self.pipeline.bind_group_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(
+                        &self
+                            .work_texture
+                            .create_view(&TextureViewDescriptor::default()),
+                    ),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: histogram_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: bounds_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("Color histogram pass"),
+            });
+            let (x, y) = compute_work_group_count(self.image_dimensions, (16, 16));
+            compute_pass.set_pipeline(&self.pipeline.pipeline);
+            compute_pass.set_bind_group(0, &bind_group, &[]);
+            compute_pass.dispatch(x, y, 1);
+        }
+
+        let staging_buffer = device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: histogram_size,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        encoder.copy_buffer_to_buffer(&histogram_buffer, 0, &staging_buffer, 0, histogram_size);
+        queue.submit(Some(encoder.finish()));
+
+        let slice = staging_buffer.slice(..);
+        let future = slice.map_async(MapMode::Read);
+
+        device.poll(wgpu::Maintain::Wait);
+
+        future.await?;
+        let histogram = bytemuck::cast_slice::<u8, u32>(&slice.get_mapped_range()).to_vec();
+        staging_buffer.unmap();
+
+        Ok(histogram)
+    }
+}
+
+pub(crate) struct MixColorsPipeline {
+    dither_pipeline: ComputePipeline,
+    meld_pipeline: ComputePipeline,
+    bind_group_layout: BindGroupLayout,
+}
+
+impl MixColorsPipeline {
+    pub fn new(device: &Device) -> Self {
+        let shader_module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("Mix colors shader"),
+            source: ShaderSource::Wgsl(include_str!("shaders/mix_colors.wgsl").into()),
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Mix colors bind group layout"),
+            entries: &[
+                WorkTexture::texture_2d_layout(0),
+                WorkTexture::texture_storage_layout(1),
+                ColorIndexTexture::texture_2d_layout(2),
+                CentroidsBuffer::layout(3, true),
+                CentroidsBuffer::layout(4, true),
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("Mix colors pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let dither_pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: Some("Mix colors pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &shader_module,
+            entry_point: "main_dither",
+        });
+        let meld_pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: Some("Mix colors pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &shader_module,
+            entry_point: "main_meld",
+        });
+
+        Self {
+            dither_pipeline,
+            meld_pipeline,
+            bind_group_layout,
+        }
+    }
+}
+
+pub(crate) struct MixColorsModule<'a> {
+    pipeline: &'a ComputePipeline,
+    bind_group: BindGroup,
+    table: Option<CentroidTable<'a>>,
+    dispatch_size: (u32, u32),
+}
+
+impl<'a> MixColorsModule<'a> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &Device,
+        pipeline: &'a MixColorsPipeline,
+        table_pipeline: Option<&'a CentroidTablePipeline>,
+        image_dimensions: (u32, u32),
+        input_texture: &WorkTexture,
+        output_texture: &WorkTexture,
+        color_index_texture: &ColorIndexTexture,
+        centroids_buffer: &CentroidsBuffer,
+        mix_mode: &MixMode,
+    ) -> Self {
+        // Only melding looks for the two closest centroids of each pixel. Without a table, it
+        // goes over every centroid.
+        let table = table_pipeline
+            .filter(|_| {
+                matches!(mix_mode, MixMode::Meld) && centroids_buffer.k() <= CentroidTable::MAX_K
+            })
+            .map(|table_pipeline| CentroidTable::new(device, table_pipeline, centroids_buffer));
+        let no_table_buffer = table.is_none().then(|| {
+            device.create_buffer(&BufferDescriptor {
+                label: Some("No centroid table buffer"),
+                size: 4,
+                usage: BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            })
+        });
+        let table_buffer = table
+            .as_ref()
+            .map(|table| &table.table_buffer)
+            .or(no_table_buffer.as_ref())
+            .unwrap();
+
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("Mix colors bind group"),
+            layout: &pipeline.bind_group_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(
+                        &input_texture.create_view(&TextureViewDescriptor::default()),
+                    ),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(
+                        &output_texture.create_view(&TextureViewDescriptor::default()),
                     ),
                 },
                 BindGroupEntry {
@@ -1326,39 +3459,534 @@ impl MixColorsModule {
                     binding: 3,
                     resource: centroids_buffer.as_entire_binding(),
                 },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: table_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        let dispatch_size = compute_work_group_count(image_dimensions, (16, 16));
+
+        Self {
+            pipeline: match mix_mode {
+                MixMode::Dither => &pipeline.dither_pipeline,
+                MixMode::Meld => &pipeline.meld_pipeline,
+            },
+            bind_group,
+            table,
+            dispatch_size,
+        }
+    }
+}
+
+impl Module for MixColorsModule<'_> {
+    fn dispatch<'a>(&'a self, compute_pass: &mut ComputePass<'a>) {
+        if let Some(table) = &self.table {
+            table.dispatch(compute_pass);
+        }
+        compute_pass.set_pipeline(self.pipeline);
+        compute_pass.set_bind_group(0, &self.bind_group, &[]);
+        compute_pass.dispatch(self.dispatch_size.0, self.dispatch_size.1, 1);
+    }
+}
+
+pub(crate) struct ClusterStatsPipeline {
+    pipeline: ComputePipeline,
+    rgb_pipeline: ComputePipeline,
+    bind_group_layout: BindGroupLayout,
+}
+
+impl ClusterStatsPipeline {
+    pub fn new(device: &Device) -> Self {
+        let shader_module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("Cluster stats shader"),
+            source: ShaderSource::Wgsl(include_str!("shaders/cluster_stats.wgsl").into()),
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Cluster stats bind group layout"),
+            entries: &[
+                WorkTexture::texture_2d_layout(0),
+                CentroidsBuffer::layout(1, true),
+                ColorIndexTexture::texture_2d_layout(2),
+                CentroidsBuffer::layout(3, false),
+                CentroidsBuffer::layout(4, false),
+                CentroidsBuffer::layout(5, false),
             ],
         });
 
         let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
-            label: Some("Mix colors pipeline layout"),
+            label: Some("Cluster stats pipeline layout"),
             bind_group_layouts: &[&bind_group_layout],
             push_constant_ranges: &[],
         });
 
         let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
-            label: Some("Mix colors pipeline"),
+            label: Some("Cluster stats pipeline"),
             layout: Some(&pipeline_layout),
             module: &shader_module,
-            entry_point: match mix_mode {
-                MixMode::Dither => "main_dither",
-                MixMode::Meld => "main_meld",
-            },
+            entry_point: "main",
+        });
+        let rgb_pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: Some("Cluster stats rgb pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &shader_module,
+            entry_point: "main_rgb",
         });
 
+        Self {
+            pipeline,
+            rgb_pipeline,
+            bind_group_layout,
+        }
+    }
+}
+
+/// How far the pixels are from their centroid.
+pub(crate) struct ClusterErrors {
+    /// Sum of the squared distances, in the work color space.
+    pub inertia: f64,
+    /// Sum of the distances, as ΔE whatever the work color space.
+    pub distance: f64,
+    /// Pixels belonging to a cluster.
+    pub pixels: u64,
+}
+
+/// Counts the pixels of each cluster, and measures the inertia, aka the sum of the squared
+/// distances between each pixel and its centroid, along with the sum of their ΔE.
+pub(crate) struct ClusterStatsModule<'a> {
+    pipeline: &'a ComputePipeline,
+    bind_group: BindGroup,
+    dispatch_size: (u32, u32),
+    counts_buffer: Buffer,
+    counts_size: u64,
+    inertia_buffer: Buffer,
+    inertia_size: u64,
+    errors_buffer: Buffer,
+}
+
+impl<'a> ClusterStatsModule<'a> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &Device,
+        pipeline: &'a ClusterStatsPipeline,
+        image_dimensions: (u32, u32),
+        k: u32,
+        color_space: &ColorSpace,
+        work_texture: &WorkTexture,
+        centroids_buffer: &CentroidsBuffer,
+        color_index_texture: &ColorIndexTexture,
+    ) -> Self {
         let dispatch_size = compute_work_group_count(image_dimensions, (16, 16));
 
+        let counts_size = k as u64 * 4;
+        let counts_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Cluster counts buffer"),
+            size: counts_size,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let inertia_size = dispatch_size.0 as u64 * dispatch_size.1 as u64 * 4;
+        let inertia_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Inertia buffer"),
+            size: inertia_size,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let errors_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Errors buffer"),
+            size: inertia_size,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("Cluster stats bind group"),
+            layout: &pipeline.bind_group_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(
+                        &work_texture.create_view(&TextureViewDescriptor::default()),
+                    ),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: centroids_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::TextureView(
+                        &color_index_texture.create_view(&TextureViewDescriptor::default()),
+                    ),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: counts_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: inertia_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: errors_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
         Self {
-            pipeline,
+            pipeline: match color_space {
+                ColorSpace::Lab => &pipeline.pipeline,
+                ColorSpace::Rgb => &pipeline.rgb_pipeline,
+            },
             bind_group,
             dispatch_size,
+            counts_buffer,
+            counts_size,
+            inertia_buffer,
+            inertia_size,
+            errors_buffer,
         }
     }
+
+    /// Waits for the stats of the current clusters, and returns their inertia.
+    pub async fn inertia(&self, device: &Device, queue: &Queue) -> Result<f64> {
+        Ok(self.errors(device, queue).await?.inertia)
+    }
+
+    /// Waits for the stats of the current clusters, and returns how far the pixels are from
+    /// their centroid.
+    pub async fn errors(&self, device: &Device, queue: &Queue) -> Result<ClusterErrors> {
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("Cluster errors pass"),
+            });
+            self.dispatch(&mut compute_pass);
+        }
+        let (counts_staging_buffer, inertia_staging_buffer) =
+            self.staging_buffers(device, &mut encoder);
+        let errors_staging_buffer = device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: self.inertia_size,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        encoder.copy_buffer_to_buffer(
+            &self.errors_buffer,
+            0,
+            &errors_staging_buffer,
+            0,
+            self.inertia_size,
+        );
+        queue.submit(Some(encoder.finish()));
+
+        let counts_slice = counts_staging_buffer.slice(..);
+        let counts_future = counts_slice.map_async(MapMode::Read);
+        let inertia_slice = inertia_staging_buffer.slice(..);
+        let inertia_future = inertia_slice.map_async(MapMode::Read);
+        let errors_slice = errors_staging_buffer.slice(..);
+        let errors_future = errors_slice.map_async(MapMode::Read);
+
+        device.poll(wgpu::Maintain::Wait);
+
+        counts_future.await?;
+        inertia_future.await?;
+        errors_future.await?;
+        let sum = |slice: &BufferSlice| {
+            bytemuck::cast_slice::<u8, f32>(&slice.get_mapped_range())
+                .iter()
+                .map(|&partial_sum| partial_sum as f64)
+                .sum()
+        };
+        let errors = ClusterErrors {
+            inertia: sum(&inertia_slice),
+            distance: sum(&errors_slice),
+            pixels: bytemuck::cast_slice::<u8, u32>(&counts_slice.get_mapped_range())
+                .iter()
+                .map(|&count| count as u64)
+                .sum(),
+        };
+
+        Ok(errors)
+    }
+
+    /// Copies the pixel counts and the inertia partial sums to mappable buffers.
+    pub fn staging_buffers(
+        &self,
+        device: &Device,
+        encoder: &mut CommandEncoder,
+    ) -> (Buffer, Buffer) {
+        let counts_staging_buffer = device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: self.counts_size,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        encoder.copy_buffer_to_buffer(
+            &self.counts_buffer,
+            0,
+            &counts_staging_buffer,
+            0,
+            self.counts_size,
+        );
+
+        let inertia_staging_buffer = device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: self.inertia_size,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        encoder.copy_buffer_to_buffer(
+            &self.inertia_buffer,
+            0,
+            &inertia_staging_buffer,
+            0,
+            self.inertia_size,
+        );
+
+        (counts_staging_buffer, inertia_staging_buffer)
+    }
 }
 
-impl Module for MixColorsModule {
+impl Module for ClusterStatsModule<'_> {
     fn dispatch<'a>(&'a self, compute_pass: &mut ComputePass<'a>) {
-        compute_pass.set_pipeline(&self.pipeline);
+        compute_pass.set_pipeline(self.pipeline);
         compute_pass.set_bind_group(0, &self.bind_group, &[]);
         compute_pass.dispatch(self.dispatch_size.0, self.dispatch_size.1, 1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{gpu_context, quadrants_image},
+        ContextBackend, GpuContext, InputTexture, OutputBuffer, OutputTexture,
+    };
+
+    /// Random values between 0 and 100, the same on every run.
+    fn random_values(seed: u32) -> impl Iterator<Item = f32> {
+        let mut state = seed;
+        std::iter::repeat_with(move || {
+            // xorshift32.
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state % 10_000) as f32 / 100.0
+        })
+    }
+
+    /// Random colors in the range of the Lab color space.
+    fn random_colors(seed: u32, count: usize) -> Vec<[f32; 3]> {
+        let mut values = random_values(seed);
+        (0..count)
+            .map(|_| {
+                let mut next = || values.next().unwrap();
+                [next(), next() - 50.0, next() - 50.0]
+            })
+            .collect()
+    }
+
+    /// Waits for the texels copied to the buffer, and reads them back.
+    fn read(gpu: &GpuContext, output_buffer: &OutputBuffer) -> Vec<u8> {
+        let slice = output_buffer.slice(..);
+        let future = slice.map_async(MapMode::Read);
+        gpu.device.poll(wgpu::Maintain::Wait);
+        pollster::block_on(future).unwrap();
+        let data = output_buffer.unpad(&slice.get_mapped_range());
+        data
+    }
+
+    #[test]
+    #[ignore = "needs a gpu adapter, run with --ignored"]
+    fn test_bounded_find_matches_exhaustive() {
+        let context = gpu_context();
+        let ContextBackend::Gpu(gpu) = &context.backend else {
+            unreachable!()
+        };
+        let device = &gpu.device;
+        // Not a multiple of the workgroups.
+        let dimensions = (61, 37);
+        let k = 16;
+        assert!(k >= FindCentroidModule::BOUNDED_MIN_K);
+
+        let texels = random_colors(7, (dimensions.0 * dimensions.1) as usize)
+            .into_iter()
+            .map(|[a, b, c]| [a, b, c, 1.0])
+            .collect::<Vec<_>>();
+        let work_texture = WorkTexture::new(device, dimensions);
+        work_texture.write(&gpu.queue, dimensions, &texels);
+        let centroids_buffer = CentroidsBuffer::empty_centroids(k, device);
+        let bounded_indices = ColorIndexTexture::new(device, dimensions);
+        let exhaustive_indices = ColorIndexTexture::new(device, dimensions);
+        let bounded_module = FindCentroidModule::bounded(
+            device,
+            &gpu.pipelines.find_centroid,
+            &gpu.pipelines.centroid_table,
+            dimensions,
+            &work_texture,
+            &centroids_buffer,
+            &bounded_indices,
+        );
+        let exhaustive_module = FindCentroidModule::new(
+            device,
+            &gpu.pipelines.find_centroid,
+            dimensions,
+            &work_texture,
+            &centroids_buffer,
+            &exhaustive_indices,
+        );
+
+        let mut centroids = random_colors(11, k as usize);
+        for round in 0..8 {
+            // Small moves like the iterations, a large one, and two equal centroids for ties.
+            let scale = if round == 4 { 20.0 } else { 0.5 };
+            let components = centroids.iter_mut().flatten();
+            for (component, shift) in components.zip(random_values(round + 1)) {
+                *component += (shift / 50.0 - 1.0) * scale;
+            }
+            centroids[15] = centroids[3];
+            centroids_buffer.write(&gpu.queue, &centroids);
+
+            let mut encoder =
+                device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+            {
+                let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("Find centroid test pass"),
+                });
+                bounded_module.dispatch(&mut compute_pass);
+                exhaustive_module.dispatch(&mut compute_pass);
+            }
+            let bounded_buffer = bounded_indices.output_buffer(device, &mut encoder, dimensions);
+            let exhaustive_buffer =
+                exhaustive_indices.output_buffer(device, &mut encoder, dimensions);
+            gpu.queue.submit(Some(encoder.finish()));
+
+            let bounded = read(gpu, &bounded_buffer);
+            let exhaustive = read(gpu, &exhaustive_buffer);
+            assert!(bounded == exhaustive, "round {round}");
+        }
+        gpu.check_device().unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a gpu adapter, run with --ignored"]
+    fn test_bounded_meld_matches_exhaustive() {
+        let context = gpu_context();
+        let ContextBackend::Gpu(gpu) = &context.backend else {
+            unreachable!()
+        };
+        let device = &gpu.device;
+        let image = quadrants_image(80);
+        let dimensions = image.dimensions;
+        let color_space = ColorSpace::Lab;
+
+        let colors = random_colors(3, 24)
+            .into_iter()
+            .map(|color| color_space.to_rgba(&color))
+            .collect::<Vec<_>>();
+        let centroids_buffer = CentroidsBuffer::fixed_centroids(&colors, &color_space, device);
+        let input_texture = InputTexture::new(device, &gpu.queue, &image);
+        let work_texture = WorkTexture::new(device, dimensions);
+        let color_index_texture = ColorIndexTexture::new(device, dimensions);
+        let converter_module = ColorConverterModule::new(
+            device,
+            gpu.pipelines.converter(&color_space),
+            dimensions,
+            &input_texture.view(),
+            &work_texture,
+            0,
+        );
+        let find_centroid_module = FindCentroidModule::new(
+            device,
+            &gpu.pipelines.find_centroid,
+            dimensions,
+            &work_texture,
+            &centroids_buffer,
+            &color_index_texture,
+        );
+
+        let mut melded = vec![];
+        for table_pipeline in [Some(&gpu.pipelines.centroid_table), None] {
+            let mixed_texture = WorkTexture::new(device, dimensions);
+            let output_texture = OutputTexture::new(device, dimensions);
+            let mix_colors_module = MixColorsModule::new(
+                device,
+                &gpu.pipelines.mix_colors,
+                table_pipeline,
+                dimensions,
+                &work_texture,
+                &mixed_texture,
+                &color_index_texture,
+                &centroids_buffer,
+                &MixMode::Meld,
+            );
+            let reverter_module = ColorReverterModule::new(
+                device,
+                gpu.pipelines.reverter(&color_space),
+                dimensions,
+                &mixed_texture,
+                &output_texture.view(),
+                &input_texture.view(),
+            );
+
+            let mut encoder =
+                device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+            {
+                let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("Meld test pass"),
+                });
+                converter_module.dispatch(&mut compute_pass);
+                find_centroid_module.dispatch(&mut compute_pass);
+                mix_colors_module.dispatch(&mut compute_pass);
+                reverter_module.dispatch(&mut compute_pass);
+            }
+            let output_buffer = output_texture.output_buffer(device, &mut encoder);
+            gpu.queue.submit(Some(encoder.finish()));
+            melded.push(read(gpu, &output_buffer));
+        }
+        assert!(melded[0] == melded[1]);
+        gpu.check_device().unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a gpu adapter, run with --ignored"]
+    fn test_kmeans_parallel_overflow_is_deterministic() {
+        let context = gpu_context();
+        let ContextBackend::Gpu(gpu) = &context.backend else {
+            unreachable!()
+        };
+        let device = &gpu.device;
+        let dimensions = (61, 37);
+        let k = 4;
+        // Fewer than the 8 samples expected each round.
+        let capacity = 5;
+
+        let texels = random_colors(11, (dimensions.0 * dimensions.1) as usize)
+            .into_iter()
+            .map(|[a, b, c]| [a, b, c, 1.0])
+            .collect::<Vec<_>>();
+        let work_texture = WorkTexture::new(device, dimensions);
+        work_texture.write(&gpu.queue, dimensions, &texels);
+
+        let candidates = || {
+            let module = KMeansParallelModule::new(
+                &gpu.pipelines.kmeans_parallel,
+                &gpu.pipelines.plus_plus_init,
+                dimensions,
+                k,
+                3,
+                &work_texture,
+            )
+            .capacity(capacity);
+            pollster::block_on(module.compute(device, &gpu.queue)).unwrap()
+        };
+        let first = candidates();
+        assert!(first.len() > 1 && first.len() <= capacity as usize);
+        assert_eq!(candidates(), first);
+        gpu.check_device().unwrap();
+    }
+}
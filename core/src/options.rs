@@ -0,0 +1,293 @@
+use crate::{
+    progress::ProgressCallback, CancellationToken, ColorSpace, Error, Phase, Progress, Result,
+};
+
+/// How the initial centroids are picked before iterating.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Init {
+    /// k-means++ seeding: each new centroid is a random pixel, picked with a probability
+    /// proportional to its squared distance to the closest centroid already picked.
+    #[default]
+    PlusPlus,
+    /// Greedy farthest-point seeding: each new centroid is the pixel the furthest away from the
+    /// centroids already picked. Only the first centroid is random, but outliers are favored.
+    FarthestPoint,
+    /// Each centroid is a pixel picked uniformly at random. The fastest, but the least reliable.
+    Random,
+    /// k-means||: a few rounds each sampling about 2k pixels at once, with a probability
+    /// proportional to their squared distance to the closest sample, then reduced to k centroids.
+    /// Close to k-means++, in a handful of passes whatever k.
+    KMeansParallel,
+    /// Median cut on a histogram of the colors: the color space is split in k boxes holding as
+    /// many pixels each, and each box gives its mean color. Deterministic.
+    MedianCut,
+    /// Exactly k starting colors, for example the palette of the previous frame to warm-start
+    /// from. The alpha channel is ignored.
+    Colors(Vec<[u8; 4]>),
+}
+
+/// How the gpu sums the pixels of each cluster, at each iteration.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Reduction {
+    /// A single pass from 16 centroids, one pass per centroid below.
+    #[default]
+    Auto,
+    /// One pass over the image per centroid, summing its pixels with a prefix sum.
+    PerCentroid,
+    /// A single pass over the image for all the centroids: each workgroup sums its pixels in k
+    /// bins, merged in fixed point. Much faster with many centroids, and always deterministic.
+    /// Up to 256 centroids, above which it falls back to one pass per centroid.
+    SinglePass,
+}
+
+/// How the centroids move at each iteration.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Algorithm {
+    /// Lloyd iterations: each centroid moves to the mean of all its pixels.
+    #[default]
+    Lloyd,
+    /// Mini-batch k-means: each centroid moves toward the mean of its pixels among a random
+    /// batch of this size, by a rate decreasing with the pixels it has seen so far. The cost of
+    /// an iteration doesn't depend on the size of the image, at the price of a less precise
+    /// palette.
+    MiniBatch(u32),
+}
+
+/// Which pixels are clustered. On the gpu, images smaller than a texture are estimated from
+/// every pixel, larger ones from a copy downsampled to fit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Estimation {
+    /// Every pixel.
+    #[default]
+    Full,
+    /// A copy of the image scaled down by this factor in both directions, each pixel being the
+    /// mean of the ones it covers.
+    Downscale(u32),
+    /// About this many pixels, each picked at random in its own cell of a grid over the image.
+    Sample(u32),
+}
+
+/// Tuning knobs for the clustering, to trade quality against speed.
+///
+/// ```
+/// use k_means_gpu::{Algorithm, Estimation, Init, KMeansOptions, Reduction};
+///
+/// let options = KMeansOptions::new()
+///     .max_iterations(32)
+///     .convergence(0.5)
+///     .convergence_check_interval(4)
+///     .init(Init::PlusPlus)
+///     .n_init(3)
+///     .alpha_threshold(128)
+///     .seed(7)
+///     .deterministic(true)
+///     .reduction(Reduction::SinglePass)
+///     .unique_colors(true)
+///     .estimation(Estimation::Sample(100_000))
+///     .algorithm(Algorithm::MiniBatch(4096));
+/// ```
+#[derive(Debug, Clone)]
+pub struct KMeansOptions {
+    pub(crate) max_iterations: u32,
+    pub(crate) convergence: Option<f32>,
+    pub(crate) convergence_check_interval: u32,
+    pub(crate) init: Init,
+    pub(crate) n_init: u32,
+    pub(crate) alpha_threshold: u8,
+    pub(crate) seed: u64,
+    pub(crate) deterministic: bool,
+    pub(crate) reduction: Reduction,
+    pub(crate) unique_colors: bool,
+    pub(crate) estimation: Estimation,
+    pub(crate) algorithm: Algorithm,
+    pub(crate) progress: Option<ProgressCallback>,
+    pub(crate) cancellation_token: Option<CancellationToken>,
+}
+
+impl KMeansOptions {
+    pub fn new() -> Self {
+        Self {
+            max_iterations: 128,
+            convergence: None,
+            convergence_check_interval: 8,
+            init: Init::default(),
+            n_init: 1,
+            alpha_threshold: 0,
+            seed: 0,
+            deterministic: false,
+            reduction: Reduction::default(),
+            unique_colors: false,
+            estimation: Estimation::default(),
+            algorithm: Algorithm::default(),
+            progress: None,
+            cancellation_token: None,
+        }
+    }
+
+    /// Maximum number of iterations, in case the centroids never converge. Defaults to 128.
+    pub fn max_iterations(mut self, max_iterations: u32) -> Self {
+        self.max_iterations = max_iterations.max(1);
+        self
+    }
+
+    /// A centroid is considered converged when it moves by less than this distance between two
+    /// iterations. Defaults to [`ColorSpace::convergence`].
+    pub fn convergence(mut self, convergence: f32) -> Self {
+        self.convergence = Some(convergence);
+        self
+    }
+
+    /// Number of iterations the gpu runs between two waits for it, when the image fits in a
+    /// texture. Iterations following the convergence are skipped by the gpu itself, so larger
+    /// batches only cost their recording, but the progress is reported and the cancellation
+    /// checked once per batch. Defaults to 8.
+    pub fn convergence_check_interval(mut self, interval: u32) -> Self {
+        self.convergence_check_interval = interval.max(1);
+        self
+    }
+
+    pub fn init(mut self, init: Init) -> Self {
+        self.init = init;
+        self
+    }
+
+    /// Number of runs, each initialized with its own seed, the one of lowest inertia being kept.
+    /// The image is converted once for all runs. Deterministic inits, [`Init::MedianCut`] and
+    /// [`Init::Colors`], always run once, as do images too large for a single texture.
+    /// The timings report the last run, earlier runs being counted in the init. Defaults to 1.
+    pub fn n_init(mut self, n_init: u32) -> Self {
+        self.n_init = n_init.max(1);
+        self
+    }
+
+    /// Pixels with an alpha below this value are left out when picking the centroids, so that
+    /// hidden colors don't pull the palette. Their alpha is preserved in the output either way.
+    /// Defaults to 0, which keeps every pixel.
+    pub fn alpha_threshold(mut self, alpha_threshold: u8) -> Self {
+        self.alpha_threshold = alpha_threshold;
+        self
+    }
+
+    /// Drives every random choice of the initialization: the same seed on the same image always
+    /// gives the same palette, and other seeds give other runs to compare. Defaults to 0.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Sums the pixels of each cluster in fixed point instead of floats, so the sums don't depend
+    /// on the order the gpu runs its workgroups in, nor lose precision on large images: the same
+    /// image and options give bit-identical palettes on every run. Slightly slower. The cpu is
+    /// always deterministic. Defaults to false.
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// How the gpu sums the pixels of each cluster. Only a matter of speed: the cpu ignores it.
+    /// Defaults to [`Reduction::Auto`].
+    pub fn reduction(mut self, reduction: Reduction) -> Self {
+        self.reduction = reduction;
+        self
+    }
+
+    /// Runs the iterations over the distinct colors of the image, each weighted by its count of
+    /// pixels, instead of every pixel. Much faster on screenshots, pixel art and flat graphics.
+    /// The colors are counted on the cpu, so the image is clustered pixel per pixel when it has
+    /// more than 4 megapixels, as well as when more than half of its pixels are distinct, or
+    /// when it is larger than a texture. Only a matter of speed: the cpu ignores it, and always
+    /// clusters every pixel. Defaults to false.
+    pub fn unique_colors(mut self, unique_colors: bool) -> Self {
+        self.unique_colors = unique_colors;
+        self
+    }
+
+    /// Runs the clustering over a smaller copy of the image, much faster on large photos. Images
+    /// are then labelled in a single pass at full resolution, and palettes skip it. Unique
+    /// colors are not counted on the copy. Both backends estimate the same way. Defaults to
+    /// [`Estimation::Full`].
+    pub fn estimation(mut self, estimation: Estimation) -> Self {
+        self.estimation = estimation;
+        self
+    }
+
+    /// How the gpu moves the centroids. Mini-batches run over a downsampled copy of images
+    /// larger than a texture, and never re-seed empty clusters. Both backends run either.
+    /// Defaults to [`Algorithm::Lloyd`].
+    pub fn algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Called at the start of each phase, and before each iteration. On the gpu, the iterations
+    /// run in batches of [`KMeansOptions::convergence_check_interval`], and are reported before
+    /// each batch.
+    pub fn on_progress(mut self, callback: impl Fn(&Progress) + Send + Sync + 'static) -> Self {
+        self.progress = Some(ProgressCallback::new(callback));
+        self
+    }
+
+    /// Checked before each phase and iteration, or batch of iterations on the gpu, to abort the
+    /// clustering.
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancellation_token = Some(token);
+        self
+    }
+
+    /// How many times to run the clustering, see [`KMeansOptions::n_init`].
+    pub(crate) fn runs(&self) -> u32 {
+        match self.init {
+            Init::MedianCut | Init::Colors(_) => 1,
+            _ => self.n_init,
+        }
+    }
+
+    /// Checks that the options can cluster in k colors.
+    pub(crate) fn validate(&self, k: u32) -> Result<()> {
+        if let Algorithm::MiniBatch(0) = self.algorithm {
+            return Err(Error::EmptyMiniBatch);
+        }
+        match &self.init {
+            Init::Colors(colors) if colors.len() != k as usize => Err(Error::InitColors {
+                k,
+                colors: colors.len(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reports the progress, after making sure the clustering was not cancelled.
+    pub(crate) fn report_progress(
+        &self,
+        phase: Phase,
+        iteration: u32,
+        converged: u32,
+    ) -> Result<()> {
+        if let Some(token) = &self.cancellation_token {
+            if token.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+        }
+
+        if let Some(callback) = &self.progress {
+            callback.call(&Progress {
+                phase,
+                iteration,
+                converged,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn convergence_for(&self, color_space: &ColorSpace) -> f32 {
+        self.convergence
+            .unwrap_or_else(|| color_space.convergence())
+    }
+}
+
+impl Default for KMeansOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
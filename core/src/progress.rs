@@ -0,0 +1,77 @@
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// The steps of a clustering, in the order they run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// The image is converted to the working color space.
+    Convert,
+    /// The initial centroids are picked.
+    Init,
+    /// One k-means iteration, or a batch of them on the gpu, is about to run.
+    Iterate,
+    /// The iterations are over, the results are being produced.
+    Finalize,
+}
+
+/// What is passed to the callback of [`crate::KMeansOptions::on_progress`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub phase: Phase,
+    /// The iteration about to run, or the number of iterations run once finalizing.
+    pub iteration: u32,
+    /// How many centroids had converged at the last convergence check.
+    pub converged: u32,
+}
+
+/// Stops a clustering from another thread: the clustering then fails with
+/// [`crate::Error::Cancelled`] at the next iteration, or the next batch of iterations on the gpu.
+///
+/// ```
+/// use k_means_gpu::{CancellationToken, KMeansOptions};
+///
+/// let token = CancellationToken::new();
+/// let options = KMeansOptions::new().cancellation_token(token.clone());
+/// token.cancel();
+/// assert!(token.is_cancelled());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ProgressCallback(Arc<dyn Fn(&Progress) + Send + Sync>);
+
+impl ProgressCallback {
+    pub fn new(callback: impl Fn(&Progress) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(callback))
+    }
+
+    pub fn call(&self, progress: &Progress) {
+        (self.0)(progress)
+    }
+}
+
+impl Debug for ProgressCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
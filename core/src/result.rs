@@ -0,0 +1,84 @@
+use crate::{Image, Timings};
+
+/// Everything the k-means clustering found out about an image.
+#[derive(Debug, Clone)]
+pub struct KMeansResult {
+    pub(crate) dimensions: (u32, u32),
+    pub(crate) centroids: Vec<[f32; 3]>,
+    pub(crate) colors: Vec<[u8; 4]>,
+    pub(crate) counts: Vec<u32>,
+    pub(crate) labels: Vec<u32>,
+    pub(crate) inertia: f64,
+    pub(crate) iterations: u32,
+    pub(crate) converged: bool,
+    pub(crate) reseeds: u32,
+    pub(crate) timings: Option<Timings>,
+}
+
+impl KMeansResult {
+    pub fn k(&self) -> u32 {
+        self.centroids.len() as u32
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.dimensions
+    }
+
+    /// The centroids, expressed in the color space used for the clustering.
+    pub fn centroids(&self) -> &[[f32; 3]] {
+        &self.centroids
+    }
+
+    /// The centroids converted to sRGB, in the same order as [`KMeansResult::centroids`].
+    pub fn colors(&self) -> &[[u8; 4]] {
+        &self.colors
+    }
+
+    /// Number of pixels belonging to each cluster, leaving out pixels below the alpha threshold.
+    pub fn counts(&self) -> &[u32] {
+        &self.counts
+    }
+
+    /// Index of the cluster of each pixel, row by row.
+    pub fn labels(&self) -> &[u32] {
+        &self.labels
+    }
+
+    /// Sum of the squared distances between each pixel and its centroid.
+    pub fn inertia(&self) -> f64 {
+        self.inertia
+    }
+
+    /// Number of iterations that ran before stopping.
+    pub fn iterations(&self) -> u32 {
+        self.iterations
+    }
+
+    /// Whether all the centroids converged before reaching the maximum number of iterations.
+    pub fn converged(&self) -> bool {
+        self.converged
+    }
+
+    /// Number of times an empty cluster was moved to a pixel far from its centroid. Every empty
+    /// cluster moves at once, each to the farthest pixel of another cluster.
+    pub fn reseeds(&self) -> u32 {
+        self.reseeds
+    }
+
+    /// Time spent in each phase, unless the gpu doesn't support timestamp queries or the image
+    /// was too large and processed in tiles.
+    pub fn timings(&self) -> Option<&Timings> {
+        self.timings.as_ref()
+    }
+
+    /// Creates the image with each pixel replaced by the color of its cluster.
+    pub fn image(&self) -> Image {
+        let rgba = self
+            .labels
+            .iter()
+            .map(|&label| self.colors[label as usize])
+            .collect();
+
+        Image::new(self.dimensions, rgba)
+    }
+}
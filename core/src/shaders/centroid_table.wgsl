@@ -0,0 +1,53 @@
+struct Centroids {
+    count: u32;
+    // Aligned 16. See https://www.w3.org/TR/WGSL/#address-space-layout-constraints
+    data: array<vec4<f32>>;
+};
+
+struct Distances {
+    data: array<f32>;
+};
+
+[[group(0), binding(0)]] var<storage, read> centroids: Centroids;
+// The distance between centroids i and j at i * count + j.
+[[group(0), binding(1)]] var<storage, read_write> table: Distances;
+// Half the distance between each centroid and its closest other centroid: a pixel closer than
+// that to a centroid has no closer centroid.
+[[group(0), binding(2)]] var<storage, read_write> half_min: Distances;
+
+let workgroup_size: u32 = 256u;
+
+let max_f32: f32 = 100000.0;
+
+// Twice the rounding error of a distance between colors, so that bounds derived from the table
+// stay on the safe side of the distances the searches compute.
+fn slack(value: f32) -> f32 {
+    return 0.0001 + abs(value) * 0.00001;
+}
+
+[[stage(compute), workgroup_size(16, 16)]]
+fn main([[builtin(global_invocation_id)]] global_id : vec3<u32>) {
+    let count = centroids.count;
+    if (global_id.x >= count || global_id.y >= count) {
+        return;
+    }
+
+    table.data[global_id.y * count + global_id.x] = distance(centroids.data[global_id.y].rgb, centroids.data[global_id.x].rgb);
+}
+
+[[stage(compute), workgroup_size(256)]]
+fn main_half_min([[builtin(global_invocation_id)]] global_id : vec3<u32>) {
+    let count = centroids.count;
+    let k = global_id.x;
+    if (k >= count) {
+        return;
+    }
+
+    var closest = max_f32;
+    for (var i: u32 = 0u; i < count; i = i + 1u) {
+        if (i != k) {
+            closest = min(closest, table.data[k * count + i]);
+        }
+    }
+    half_min.data[k] = max(0.5 * (closest - slack(closest)), 0.0);
+}
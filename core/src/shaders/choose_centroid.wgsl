@@ -22,6 +22,51 @@ struct ColorAggregator {
     count: u32;
 };
 
+struct Sums {
+    data: array<ColorAggregator>;
+};
+
+// Sums of each centroid in 64 bits fixed point, two words per value, low word first: red,
+// green, blue, then the count and a padding word.
+struct FixedSums {
+    data: array<atomic<u32>>;
+};
+
+// The pixel of a cluster the farthest from its centroid, where an empty cluster can move.
+struct Farthest {
+    // The largest distance between a pixel of the cluster and its centroid so far, as bits.
+    distance: atomic<u32>;
+    // The first pixel of the current texture at that distance, or max_int.
+    pixel: atomic<u32>;
+    // 1 when the cluster had no pixel at the last pick.
+    empty: u32;
+    // Aligned 16.
+    color: vec4<f32>;
+};
+
+struct Reseed {
+    // The empty clusters found so far by the current pick.
+    empties: atomic<u32>;
+    // Aligned 16.
+    data: array<Farthest>;
+};
+
+// The farthest distance and the cluster it belongs to.
+struct Candidate {
+    distance: u32;
+    cluster: u32;
+};
+
+// A pixel's cluster and the distance to its centroid.
+struct Assignment {
+    cluster: u32;
+    distance: f32;
+};
+
+struct Dispatches {
+    data: array<u32>;
+};
+
 [[group(0), binding(0)]] var<storage, read_write> centroids: Centroids;
 [[group(0), binding(1)]] var color_indices: texture_2d<u32>;
 [[group(0), binding(2)]] var pixels: texture_2d<f32>;
@@ -30,18 +75,46 @@ struct ColorAggregator {
 [[group(1), binding(1)]] var<storage, read_write> flag_buffer: AtomicBuffer;
 [[group(1), binding(2)]] var<storage, read_write> convergence: AtomicBuffer;
 [[group(1), binding(3)]] var<uniform> settings: Settings;
+// Sums of each centroid over all the tiles processed so far, for images larger than a texture.
+[[group(1), binding(4)]] var<storage, read_write> tile_sums: Sums;
+[[group(1), binding(5)]] var<storage, read_write> reseed_state: Reseed;
+[[group(1), binding(6)]] var<storage, read_write> fixed_sums: FixedSums;
 [[group(2), binding(0)]] var<uniform> k_index: KIndex;
+// The arguments of the indirect dispatches of an iteration, followed by their full sizes. See
+// `gate`, the only one binding them.
+[[group(3), binding(0)]] var<storage, read_write> dispatches: Dispatches;
 
 let workgroup_size: u32 = 256u;
 
 var<workgroup> scratch: array<ColorAggregator, workgroup_size>;
 var<workgroup> shared_flag: u32;
 var<workgroup> part_id: u32;
+var<workgroup> fixed_scratch: array<atomic<u32>, 8>;
+var<workgroup> candidates: array<Candidate, workgroup_size>;
 
 let FLAG_NOT_READY = 0u;
 let FLAG_AGGREGATE_READY = 1u;
 let FLAG_PREFIX_READY = 2u;
 
+let max_int: u32 = 4294967295u;
+
+// Components are rounded to 1/65536, then multiplied by the weight of the pixel in 64 bits.
+let FIXED_SCALE: f32 = 65536.0;
+
+// Seven words for each of the 256 centroids at most summed in a single pass, limited by the
+// memory of a workgroup.
+let BIN_WORDS: u32 = 1792u;
+
+// Five dispatches of three words: over the image in 16x16 workgroups, over the image in
+// sequences of pixels, a single workgroup, then the same first and last ones for the search of
+// the farthest pixels and the re-seed, which only run when a cluster is empty.
+let DISPATCH_WORDS: u32 = 15u;
+let ITERATION_WORDS: u32 = 9u;
+
+// The fixed point sums of every centroid over the pixels of the workgroup, laid out like
+// FixedSums without the padding word.
+var<workgroup> bins: array<atomic<u32>, BIN_WORDS>;
+
 fn coords(global_x: u32, dimensions: vec2<i32>) -> vec2<i32> {
     return vec2<i32>(vec2<u32>(global_x % u32(dimensions.x), global_x / u32(dimensions.x)));
 }
@@ -103,8 +176,13 @@ fn main(
         let index = global_x * N_SEQ + i;
         let coords = coords(index, dimensions);
         if (in_bounds(index, dimensions) && match_centroid(k, coords)) {
-            local.color = local.color + textureLoad(pixels, coords, 0).rgb;
-            local.count = local.count + 1u;
+            let pixel = textureLoad(pixels, coords, 0);
+            // Transparent pixels have a weight of 0, and don't pull the centroid. Unique colors
+            // weigh their count of pixels.
+            if (pixel.a > 0.0) {
+                local.color = local.color + pixel.rgb * pixel.a;
+                local.count = local.count + u32(pixel.a);
+            }
         }
     }
 
@@ -183,11 +261,165 @@ fn main(
     }
 }
 
-[[stage(compute), workgroup_size(1)]]
-fn pick() {
-    let dimensions = textureDimensions(pixels);
-    let sum = atomicLoadPrefixVec(last_group_idx() * 8u + 0u);
+// A signed value times a weight, as a 64 bits two's complement integer, low word first.
+fn mul_wide(value: i32, weight: u32) -> vec2<u32> {
+    let magnitude = u32(abs(value));
+    let a = magnitude & 0xffffu;
+    let b = magnitude >> 16u;
+    let c = weight & 0xffffu;
+    let d = weight >> 16u;
+    let ad = a * d;
+    let bc = b * c;
+    var low = a * c;
+    var high = b * d;
+    low = low + (ad << 16u);
+    high = high + (ad >> 16u) + select(0u, 1u, low < (ad << 16u));
+    low = low + (bc << 16u);
+    high = high + (bc >> 16u) + select(0u, 1u, low < (bc << 16u));
+    if (value < 0) {
+        low = ~low + 1u;
+        high = ~high + select(0u, 1u, low == 0u);
+    }
+    return vec2<u32>(low, high);
+}
+
+fn add_wide(a: vec2<u32>, b: vec2<u32>) -> vec2<u32> {
+    let low = a.x + b.x;
+    return vec2<u32>(low, a.y + b.y + select(0u, 1u, low < b.x));
+}
+
+// Adds a 64 bits integer to one of the workgroup. Integer additions give the same sum whatever
+// the order of the invocations.
+fn add_fixed_scratch(index: u32, value: vec2<u32>) {
+    let previous = atomicAdd(&fixed_scratch[index], value.x);
+    let carry = select(0u, 1u, previous + value.x < previous);
+    atomicAdd(&fixed_scratch[index + 1u], value.y + carry);
+}
+
+// Adds a 64 bits integer to the sums of the centroid, whatever the order of the workgroups.
+fn add_fixed_sums(index: u32, low: u32, high: u32) {
+    let previous = atomicAdd(&fixed_sums.data[index], low);
+    let carry = select(0u, 1u, previous + low < previous);
+    atomicAdd(&fixed_sums.data[index + 1u], high + carry);
+}
+
+// Same as main, in fixed point: the sums are exactly the same on every run and every gpu.
+[[stage(compute), workgroup_size(256)]]
+fn main_fixed(
+    [[builtin(local_invocation_id)]] local_id : vec3<u32>,
+    [[builtin(workgroup_id)]] workgroup_id : vec3<u32>,
+) {
+    if (local_id.x < 8u) {
+        atomicStore(&fixed_scratch[local_id.x], 0u);
+    }
+    workgroupBarrier();
+
     let k = k_index.k;
+    let N_SEQ = settings.n_seq;
+    let dimensions = textureDimensions(pixels);
+    let global_x = workgroup_id.x * workgroup_size + local_id.x;
+
+    var red = vec2<u32>(0u);
+    var green = vec2<u32>(0u);
+    var blue = vec2<u32>(0u);
+    var count = 0u;
+    for (var i: u32 = 0u; i < N_SEQ; i = i + 1u) {
+        let index = global_x * N_SEQ + i;
+        let coords = coords(index, dimensions);
+        if (in_bounds(index, dimensions) && match_centroid(k, coords)) {
+            let pixel = textureLoad(pixels, coords, 0);
+            // Transparent pixels have a weight of 0, and don't pull the centroid.
+            if (pixel.a > 0.0) {
+                let weight = u32(pixel.a);
+                let color = vec3<i32>(round(pixel.rgb * FIXED_SCALE));
+                red = add_wide(red, mul_wide(color.r, weight));
+                green = add_wide(green, mul_wide(color.g, weight));
+                blue = add_wide(blue, mul_wide(color.b, weight));
+                count = count + weight;
+            }
+        }
+    }
+
+    if (count > 0u) {
+        add_fixed_scratch(0u, red);
+        add_fixed_scratch(2u, green);
+        add_fixed_scratch(4u, blue);
+        atomicAdd(&fixed_scratch[6], count);
+    }
+    workgroupBarrier();
+
+    if (local_id.x == 0u) {
+        let base = k * 8u;
+        for (var i: u32 = 0u; i < 6u; i = i + 2u) {
+            add_fixed_sums(base + i, atomicLoad(&fixed_scratch[i]), atomicLoad(&fixed_scratch[i + 1u]));
+        }
+        atomicAdd(&fixed_sums.data[base + 6u], atomicLoad(&fixed_scratch[6]));
+    }
+}
+
+// Reads a 64 bits fixed point sum back to a float, and resets it for the next iteration.
+fn take_fixed_sum(index: u32) -> f32 {
+    let low = atomicExchange(&fixed_sums.data[index], 0u);
+    let high = atomicExchange(&fixed_sums.data[index + 1u], 0u);
+    return (f32(bitcast<i32>(high)) * 4294967296.0 + f32(low)) / FIXED_SCALE;
+}
+
+// Adds a 64 bits integer to one of the workgroup bins.
+fn add_bin(index: u32, value: vec2<u32>) {
+    let previous = atomicAdd(&bins[index], value.x);
+    let carry = select(0u, 1u, previous + value.x < previous);
+    atomicAdd(&bins[index + 1u], value.y + carry);
+}
+
+// Sums the pixels of every centroid at once, in workgroup bins merged into the fixed point sums,
+// instead of going over the image once per centroid.
+[[stage(compute), workgroup_size(256)]]
+fn sum_all(
+    [[builtin(local_invocation_id)]] local_id : vec3<u32>,
+    [[builtin(workgroup_id)]] workgroup_id : vec3<u32>,
+) {
+    let words = centroids.count * 7u;
+    for (var i = local_id.x; i < words; i = i + workgroup_size) {
+        atomicStore(&bins[i], 0u);
+    }
+    workgroupBarrier();
+
+    let N_SEQ = settings.n_seq;
+    let dimensions = textureDimensions(pixels);
+    let global_x = workgroup_id.x * workgroup_size + local_id.x;
+
+    for (var i: u32 = 0u; i < N_SEQ; i = i + 1u) {
+        let index = global_x * N_SEQ + i;
+        if (in_bounds(index, dimensions)) {
+            let coords = coords(index, dimensions);
+            let pixel = textureLoad(pixels, coords, 0);
+            // Transparent pixels have a weight of 0, and don't pull the centroid.
+            if (pixel.a > 0.0) {
+                let base = textureLoad(color_indices, coords, 0).r * 7u;
+                let weight = u32(pixel.a);
+                let color = vec3<i32>(round(pixel.rgb * FIXED_SCALE));
+                add_bin(base, mul_wide(color.r, weight));
+                add_bin(base + 2u, mul_wide(color.g, weight));
+                add_bin(base + 4u, mul_wide(color.b, weight));
+                atomicAdd(&bins[base + 6u], weight);
+            }
+        }
+    }
+    workgroupBarrier();
+
+    for (var k = local_id.x; k < centroids.count; k = k + workgroup_size) {
+        let count = atomicLoad(&bins[k * 7u + 6u]);
+        if (count > 0u) {
+            for (var i: u32 = 0u; i < 6u; i = i + 2u) {
+                add_fixed_sums(k * 8u + i, atomicLoad(&bins[k * 7u + i]), atomicLoad(&bins[k * 7u + i + 1u]));
+            }
+            atomicAdd(&fixed_sums.data[k * 8u + 6u], count);
+        }
+    }
+}
+
+// Moves a centroid to the mean of its pixels, and tells whether it converged.
+fn move_centroid(k: u32, sum: ColorAggregator) {
     if(sum.count > 0u) {
         let new_centroid = vec4<f32>(sum.color / f32(sum.count), 1.0);
         let previous_centroid = centroids.data[k];
@@ -197,16 +429,213 @@ fn pick() {
         atomicStore(&convergence.data[k], u32(distance(new_centroid, previous_centroid) < settings.convergence));
     } else {
         atomicStore(&convergence.data[k], 0u);
+        reseed_state.data[k].empty = 1u;
+        atomicAdd(&reseed_state.empties, 1u);
     }
+}
 
+// Counts the converged centroids and the empty clusters, once they all moved.
+fn count_converged() {
+    var converge = atomicExchange(&convergence.data[0u], 0u);
+    for (var i = 1u; i < centroids.count; i = i + 1u) {
+        converge = converge + atomicExchange(&convergence.data[i], 0u);
+    }
+    atomicStore(&convergence.data[centroids.count], converge);
+    atomicStore(&convergence.data[centroids.count + 3u], atomicExchange(&reseed_state.empties, 0u));
+}
+
+fn update_centroid(k: u32, sum: ColorAggregator) {
+    move_centroid(k, sum);
     if (k == centroids.count - 1u) {
-        var converge = atomicExchange(&convergence.data[0u], 0u);
-        for (var i = 1u; i < centroids.count; i = i + 1u) {
-            converge = converge + atomicExchange(&convergence.data[i], 0u);
-        }
-        atomicStore(&convergence.data[centroids.count], converge);
+        count_converged();
     }
-    
+}
+
+// Reads the fixed point sums of a centroid back to floats, and resets them.
+fn take_fixed_sums(k: u32) -> ColorAggregator {
+    let base = k * 8u;
+    var sum: ColorAggregator;
+    sum.color = vec3<f32>(take_fixed_sum(base), take_fixed_sum(base + 2u), take_fixed_sum(base + 4u));
+    sum.count = atomicExchange(&fixed_sums.data[base + 6u], 0u);
+    return sum;
+}
+
+// Same as pick_fixed, for every centroid at once after sum_all.
+[[stage(compute), workgroup_size(256)]]
+fn pick_all(
+    [[builtin(local_invocation_id)]] local_id : vec3<u32>,
+) {
+    for (var k = local_id.x; k < centroids.count; k = k + workgroup_size) {
+        move_centroid(k, take_fixed_sums(k));
+    }
+    storageBarrier();
+    workgroupBarrier();
+
+    if (local_id.x == 0u) {
+        count_converged();
+    }
+}
+
+[[stage(compute), workgroup_size(1)]]
+fn pick() {
+    update_centroid(k_index.k, atomicLoadPrefixVec(last_group_idx() * 8u + 0u));
+
     // Reset part ids for next centroid.
     atomicStore(&part_id_buffer.data[0], 0u);
-}
\ No newline at end of file
+}
+
+// Same as pick, or finalize once every tile has been summed, from the fixed point sums.
+[[stage(compute), workgroup_size(1)]]
+fn pick_fixed() {
+    update_centroid(k_index.k, take_fixed_sums(k_index.k));
+}
+
+// Adds the sum of the current tile to the sums of the previous ones, instead of picking.
+[[stage(compute), workgroup_size(1)]]
+fn accumulate() {
+    let k = k_index.k;
+    let sum = atomicLoadPrefixVec(last_group_idx() * 8u + 0u);
+    tile_sums.data[k].color = tile_sums.data[k].color + sum.color;
+    tile_sums.data[k].count = tile_sums.data[k].count + sum.count;
+
+    // Reset part ids for next centroid.
+    atomicStore(&part_id_buffer.data[0], 0u);
+}
+
+// Picks the centroid once every tile has been accumulated.
+[[stage(compute), workgroup_size(1)]]
+fn finalize() {
+    let k = k_index.k;
+    update_centroid(k, tile_sums.data[k]);
+
+    tile_sums.data[k] = ColorAggregator(vec3<f32>(0.0), 0u);
+}
+
+// The cluster of a pixel and the distance to its centroid, or a distance of -1 for transparent
+// pixels and out of bounds.
+fn assignment(coords: vec2<i32>, dimensions: vec2<i32>) -> Assignment {
+    if (coords.x >= dimensions.x || coords.y >= dimensions.y) {
+        return Assignment(0u, -1.0);
+    }
+    let pixel = textureLoad(pixels, coords, 0);
+    if (pixel.a <= 0.0) {
+        return Assignment(0u, -1.0);
+    }
+    let index = textureLoad(color_indices, coords, 0).r;
+    return Assignment(index, distance(pixel.rgb, centroids.data[index].rgb));
+}
+
+// Keeps the largest distance between a pixel of each cluster and its centroid.
+[[stage(compute), workgroup_size(16, 16)]]
+fn measure_farthest(
+    [[builtin(global_invocation_id)]] global_id : vec3<u32>,
+) {
+    let assigned = assignment(vec2<i32>(global_id.xy), textureDimensions(pixels));
+    if (assigned.distance > 0.0) {
+        atomicMax(&reseed_state.data[assigned.cluster].distance, bitcast<u32>(assigned.distance));
+    }
+}
+
+// Picks the first pixel of each cluster at its largest distance. Positive floats compare like
+// their bits.
+[[stage(compute), workgroup_size(16, 16)]]
+fn select_farthest(
+    [[builtin(global_invocation_id)]] global_id : vec3<u32>,
+) {
+    let dimensions = textureDimensions(pixels);
+    let assigned = assignment(vec2<i32>(global_id.xy), dimensions);
+    let k = assigned.cluster;
+    if (assigned.distance > 0.0 && bitcast<u32>(assigned.distance) == atomicLoad(&reseed_state.data[k].distance)) {
+        atomicMin(&reseed_state.data[k].pixel, global_id.y * u32(dimensions.x) + global_id.x);
+    }
+}
+
+// Keeps the color of the picked pixels, as the texture is replaced by the next tile.
+[[stage(compute), workgroup_size(256)]]
+fn keep_farthest(
+    [[builtin(local_invocation_id)]] local_id : vec3<u32>,
+) {
+    let dimensions = textureDimensions(pixels);
+    for (var k = local_id.x; k < centroids.count; k = k + workgroup_size) {
+        let pixel = atomicExchange(&reseed_state.data[k].pixel, max_int);
+        if (pixel != max_int) {
+            reseed_state.data[k].color = vec4<f32>(textureLoad(pixels, coords(pixel, dimensions), 0).rgb, 1.0);
+        }
+    }
+}
+
+// The farther candidate, or the one of the lowest cluster on ties.
+fn farther(a: Candidate, b: Candidate) -> Candidate {
+    if (b.distance > a.distance || (b.distance == a.distance && b.cluster < a.cluster)) {
+        return b;
+    }
+    return a;
+}
+
+// Moves every empty cluster, in order, to the farthest pixel of a distinct cluster, taking the
+// farthest of them first. Clusters whose pixels all sit on their centroid give none, so when
+// they leave too few pixels, the remaining empty clusters wait for the next iterations. Each
+// re-seed is counted in convergence.data[count + 1].
+[[stage(compute), workgroup_size(256)]]
+fn reseed(
+    [[builtin(local_invocation_id)]] local_id : vec3<u32>,
+) {
+    for (var k = 0u; k < centroids.count; k = k + 1u) {
+        if (reseed_state.data[k].empty == 0u) {
+            continue;
+        }
+
+        var local = Candidate(0u, max_int);
+        for (var i = local_id.x; i < centroids.count; i = i + workgroup_size) {
+            local = farther(local, Candidate(atomicLoad(&reseed_state.data[i].distance), i));
+        }
+        candidates[local_id.x] = local;
+        workgroupBarrier();
+        for (var stride = workgroup_size / 2u; stride > 0u; stride = stride / 2u) {
+            if (local_id.x < stride) {
+                candidates[local_id.x] = farther(candidates[local_id.x], candidates[local_id.x + stride]);
+            }
+            workgroupBarrier();
+        }
+
+        if (local_id.x == 0u) {
+            let farthest = candidates[0];
+            if (farthest.distance > 0u) {
+                centroids.data[k] = reseed_state.data[farthest.cluster].color;
+                // Each pixel moves a single cluster.
+                atomicStore(&reseed_state.data[farthest.cluster].distance, 0u);
+                atomicAdd(&convergence.data[centroids.count + 1u], 1u);
+            }
+            reseed_state.data[k].empty = 0u;
+        }
+        storageBarrier();
+        workgroupBarrier();
+    }
+
+    for (var k = local_id.x; k < centroids.count; k = k + workgroup_size) {
+        atomicStore(&reseed_state.data[k].distance, 0u);
+    }
+}
+
+// Runs at the start of each iteration: once every centroid converged, shrinks the dispatches of
+// the following iterations to nothing, so they are skipped without waiting for the cpu.
+[[stage(compute), workgroup_size(1)]]
+fn gate() {
+    let done = atomicLoad(&convergence.data[centroids.count]) >= centroids.count;
+    for (var i: u32 = 0u; i < ITERATION_WORDS; i = i + 1u) {
+        dispatches.data[i] = select(dispatches.data[DISPATCH_WORDS + i], 0u, done);
+    }
+    if (!done) {
+        atomicAdd(&convergence.data[centroids.count + 2u], 1u);
+    }
+}
+
+// Runs after the pick: shrinks the dispatches of the search of the farthest pixels and of the
+// re-seed to nothing, unless the pick left empty clusters.
+[[stage(compute), workgroup_size(1)]]
+fn gate_reseed() {
+    let empty = atomicLoad(&convergence.data[centroids.count + 3u]) > 0u;
+    for (var i: u32 = ITERATION_WORDS; i < DISPATCH_WORDS; i = i + 1u) {
+        dispatches.data[i] = select(0u, dispatches.data[DISPATCH_WORDS + i], empty);
+    }
+}
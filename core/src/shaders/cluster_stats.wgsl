@@ -0,0 +1,105 @@
+struct Centroids {
+    count: u32;
+    // Aligned 16. See https://www.w3.org/TR/WGSL/#address-space-layout-constraints
+    data: array<vec4<f32>>;
+};
+
+struct AtomicBuffer {
+    data: array<atomic<u32>>;
+};
+
+struct PartialSums {
+    data: array<f32>;
+};
+
+[[group(0), binding(0)]] var pixels: texture_2d<f32>;
+[[group(0), binding(1)]] var<storage, read> centroids: Centroids;
+[[group(0), binding(2)]] var color_indices: texture_2d<u32>;
+[[group(0), binding(3)]] var<storage, read_write> counts: AtomicBuffer;
+[[group(0), binding(4)]] var<storage, read_write> inertia: PartialSums;
+[[group(0), binding(5)]] var<storage, read_write> errors: PartialSums;
+
+let workgroup_size: u32 = 256u;
+
+var<workgroup> scratch: array<f32, workgroup_size>;
+var<workgroup> error_scratch: array<f32, workgroup_size>;
+
+fn rgb_to_lab(rgb: vec3<f32>) -> vec3<f32> {
+    let linear = select(rgb / 12.92, pow((rgb + 0.055) / 1.055, vec3<f32>(2.4)), rgb > vec3<f32>(0.04045)) * 100.0;
+    let xyz = vec3<f32>(
+        linear.r * 0.4124 + linear.g * 0.3576 + linear.b * 0.1805,
+        linear.r * 0.2126 + linear.g * 0.7152 + linear.b * 0.0722,
+        linear.r * 0.0193 + linear.g * 0.1192 + linear.b * 0.9505,
+    ) / vec3<f32>(95.047, 100.0, 108.883);
+    let f = select(7.787 * xyz + 16.0 / 116.0, pow(xyz, vec3<f32>(1.0 / 3.0)), xyz > vec3<f32>(0.008856));
+    return vec3<f32>(116.0 * f.y - 16.0, 500.0 * (f.x - f.y), 200.0 * (f.y - f.z));
+}
+
+// Counts the pixels of each cluster, and sums the squared distances and the distances of each
+// pixel to its centroid, one partial sum per workgroup. The squared distances are in the work
+// color space, while the distances are a ΔE, measured in Lab when `rgb` is true.
+fn stats(global_id: vec3<u32>, local_index: u32, workgroup_id: vec3<u32>, rgb: bool) {
+    let dimensions = textureDimensions(pixels);
+    let coords = vec2<i32>(global_id.xy);
+
+    var squared_distance: f32 = 0.0;
+    var error: f32 = 0.0;
+    var weight: f32 = 0.0;
+    if (coords.x < dimensions.x && coords.y < dimensions.y) {
+        let pixel = textureLoad(pixels, coords, 0);
+        // Transparent pixels have a weight of 0, and are not part of any cluster. Unique colors
+        // weigh their count of pixels.
+        if (pixel.a > 0.0) {
+            let index = textureLoad(color_indices, coords, 0).r;
+            let centroid = centroids.data[index].rgb;
+            let difference = pixel.rgb - centroid;
+            squared_distance = dot(difference, difference);
+            if (rgb) {
+                error = distance(rgb_to_lab(pixel.rgb), rgb_to_lab(centroid));
+            } else {
+                error = sqrt(squared_distance);
+            }
+            weight = pixel.a;
+            atomicAdd(&counts.data[index], u32(weight));
+        }
+    }
+
+    scratch[local_index] = squared_distance * weight;
+    error_scratch[local_index] = error * weight;
+    workgroupBarrier();
+
+    for (var stride: u32 = workgroup_size / 2u; stride > 0u; stride = stride / 2u) {
+        if (local_index < stride) {
+            scratch[local_index] = scratch[local_index] + scratch[local_index + stride];
+            error_scratch[local_index] = error_scratch[local_index] + error_scratch[local_index + stride];
+        }
+        workgroupBarrier();
+    }
+
+    if (local_index == 0u) {
+        let workgroups_per_row = (u32(dimensions.x) + 15u) / 16u;
+        let workgroup_index = workgroup_id.x + workgroup_id.y * workgroups_per_row;
+        inertia.data[workgroup_index] = scratch[0];
+        errors.data[workgroup_index] = error_scratch[0];
+    }
+}
+
+// The stats of pixels in Lab.
+[[stage(compute), workgroup_size(16, 16)]]
+fn main(
+    [[builtin(global_invocation_id)]] global_id : vec3<u32>,
+    [[builtin(local_invocation_index)]] local_index : u32,
+    [[builtin(workgroup_id)]] workgroup_id : vec3<u32>,
+) {
+    stats(global_id, local_index, workgroup_id, false);
+}
+
+// The stats of pixels in RGB, whose distances are converted to Lab.
+[[stage(compute), workgroup_size(16, 16)]]
+fn main_rgb(
+    [[builtin(global_invocation_id)]] global_id : vec3<u32>,
+    [[builtin(local_invocation_index)]] local_index : u32,
+    [[builtin(workgroup_id)]] workgroup_id : vec3<u32>,
+) {
+    stats(global_id, local_index, workgroup_id, true);
+}
@@ -0,0 +1,42 @@
+struct Histogram {
+    data: array<atomic<u32>>;
+};
+
+struct Bounds {
+    // The lowest value of each channel in the color space.
+    min: vec4<f32>;
+    // Bins per unit of each channel.
+    scale: vec4<f32>;
+};
+
+let BINS: u32 = 64u;
+
+[[group(0), binding(0)]] var pixels: texture_2d<f32>;
+[[group(0), binding(1)]] var<storage, read_write> histogram: Histogram;
+[[group(0), binding(2)]] var<uniform> bounds: Bounds;
+
+// Counts the pixels falling in each of the BINS³ bins of the color space.
+[[stage(compute), workgroup_size(16, 16)]]
+fn main(
+    [[builtin(global_invocation_id)]] global_id : vec3<u32>,
+) {
+    let dimensions = textureDimensions(pixels);
+    let coords = vec2<i32>(global_id.xy);
+
+    if(coords.x >= dimensions.x || coords.y >= dimensions.y) {
+        return;
+    }
+
+    let pixel = textureLoad(pixels, coords, 0);
+    if (pixel.a == 0.0) {
+        return;
+    }
+
+    let position = clamp(
+        (pixel.rgb - bounds.min.rgb) * bounds.scale.rgb,
+        vec3<f32>(0.0),
+        vec3<f32>(f32(BINS - 1u)),
+    );
+    let bin = vec3<u32>(position);
+    atomicAdd(&histogram.data[(bin.x * BINS + bin.y) * BINS + bin.z], 1u);
+}
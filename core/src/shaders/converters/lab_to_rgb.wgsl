@@ -1,5 +1,7 @@
 [[group(0), binding(0)]] var input_texture : texture_2d<f32>;
 [[group(0), binding(1)]] var output_texture : texture_storage_2d<rgba8unorm, write>;
+// The image before conversion, to restore its alpha.
+[[group(0), binding(2)]] var original_texture : texture_2d<f32>;
 
 fn xyz_to_rgb(xyz: vec4<f32>) -> vec4<f32> {
     var x = xyz.x / 100.0;
@@ -68,6 +70,7 @@ fn main(
         return;
     }
 
-    let lab = xyz_to_rgb(lab_to_xyz(textureLoad(input_texture, coords, 0)));
-    textureStore(output_texture, coords, lab);
+    let rgb = xyz_to_rgb(lab_to_xyz(textureLoad(input_texture, coords, 0)));
+    let alpha = textureLoad(original_texture, coords, 0).a;
+    textureStore(output_texture, coords, vec4<f32>(rgb.rgb, alpha));
 }
\ No newline at end of file
@@ -1,5 +1,7 @@
 [[group(0), binding(0)]] var input_texture : texture_2d<f32>;
 [[group(0), binding(1)]] var output_texture : texture_storage_2d<rgba8unorm, write>;
+// The image before conversion, to restore its alpha.
+[[group(0), binding(2)]] var original_texture : texture_2d<f32>;
 
 [[stage(compute), workgroup_size(16, 16)]]
 fn main(
@@ -13,5 +15,6 @@ fn main(
     }
 
     let texel = textureLoad(input_texture, coords, 0);
-    textureStore(output_texture, coords, texel);
+    let alpha = textureLoad(original_texture, coords, 0).a;
+    textureStore(output_texture, coords, vec4<f32>(texel.rgb, alpha));
 }
\ No newline at end of file
@@ -1,5 +1,16 @@
+struct Settings {
+    alpha_threshold: u32;
+};
+
 [[group(0), binding(0)]] var input_texture : texture_2d<f32>;
 [[group(0), binding(1)]] var output_texture : texture_storage_2d<rgba32float, write>;
+[[group(0), binding(2)]] var<uniform> settings: Settings;
+
+// Pixels more transparent than the threshold are stored with a weight of 0 in the alpha
+// channel, which leaves them out of the clustering.
+fn weight(alpha: f32) -> f32 {
+    return select(0.0, 1.0, u32(round(alpha * 255.0)) >= settings.alpha_threshold);
+}
 
 [[stage(compute), workgroup_size(16, 16)]]
 fn main(
@@ -13,5 +24,5 @@ fn main(
     }
 
     let texel = textureLoad(input_texture, coords, 0);
-    textureStore(output_texture, coords, texel);
+    textureStore(output_texture, coords, vec4<f32>(texel.rgb, weight(texel.a)));
 }
\ No newline at end of file
@@ -1,5 +1,16 @@
+struct Settings {
+    alpha_threshold: u32;
+};
+
 [[group(0), binding(0)]] var input_texture : texture_2d<f32>;
 [[group(0), binding(1)]] var output_texture : texture_storage_2d<rgba32float, write>;
+[[group(0), binding(2)]] var<uniform> settings: Settings;
+
+// Pixels more transparent than the threshold are stored with a weight of 0 in the alpha
+// channel, which leaves them out of the clustering.
+fn weight(alpha: f32) -> f32 {
+    return select(0.0, 1.0, u32(round(alpha * 255.0)) >= settings.alpha_threshold);
+}
 
 fn rgb_to_xyz(rgb: vec4<f32>) -> vec4<f32> {
     var r = rgb.r;
@@ -69,6 +80,7 @@ fn main(
         return;
     }
 
-    let lab = xyz_to_lab(rgb_to_xyz(textureLoad(input_texture, coords, 0)));
-    textureStore(output_texture, coords, lab);
+    let texel = textureLoad(input_texture, coords, 0);
+    let lab = xyz_to_lab(rgb_to_xyz(texel));
+    textureStore(output_texture, coords, vec4<f32>(lab.xyz, weight(texel.a)));
 }
\ No newline at end of file
@@ -0,0 +1,69 @@
+struct Settings {
+    // The u64 seed, low bits first.
+    seed: vec2<u32>;
+    // Side of the square of pixels each texel of the estimate stands for.
+    cell: u32;
+    alpha_threshold: u32;
+};
+
+[[group(0), binding(0)]] var input_texture: texture_2d<f32>;
+[[group(0), binding(1)]] var output_texture: texture_storage_2d<rgba8unorm, write>;
+[[group(0), binding(2)]] var<uniform> settings: Settings;
+
+// PCG hash, see https://www.jcgt.org/published/0009/03/02/
+fn pcg(value: u32) -> u32 {
+    let state = value * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// A random number for each stream, only depending on the seed.
+fn random(stream: u32) -> u32 {
+    return pcg(settings.seed.x ^ pcg(settings.seed.y ^ pcg(stream)));
+}
+
+// Each texel is the mean of the opaque enough pixels of its cell, an area filter. Cells without
+// any stay transparent, and out of the clustering.
+[[stage(compute), workgroup_size(16, 16)]]
+fn downscale([[builtin(global_invocation_id)]] global_id : vec3<u32>) {
+    let output_dimensions = vec2<u32>(textureDimensions(output_texture));
+    if (global_id.x >= output_dimensions.x || global_id.y >= output_dimensions.y) {
+        return;
+    }
+
+    let start = global_id.xy * settings.cell;
+    let end = min(start + vec2<u32>(settings.cell), vec2<u32>(textureDimensions(input_texture)));
+    var sum = vec3<f32>(0.0);
+    var count = 0u;
+    for (var y = start.y; y < end.y; y = y + 1u) {
+        for (var x = start.x; x < end.x; x = x + 1u) {
+            let pixel = textureLoad(input_texture, vec2<i32>(vec2<u32>(x, y)), 0);
+            if (u32(round(pixel.a * 255.0)) >= settings.alpha_threshold) {
+                sum = sum + pixel.rgb;
+                count = count + 1u;
+            }
+        }
+    }
+
+    var texel = vec4<f32>(0.0);
+    if (count > 0u) {
+        texel = vec4<f32>(sum / f32(count), 1.0);
+    }
+    textureStore(output_texture, vec2<i32>(global_id.xy), texel);
+}
+
+// Each texel is a pixel of its cell picked at random, a stratified sample.
+[[stage(compute), workgroup_size(16, 16)]]
+fn sample([[builtin(global_invocation_id)]] global_id : vec3<u32>) {
+    let output_dimensions = vec2<u32>(textureDimensions(output_texture));
+    if (global_id.x >= output_dimensions.x || global_id.y >= output_dimensions.y) {
+        return;
+    }
+
+    let start = global_id.xy * settings.cell;
+    let end = min(start + vec2<u32>(settings.cell), vec2<u32>(textureDimensions(input_texture)));
+    let size = end - start;
+    let picked = random(global_id.x + global_id.y * output_dimensions.x) % (size.x * size.y);
+    let coords = start + vec2<u32>(picked % size.x, picked / size.x);
+    textureStore(output_texture, vec2<i32>(global_id.xy), textureLoad(input_texture, vec2<i32>(coords), 0));
+}
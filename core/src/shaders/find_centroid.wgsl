@@ -7,13 +7,54 @@ struct Indices {
     data: array<u32>;
 };
 
+struct Distances {
+    data: array<f32>;
+};
+
+// Shared by the bounded searches of an image, see `main_bounded`.
+struct BoundsState {
+    // The bounds texture written by the last search that ran: 0 or 1, or 2 before the first.
+    latest: atomic<u32>;
+    // Whether a search ran since the last `prepare`, which then restarts the drifts.
+    ran: atomic<u32>;
+    // Whether the bounds read by the coming search are the latest, set by `prepare`.
+    valid: u32;
+    // The largest drift of a centroid.
+    max_drift: f32;
+};
+
+struct Snapshot {
+    data: array<vec4<f32>>;
+};
+
+struct Parity {
+    // The bounds texture read by this search, the other one being written.
+    input: u32;
+};
+
 [[group(0), binding(0)]] var pixels: texture_2d<f32>;
 [[group(0), binding(1)]] var<storage, read> centroids: Centroids;
 [[group(0), binding(2)]] var color_indices: texture_storage_2d<r32uint, write>;
+// For each pixel, a lower bound of its distance to every centroid but its own as bits, and the
+// index of its own. Storage textures are write only, so the searches alternate between two.
+[[group(0), binding(3)]] var bounds_in: texture_2d<u32>;
+[[group(0), binding(4)]] var bounds_out: texture_storage_2d<rg32uint, write>;
+[[group(0), binding(5)]] var<storage, read_write> state: BoundsState;
+// The centroids at the last `prepare`, and how far each one moved since the last search ran.
+[[group(0), binding(6)]] var<storage, read_write> snapshot: Snapshot;
+[[group(0), binding(7)]] var<storage, read_write> drifts: Distances;
+// See `centroid_table.wgsl`.
+[[group(0), binding(8)]] var<storage, read> table: Distances;
+[[group(0), binding(9)]] var<storage, read> half_min: Distances;
+[[group(0), binding(10)]] var<uniform> parity: Parity;
 
 let max_int : u32 = 4294967295u;
 let max_f32: f32 = 100000.0;
 
+let workgroup_size: u32 = 256u;
+
+var<workgroup> scratch: array<f32, workgroup_size>;
+
 [[stage(compute), workgroup_size(16, 16)]]
 fn main(
     [[builtin(global_invocation_id)]] global_id : vec3<u32>,
@@ -43,3 +84,109 @@ fn main(
 
     textureStore(color_indices, coords, vec4<u32>(found_index, 0u, 0u, 0u));
 }
+
+// Twice the rounding error of a distance between colors, see `centroid_table.wgsl`.
+fn slack(value: f32) -> f32 {
+    return 0.0001 + abs(value) * 0.00001;
+}
+
+// Runs before each bounded search: adds how far each centroid moved since the last one to its
+// drift, restarting them when a search ran in between, and tells whether the bounds to read
+// are the latest. A skipped search keeps the drifts growing, and the bounds of the one before.
+[[stage(compute), workgroup_size(256)]]
+fn prepare([[builtin(local_invocation_id)]] local_id : vec3<u32>) {
+    let restart = atomicLoad(&state.ran) == 1u;
+    var local_max = 0.0;
+    for (var k = local_id.x; k < centroids.count; k = k + workgroup_size) {
+        let moved = distance(snapshot.data[k].rgb, centroids.data[k].rgb);
+        let drift = select(drifts.data[k], 0.0, restart) + moved + slack(moved);
+        drifts.data[k] = drift;
+        snapshot.data[k] = centroids.data[k];
+        local_max = max(local_max, drift);
+    }
+    scratch[local_id.x] = local_max;
+    workgroupBarrier();
+
+    if (local_id.x == 0u) {
+        var max_drift = 0.0;
+        for (var i: u32 = 0u; i < workgroup_size; i = i + 1u) {
+            max_drift = max(max_drift, scratch[i]);
+        }
+        state.max_drift = max_drift;
+        state.valid = u32(atomicLoad(&state.latest) == parity.input);
+        atomicStore(&state.ran, 0u);
+    }
+}
+
+// Same as main, skipping every centroid it can. Each pixel keeps a lower bound of its distance
+// to the centroids but its own, lowered by the largest drift at each search: while its distance
+// to its own centroid stays below it, or below half the distance to the closest other centroid,
+// no other centroid can be as close (Hamerly). Otherwise, the search skips the centroids the
+// table puts too far from the closest one so far (Elkan). The bounds keep a margin over the
+// rounding errors, so the index is always the one of the exhaustive search.
+[[stage(compute), workgroup_size(16, 16)]]
+fn main_bounded(
+    [[builtin(global_invocation_id)]] global_id : vec3<u32>,
+) {
+    let dimensions = textureDimensions(pixels);
+    let coords = vec2<i32>(global_id.xy);
+
+    if(coords.x >= dimensions.x || coords.y >= dimensions.y) {
+        return;
+    }
+    atomicStore(&state.latest, 1u - parity.input);
+    atomicStore(&state.ran, 1u);
+
+    let pixel : vec3<f32> = textureLoad(pixels, coords.xy, 0).rgb;
+    let count = centroids.count;
+
+    var start = 0u;
+    if (state.valid == 1u) {
+        let bounds = textureLoad(bounds_in, coords, 0);
+        let index = bounds.y;
+        let decayed = bitcast<f32>(bounds.x) - state.max_drift;
+        let lower = decayed - abs(decayed) * 0.000001;
+        let upper = distance(pixel, centroids.data[index].rgb);
+        let half = half_min.data[index];
+        if (upper + slack(upper) + slack(half) < half || upper < lower - slack(lower)) {
+            textureStore(color_indices, coords, vec4<u32>(index, 0u, 0u, 0u));
+            textureStore(bounds_out, coords, vec4<u32>(bitcast<u32>(lower), index, 0u, 0u));
+            return;
+        }
+        start = index;
+    }
+
+    // Starts from the previous centroid, likely the closest, and breaks ties on the lowest index
+    // like the exhaustive search.
+    var found_index = start;
+    var min_distance = distance(pixel, centroids.data[start].rgb);
+    var lower = max_f32;
+    for (var index: u32 = 0u; index < count; index = index + 1u) {
+        if (index == start) {
+            continue;
+        }
+
+        let centroids_distance = table.data[found_index * count + index];
+        let bound = centroids_distance - min_distance - slack(centroids_distance) - slack(min_distance);
+        if (bound - slack(bound) > min_distance) {
+            lower = min(lower, bound);
+            continue;
+        }
+
+        let distance: f32 = distance(pixel, centroids.data[index].rgb);
+        if (distance < min_distance || (distance == min_distance && index < found_index)) {
+            lower = min(lower, min_distance - slack(min_distance));
+            min_distance = distance;
+            found_index = index;
+        } else {
+            lower = min(lower, distance - slack(distance));
+        }
+    }
+    if (min_distance >= max_f32) {
+        found_index = 0u;
+        lower = 0.0;
+    }
+
+    textureStore(color_indices, coords, vec4<u32>(found_index, 0u, 0u, 0u));
+    textureStore(bounds_out, coords, vec4<u32>(bitcast<u32>(lower), found_index, 0u, 0u));
+}
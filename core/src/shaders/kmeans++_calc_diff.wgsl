@@ -11,6 +11,8 @@ struct KIndex {
 [[group(0), binding(0)]] var<storage, read> centroids: Centroids;
 [[group(0), binding(1)]] var pixels: texture_2d<f32>;
 [[group(0), binding(2)]] var distance_map: texture_storage_2d<r32float, write>;
+// The map of the previous centroid, so that each pass only measures the distance to the latest.
+[[group(0), binding(3)]] var previous_map: texture_2d<f32>;
 [[group(1), binding(0)]] var<uniform> k_index: KIndex;
 
 [[stage(compute), workgroup_size(16, 16)]]
@@ -24,12 +26,19 @@ fn main(
         return;
     }
 
-    let color = textureLoad(pixels, coords, 0).rgb;
+    let pixel = textureLoad(pixels, coords, 0);
+    if (pixel.a == 0.0) {
+        // Transparent pixels can never be picked.
+        textureStore(distance_map, coords, vec4<f32>(-1.0, 0.0, 0.0, 0.0));
+        return;
+    }
+
+    let latest = k_index.k - 1u;
     var min_distance: f32 = 1000000.0;
-    for (var k: u32 = 0u; k < k_index.k; k = k + 1u) {
-        let distance_to_centroid = distance(color, centroids.data[k].rgb);
-        min_distance = min(min_distance, distance_to_centroid);
+    if (latest > 0u) {
+        min_distance = textureLoad(previous_map, coords, 0).r;
     }
+    min_distance = min(min_distance, distance(pixel.rgb, centroids.data[latest].rgb));
 
     textureStore(distance_map, coords, vec4<f32>(min_distance, 0.0, 0.0, 0.0));
 }
\ No newline at end of file
@@ -0,0 +1,270 @@
+struct Candidates {
+    count: atomic<u32>;
+    // How many candidates the distances already account for.
+    checked: u32;
+    // Aligned 16. See https://www.w3.org/TR/WGSL/#address-space-layout-constraints
+    data: array<vec4<f32>>;
+};
+
+struct Distances {
+    data: array<f32>;
+};
+
+struct Weights {
+    data: array<atomic<u32>>;
+};
+
+struct Settings {
+    // The u64 seed, low bits first.
+    seed: vec2<u32>;
+    // Expected number of candidates sampled each round.
+    oversampling: f32;
+};
+
+struct Round {
+    index: u32;
+};
+
+struct Priorities {
+    // The bins of priorities kept this round.
+    limit: u32;
+    // How many pixels were sampled with a priority in each bin.
+    bins: array<atomic<u32>>;
+};
+
+let N_SEQ = 16u;
+let workgroup_size: u32 = 256u;
+let max_f32: f32 = 4294967295.0;
+let priority_bins: u32 = 256u;
+
+[[group(0), binding(0)]] var<storage, read_write> candidates: Candidates;
+[[group(0), binding(1)]] var pixels: texture_2d<f32>;
+[[group(0), binding(2)]] var<storage, read_write> distances: Distances;
+[[group(0), binding(3)]] var<storage, read_write> partial_sums: Distances;
+[[group(0), binding(4)]] var<storage, read_write> weights: Weights;
+[[group(0), binding(5)]] var<uniform> settings: Settings;
+[[group(0), binding(6)]] var<storage, read_write> priorities: Priorities;
+[[group(1), binding(0)]] var<uniform> round: Round;
+
+var<workgroup> scratch: array<f32, workgroup_size>;
+
+fn coords(global_x: u32, dimensions: vec2<i32>) -> vec2<i32> {
+    return vec2<i32>(vec2<u32>(global_x % u32(dimensions.x), global_x / u32(dimensions.x)));
+}
+
+fn in_bounds(global_x: u32, dimensions: vec2<i32>) -> bool {
+    return global_x < u32(dimensions.x) * u32(dimensions.y);
+}
+
+// The threshold pass keeps the count within the buffer.
+fn candidate_count() -> u32 {
+    return atomicLoad(&candidates.count);
+}
+
+// PCG hash, see https://www.jcgt.org/published/0009/03/02/
+fn pcg(value: u32) -> u32 {
+    let state = value * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// A random number for each stream, only depending on the seed.
+fn random(stream: u32) -> u32 {
+    return pcg(settings.seed.x ^ pcg(settings.seed.y ^ pcg(stream)));
+}
+
+// Same as random, in [0, 1).
+fn random_unit(stream: u32) -> f32 {
+    return f32(random(stream) >> 8u) / 16777216.0;
+}
+
+// Sum of the values of the workgroup, in scratch[0].
+fn reduce(local_x: u32, value: f32) {
+    scratch[local_x] = value;
+    for (var stride = workgroup_size / 2u; stride > 0u; stride = stride / 2u) {
+        workgroupBarrier();
+        if (local_x < stride) {
+            scratch[local_x] = scratch[local_x] + scratch[local_x + stride];
+        }
+    }
+    workgroupBarrier();
+}
+
+// The first candidate was sampled among the opaque pixels by the ++ init passes, see
+// plus_plus_sample.wgsl.
+[[stage(compute), workgroup_size(1)]]
+fn initial() {
+    atomicStore(&candidates.count, 1u);
+    candidates.checked = 0u;
+}
+
+// Updates the squared distance of each pixel to its closest candidate with the candidates
+// sampled since the last update, and sums them for each workgroup.
+[[stage(compute), workgroup_size(256)]]
+fn measure(
+    [[builtin(local_invocation_id)]] local_id : vec3<u32>,
+    [[builtin(workgroup_id)]] workgroup_id : vec3<u32>,
+) {
+    let dimensions = textureDimensions(pixels);
+    let global_x = workgroup_id.x * workgroup_size + local_id.x;
+    let first = candidates.checked;
+    let count = candidate_count();
+
+    var local = 0.0;
+    for (var i: u32 = 0u; i < N_SEQ; i = i + 1u) {
+        let pixel_index = global_x * N_SEQ + i;
+        if (in_bounds(pixel_index, dimensions)) {
+            let pixel = textureLoad(pixels, coords(pixel_index, dimensions), 0);
+            // Transparent pixels can never be sampled.
+            var min_distance = 0.0;
+            if (pixel.a != 0.0) {
+                min_distance = max_f32;
+                if (first > 0u) {
+                    min_distance = distances.data[pixel_index];
+                }
+                for (var c = first; c < count; c = c + 1u) {
+                    let difference = pixel.rgb - candidates.data[c].rgb;
+                    min_distance = min(min_distance, dot(difference, difference));
+                }
+            }
+            distances.data[pixel_index] = min_distance;
+            local = local + min_distance;
+        }
+    }
+
+    reduce(local_id.x, local);
+    if (local_id.x == 0u) {
+        partial_sums.data[workgroup_id.x] = scratch[0];
+    }
+}
+
+// Marks the candidates as accounted for by the distances, before sampling new ones.
+[[stage(compute), workgroup_size(1)]]
+fn advance() {
+    candidates.checked = candidate_count();
+}
+
+// The sum of the squared distances, from the partial sums of every workgroup. There are few
+// enough for each workgroup to add them up.
+fn total_distance(local_x: u32) -> f32 {
+    var local = 0.0;
+    for (var group = local_x; group < arrayLength(&partial_sums.data); group = group + workgroup_size) {
+        local = local + partial_sums.data[group];
+    }
+    reduce(local_x, local);
+    return scratch[0];
+}
+
+// Samples each pixel independently, with a probability proportional to its squared distance.
+// The priority of a sampled pixel is its random number over its probability, from 0 the highest
+// to 1, uniform whatever the probability. Pixels not sampled have a priority of 1.
+fn priority(pixel_index: u32, total: f32) -> f32 {
+    let distance = distances.data[pixel_index];
+    let probability = settings.oversampling * distance / total;
+    let value = random_unit(pcg(round.index) ^ pixel_index);
+    if (distance > 0.0 && value < probability) {
+        return value / probability;
+    }
+    return 1.0;
+}
+
+fn priority_bin(priority: f32) -> u32 {
+    return min(u32(priority * f32(priority_bins)), priority_bins - 1u);
+}
+
+// Counts the pixels sampled this round in each bin of priorities.
+[[stage(compute), workgroup_size(256)]]
+fn rank(
+    [[builtin(local_invocation_id)]] local_id : vec3<u32>,
+    [[builtin(workgroup_id)]] workgroup_id : vec3<u32>,
+) {
+    let dimensions = textureDimensions(pixels);
+    let global_x = workgroup_id.x * workgroup_size + local_id.x;
+    let total = total_distance(local_id.x);
+    if (total <= 0.0) {
+        return;
+    }
+
+    for (var i: u32 = 0u; i < N_SEQ; i = i + 1u) {
+        let pixel_index = global_x * N_SEQ + i;
+        if (in_bounds(pixel_index, dimensions)) {
+            let pixel_priority = priority(pixel_index, total);
+            if (pixel_priority < 1.0) {
+                atomicAdd(&priorities.bins[priority_bin(pixel_priority)], 1u);
+            }
+        }
+    }
+}
+
+// Keeps the bins of the highest priorities, as many as the buffer still holds, for the samples
+// kept not to depend on the order the workgroups run in. Resets the counts for the next round.
+[[stage(compute), workgroup_size(1)]]
+fn threshold() {
+    let remaining = arrayLength(&candidates.data) - candidate_count();
+    var kept = 0u;
+    var limit = 0u;
+    for (var bin = 0u; bin < priority_bins; bin = bin + 1u) {
+        let count = atomicLoad(&priorities.bins[bin]);
+        if (limit == bin && kept + count <= remaining) {
+            kept = kept + count;
+            limit = bin + 1u;
+        }
+        atomicStore(&priorities.bins[bin], 0u);
+    }
+    priorities.limit = limit;
+}
+
+// Adds the pixels sampled in the bins kept to the candidates.
+[[stage(compute), workgroup_size(256)]]
+fn oversample(
+    [[builtin(local_invocation_id)]] local_id : vec3<u32>,
+    [[builtin(workgroup_id)]] workgroup_id : vec3<u32>,
+) {
+    let dimensions = textureDimensions(pixels);
+    let global_x = workgroup_id.x * workgroup_size + local_id.x;
+    let total = total_distance(local_id.x);
+    if (total <= 0.0) {
+        return;
+    }
+
+    for (var i: u32 = 0u; i < N_SEQ; i = i + 1u) {
+        let pixel_index = global_x * N_SEQ + i;
+        if (in_bounds(pixel_index, dimensions)) {
+            let pixel_priority = priority(pixel_index, total);
+            if (pixel_priority < 1.0 && priority_bin(pixel_priority) < priorities.limit) {
+                let index = atomicAdd(&candidates.count, 1u);
+                let pixel = textureLoad(pixels, coords(pixel_index, dimensions), 0);
+                candidates.data[index] = vec4<f32>(pixel.rgb, 1.0);
+            }
+        }
+    }
+}
+
+// Counts the pixels closest to each candidate.
+[[stage(compute), workgroup_size(256)]]
+fn weigh(
+    [[builtin(global_invocation_id)]] global_id : vec3<u32>,
+) {
+    let dimensions = textureDimensions(pixels);
+    let count = candidate_count();
+
+    for (var i: u32 = 0u; i < N_SEQ; i = i + 1u) {
+        let pixel_index = global_id.x * N_SEQ + i;
+        if (in_bounds(pixel_index, dimensions)) {
+            let pixel = textureLoad(pixels, coords(pixel_index, dimensions), 0);
+            if (pixel.a != 0.0) {
+                var min_distance = max_f32;
+                var closest = 0u;
+                for (var c = 0u; c < count; c = c + 1u) {
+                    let difference = pixel.rgb - candidates.data[c].rgb;
+                    let distance = dot(difference, difference);
+                    if (distance < min_distance) {
+                        min_distance = distance;
+                        closest = c;
+                    }
+                }
+                atomicAdd(&weights.data[closest], 1u);
+            }
+        }
+    }
+}
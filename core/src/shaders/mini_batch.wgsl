@@ -0,0 +1,179 @@
+struct Centroids {
+    count: u32;
+    // Aligned 16. See https://www.w3.org/TR/WGSL/#address-space-layout-constraints
+    data: array<vec4<f32>>;
+};
+
+struct AtomicBuffer {
+    data: array<atomic<u32>>;
+};
+
+// The weight of the pixels each centroid has seen over all the batches so far.
+struct Seen {
+    data: array<f32>;
+};
+
+struct Settings {
+    // The u64 seed, low bits first.
+    seed: vec2<u32>;
+    batch_size: u32;
+    convergence: f32;
+};
+
+struct Dispatches {
+    data: array<u32>;
+};
+
+[[group(0), binding(0)]] var pixels: texture_2d<f32>;
+[[group(0), binding(1)]] var<storage, read_write> centroids: Centroids;
+// Sums of each centroid over the batch in 64 bits fixed point, laid out like `FixedSums` in
+// `choose_centroid.wgsl`.
+[[group(0), binding(2)]] var<storage, read_write> batch_sums: AtomicBuffer;
+[[group(0), binding(3)]] var<storage, read_write> seen: Seen;
+// Whether each centroid converged, followed by their count, the count of re-seeds, always 0,
+// and the count of iterations run.
+[[group(0), binding(4)]] var<storage, read_write> convergence: AtomicBuffer;
+[[group(0), binding(5)]] var<uniform> settings: Settings;
+// The arguments of the indirect dispatches of an iteration, followed by their full sizes.
+[[group(1), binding(0)]] var<storage, read_write> dispatches: Dispatches;
+
+let workgroup_size: u32 = 256u;
+
+let max_f32: f32 = 100000.0;
+
+// Components are rounded to 1/65536, then multiplied by the weight of the pixel in 64 bits.
+let FIXED_SCALE: f32 = 65536.0;
+
+// Two dispatches of three words: over the batch, and a single workgroup.
+let DISPATCH_WORDS: u32 = 6u;
+
+// PCG hash, see https://www.jcgt.org/published/0009/03/02/
+fn pcg(value: u32) -> u32 {
+    let state = value * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// A random number for each stream, only depending on the seed.
+fn random(stream: u32) -> u32 {
+    return pcg(settings.seed.x ^ pcg(settings.seed.y ^ pcg(stream)));
+}
+
+// A signed value times a weight, as a 64 bits two's complement integer, low word first.
+fn mul_wide(value: i32, weight: u32) -> vec2<u32> {
+    let magnitude = u32(abs(value));
+    let a = magnitude & 0xffffu;
+    let b = magnitude >> 16u;
+    let c = weight & 0xffffu;
+    let d = weight >> 16u;
+    let ad = a * d;
+    let bc = b * c;
+    var low = a * c;
+    var high = b * d;
+    low = low + (ad << 16u);
+    high = high + (ad >> 16u) + select(0u, 1u, low < (ad << 16u));
+    low = low + (bc << 16u);
+    high = high + (bc >> 16u) + select(0u, 1u, low < (bc << 16u));
+    if (value < 0) {
+        low = ~low + 1u;
+        high = ~high + select(0u, 1u, low == 0u);
+    }
+    return vec2<u32>(low, high);
+}
+
+// Adds a 64 bits integer to the sums of the batch, whatever the order of the invocations.
+fn add_batch_sum(index: u32, value: vec2<u32>) {
+    let previous = atomicAdd(&batch_sums.data[index], value.x);
+    let carry = select(0u, 1u, previous + value.x < previous);
+    atomicAdd(&batch_sums.data[index + 1u], value.y + carry);
+}
+
+// Reads a 64 bits fixed point sum back to a float, and resets it for the next batch.
+fn take_batch_sum(index: u32) -> f32 {
+    let low = atomicExchange(&batch_sums.data[index], 0u);
+    let high = atomicExchange(&batch_sums.data[index + 1u], 0u);
+    return (f32(bitcast<i32>(high)) * 4294967296.0 + f32(low)) / FIXED_SCALE;
+}
+
+// The centroid closest to the pixel, the first one on ties, like `find_centroid.wgsl`.
+fn closest(pixel: vec3<f32>) -> u32 {
+    var min_distance: f32 = max_f32;
+    var found_index: u32 = 0u;
+    for (var index: u32 = 0u; index < centroids.count; index = index + 1u) {
+        let distance: f32 = distance(pixel, centroids.data[index].rgb);
+        if (distance < min_distance) {
+            min_distance = distance;
+            found_index = index;
+        }
+    }
+    return found_index;
+}
+
+// Each invocation picks a pixel at random, and adds it to the sums of its closest centroid.
+[[stage(compute), workgroup_size(256)]]
+fn sample([[builtin(global_invocation_id)]] global_id : vec3<u32>) {
+    if (global_id.x >= settings.batch_size) {
+        return;
+    }
+
+    let dimensions = vec2<u32>(textureDimensions(pixels));
+    let iteration = atomicLoad(&convergence.data[centroids.count + 2u]);
+    let index = random(iteration * settings.batch_size + global_id.x) % (dimensions.x * dimensions.y);
+    let pixel = textureLoad(pixels, vec2<i32>(vec2<u32>(index % dimensions.x, index / dimensions.x)), 0);
+    // Transparent pixels have a weight of 0, and don't pull the centroid. Unique colors weigh
+    // their count of pixels.
+    if (pixel.a <= 0.0) {
+        return;
+    }
+
+    let base = closest(pixel.rgb) * 8u;
+    let weight = u32(pixel.a);
+    let color = vec3<i32>(round(pixel.rgb * FIXED_SCALE));
+    add_batch_sum(base, mul_wide(color.r, weight));
+    add_batch_sum(base + 2u, mul_wide(color.g, weight));
+    add_batch_sum(base + 4u, mul_wide(color.b, weight));
+    atomicAdd(&batch_sums.data[base + 6u], weight);
+}
+
+// Moves each centroid toward the mean of its pixels in the batch, by the share of the batch
+// among all the pixels it has seen, and counts the converged centroids. A centroid missing from
+// the batch keeps its previous convergence.
+[[stage(compute), workgroup_size(256)]]
+fn update([[builtin(local_invocation_id)]] local_id : vec3<u32>) {
+    for (var k = local_id.x; k < centroids.count; k = k + workgroup_size) {
+        let base = k * 8u;
+        let sum = vec3<f32>(take_batch_sum(base), take_batch_sum(base + 2u), take_batch_sum(base + 4u));
+        let count = atomicExchange(&batch_sums.data[base + 6u], 0u);
+        if (count > 0u) {
+            seen.data[k] = seen.data[k] + f32(count);
+            let rate = f32(count) / seen.data[k];
+            let previous = centroids.data[k].rgb;
+            let centroid = mix(previous, sum / f32(count), rate);
+            centroids.data[k] = vec4<f32>(centroid, 1.0);
+            atomicStore(&convergence.data[k], u32(distance(centroid, previous) < settings.convergence));
+        }
+    }
+    storageBarrier();
+    workgroupBarrier();
+
+    if (local_id.x == 0u) {
+        var converged = 0u;
+        for (var i = 0u; i < centroids.count; i = i + 1u) {
+            converged = converged + atomicLoad(&convergence.data[i]);
+        }
+        atomicStore(&convergence.data[centroids.count], converged);
+    }
+}
+
+// Runs at the start of each iteration: once every centroid converged, shrinks the dispatches of
+// the following iterations to nothing, like `gate` in `choose_centroid.wgsl`.
+[[stage(compute), workgroup_size(1)]]
+fn gate() {
+    let done = atomicLoad(&convergence.data[centroids.count]) >= centroids.count;
+    for (var i: u32 = 0u; i < DISPATCH_WORDS; i = i + 1u) {
+        dispatches.data[i] = select(dispatches.data[DISPATCH_WORDS + i], 0u, done);
+    }
+    if (!done) {
+        atomicAdd(&convergence.data[centroids.count + 2u], 1u);
+    }
+}
@@ -6,10 +6,16 @@ struct Centroids {
     data: array<vec4<f32>>;
 };
 
+struct Distances {
+    data: array<f32>;
+};
+
 [[group(0), binding(0)]] var input_texture: texture_2d<f32>;
 [[group(0), binding(1)]] var output_texture : texture_storage_2d<rgba32float, write>;
 [[group(0), binding(2)]] var color_indices: texture_2d<u32>;
 [[group(0), binding(3)]] var<storage, read> centroids: Centroids;
+// See `centroid_table.wgsl`, or a single value when there is no table.
+[[group(0), binding(4)]] var<storage, read> table: Distances;
 
 let index_matrix: array<i32, 16> = array<i32, 16>(0,  8,  2,  10,
                                                   12, 4,  14, 6,
@@ -24,7 +30,60 @@ fn index_value(coords: vec2<i32>) -> f32 {
     return f32(mine[index]) / 16.0;
 }
 
-fn two_closest_colors(color: vec4<f32>) -> array<vec4<f32>, 2> {
+// Twice the rounding error of a distance between colors, see `centroid_table.wgsl`.
+fn slack(value: f32) -> f32 {
+    return 0.0001 + abs(value) * 0.00001;
+}
+
+// Same as `two_closest_colors`, starting from the centroid of the pixel, and skipping the
+// centroids the table puts farther than the second closest so far. Ties go to the lowest index,
+// which gives the same colors as going over every centroid in order.
+fn two_closest_colors_bounded(color: vec4<f32>, coords: vec2<i32>) -> array<vec4<f32>, 2> {
+    var values: array<vec4<f32>, 2>;
+    let count = centroids.count;
+    let start = min(textureLoad(color_indices, coords, 0).r, count - 1u);
+    let start_distance = distance(color.rgb, centroids.data[start].rgb);
+
+    var closest_index = start;
+    var closest_distance = start_distance;
+    var second_index = count;
+    var second_distance = distance(color.rgb, vec3<f32>(10000.0));
+    for (var i: u32 = 0u; i < count; i = i + 1u) {
+        if (i == start) {
+            continue;
+        }
+
+        let centroids_distance = table.data[start * count + i];
+        let bound = centroids_distance - start_distance - slack(centroids_distance) - slack(start_distance);
+        if (bound - slack(bound) > second_distance) {
+            continue;
+        }
+
+        let temp_distance = distance(color.rgb, centroids.data[i].rgb);
+        if (temp_distance < closest_distance || (temp_distance == closest_distance && i < closest_index)) {
+            second_index = closest_index;
+            second_distance = closest_distance;
+            closest_index = i;
+            closest_distance = temp_distance;
+        } else if (temp_distance < second_distance || (temp_distance == second_distance && i < second_index)) {
+            second_index = i;
+            second_distance = temp_distance;
+        }
+    }
+    values[0] = centroids.data[closest_index];
+    values[1] = vec4<f32>(10000.0);
+    if (second_index < count) {
+        values[1] = centroids.data[second_index];
+    }
+
+    return values;
+}
+
+fn two_closest_colors(color: vec4<f32>, coords: vec2<i32>) -> array<vec4<f32>, 2> {
+    if (arrayLength(&table.data) >= centroids.count * centroids.count) {
+        return two_closest_colors_bounded(color, coords);
+    }
+
     var values: array<vec4<f32>, 2>;
     var closest = vec4<f32>(10000.0);
     var second_closest = vec4<f32>(10000.0);
@@ -46,7 +105,7 @@ fn two_closest_colors(color: vec4<f32>) -> array<vec4<f32>, 2> {
 }
 
 fn dither(color: vec4<f32>, coords: vec2<i32>) -> vec4<f32> {
-    let closest_colors = two_closest_colors(color);
+    let closest_colors = two_closest_colors(color, coords);
     let index_value = index_value(coords);
     let factor = distance(color.rgb, closest_colors[0].rgb) / distance(closest_colors[0].rgb, closest_colors[1].rgb);
 
@@ -72,7 +131,7 @@ fn dither2(color: vec4<f32>, coords: vec2<i32>) -> vec4<f32> {
 }
 
 fn meld(color: vec4<f32>, coords: vec2<i32>) -> vec4<f32> {
-    let closest_colors = two_closest_colors(color);
+    let closest_colors = two_closest_colors(color, coords);
     let index_value = index_value(coords);
     let factor = distance(color.rgb, closest_colors[1].rgb) / distance(closest_colors[0].rgb, closest_colors[1].rgb);
 
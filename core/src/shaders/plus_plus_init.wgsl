@@ -63,10 +63,6 @@ fn atomicLoadCandidate(index: u32, dimensions: vec2<i32>) -> Candidate {
     return output;
 }
 
-fn rand(seed: f32) -> f32 {
-    return fract(sin(dot(vec2<f32>(seed), vec2<f32>(12.9898,78.233))) * 43758.5453);
-}
-
 fn selectCandidate(a: Candidate, b: Candidate) -> Candidate {
     if (a.distance < b.distance) {
         return b;
@@ -89,7 +85,7 @@ fn main(
     let width = u32(dimensions.x);
     let global_x = workgroup_x * workgroup_size + local_id.x;
 
-    var local = Candidate(0u, 0.0);
+    var local = Candidate(0u, -1.0);
 
     for (var i: u32 = 0u; i < N_SEQ; i = i + 1u) {
         let pixel_index = global_x * N_SEQ + i;
@@ -165,17 +161,6 @@ fn main(
     }
 }
 
-[[stage(compute), workgroup_size(1)]]
-fn initial() {
-    let dimensions = textureDimensions(pixels);
-    let x = i32(f32(dimensions.x) * rand(42.0));
-    let y = i32(f32(dimensions.y) * rand(12.0));
-
-    let new_centroid = textureLoad(pixels, vec2<i32>(x, y), 0);
-
-    centroids.data[0] = new_centroid;
-}
-
 [[stage(compute), workgroup_size(1)]]
 fn pick() {
     let dimensions = textureDimensions(pixels);
@@ -0,0 +1,197 @@
+struct Centroids {
+    count: u32;
+    // Aligned 16. See https://www.w3.org/TR/WGSL/#address-space-layout-constraints
+    data: array<vec4<f32>>;
+};
+
+struct KIndex {
+    k: u32;
+};
+
+struct PartialSums {
+    data: array<f32>;
+};
+
+struct Settings {
+    // The u64 seed, low bits first.
+    seed: vec2<u32>;
+    // Whether every centroid is sampled like the first one, for the random init and the first
+    // candidate of k-means||.
+    opaque_only: u32;
+};
+
+let N_SEQ = 16u;
+let workgroup_size: u32 = 256u;
+
+[[group(0), binding(0)]] var<storage, read_write> centroids: Centroids;
+[[group(0), binding(1)]] var pixels: texture_2d<f32>;
+[[group(0), binding(2)]] var distance_map: texture_2d<f32>;
+[[group(0), binding(3)]] var<storage, read_write> partial_sums: PartialSums;
+[[group(0), binding(4)]] var<uniform> settings: Settings;
+[[group(1), binding(0)]] var<uniform> k_index: KIndex;
+
+var<workgroup> scratch: array<f32, workgroup_size>;
+var<workgroup> selected_group: u32;
+var<workgroup> remainder: f32;
+
+fn coords(global_x: u32, dimensions: vec2<i32>) -> vec2<i32> {
+    return vec2<i32>(vec2<u32>(global_x % u32(dimensions.x), global_x / u32(dimensions.x)));
+}
+
+fn in_bounds(global_x: u32, dimensions: vec2<i32>) -> bool {
+    return global_x < u32(dimensions.x) * u32(dimensions.y);
+}
+
+// PCG hash, see https://www.jcgt.org/published/0009/03/02/
+fn pcg(value: u32) -> u32 {
+    let state = value * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// A random number in [0, 1) for each stream, only depending on the seed.
+fn random(stream: u32) -> f32 {
+    let value = pcg(settings.seed.x ^ pcg(settings.seed.y ^ pcg(stream)));
+    return f32(value >> 8u) / 16777216.0;
+}
+
+// The probability of picking a pixel is proportional to its squared distance to the closest
+// centroid. Transparent pixels have a negative distance, and can never be picked. The first
+// centroid is any pixel opaque enough, all equally likely.
+fn weight(pixel_index: u32, dimensions: vec2<i32>) -> f32 {
+    if (k_index.k == 0u || settings.opaque_only != 0u) {
+        return select(0.0, 1.0, textureLoad(pixels, coords(pixel_index, dimensions), 0).a > 0.0);
+    }
+    let distance = max(textureLoad(distance_map, coords(pixel_index, dimensions), 0).r, 0.0);
+    return distance * distance;
+}
+
+// Inclusive prefix sum of the values of the workgroup, in place in scratch.
+fn scan(local_x: u32, value: f32) {
+    var sum = value;
+    scratch[local_x] = sum;
+    for (var i: u32 = 0u; i < 8u; i = i + 1u) {
+        workgroupBarrier();
+        if (local_x >= (1u << i)) {
+            sum = sum + scratch[local_x - (1u << i)];
+        }
+        workgroupBarrier();
+        scratch[local_x] = sum;
+    }
+    workgroupBarrier();
+}
+
+// Sums the weights of the pixels of each workgroup.
+[[stage(compute), workgroup_size(256)]]
+fn sum(
+    [[builtin(local_invocation_id)]] local_id : vec3<u32>,
+    [[builtin(workgroup_id)]] workgroup_id : vec3<u32>,
+) {
+    let dimensions = textureDimensions(pixels);
+    let global_x = workgroup_id.x * workgroup_size + local_id.x;
+
+    var local = 0.0;
+    for (var i: u32 = 0u; i < N_SEQ; i = i + 1u) {
+        let pixel_index = global_x * N_SEQ + i;
+        if (in_bounds(pixel_index, dimensions)) {
+            local = local + weight(pixel_index, dimensions);
+        }
+    }
+
+    scan(local_id.x, local);
+    if (local_id.x == workgroup_size - 1u) {
+        partial_sums.data[workgroup_id.x] = scratch[local_id.x];
+    }
+}
+
+// Draws a threshold below the total weight, and picks the pixel where the prefix sum of the
+// weights crosses it: first among the workgroup sums, then among the pixels of that workgroup.
+[[stage(compute), workgroup_size(256)]]
+fn sample(
+    [[builtin(local_invocation_id)]] local_id : vec3<u32>,
+) {
+    let dimensions = textureDimensions(pixels);
+    let group_count = arrayLength(&partial_sums.data);
+    let chunk = (group_count + workgroup_size - 1u) / workgroup_size;
+    let first_group = local_id.x * chunk;
+    let last_group = min(first_group + chunk, group_count);
+
+    var local = 0.0;
+    for (var group = first_group; group < last_group; group = group + 1u) {
+        local = local + partial_sums.data[group];
+    }
+    scan(local_id.x, local);
+
+    let total = scratch[workgroup_size - 1u];
+    if (total <= 0.0) {
+        // Every pixel is already a centroid: there is nothing left to pick. Without any opaque
+        // pixel, the first centroid is left as is.
+        if (local_id.x == 0u && k_index.k > 0u) {
+            centroids.data[k_index.k] = centroids.data[k_index.k - 1u];
+        }
+        return;
+    }
+
+    let threshold = random(k_index.k) * total;
+    if (local_id.x == 0u) {
+        // Rounding may leave the threshold above every prefix: default to the last group, and
+        // to the previous centroid if no pixel is picked there.
+        selected_group = group_count - 1u;
+        remainder = 0.0;
+        if (k_index.k > 0u) {
+            centroids.data[k_index.k] = centroids.data[k_index.k - 1u];
+        }
+    }
+    storageBarrier();
+    workgroupBarrier();
+
+    let start = scratch[local_id.x] - local;
+    if (local > 0.0 && start <= threshold && threshold < scratch[local_id.x]) {
+        var prefix = start;
+        for (var group = first_group; group < last_group; group = group + 1u) {
+            let next = prefix + partial_sums.data[group];
+            if (threshold < next) {
+                selected_group = group;
+                remainder = threshold - prefix;
+                break;
+            }
+            prefix = next;
+        }
+    }
+    workgroupBarrier();
+
+    let group = selected_group;
+    let group_threshold = remainder;
+    let global_x = group * workgroup_size + local_id.x;
+    var pixel_sum = 0.0;
+    for (var i: u32 = 0u; i < N_SEQ; i = i + 1u) {
+        let pixel_index = global_x * N_SEQ + i;
+        if (in_bounds(pixel_index, dimensions)) {
+            pixel_sum = pixel_sum + weight(pixel_index, dimensions);
+        }
+    }
+    scan(local_id.x, pixel_sum);
+
+    let pixel_start = scratch[local_id.x] - pixel_sum;
+    let last_thread = local_id.x == workgroup_size - 1u;
+    // The last thread takes whatever rounding left above the sum of the group.
+    if (pixel_sum > 0.0 && pixel_start <= group_threshold
+        && (group_threshold < scratch[local_id.x] || last_thread)) {
+        var prefix = pixel_start;
+        var picked = global_x * N_SEQ;
+        for (var i: u32 = 0u; i < N_SEQ; i = i + 1u) {
+            let pixel_index = global_x * N_SEQ + i;
+            if (in_bounds(pixel_index, dimensions)) {
+                let pixel_weight = weight(pixel_index, dimensions);
+                if (pixel_weight > 0.0) {
+                    picked = pixel_index;
+                }
+                prefix = prefix + pixel_weight;
+                if (pixel_weight > 0.0 && group_threshold < prefix) {
+                    break;
+                }
+            }
+        }
+        centroids.data[k_index.k] = vec4<f32>(textureLoad(pixels, coords(picked, dimensions), 0).rgb, 1.0);
+    }
+}
@@ -0,0 +1,105 @@
+use wgpu::{CommandEncoderDescriptor, ComputePassDescriptor, Texture, TextureView};
+
+use crate::{
+    modules::{ColorReverterModule, Module, SwapModule},
+    CentroidsBuffer, ColorSpace, Error, GpuContext, ImageTextures, KMeansOptions, Result,
+    Timestamps,
+};
+
+/// Where [`crate::KMeansContext::kmeans_texture`] writes its result.
+pub enum TextureOutput<'a> {
+    /// Each pixel replaced by the color of its cluster, written to a view of an `Rgba8Unorm`
+    /// texture created with [`wgpu::TextureUsages::STORAGE_BINDING`].
+    Colors(&'a TextureView),
+    /// The index of the cluster of each pixel, copied to an `R32Uint` texture created with
+    /// [`wgpu::TextureUsages::COPY_DST`].
+    Labels(&'a Texture),
+}
+
+impl GpuContext<'_> {
+    /// Records the clustering of the input texture and the writing of its result, without waiting
+    /// for the gpu to finish.
+    pub(crate) async fn kmeans_texture(
+        &self,
+        k: u32,
+        input_view: &TextureView,
+        dimensions: (u32, u32),
+        output: TextureOutput<'_>,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Result<()> {
+        let device = &self.device;
+        // Unlike images, textures of the caller can't be split in tiles.
+        let max_dimension = device.limits().max_texture_dimension_2d;
+        if dimensions.0 > max_dimension || dimensions.1 > max_dimension {
+            return Err(Error::ImageTooLarge {
+                dimensions,
+                max_dimension,
+            });
+        }
+
+        let centroids_buffer = CentroidsBuffer::empty_centroids(k, device);
+        let textures = ImageTextures::new(device, dimensions);
+        // Nothing is read back, so there is no time to report either.
+        let timestamps = Timestamps::new(self, 0);
+
+        self.compute_centroids(
+            k,
+            dimensions,
+            color_space,
+            input_view,
+            &textures.work_texture,
+            &textures.color_index_texture,
+            &centroids_buffer,
+            options,
+            &timestamps,
+            None,
+            true,
+            None,
+        )
+        .await?;
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        match output {
+            TextureOutput::Colors(output_view) => {
+                let swap_module = SwapModule::new(
+                    device,
+                    &self.pipelines.swap,
+                    dimensions,
+                    &textures.work_texture,
+                    &centroids_buffer,
+                    &textures.color_index_texture,
+                );
+                let color_reverter_module = ColorReverterModule::new(
+                    device,
+                    self.pipelines.reverter(color_space),
+                    dimensions,
+                    &textures.work_texture,
+                    output_view,
+                    input_view,
+                );
+
+                let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("Swap to output texture pass"),
+                });
+                swap_module.dispatch(&mut compute_pass);
+                color_reverter_module.dispatch(&mut compute_pass);
+            }
+            TextureOutput::Labels(output_texture) => {
+                let (width, height) = dimensions;
+                encoder.copy_texture_to_texture(
+                    textures.color_index_texture.as_image_copy(),
+                    output_texture.as_image_copy(),
+                    wgpu::Extent3d {
+                        width,
+                        height,
+                        depth_or_array_layers: 1,
+                    },
+                );
+            }
+        }
+        self.queue.submit(Some(encoder.finish()));
+
+        self.check_device()
+    }
+}
@@ -0,0 +1,701 @@
+use wgpu::{CommandEncoder, CommandEncoderDescriptor, ComputePassDescriptor, Device, MapMode};
+
+use crate::{
+    modules::{
+        ChooseCentroidModule, ChooseCentroidState, ClusterStatsModule, ColorConverterModule,
+        ColorReverterModule, Convergence, FindCentroidModule, MiniBatchModule, MixColorsModule,
+        Module, SwapModule,
+    },
+    sort_by_lightness, Algorithm, CentroidsBuffer, ColorIndexTexture, ColorSpace, Estimation,
+    GpuContext, Image, InputTexture, KMeansOptions, KMeansResult, MixMode, OutputBuffer,
+    OutputTexture, Phase, Result, Timestamps, WorkTexture,
+};
+
+/// Largest side of a tile. Bigger tiles would need gigabytes of textures.
+const MAX_TILE_SIZE: u32 = 4096;
+
+/// A part of an image too large for a single texture.
+struct Tile {
+    origin: (u32, u32),
+    image: Image,
+}
+
+/// Splits the image in tiles of at most `tile_size` pixels on each side, row by row.
+fn tiles(image: &Image, tile_size: u32) -> impl Iterator<Item = Tile> + '_ {
+    let (width, height) = image.dimensions;
+    (0..height).step_by(tile_size as usize).flat_map(move |y| {
+        (0..width).step_by(tile_size as usize).map(move |x| {
+            let dimensions = (tile_size.min(width - x), tile_size.min(height - y));
+            Tile {
+                origin: (x, y),
+                image: crop(image, (x, y), dimensions),
+            }
+        })
+    })
+}
+
+fn crop(image: &Image, (x, y): (u32, u32), (width, height): (u32, u32)) -> Image {
+    let mut rgba = Vec::with_capacity(width as usize * height as usize);
+    for row in y..y + height {
+        let start = x as usize + row as usize * image.dimensions.0 as usize;
+        rgba.extend_from_slice(&image.rgba[start..start + width as usize]);
+    }
+    Image::new((width, height), rgba)
+}
+
+/// Copies the pixels computed for a tile at their place in the whole image.
+fn paste<T: Copy>(target: &mut [T], target_width: u32, tile: &Tile, pixels: &[T]) {
+    let (x, y) = tile.origin;
+    let width = tile.image.dimensions.0 as usize;
+    for (row, pixels) in pixels.chunks_exact(width).enumerate() {
+        let start = x as usize + (y as usize + row) * target_width as usize;
+        target[start..start + width].copy_from_slice(pixels);
+    }
+}
+
+/// How many pixels [`downsample`] steps over in both directions.
+pub(crate) fn downsample_stride((width, height): (u32, u32), max_dimension: u32) -> u32 {
+    width.max(height).div_ceil(max_dimension)
+}
+
+/// Keeps one pixel out of `stride` in both directions, so that the image fits in
+/// `max_dimension`.
+pub(crate) fn downsample(image: &Image, max_dimension: u32) -> Image {
+    let (width, height) = image.dimensions;
+    let stride = downsample_stride(image.dimensions, max_dimension) as usize;
+    let dimensions = (
+        width.div_ceil(stride as u32),
+        height.div_ceil(stride as u32),
+    );
+
+    let rgba = (0..height as usize)
+        .step_by(stride)
+        .flat_map(|y| {
+            (0..width as usize)
+                .step_by(stride)
+                .map(move |x| image.rgba[x + y * width as usize])
+        })
+        .collect();
+    Image::new(dimensions, rgba)
+}
+
+/// The textures of a tile, converted to the work color space and labelled with the index of
+/// the closest centroid.
+struct TileTextures {
+    input_texture: InputTexture,
+    work_texture: WorkTexture,
+    color_index_texture: ColorIndexTexture,
+}
+
+impl TileTextures {
+    fn new(device: &Device, dimensions: (u32, u32)) -> Self {
+        Self {
+            input_texture: InputTexture::empty(device, dimensions),
+            work_texture: WorkTexture::new(device, dimensions),
+            color_index_texture: ColorIndexTexture::new(device, dimensions),
+        }
+    }
+}
+
+/// The textures and the module of the tiles of one size, created once for all the iterations.
+/// An image has at most four sizes of tiles: whole ones, and the ones cut by its right and
+/// bottom edges.
+struct TileSize<'a> {
+    dimensions: (u32, u32),
+    textures: TileTextures,
+    choose_centroid_module: ChooseCentroidModule<'a>,
+}
+
+impl GpuContext<'_> {
+    /// Whether the image is larger than the textures this device supports.
+    pub(crate) fn needs_tiles(&self, image: &Image) -> bool {
+        let max_dimension = self.device.limits().max_texture_dimension_2d;
+        image.dimensions.0 > max_dimension || image.dimensions.1 > max_dimension
+    }
+
+    pub(crate) fn tile_size(&self) -> u32 {
+        self.device
+            .limits()
+            .max_texture_dimension_2d
+            .min(MAX_TILE_SIZE)
+    }
+
+    pub(crate) async fn kmeans_tiled(
+        &self,
+        k: u32,
+        image: &Image,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Result<Image> {
+        let centroids_buffer = CentroidsBuffer::empty_centroids(k, &self.device);
+        self.compute_tiled_centroids(k, image, color_space, &centroids_buffer, options)
+            .await?;
+
+        self.render_tiles(image, color_space, &centroids_buffer, None)
+            .await
+    }
+
+    pub(crate) async fn palette_tiled(
+        &self,
+        k: u32,
+        image: &Image,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Result<Vec<[u8; 4]>> {
+        let centroids_buffer = CentroidsBuffer::empty_centroids(k, &self.device);
+        self.compute_tiled_centroids(k, image, color_space, &centroids_buffer, options)
+            .await?;
+
+        let mut colors: Vec<_> = self
+            .read_centroids(&centroids_buffer)
+            .await?
+            .iter()
+            .map(|centroid| color_space.to_rgba(centroid))
+            .collect();
+        sort_by_lightness(&mut colors);
+        Ok(colors)
+    }
+
+    pub(crate) async fn cluster_tiled(
+        &self,
+        k: u32,
+        image: &Image,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Result<KMeansResult> {
+        let device = &self.device;
+        let queue = &self.queue;
+
+        let centroids_buffer = CentroidsBuffer::empty_centroids(k, device);
+        let convergence = self
+            .compute_tiled_centroids(k, image, color_space, &centroids_buffer, options)
+            .await?;
+
+        let (width, height) = image.dimensions;
+        let mut labels = vec![0; width as usize * height as usize];
+        let mut counts = vec![0; k as usize];
+        let mut inertia = 0.0;
+        for tile in tiles(image, self.tile_size()) {
+            let mut encoder =
+                device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+            let textures = TileTextures::new(device, tile.image.dimensions);
+            self.prepare_tile(
+                &textures,
+                &tile.image,
+                color_space,
+                &centroids_buffer,
+                options.alpha_threshold,
+                &mut encoder,
+            );
+            let cluster_stats_module = ClusterStatsModule::new(
+                device,
+                &self.pipelines.cluster_stats,
+                tile.image.dimensions,
+                k,
+                color_space,
+                &textures.work_texture,
+                &centroids_buffer,
+                &textures.color_index_texture,
+            );
+            {
+                let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("Cluster stats pass"),
+                });
+                cluster_stats_module.dispatch(&mut compute_pass);
+            }
+
+            let (counts_staging_buffer, inertia_staging_buffer) =
+                cluster_stats_module.staging_buffers(device, &mut encoder);
+            let labels_buffer = textures.color_index_texture.output_buffer(
+                device,
+                &mut encoder,
+                tile.image.dimensions,
+            );
+            queue.submit(Some(encoder.finish()));
+
+            let counts_slice = counts_staging_buffer.slice(..);
+            let counts_future = counts_slice.map_async(MapMode::Read);
+            let inertia_slice = inertia_staging_buffer.slice(..);
+            let inertia_future = inertia_slice.map_async(MapMode::Read);
+            let labels_slice = labels_buffer.slice(..);
+            let labels_future = labels_slice.map_async(MapMode::Read);
+
+            device.poll(wgpu::Maintain::Wait);
+            self.check_device()?;
+
+            counts_future.await?;
+            inertia_future.await?;
+            labels_future.await?;
+
+            for (count, tile_count) in counts.iter_mut().zip(bytemuck::cast_slice::<u8, u32>(
+                &counts_slice.get_mapped_range(),
+            )) {
+                *count += tile_count;
+            }
+            inertia += bytemuck::cast_slice::<u8, f32>(&inertia_slice.get_mapped_range())
+                .iter()
+                .map(|&partial_sum| partial_sum as f64)
+                .sum::<f64>();
+            paste(
+                &mut labels,
+                width,
+                &tile,
+                bytemuck::cast_slice(&labels_buffer.unpad(&labels_slice.get_mapped_range())),
+            );
+        }
+
+        let centroids = self.read_centroids(&centroids_buffer).await?;
+        let colors = centroids
+            .iter()
+            .map(|centroid| color_space.to_rgba(centroid))
+            .collect();
+
+        Ok(KMeansResult {
+            dimensions: image.dimensions,
+            centroids,
+            colors,
+            counts,
+            labels,
+            inertia,
+            iterations: convergence.iterations,
+            converged: convergence.converged,
+            reseeds: convergence.reseeds,
+            timings: None,
+        })
+    }
+
+    pub(crate) async fn find_tiled(
+        &self,
+        image: &Image,
+        colors: &[[u8; 4]],
+        color_space: &ColorSpace,
+    ) -> Result<Image> {
+        let centroids_buffer = CentroidsBuffer::fixed_centroids(colors, color_space, &self.device);
+
+        self.render_tiles(image, color_space, &centroids_buffer, None)
+            .await
+    }
+
+    pub(crate) async fn mix_tiled(
+        &self,
+        k: u32,
+        image: &Image,
+        color_space: &ColorSpace,
+        mix_mode: &MixMode,
+        options: &KMeansOptions,
+    ) -> Result<Image> {
+        let centroids_buffer = CentroidsBuffer::empty_centroids(k, &self.device);
+        self.compute_tiled_centroids(k, image, color_space, &centroids_buffer, options)
+            .await?;
+
+        self.render_tiles(image, color_space, &centroids_buffer, Some(mix_mode))
+            .await
+    }
+
+    /// Runs the ++ init on a downsampled copy of the image, then the k-means iterations over
+    /// all the tiles, or only over the estimate of that copy or random batches of it when the
+    /// options ask for them. The sums of every tile are accumulated before moving the centroids,
+    /// so the result is the same as if the image fit in a single texture.
+    async fn compute_tiled_centroids(
+        &self,
+        k: u32,
+        image: &Image,
+        color_space: &ColorSpace,
+        centroids_buffer: &CentroidsBuffer,
+        options: &KMeansOptions,
+    ) -> Result<Convergence> {
+        let device = &self.device;
+        let queue = &self.queue;
+        let tile_size = self.tile_size();
+
+        options.report_progress(Phase::Convert, 0, 0)?;
+        if options.estimation != Estimation::Full {
+            // The estimate is much smaller than a tile, so it is clustered in a single texture,
+            // and the tiles are only labelled when they are rendered.
+            let downsampled = downsample(image, tile_size);
+            let input_texture = InputTexture::new(device, queue, &downsampled);
+            let timestamps = Timestamps::new(self, options.max_iterations);
+            if let Some(textures) = self.estimate(
+                downsampled.dimensions,
+                color_space,
+                &input_texture.view(),
+                downsample_stride(image.dimensions, tile_size),
+                options,
+                &timestamps,
+            )? {
+                return self
+                    .cluster_converted(
+                        k,
+                        textures.dimensions,
+                        color_space,
+                        &textures.work_texture,
+                        &textures.color_index_texture,
+                        centroids_buffer,
+                        None,
+                        options,
+                        &timestamps,
+                    )
+                    .await;
+            }
+        }
+        {
+            let downsampled = downsample(image, tile_size);
+            let input_texture = InputTexture::new(device, queue, &downsampled);
+            let work_texture = WorkTexture::new(device, downsampled.dimensions);
+            let color_converter_module = ColorConverterModule::new(
+                device,
+                self.pipelines.converter(color_space),
+                downsampled.dimensions,
+                &input_texture.view(),
+                &work_texture,
+                options.alpha_threshold,
+            );
+
+            let mut encoder =
+                device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+            {
+                let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("Init pass"),
+                });
+                color_converter_module.dispatch(&mut compute_pass);
+            }
+            queue.submit(Some(encoder.finish()));
+
+            options.report_progress(Phase::Init, 0, 0)?;
+            self.init_centroids(
+                k,
+                downsampled.dimensions,
+                color_space,
+                &work_texture,
+                centroids_buffer,
+                options,
+                options.seed,
+            )
+            .await?;
+
+            if let Algorithm::MiniBatch(batch_size) = options.algorithm {
+                // Random batches of the downsampled copy stand for the whole image, and the
+                // tiles are only labelled when they are rendered.
+                let color_index_texture = ColorIndexTexture::new(device, downsampled.dimensions);
+                let find_centroid_module = FindCentroidModule::new(
+                    device,
+                    &self.pipelines.find_centroid,
+                    downsampled.dimensions,
+                    &work_texture,
+                    centroids_buffer,
+                    &color_index_texture,
+                );
+                return MiniBatchModule::new(
+                    device,
+                    &self.pipelines.mini_batch,
+                    k,
+                    batch_size,
+                    &work_texture,
+                    centroids_buffer,
+                    color_space,
+                    options,
+                )
+                .compute(
+                    device,
+                    queue,
+                    options,
+                    &find_centroid_module,
+                    &Timestamps::new(self, options.max_iterations),
+                )
+                .await;
+            }
+        }
+
+        // Restarts would upload every tile again for each run, so this path runs once whatever
+        // `n_init`, as documented.
+        let (width, height) = image.dimensions;
+        let tile_count = width.div_ceil(tile_size) as usize * height.div_ceil(tile_size) as usize;
+        let choose_centroid_state = ChooseCentroidState::new(device, k, color_space, options);
+        let mut tile_sizes: Vec<TileSize> = Vec::new();
+        for tile in tiles(image, tile_size) {
+            let dimensions = tile.image.dimensions;
+            if tile_sizes.iter().any(|size| size.dimensions == dimensions) {
+                continue;
+            }
+            let textures = TileTextures::new(device, dimensions);
+            let choose_centroid_module = ChooseCentroidModule::new(
+                device,
+                &self.pipelines.choose_centroid,
+                &choose_centroid_state,
+                dimensions,
+                k,
+                &textures.work_texture,
+                centroids_buffer,
+                &textures.color_index_texture,
+            );
+            tile_sizes.push(TileSize {
+                dimensions,
+                textures,
+                choose_centroid_module,
+            });
+        }
+
+        let mut converged = 0;
+        let mut reseeds = 0;
+        // The farthest pixels are only searched once a cluster was found empty, as the tiles
+        // are gone by the time the centroids move, so empty clusters wait one more iteration.
+        let mut empties = 0;
+        for iteration in 0..options.max_iterations {
+            options.report_progress(Phase::Iterate, iteration, converged)?;
+
+            for (index, tile) in tiles(image, tile_size).enumerate() {
+                let mut encoder =
+                    device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+                let TileSize {
+                    textures,
+                    choose_centroid_module,
+                    ..
+                } = tile_sizes
+                    .iter()
+                    .find(|size| size.dimensions == tile.image.dimensions)
+                    .expect("Every size of tile has its textures");
+                self.prepare_tile(
+                    textures,
+                    &tile.image,
+                    color_space,
+                    centroids_buffer,
+                    options.alpha_threshold,
+                    &mut encoder,
+                );
+                choose_centroid_module.accumulate(&mut encoder);
+                if empties > 0 {
+                    choose_centroid_module.measure_farthest(&mut encoder);
+                }
+
+                if index + 1 < tile_count {
+                    queue.submit(Some(encoder.finish()));
+                    // Let each tile finish before uploading the next one, to bound the memory.
+                    device.poll(wgpu::Maintain::Wait);
+                    continue;
+                }
+
+                choose_centroid_module.finalize(&mut encoder);
+                choose_centroid_module.reseed(&mut encoder);
+                // Each tile already waits for the gpu, so checking every iteration is cheap, and
+                // stops at the same iteration as smaller images.
+                let counts = choose_centroid_state
+                    .converged_count(device, queue, encoder)
+                    .await?;
+                (converged, reseeds, empties) = (counts.converged, counts.reseeds, counts.empties);
+                if converged >= k {
+                    self.check_device()?;
+                    options.report_progress(Phase::Finalize, iteration + 1, converged)?;
+                    return Ok(Convergence {
+                        iterations: iteration + 1,
+                        converged: true,
+                        reseeds,
+                    });
+                }
+            }
+        }
+
+        self.check_device()?;
+        options.report_progress(Phase::Finalize, options.max_iterations, converged)?;
+        Ok(Convergence {
+            iterations: options.max_iterations,
+            converged: false,
+            reseeds,
+        })
+    }
+
+    /// Uploads a tile to textures of its size, and records its conversion and the search of the
+    /// closest centroids.
+    fn prepare_tile(
+        &self,
+        textures: &TileTextures,
+        tile: &Image,
+        color_space: &ColorSpace,
+        centroids_buffer: &CentroidsBuffer,
+        alpha_threshold: u8,
+        encoder: &mut CommandEncoder,
+    ) {
+        let device = &self.device;
+        textures.input_texture.write(&self.queue, tile);
+
+        let color_converter_module = ColorConverterModule::new(
+            device,
+            self.pipelines.converter(color_space),
+            tile.dimensions,
+            &textures.input_texture.view(),
+            &textures.work_texture,
+            alpha_threshold,
+        );
+        let find_centroid_module = FindCentroidModule::new(
+            device,
+            &self.pipelines.find_centroid,
+            tile.dimensions,
+            &textures.work_texture,
+            centroids_buffer,
+            &textures.color_index_texture,
+        );
+
+        let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("Prepare tile pass"),
+        });
+        color_converter_module.dispatch(&mut compute_pass);
+        find_centroid_module.dispatch(&mut compute_pass);
+    }
+
+    /// Replaces the colors of every tile by their centroid, or mixes them, and stitches the
+    /// tiles back together.
+    async fn render_tiles(
+        &self,
+        image: &Image,
+        color_space: &ColorSpace,
+        centroids_buffer: &CentroidsBuffer,
+        mix_mode: Option<&MixMode>,
+    ) -> Result<Image> {
+        let device = &self.device;
+        let queue = &self.queue;
+
+        let (width, height) = image.dimensions;
+        let mut rgba = vec![[0; 4]; width as usize * height as usize];
+        for tile in tiles(image, self.tile_size()) {
+            let mut encoder =
+                device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+            let textures = TileTextures::new(device, tile.image.dimensions);
+            self.prepare_tile(
+                &textures,
+                &tile.image,
+                color_space,
+                centroids_buffer,
+                0,
+                &mut encoder,
+            );
+            let output_texture = OutputTexture::new(device, tile.image.dimensions);
+
+            match mix_mode {
+                None => {
+                    let swap_module = SwapModule::new(
+                        device,
+                        &self.pipelines.swap,
+                        tile.image.dimensions,
+                        &textures.work_texture,
+                        centroids_buffer,
+                        &textures.color_index_texture,
+                    );
+                    let color_reverter_module = ColorReverterModule::new(
+                        device,
+                        self.pipelines.reverter(color_space),
+                        tile.image.dimensions,
+                        &textures.work_texture,
+                        &output_texture.view(),
+                        &textures.input_texture.view(),
+                    );
+
+                    let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                        label: Some("Swap and fetch result pass"),
+                    });
+                    swap_module.dispatch(&mut compute_pass);
+                    color_reverter_module.dispatch(&mut compute_pass);
+                }
+                Some(mix_mode) => {
+                    let dithered_texture = WorkTexture::new(device, tile.image.dimensions);
+                    let mix_colors_module = MixColorsModule::new(
+                        device,
+                        &self.pipelines.mix_colors,
+                        Some(&self.pipelines.centroid_table),
+                        tile.image.dimensions,
+                        &textures.work_texture,
+                        &dithered_texture,
+                        &textures.color_index_texture,
+                        centroids_buffer,
+                        mix_mode,
+                    );
+                    let color_reverter_module = ColorReverterModule::new(
+                        device,
+                        self.pipelines.reverter(color_space),
+                        tile.image.dimensions,
+                        &dithered_texture,
+                        &output_texture.view(),
+                        &textures.input_texture.view(),
+                    );
+
+                    let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                        label: Some("Mix and fetch result pass"),
+                    });
+                    mix_colors_module.dispatch(&mut compute_pass);
+                    color_reverter_module.dispatch(&mut compute_pass);
+                }
+            }
+
+            let output_buffer = output_texture.output_buffer(device, &mut encoder);
+            queue.submit(Some(encoder.finish()));
+
+            let pixels = self.read_tile(&output_buffer).await?;
+            paste(&mut rgba, width, &tile, bytemuck::cast_slice(&pixels));
+        }
+
+        Ok(Image::new(image.dimensions, rgba))
+    }
+
+    async fn read_tile(&self, output_buffer: &OutputBuffer) -> Result<Vec<u8>> {
+        let buffer_slice = output_buffer.slice(..);
+        let buffer_future = buffer_slice.map_async(MapMode::Read);
+
+        self.device.poll(wgpu::Maintain::Wait);
+        self.check_device()?;
+        buffer_future.await?;
+
+        let pixels = output_buffer.unpad(&buffer_slice.get_mapped_range());
+        Ok(pixels)
+    }
+
+    pub(crate) async fn read_centroids(
+        &self,
+        centroids_buffer: &CentroidsBuffer,
+    ) -> Result<Vec<[f32; 3]>> {
+        let mut encoder = self
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor { label: None });
+        let staging_buffer = centroids_buffer.staging_buffer(&self.device, &mut encoder);
+        self.queue.submit(Some(encoder.finish()));
+
+        let centroids_slice = staging_buffer.slice(..);
+        let centroids_future = centroids_slice.map_async(MapMode::Read);
+
+        self.device.poll(wgpu::Maintain::Wait);
+        self.check_device()?;
+        centroids_future.await?;
+
+        let centroids = bytemuck::cast_slice::<u8, f32>(&centroids_slice.get_mapped_range()[16..])
+            .chunks_exact(4)
+            .map(|centroid| [centroid[0], centroid[1], centroid[2]])
+            .collect();
+        Ok(centroids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiles_cover_the_image() {
+        let rgba = (0..35u8).map(|i| [i, 0, 0, 255]).collect();
+        let image = Image::new((7, 5), rgba);
+
+        let mut stitched = vec![[0; 4]; 35];
+        for tile in tiles(&image, 3) {
+            assert!(tile.image.dimensions.0 <= 3 && tile.image.dimensions.1 <= 3);
+            paste(&mut stitched, 7, &tile, &tile.image.rgba);
+        }
+        assert_eq!(stitched, image.rgba);
+    }
+
+    #[test]
+    fn test_downsample() {
+        let rgba = (0..35u8).map(|i| [i, 0, 0, 255]).collect();
+        let image = Image::new((7, 5), rgba);
+
+        let downsampled = downsample(&image, 3);
+        assert_eq!(downsampled.dimensions, (3, 2));
+        assert_eq!(downsampled.rgba[1][0], 3);
+        assert_eq!(downsampled.rgba[3][0], 21);
+    }
+}
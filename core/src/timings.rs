@@ -0,0 +1,35 @@
+use std::time::Duration;
+
+use log::debug;
+
+/// Time spent on each phase of a clustering: measured on the gpu with timestamp queries, or with
+/// the system clock on the cpu backend.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Timings {
+    /// Conversion of the image to the working color space.
+    pub conversion: Duration,
+    /// Picking of the initial centroids.
+    pub init: Duration,
+    /// Each k-means iteration that ran, in order.
+    pub iterations: Vec<Duration>,
+    /// Everything after the iterations: computing the outputs and copying them back.
+    pub readback: Duration,
+}
+
+impl Timings {
+    pub fn total(&self) -> Duration {
+        self.conversion + self.init + self.iterations.iter().sum::<Duration>() + self.readback
+    }
+
+    pub(crate) fn log(&self) {
+        debug!("Conversion: {:?}", self.conversion);
+        debug!("Init: {:?}", self.init);
+        debug!(
+            "{} iterations: {:?}",
+            self.iterations.len(),
+            self.iterations.iter().sum::<Duration>()
+        );
+        debug!("Readback: {:?}", self.readback);
+        debug!("Total: {:?}", self.total());
+    }
+}
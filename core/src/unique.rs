@@ -0,0 +1,154 @@
+use log::debug;
+use std::collections::HashMap;
+
+use crate::{cpu::convert, ColorSpace, GpuContext, Image, ImageTextures, KMeansOptions};
+
+/// The distinct colors of an image, each weighted by its count of pixels.
+pub(crate) struct UniqueColors {
+    /// A single row of colors, in the order of their first pixel.
+    colors: Image,
+    weights: Vec<u32>,
+}
+
+impl UniqueColors {
+    /// Above, counting the colors on the cpu would take longer than clustering every pixel on
+    /// the gpu.
+    pub(crate) const MAX_PIXELS: usize = 1 << 22;
+
+    /// Counts the distinct colors of the pixels opaque enough to be clustered, or gives up when
+    /// they are more than half the pixels, as clustering them would hardly be faster. The count
+    /// runs on the cpu, so images of more than [`Self::MAX_PIXELS`] are not counted at all.
+    pub(crate) fn new(image: &Image, alpha_threshold: u8) -> Option<Self> {
+        if image.rgba.len() > Self::MAX_PIXELS {
+            debug!("Too many pixels to count the unique colors");
+            return None;
+        }
+
+        let max_colors = image.rgba.len() / 2;
+        let mut indices = HashMap::new();
+        let mut colors = Vec::new();
+        let mut weights = Vec::new();
+        for rgba in &image.rgba {
+            if rgba[3] < alpha_threshold {
+                continue;
+            }
+
+            let index = *indices
+                .entry(u32::from_le_bytes([rgba[0], rgba[1], rgba[2], 0]))
+                .or_insert_with(|| {
+                    colors.push([rgba[0], rgba[1], rgba[2], 255]);
+                    weights.push(0);
+                    colors.len() - 1
+                });
+            weights[index] += 1;
+            if colors.len() > max_colors {
+                return None;
+            }
+        }
+        if colors.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            colors: Image::new((colors.len() as u32, 1), colors),
+            weights,
+        })
+    }
+
+    /// Rows as wide as the image, so that the colors fit in a texture wherever the image does.
+    fn dimensions(&self, image: &Image) -> (u32, u32) {
+        let width = image.dimensions.0;
+        (width, (self.weights.len() as u32).div_ceil(width))
+    }
+
+    /// The colors in the work color space, with their weight in the alpha channel, where the
+    /// converters store 1 for each opaque enough pixel. Padding texels weigh 0.
+    fn texels(&self, color_space: &ColorSpace, (width, height): (u32, u32)) -> Vec<[f32; 4]> {
+        let mut texels = vec![[0.0; 4]; (width * height) as usize];
+        let colors = convert(&self.colors, color_space);
+        for ((texel, [r, g, b]), &weight) in texels.iter_mut().zip(colors).zip(&self.weights) {
+            // Exact up to 2^24 pixels of the same color, and close enough above.
+            *texel = [r, g, b, weight as f32];
+        }
+        texels
+    }
+}
+
+impl GpuContext<'_> {
+    /// Uploads the unique colors of the image, when the options ask for them and there are few
+    /// enough. The iterations then run over these colors instead of every pixel.
+    pub(crate) fn unique_textures(
+        &self,
+        image: &Image,
+        color_space: &ColorSpace,
+        options: &KMeansOptions,
+    ) -> Option<ImageTextures> {
+        if !options.unique_colors || self.needs_tiles(image) {
+            return None;
+        }
+        let unique_colors = UniqueColors::new(image, options.alpha_threshold)?;
+        debug!(
+            "{} unique colors out of {} pixels",
+            unique_colors.weights.len(),
+            image.rgba.len()
+        );
+
+        let dimensions = unique_colors.dimensions(image);
+        let textures = ImageTextures::new(&self.device, dimensions);
+        textures.work_texture.write(
+            &self.queue,
+            dimensions,
+            &unique_colors.texels(color_space, dimensions),
+        );
+        Some(textures)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unique_colors() {
+        let red = [255, 0, 0, 255];
+        let blue = [0, 0, 255, 128];
+        let clear = [0, 255, 0, 0];
+        let image = Image::new(
+            (3, 3),
+            vec![red, blue, red, clear, red, red, blue, red, clear],
+        );
+
+        let unique_colors = UniqueColors::new(&image, 1).unwrap();
+        assert_eq!(unique_colors.colors.rgba, vec![red, [0, 0, 255, 255]]);
+        assert_eq!(unique_colors.weights, vec![5, 2]);
+        assert_eq!(unique_colors.dimensions(&image), (3, 1));
+
+        let texels = unique_colors.texels(&ColorSpace::Rgb, (3, 1));
+        assert_eq!(
+            texels,
+            vec![[1.0, 0.0, 0.0, 5.0], [0.0, 0.0, 1.0, 2.0], [0.0; 4]]
+        );
+
+        // Without a threshold, transparent pixels are clustered too.
+        assert!(UniqueColors::new(&image, 0).is_some());
+        let noise = Image::new((2, 2), vec![red, blue, clear, [1, 2, 3, 255]]);
+        assert!(UniqueColors::new(&noise, 0).is_none());
+    }
+
+    #[test]
+    fn test_large_images_are_not_counted() {
+        let width = 2048;
+        let height = UniqueColors::MAX_PIXELS as u32 / width;
+        let image = Image::new(
+            (width, height),
+            vec![[255, 0, 0, 255]; (width * height) as usize],
+        );
+        assert!(UniqueColors::new(&image, 0).is_some());
+
+        let image = Image::new(
+            (width, height + 1),
+            vec![[255, 0, 0, 255]; (width * (height + 1)) as usize],
+        );
+        assert!(UniqueColors::new(&image, 0).is_none());
+    }
+}